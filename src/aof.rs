@@ -0,0 +1,1015 @@
+use std::{
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    config::Config,
+    mem::{Databases, MemDB},
+    rdb::{self, Snapshot},
+    resp::{
+        commands::structs::{Data, Value},
+        frame::RespFrame,
+        parser::{Request, parse_command, parse_request},
+    },
+    snapshot::{restore, sync_dir, take_snapshot},
+    transaction::exclusively,
+};
+
+// How often the background cycle checks whether the AOF has to be started or synced
+const AOF_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many list elements a rewrite puts in a single RPUSH
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+// When the written commands are flushed to disk
+#[derive(Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    // After every write, before the client gets its reply
+    Always,
+    // Once per second from the background, at most a second of writes is lost on a crash
+    EverySec,
+    // Whenever the operating system decides to
+    No,
+}
+
+impl FsyncPolicy {
+    fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(anyhow!(
+                "argument(s) must be one of the following: always, everysec, no"
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+}
+
+// A file of the AOF, named after appendfilename, its sequence number and its type
+#[derive(Clone)]
+struct AofFile {
+    name: String,
+    seq: u64,
+}
+
+// Manifest lists the files the AOF is made of, in the format of Redis 7: the base holds the
+// dataset as of the last rewrite and the incr files the writes since, replayed in order
+#[derive(Clone, Default)]
+struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    // Each line reads "file <name> seq <seq> type <b|i|h>", history files are already obsolete
+    fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if !parts.len().is_multiple_of(2) {
+                return Err(anyhow!("Invalid AOF manifest line: {}", line));
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in parts.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse().ok(),
+                    "type" => kind = Some(pair[1]),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(anyhow!("Invalid AOF manifest line: {}", line));
+            };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(AofFile { name, seq }),
+                "b" => return Err(anyhow!("Found duplicate base file information")),
+                "i" => manifest.incrs.push(AofFile { name, seq }),
+                "h" => {}
+                _ => return Err(anyhow!("Unknown AOF file type: {}", kind)),
+            }
+        }
+        if manifest.is_empty() {
+            return Err(anyhow!("Found an empty AOF manifest"));
+        }
+        Ok(manifest)
+    }
+
+    fn encode(&self) -> String {
+        let mut text = String::new();
+        if let Some(base) = &self.base {
+            text.push_str(&format!("file {} seq {} type b\n", base.name, base.seq));
+        }
+        for incr in &self.incrs {
+            text.push_str(&format!("file {} seq {} type i\n", incr.name, incr.seq));
+        }
+        text
+    }
+
+    fn is_empty(&self) -> bool {
+        self.base.is_none() && self.incrs.is_empty()
+    }
+
+    fn files(&self) -> Vec<&AofFile> {
+        self.base.iter().chain(self.incrs.iter()).collect()
+    }
+}
+
+struct State {
+    // enabled is the appendonly setting, the background cycle starts the AOF when it is
+    // turned on at runtime
+    enabled: bool,
+    filename: String,
+    dirname: String,
+    fsync: FsyncPolicy,
+    load_truncated: bool,
+    use_rdb_preamble: bool,
+    manifest: Manifest,
+    // file is the incr file writes are appended to
+    file: Option<File>,
+    // pending_fsync is set when commands were written since the last fsync
+    pending_fsync: bool,
+    last_fsync: Instant,
+    // written_offset is the replication offset the file holds the writes up to, synced_offset
+    // the one it is known to be on disk up to, which WAITAOF waits on
+    written_offset: u64,
+    synced_offset: u64,
+    last_write_ok: bool,
+    rewrite_in_progress: bool,
+    last_rewrite_ok: bool,
+}
+
+// Aof appends every write to the append only file, in the RESP form clients send them in.
+// The AOF is split into files tracked by a manifest like Redis 7 does. A rewrite switches
+// writes to a new incr file right away, then writes the new base in the background and
+// only drops the old files once the manifest lists the new ones, so a crash at any point
+// leaves a manifest whose files hold the whole dataset.
+#[derive(Clone)]
+pub struct Aof {
+    state: Arc<Mutex<State>>,
+}
+
+impl Aof {
+    pub fn new() -> Self {
+        Aof {
+            state: Arc::new(Mutex::new(State {
+                enabled: false,
+                filename: "appendonly.aof".to_string(),
+                dirname: "appendonlydir".to_string(),
+                fsync: FsyncPolicy::EverySec,
+                load_truncated: true,
+                use_rdb_preamble: true,
+                manifest: Manifest::default(),
+                file: None,
+                pending_fsync: false,
+                last_fsync: Instant::now(),
+                written_offset: 0,
+                synced_offset: 0,
+                last_write_ok: true,
+                rewrite_in_progress: false,
+                last_rewrite_ok: true,
+            })),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    /// Turns the AOF on or off. Turning it off closes the file right away, turning it on
+    /// leaves starting it to the background cycle.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = enabled;
+        if !enabled && let Some(file) = state.file.take() {
+            let _ = file.sync_data();
+        }
+    }
+
+    pub fn filename(&self) -> String {
+        self.state.lock().unwrap().filename.clone()
+    }
+
+    pub fn set_filename(&self, name: &str) -> Result<()> {
+        if name.contains('/') || name.contains('\\') {
+            return Err(anyhow!("appendfilename can't be a path, just a filename"));
+        }
+        self.state.lock().unwrap().filename = name.to_string();
+        Ok(())
+    }
+
+    pub fn dirname(&self) -> String {
+        self.state.lock().unwrap().dirname.clone()
+    }
+
+    pub fn set_dirname(&self, name: &str) -> Result<()> {
+        if name.contains('/') || name.contains('\\') {
+            return Err(anyhow!("appenddirname can't be a path, just a dirname"));
+        }
+        self.state.lock().unwrap().dirname = name.to_string();
+        Ok(())
+    }
+
+    pub fn fsync_policy(&self) -> String {
+        self.state.lock().unwrap().fsync.name().to_string()
+    }
+
+    pub fn set_fsync_policy(&self, policy: &str) -> Result<()> {
+        self.state.lock().unwrap().fsync = FsyncPolicy::parse(policy)?;
+        Ok(())
+    }
+
+    pub fn load_truncated(&self) -> bool {
+        self.state.lock().unwrap().load_truncated
+    }
+
+    pub fn set_load_truncated(&self, load_truncated: bool) {
+        self.state.lock().unwrap().load_truncated = load_truncated;
+    }
+
+    pub fn use_rdb_preamble(&self) -> bool {
+        self.state.lock().unwrap().use_rdb_preamble
+    }
+
+    pub fn set_use_rdb_preamble(&self, use_rdb_preamble: bool) {
+        self.state.lock().unwrap().use_rdb_preamble = use_rdb_preamble;
+    }
+
+    // The directory holding the files of the AOF and its manifest
+    fn dir(&self, config: &Config) -> PathBuf {
+        PathBuf::from(config.persistence.dir()).join(self.dirname())
+    }
+
+    /// Replays the files of the AOF into the empty databases. Returns false when there is no
+    /// AOF yet. The last file may be cut short in the middle of a command, as a crash leaves
+    /// it, it is then loaded up to the last complete command when aof-load-truncated is set.
+    pub fn load(&self, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<bool> {
+        let dir = self.dir(config);
+        let manifest_path = dir.join(format!("{}.manifest", self.filename()));
+        let manifest = match std::fs::read_to_string(&manifest_path) {
+            Ok(text) => Manifest::parse(&text)
+                .with_context(|| format!("loading {}", manifest_path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                match self.upgrade(config)? {
+                    Some(manifest) => manifest,
+                    None => return Ok(false),
+                }
+            }
+            Err(err) => return Err(err).context(format!("reading {}", manifest_path.display())),
+        };
+
+        let files = manifest.files();
+        let mut commands = 0;
+        for (i, file) in files.iter().enumerate() {
+            let last = i + 1 == files.len();
+            commands += self.load_file(&dir.join(&file.name), last, dbs, config)?;
+        }
+        self.state.lock().unwrap().manifest = manifest;
+        println!("DB loaded from append only file: {} commands", commands);
+        Ok(true)
+    }
+
+    // An AOF written before the files were split is a single file next to the RDB file. It
+    // is moved into the AOF directory and becomes the base of a new manifest.
+    fn upgrade(&self, config: &Config) -> Result<Option<Manifest>> {
+        let filename = self.filename();
+        let legacy = PathBuf::from(config.persistence.dir()).join(&filename);
+        if !legacy.exists() {
+            return Ok(None);
+        }
+
+        let dir = self.dir(config);
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        std::fs::rename(&legacy, dir.join(&filename))
+            .with_context(|| format!("moving {} to {}", legacy.display(), dir.display()))?;
+        let manifest = Manifest {
+            base: Some(AofFile {
+                name: filename.clone(),
+                seq: 1,
+            }),
+            incrs: Vec::new(),
+        };
+        write_manifest(&dir, &filename, &manifest)?;
+        println!(
+            "Moved {} to {} as the base of the AOF",
+            legacy.display(),
+            dir.display()
+        );
+        Ok(Some(manifest))
+    }
+
+    // Replays a single file, which may start with an RDB preamble or be a plain RDB file,
+    // returning how many commands it held. Its commands apply to database 0 until a SELECT.
+    fn load_file(
+        &self,
+        path: &Path,
+        last: bool,
+        dbs: &[&RwLock<MemDB<Data>>],
+        config: &Config,
+    ) -> Result<usize> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        let mut pos = 0;
+        if data.starts_with(b"REDIS") {
+            let (snapshot, len) = rdb::decode_prefix(&data)
+                .with_context(|| format!("loading the RDB preamble of {}", path.display()))?;
+            restore(snapshot, dbs, config)?;
+            pos = len;
+        }
+        let mut selected = 0;
+
+        // Commands of a transaction are only applied once its EXEC is read, and `loaded` only
+        // moves past complete commands and transactions
+        let mut transaction: Option<Vec<Request>> = None;
+        let mut loaded = pos;
+        let mut commands = 0;
+        loop {
+            let request = match parse_request(&data[pos..]) {
+                Ok(Some((request, len))) => {
+                    pos += len;
+                    request
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    return Err(anyhow!(
+                        "Bad file format reading the append only file {}: {}",
+                        path.display(),
+                        err
+                    ));
+                }
+            };
+
+            match (request.name.as_str(), transaction.as_mut()) {
+                ("multi", None) => transaction = Some(Vec::new()),
+                ("exec", Some(_)) => {
+                    for request in transaction.take().unwrap_or_default() {
+                        replay(request, dbs, &mut selected, config, path)?;
+                        commands += 1;
+                    }
+                    loaded = pos;
+                }
+                (_, Some(queued)) => queued.push(request),
+                (_, None) => {
+                    replay(request, dbs, &mut selected, config, path)?;
+                    commands += 1;
+                    loaded = pos;
+                }
+            }
+        }
+
+        if loaded < data.len() {
+            if !last || !self.load_truncated() {
+                return Err(anyhow!(
+                    "Unexpected end of file reading the append only file {}. Make a backup of it, then either remove the incomplete command at its end or set aof-load-truncated to yes and restart the server.",
+                    path.display()
+                ));
+            }
+            eprintln!(
+                "!!! Warning: short read while loading the AOF file {}, {} trailing bytes are dropped !!!",
+                path.display(),
+                data.len() - loaded
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(loaded as u64))
+                .with_context(|| format!("truncating {}", path.display()))?;
+        }
+        Ok(commands)
+    }
+
+    /// Starts appending once the AOF was loaded at startup, to its last incr file. Without
+    /// an AOF yet one is created from the dataset through a rewrite.
+    pub fn start(&self, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<()> {
+        let dir = self.dir(config);
+        let mut state = self.state.lock().unwrap();
+        if state.manifest.is_empty() {
+            drop(state);
+            return self.rewrite(dbs, config);
+        }
+
+        let Some(incr) = state.manifest.incrs.last() else {
+            drop(state);
+            return self.open_incr(&dir).map(|_| ());
+        };
+        let path = dir.join(&incr.name);
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        state.file = Some(file);
+        Ok(())
+    }
+
+    /// Compacts the AOF into a new base holding the current dataset, as an RDB file or as
+    /// the commands rebuilding it depending on aof-use-rdb-preamble. The writes made while
+    /// the base is written go to a new incr file.
+    pub fn rewrite(&self, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.rewrite_in_progress {
+                return Err(anyhow!(
+                    "ERR Background append only file rewriting already in progress"
+                ));
+            }
+            state.rewrite_in_progress = true;
+        }
+
+        // Clients are held off between the copy and the switch to the new incr file, so
+        // every write lands either in the base or in the incr file
+        let dir = self.dir(config);
+        let copied = exclusively(|| -> Result<(Snapshot, Option<u64>)> {
+            let (snapshot, _) = take_snapshot(dbs, config)?;
+            let incr = if self.enabled() {
+                let incr = self.open_incr(&dir)?;
+                config.reset_propagated_db();
+                Some(incr)
+            } else {
+                None
+            };
+            Ok((snapshot, incr))
+        });
+        let (snapshot, incr) = match copied {
+            Ok(copied) => copied,
+            Err(err) => {
+                self.finish_rewrite(false);
+                return Err(err);
+            }
+        };
+
+        let aof = self.clone();
+        let redis_version = config.server.redis_version.clone();
+        std::thread::spawn(move || {
+            let result = aof.write_base(&dir, &snapshot, &redis_version, incr);
+            if let Err(err) = &result {
+                eprintln!("Background AOF rewrite error: {}", err);
+            }
+            aof.finish_rewrite(result.is_ok());
+        });
+        Ok(())
+    }
+
+    // Creates the next incr file, lists it in the manifest and appends to it from now on,
+    // returning its sequence number
+    fn open_incr(&self, dir: &Path) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+
+        let seq = state.manifest.incrs.last().map_or(1, |incr| incr.seq + 1);
+        let name = format!("{}.{}.incr.aof", state.filename, seq);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(&name))
+            .with_context(|| format!("creating {}", name))?;
+
+        let mut manifest = state.manifest.clone();
+        manifest.incrs.push(AofFile { name, seq });
+        write_manifest(dir, &state.filename, &manifest)?;
+        state.manifest = manifest;
+        if let Some(previous) = state.file.replace(file) {
+            let _ = previous.sync_data();
+        }
+        state.last_write_ok = true;
+        Ok(seq)
+    }
+
+    // Writes the new base, then switches the manifest over to it along with the incr files
+    // from `incr` on, and removes the files it no longer lists
+    fn write_base(
+        &self,
+        dir: &Path,
+        snapshot: &Snapshot,
+        redis_version: &str,
+        incr: Option<u64>,
+    ) -> Result<()> {
+        let (filename, seq, use_rdb_preamble) = {
+            let state = self.state.lock().unwrap();
+            let seq = state.manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+            (state.filename.clone(), seq, state.use_rdb_preamble)
+        };
+
+        let commands = (!use_rdb_preamble)
+            .then(|| rewrite_commands(snapshot))
+            .flatten();
+        if !use_rdb_preamble && commands.is_none() {
+            eprintln!(
+                "The dataset holds values no command can rebuild, the AOF base is written as RDB"
+            );
+        }
+        let (name, payload) = match commands {
+            Some(commands) => (format!("{}.{}.base.aof", filename, seq), commands),
+            None => (
+                format!("{}.{}.base.rdb", filename, seq),
+                rdb::encode(snapshot, redis_version),
+            ),
+        };
+
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let temp = dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        File::create(&temp)
+            .and_then(|mut file| file.write_all(&payload).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&temp, dir.join(&name)))
+            .and_then(|_| sync_dir(&temp))
+            .map_err(|err| {
+                let _ = std::fs::remove_file(&temp);
+                anyhow!("Failed writing the AOF base: {}", err)
+            })?;
+
+        let mut state = self.state.lock().unwrap();
+        let previous = state.manifest.clone();
+        let manifest = Manifest {
+            base: Some(AofFile { name, seq }),
+            incrs: previous
+                .incrs
+                .iter()
+                .filter(|file| incr.is_some_and(|incr| file.seq >= incr))
+                .cloned()
+                .collect(),
+        };
+        write_manifest(dir, &state.filename, &manifest)?;
+
+        for file in previous.files() {
+            if !manifest.files().iter().any(|kept| kept.name == file.name) {
+                let _ = std::fs::remove_file(dir.join(&file.name));
+            }
+        }
+        state.manifest = manifest;
+        Ok(())
+    }
+
+    fn finish_rewrite(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.rewrite_in_progress = false;
+        state.last_rewrite_ok = ok;
+    }
+
+    /// Appends commands, already encoded, to the current incr file. Nothing is written
+    /// while the AOF is off.
+    pub fn feed(&self, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let always = state.fsync == FsyncPolicy::Always;
+        let Some(file) = state.file.as_mut() else {
+            return;
+        };
+
+        let mut result = file.write_all(payload);
+        if result.is_ok() && always {
+            result = file.sync_data();
+        }
+
+        if let Err(err) = &result {
+            eprintln!("Error writing to the AOF file: {}", err);
+        }
+        state.last_write_ok = result.is_ok();
+        state.pending_fsync = !always;
+    }
+
+    /// Records that the file holds the writes up to replication `offset`. Under always they
+    /// are synced already, under no syncing is left to the system so they count as synced.
+    pub fn advance(&self, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() || !state.last_write_ok {
+            return;
+        }
+        state.written_offset = offset;
+        if state.fsync != FsyncPolicy::EverySec {
+            state.synced_offset = offset;
+        }
+    }
+
+    pub fn synced_offset(&self) -> u64 {
+        self.state.lock().unwrap().synced_offset
+    }
+
+    fn mark_synced(&self, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.synced_offset = state.synced_offset.max(offset);
+    }
+
+    // Syncs the file once a second under everysec. The sync runs on a duplicated handle so
+    // clients appending in the meantime aren't held up by the disk. The offset written so far
+    // is synced along with it.
+    fn sync_due(&self) -> Option<(File, u64)> {
+        let mut state = self.state.lock().unwrap();
+        if state.fsync != FsyncPolicy::EverySec
+            || !state.pending_fsync
+            || state.last_fsync.elapsed() < Duration::from_secs(1)
+        {
+            return None;
+        }
+        let file = state.file.as_ref()?.try_clone().ok()?;
+        state.pending_fsync = false;
+        state.last_fsync = Instant::now();
+        Some((file, state.written_offset))
+    }
+
+    // The AOF still has to be started when it was turned on at runtime
+    fn start_due(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.enabled && state.file.is_none() && !state.rewrite_in_progress
+    }
+}
+
+// The AOF part of the persistence section of INFO
+impl Display for Aof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        let mut builder = String::new();
+        builder.push_str(&format!("aof_enabled:{}\r\n", state.file.is_some() as u8));
+        builder.push_str(&format!(
+            "aof_rewrite_in_progress:{}\r\n",
+            state.rewrite_in_progress as u8
+        ));
+        builder.push_str(&format!(
+            "aof_last_bgrewrite_status:{}\r\n",
+            if state.last_rewrite_ok { "ok" } else { "err" }
+        ));
+        builder.push_str(&format!(
+            "aof_last_write_status:{}\r\n",
+            if state.last_write_ok { "ok" } else { "err" }
+        ));
+        f.write_str(&builder)
+    }
+}
+
+/// Starts the AOF once it is turned on and syncs it under everysec, runs for as long as the
+/// server does
+pub async fn aof_cycle(dbs: Arc<Databases<Data>>, config: Config) {
+    let mut interval = tokio::time::interval(AOF_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // The dataset may have changed since the AOF was last written, it starts over from a rewrite
+        if config.aof.start_due() {
+            let result = tokio::task::block_in_place(|| config.aof.rewrite(&dbs.all(), &config));
+            if let Err(err) = result {
+                eprintln!("Unable to turn on the AOF: {}", err);
+                config.aof.set_enabled(false);
+            }
+        }
+        if let Some((file, offset)) = config.aof.sync_due() {
+            let config = config.clone();
+            tokio::task::spawn_blocking(move || match file.sync_data() {
+                Ok(()) => {
+                    config.aof.mark_synced(offset);
+                    config.replication.master.wake_waiters();
+                }
+                Err(err) => eprintln!("Error syncing the AOF file: {}", err),
+            });
+        }
+    }
+}
+
+// Replaces the manifest through a temporary file, so it is never seen half written
+fn write_manifest(dir: &Path, filename: &str, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(format!("{}.manifest", filename));
+    let temp = dir.join(format!("temp-{}.manifest", filename));
+    File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(manifest.encode().as_bytes())
+                .and_then(|_| file.sync_all())
+        })
+        .and_then(|_| std::fs::rename(&temp, &path))
+        .and_then(|_| sync_dir(&path))
+        .map_err(|err| {
+            let _ = std::fs::remove_file(&temp);
+            anyhow!("Failed writing the AOF manifest: {}", err)
+        })
+}
+
+/// Encodes commands as RESP arrays of bulk strings, the form they are propagated in
+pub fn encode_commands(commands: &[Vec<Vec<u8>>]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for argv in commands {
+        let frame = RespFrame::Array(argv.iter().cloned().map(RespFrame::BulkBytes).collect());
+        buffer.extend_from_slice(&frame.encode());
+    }
+    buffer
+}
+
+// The commands rebuilding the snapshot, or None when it holds a value no command can
+// rebuild: sorted sets, sets, hashes and expiry times on anything but strings
+fn rewrite_commands(snapshot: &Snapshot) -> Option<Vec<u8>> {
+    let mut commands: Vec<Vec<Vec<u8>>> = snapshot
+        .libraries
+        .iter()
+        .map(|code| {
+            vec![
+                b"FUNCTION".to_vec(),
+                b"LOAD".to_vec(),
+                b"REPLACE".to_vec(),
+                code.as_bytes().to_vec(),
+            ]
+        })
+        .collect();
+
+    let now = Instant::now();
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    let mut selected = None;
+    for (index, key, data) in &snapshot.entries {
+        if selected != Some(*index) {
+            commands.push(vec![b"SELECT".to_vec(), index.to_string().into_bytes()]);
+            selected = Some(*index);
+        }
+        match (&data.value, data.expires_at) {
+            (Value::String(value), expires_at) => {
+                let mut argv = vec![b"SET".to_vec(), key.as_bytes().to_vec(), value.clone()];
+                if let Some(at) = expires_at {
+                    let at_ms = now_ms + at.saturating_duration_since(now).as_millis() as u64;
+                    argv.push(b"PXAT".to_vec());
+                    argv.push(at_ms.to_string().into_bytes());
+                }
+                commands.push(argv);
+            }
+            (Value::List(items), None) => {
+                for chunk in items.chunks(REWRITE_ITEMS_PER_COMMAND) {
+                    let mut argv = vec![b"RPUSH".to_vec(), key.as_bytes().to_vec()];
+                    argv.extend(chunk.iter().map(|item| item.as_bytes().to_vec()));
+                    commands.push(argv);
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(encode_commands(&commands))
+}
+
+// Applies a command read back from the file to the selected database, or selects another
+fn replay(
+    request: Request,
+    dbs: &[&RwLock<MemDB<Data>>],
+    selected: &mut usize,
+    config: &Config,
+    path: &Path,
+) -> Result<()> {
+    if request.name == "select" {
+        *selected = request
+            .args
+            .first()
+            .and_then(|index| index.parse::<usize>().ok())
+            .filter(|index| *index < dbs.len())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid SELECT {} reading the append only file {}",
+                    request.args.join(" "),
+                    path.display()
+                )
+            })?;
+        return Ok(());
+    }
+    let db = dbs[*selected];
+    let name = request.name.clone();
+    let command = parse_command(request).map_err(|_| {
+        anyhow!(
+            "Unknown command '{}' reading the append only file {}",
+            name,
+            path.display()
+        )
+    })?;
+    // The commands succeeded when they were written, they only fail again if the file was edited
+    if let Err(err) = command.validate().and_then(|_| command.execute(db, config)) {
+        eprintln!(
+            "Error replaying '{}' from the append only file: {}",
+            name, err
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Data {
+        Data {
+            value: Value::String(value.as_bytes().to_vec()),
+            expires_at: None,
+        }
+    }
+
+    // A config persisting to its own directory, with the AOF written as commands
+    fn config(dir: &Path) -> Config {
+        let config = Config::new(6379, None);
+        config.persistence.set_dir(dir.to_str().unwrap());
+        config.aof.set_use_rdb_preamble(false);
+        config
+    }
+
+    fn get(config: &Config, db: usize, key: &str) -> Option<String> {
+        let db = config.databases.all()[db].read().unwrap();
+        db.get(key).unwrap().map(|data| match &data.value {
+            Value::String(value) => String::from_utf8_lossy(value).into_owned(),
+            Value::List(items) => items.join(","),
+            _ => unreachable!(),
+        })
+    }
+
+    fn wait_for_rewrite(config: &Config) {
+        while config.aof.to_string().contains("aof_rewrite_in_progress:1") {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_files() {
+        let dir = std::env::temp_dir().join(format!("aof-round-trip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let written = config(&dir);
+        {
+            let dbs = written.databases.all();
+            let mut db = dbs[0].write().unwrap();
+            db.set("a".to_string(), string("1"));
+            let mut expiring = string("soon");
+            expiring.expires_at = Some(Instant::now() + Duration::from_secs(3600));
+            db.set("ttl".to_string(), expiring);
+            db.set(
+                "list".to_string(),
+                Data {
+                    value: Value::List(vec!["x".to_string(), "y".to_string()]),
+                    expires_at: None,
+                },
+            );
+            dbs[3].write().unwrap().set("b".to_string(), string("2"));
+        }
+
+        // The base holds the dataset, the writes after it go to the incr file
+        written.aof.set_enabled(true);
+        written
+            .aof
+            .start(&written.databases.all(), &written)
+            .unwrap();
+        wait_for_rewrite(&written);
+        let argv = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        written.propagate(3, vec![argv(&["SET", "c", "3"])]);
+        written.propagate(0, vec![argv(&["RPUSH", "list", "z"]), argv(&["DEL", "a"])]);
+        // A crash in the middle of a command leaves it cut short, it is dropped on load
+        let incr = dir.join("appendonlydir/appendonly.aof.1.incr.aof");
+        let complete = std::fs::metadata(&incr).unwrap().len();
+        written.aof.feed(b"*3\r\n$3\r\nSET\r\n$1\r\nd");
+
+        let loaded = config(&dir);
+        assert!(loaded.aof.load(&loaded.databases.all(), &loaded).unwrap());
+        assert_eq!(get(&loaded, 0, "a"), None);
+        assert_eq!(get(&loaded, 0, "list").as_deref(), Some("x,y,z"));
+        assert_eq!(get(&loaded, 0, "ttl").as_deref(), Some("soon"));
+        assert_eq!(get(&loaded, 3, "b").as_deref(), Some("2"));
+        assert_eq!(get(&loaded, 3, "c").as_deref(), Some("3"));
+        assert_eq!(get(&loaded, 3, "d"), None);
+        let ttl = loaded.databases.all()[0]
+            .read()
+            .unwrap()
+            .get("ttl")
+            .unwrap()
+            .unwrap()
+            .expires_at;
+        assert!(ttl.is_some_and(|at| at > Instant::now() + Duration::from_secs(3590)));
+        assert_eq!(std::fs::metadata(&incr).unwrap().len(), complete);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_reads_the_format_of_redis() {
+        let manifest = Manifest::parse(
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type h\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n\
+             file appendonly.aof.4.incr.aof seq 4 type i\n",
+        )
+        .unwrap();
+        // History files are left out, they are only waiting to be deleted
+        assert_eq!(
+            manifest.encode(),
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n\
+             file appendonly.aof.4.incr.aof seq 4 type i\n"
+        );
+
+        for (text, error) in [
+            ("", "Found an empty AOF manifest"),
+            (
+                "file a seq 1 type b\nfile b seq 2 type b",
+                "Found duplicate base file information",
+            ),
+            ("file a seq 1 type x", "Unknown AOF file type: x"),
+            (
+                "file a seq one type i",
+                "Invalid AOF manifest line: file a seq one type i",
+            ),
+            (
+                "file a seq 1 type",
+                "Invalid AOF manifest line: file a seq 1 type",
+            ),
+        ] {
+            assert!(Manifest::parse(text).is_err_and(|err| err.to_string() == error));
+        }
+    }
+
+    #[test]
+    fn rewrite_switches_the_manifest_to_a_new_base() {
+        let dir = std::env::temp_dir().join(format!("aof-rewrite-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let aof_dir = dir.join("appendonlydir");
+        let files = || -> Vec<String> {
+            let mut files: Vec<String> = std::fs::read_dir(&aof_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        };
+        let manifest = || std::fs::read_to_string(aof_dir.join("appendonly.aof.manifest")).unwrap();
+        let argv = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+
+        // An AOF written before the split into files becomes the base of the manifest
+        std::fs::write(
+            dir.join("appendonly.aof"),
+            encode_commands(&[argv(&["SET", "legacy", "1"])]),
+        )
+        .unwrap();
+        let written = config(&dir);
+        written.aof.set_enabled(true);
+        assert!(
+            written
+                .aof
+                .load(&written.databases.all(), &written)
+                .unwrap()
+        );
+        written
+            .aof
+            .start(&written.databases.all(), &written)
+            .unwrap();
+        written.propagate(0, vec![argv(&["SET", "a", "1"])]);
+        assert_eq!(
+            manifest(),
+            "file appendonly.aof seq 1 type b\nfile appendonly.aof.1.incr.aof seq 1 type i\n"
+        );
+
+        // The rewrite moves on to the next base and incr file and drops the ones before
+        written.databases.all()[0]
+            .write()
+            .unwrap()
+            .set("a".to_string(), string("1"));
+        written
+            .aof
+            .rewrite(&written.databases.all(), &written)
+            .unwrap();
+        written.propagate(0, vec![argv(&["SET", "b", "2"])]);
+        wait_for_rewrite(&written);
+        assert!(
+            written
+                .aof
+                .to_string()
+                .contains("aof_last_bgrewrite_status:ok")
+        );
+        assert_eq!(
+            manifest(),
+            "file appendonly.aof.2.base.aof seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(
+            files(),
+            [
+                "appendonly.aof.2.base.aof",
+                "appendonly.aof.2.incr.aof",
+                "appendonly.aof.manifest"
+            ]
+        );
+
+        let loaded = config(&dir);
+        assert!(loaded.aof.load(&loaded.databases.all(), &loaded).unwrap());
+        assert_eq!(get(&loaded, 0, "legacy").as_deref(), Some("1"));
+        assert_eq!(get(&loaded, 0, "a").as_deref(), Some("1"));
+        assert_eq!(get(&loaded, 0, "b").as_deref(), Some("2"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1655 @@
+// Cluster mode: the key space is split in 16384 hash slots, each served by a single master.
+// A command on keys of a slot served by another node is redirected there with -MOVED, the
+// way cluster clients expect. The nodes and the slot table are kept in the cluster config
+// file (nodes.conf), in the same format CLUSTER NODES shows them.
+//
+// Nodes talk to each other on the cluster bus, on their port plus 10000. Every second each
+// node pings the others with what it knows about itself and gossip about a few other
+// nodes, which is how nodes meet, find out about failing ones and agree on who serves each
+// slot: a claim on a slot wins when it comes with a newer config epoch. The replicas of a
+// failed master elect one of them to take its slots over.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use rand::{Rng, seq::IteratorRandom};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    connection::Connection,
+    mem::MemDB,
+    replication::{self, MasterAddr},
+    resp::{commands::structs::Data, frame::RespFrame},
+    slot::{CLUSTER_SLOTS, key_hash_slot},
+};
+
+// The cluster bus of a node listens on its port plus this offset
+pub const BUS_PORT_OFFSET: u16 = 10000;
+// How long a node may go without answering a ping before it is suspected to fail, in ms
+pub const DEFAULT_NODE_TIMEOUT: u64 = 15000;
+
+const TIMER_PERIOD: Duration = Duration::from_millis(100);
+const PING_PERIOD: Duration = Duration::from_secs(1);
+// A replica waits this long, plus up to as much again at random, before asking for votes so
+// that the FAIL state of its master reached every node. Each replica with more data than
+// it waits a little longer, to give the best replica a head start.
+const FAILOVER_DELAY: u64 = 500;
+const FAILOVER_RANK_DELAY: u64 = 1000;
+
+// The fields every message starts with, and those of each gossip entry following them
+const HEADER_FIELDS: usize = 10;
+const GOSSIP_FIELDS: usize = 5;
+
+// A node of the cluster, as CLUSTER NODES lists it
+#[derive(Clone)]
+pub struct Node {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub myself: bool,
+    // master_id is set on replicas, to the master they replicate
+    pub master_id: Option<String>,
+    // config_epoch versions the slots the node claims, the highest epoch wins a conflict
+    pub config_epoch: u64,
+    // When the last ping was sent and the last pong received, in unix milliseconds
+    pub ping_sent: u64,
+    pub pong_recv: u64,
+    pub connected: bool,
+    // handshake is set on a node met through CLUSTER MEET or gossip until it answers with
+    // its ID, it goes by a random one meanwhile
+    pub handshake: bool,
+    // pfail is set when the node didn't answer a ping within the node timeout, fail once
+    // the majority of masters agrees
+    pub pfail: bool,
+    pub fail: bool,
+    fail_time: u64,
+    // When the node was added, and the replication offset it last announced
+    ctime: u64,
+    offset: u64,
+}
+
+impl Node {
+    fn new(id: String, ip: &str, port: u16) -> Self {
+        Node {
+            id,
+            ip: ip.to_string(),
+            port,
+            bus_port: port + BUS_PORT_OFFSET,
+            myself: false,
+            master_id: None,
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_recv: 0,
+            connected: false,
+            handshake: false,
+            pfail: false,
+            fail: false,
+            fail_time: 0,
+            ctime: now_ms(),
+            offset: 0,
+        }
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.master_id.is_none()
+    }
+
+    pub fn flags(&self) -> String {
+        let mut flags = Vec::new();
+        if self.myself {
+            flags.push("myself");
+        }
+        flags.push(if self.is_master() { "master" } else { "slave" });
+        if self.pfail {
+            flags.push("fail?");
+        }
+        if self.fail {
+            flags.push("fail");
+        }
+        if self.handshake {
+            flags.push("handshake");
+        }
+        flags.join(",")
+    }
+}
+
+// A replica's bid for the slots of its failed master
+struct Election {
+    // When the replica asks the masters for their vote, in unix milliseconds
+    start: u64,
+    requested: bool,
+    epoch: u64,
+    votes: usize,
+}
+
+struct State {
+    myself: String,
+    // current_epoch is the highest epoch known in the cluster
+    current_epoch: u64,
+    last_vote_epoch: u64,
+    node_timeout: u64,
+    nodes: BTreeMap<String, Node>,
+    // slots holds the ID of the master serving each slot
+    slots: Vec<Option<String>>,
+    // While resharding, the slots this node moves out to another node and the slots it
+    // takes in from another node
+    migrating: BTreeMap<u16, String>,
+    importing: BTreeMap<u16, String>,
+    // reports holds, for each node, the masters which found it failing and when
+    reports: HashMap<String, HashMap<String, u64>>,
+    // voted holds when this master last voted for a replica of each master
+    voted: HashMap<String, u64>,
+    election: Option<Election>,
+    // outbox holds the messages waiting for the link to each node
+    outbox: HashMap<String, Vec<Vec<String>>>,
+}
+
+impl State {
+    // Whether every slot is served by a working master, the cluster refuses keys otherwise
+    fn is_ok(&self) -> bool {
+        self.slots.iter().all(|owner| {
+            owner
+                .as_ref()
+                .and_then(|id| self.nodes.get(id))
+                .is_some_and(|node| !node.fail)
+        })
+    }
+
+    fn myself_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    // The slots served by `id` as ranges of consecutive slots
+    fn ranges_of(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..CLUSTER_SLOTS {
+            if self.slots[slot as usize].as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    fn count_slots(&self, id: &str) -> usize {
+        self.slots
+            .iter()
+            .filter(|owner| owner.as_deref() == Some(id))
+            .count()
+    }
+
+    // The size of the cluster is its number of masters serving slots, a majority of them
+    // is needed to mark a node as failed or elect a replica
+    fn quorum(&self) -> usize {
+        let size = self
+            .nodes
+            .values()
+            .filter(|node| node.is_master() && self.count_slots(&node.id) > 0)
+            .count();
+        size / 2 + 1
+    }
+
+    // One line of CLUSTER NODES, and of the cluster config file
+    fn node_line(&self, node: &Node) -> String {
+        let mut line = format!(
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.bus_port,
+            node.flags(),
+            node.master_id.as_deref().unwrap_or("-"),
+            node.ping_sent,
+            node.pong_recv,
+            node.config_epoch,
+            if node.connected || node.myself {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+        for (start, end) in self.ranges_of(&node.id) {
+            if start == end {
+                line.push_str(&format!(" {}", start));
+            } else {
+                line.push_str(&format!(" {}-{}", start, end));
+            }
+        }
+        if node.myself {
+            for (slot, target) in &self.migrating {
+                line.push_str(&format!(" [{}->-{}]", slot, target));
+            }
+            for (slot, source) in &self.importing {
+                line.push_str(&format!(" [{}-<-{}]", slot, source));
+            }
+        }
+        line
+    }
+
+    // Builds a message: what this node knows about itself, gossip about some of the other
+    // nodes, then the fields specific to the message type
+    fn message(&self, kind: &str, offset: u64, extra: Vec<String>) -> Vec<String> {
+        let me = &self.nodes[&self.myself];
+        // Replicas announce the slots and config epoch of their master
+        let master = me
+            .master_id
+            .as_ref()
+            .and_then(|id| self.nodes.get(id))
+            .unwrap_or(me);
+        let mut argv = vec![
+            kind.to_string(),
+            me.id.clone(),
+            me.port.to_string(),
+            me.bus_port.to_string(),
+            me.master_id.clone().unwrap_or("-".to_string()),
+            self.current_epoch.to_string(),
+            master.config_epoch.to_string(),
+            offset.to_string(),
+            encode_slots(&self.ranges_of(&master.id)),
+        ];
+
+        // A tenth of the nodes, at least 3, and every node suspected to fail so that the
+        // reports about it spread quickly
+        let others = self
+            .nodes
+            .values()
+            .filter(|node| !node.myself && !node.handshake);
+        let wanted = (self.nodes.len() / 10).max(3);
+        let mut gossip: Vec<&Node> = others.clone().choose_multiple(&mut rand::rng(), wanted);
+        let failing: Vec<&Node> = others
+            .filter(|node| {
+                (node.pfail || node.fail) && !gossip.iter().any(|other| other.id == node.id)
+            })
+            .collect();
+        gossip.extend(failing);
+        argv.push(gossip.len().to_string());
+        for node in gossip {
+            argv.extend([
+                node.id.clone(),
+                node.ip.clone(),
+                node.port.to_string(),
+                node.bus_port.to_string(),
+                if node.fail {
+                    "fail"
+                } else if node.pfail {
+                    "pfail"
+                } else {
+                    "ok"
+                }
+                .to_string(),
+            ]);
+        }
+        argv.extend(extra);
+        argv
+    }
+
+    fn send(&mut self, id: &str, message: Vec<String>) {
+        self.outbox.entry(id.to_string()).or_default().push(message);
+    }
+
+    fn broadcast(&mut self, message: Vec<String>) {
+        let ids: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|node| !node.myself && !node.handshake)
+            .map(|node| node.id.clone())
+            .collect();
+        for id in ids {
+            self.send(&id, message.clone());
+        }
+    }
+
+    fn start_handshake(&mut self, ip: &str, port: u16, bus_port: u16) {
+        if self
+            .nodes
+            .values()
+            .any(|node| node.ip == ip && node.port == port)
+        {
+            return;
+        }
+        let mut node = Node::new(random_id(), ip, port);
+        node.bus_port = bus_port;
+        node.handshake = true;
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    // Hands the slots a master claims over to it, unless they are served by a master with
+    // a newer config epoch, which the claimer is told about with an UPDATE instead. A
+    // master losing its last slot this way becomes a replica of the claimer, and so do its
+    // replicas: it is how a master which failed rejoins once a replica took over.
+    fn claim(
+        &mut self,
+        claimer: &str,
+        epoch: u64,
+        slots: &[u16],
+        offset: u64,
+        config: &Config,
+    ) -> bool {
+        let my_master = self.nodes[&self.myself]
+            .master_id
+            .clone()
+            .unwrap_or(self.myself.clone());
+        let served = self.count_slots(&my_master);
+        let mut changed = false;
+        let mut newer = None;
+        for slot in slots {
+            let owner = &self.slots[*slot as usize];
+            // Slots being imported are handed over by the one resharding
+            if owner.as_deref() == Some(claimer) || self.importing.contains_key(slot) {
+                continue;
+            }
+            match owner.as_ref().and_then(|id| self.nodes.get(id)) {
+                Some(node) if node.config_epoch > epoch => newer = Some(node.id.clone()),
+                Some(node) if node.config_epoch == epoch => {}
+                _ => {
+                    self.slots[*slot as usize] = Some(claimer.to_string());
+                    changed = true;
+                }
+            }
+        }
+
+        if let Some(owner) = newer {
+            let update = vec![
+                owner.clone(),
+                self.nodes[&owner].config_epoch.to_string(),
+                encode_slots(&self.ranges_of(&owner)),
+            ];
+            let message = self.message("UPDATE", offset, update);
+            self.send(claimer, message);
+        }
+        if served > 0 && self.count_slots(&my_master) == 0 {
+            self.follow(claimer, config);
+        }
+        changed
+    }
+
+    // Makes this node a replica of `master`
+    fn follow(&mut self, master: &str, config: &Config) {
+        let Some(node) = self.nodes.get(master) else {
+            return;
+        };
+        let addr = MasterAddr {
+            host: node.ip.clone(),
+            port: node.port,
+        };
+        println!("Configuration change detected, replicating {}", master);
+        self.myself_mut().master_id = Some(master.to_string());
+        self.migrating.clear();
+        self.importing.clear();
+        self.election = None;
+        replication::replicate(config, addr);
+    }
+
+    // Marks a node suspected to fail as failed once the majority of masters reported it,
+    // and tells every node about it
+    fn mark_as_failing(&mut self, id: &str, now: u64, offset: u64) -> bool {
+        if !self
+            .nodes
+            .get(id)
+            .is_some_and(|node| node.pfail && !node.fail)
+        {
+            return false;
+        }
+        let validity = self.node_timeout * 2;
+        let reports = self.reports.entry(id.to_string()).or_default();
+        reports.retain(|_, at| now.saturating_sub(*at) <= validity);
+        let mut failures = reports.len();
+        if self.nodes[&self.myself].is_master() {
+            failures += 1;
+        }
+        if failures < self.quorum() {
+            return false;
+        }
+
+        let node = self.nodes.get_mut(id).unwrap();
+        node.pfail = false;
+        node.fail = true;
+        node.fail_time = now;
+        println!("Marking node {} as failing (quorum reached)", id);
+        let message = self.message("FAIL", offset, vec![id.to_string()]);
+        self.broadcast(message);
+        true
+    }
+
+    // Clears the FAIL state of a node which answers again. A master serving slots stays
+    // failed for a while, so that its replicas get a chance to take over.
+    fn clear_failure(&mut self, id: &str, now: u64) -> bool {
+        let undo_time = self.node_timeout * 2;
+        let has_slots = self.count_slots(id) > 0;
+        let Some(node) = self.nodes.get_mut(id) else {
+            return false;
+        };
+        if !node.fail
+            || (node.is_master() && has_slots && now.saturating_sub(node.fail_time) < undo_time)
+        {
+            return false;
+        }
+        node.fail = false;
+        println!("Clear FAIL state for node {}: is reachable again", id);
+        true
+    }
+
+    // Votes for a replica asking to take over from its failed master, once per epoch
+    fn vote(&mut self, header: &Header, now: u64) -> bool {
+        if !self.nodes[&self.myself].is_master() || self.count_slots(&self.myself) == 0 {
+            return false;
+        }
+        if header.current_epoch < self.current_epoch || self.last_vote_epoch == self.current_epoch {
+            return false;
+        }
+        let Some(master) = header.master_id.as_ref().and_then(|id| self.nodes.get(id)) else {
+            return false;
+        };
+        if !master.fail {
+            return false;
+        }
+        let validity = self.node_timeout * 2;
+        if self
+            .voted
+            .get(&master.id)
+            .is_some_and(|at| now.saturating_sub(*at) < validity)
+        {
+            return false;
+        }
+        // The replica must not claim slots which moved on since its master served them
+        let stale = header.slots.iter().any(|slot| {
+            self.slots[*slot as usize]
+                .as_ref()
+                .and_then(|id| self.nodes.get(id))
+                .is_some_and(|owner| owner.config_epoch > header.config_epoch)
+        });
+        if stale {
+            return false;
+        }
+
+        self.last_vote_epoch = self.current_epoch;
+        self.voted.insert(master.id.clone(), now);
+        println!(
+            "Failover auth granted to {} for epoch {}",
+            header.sender, self.current_epoch
+        );
+        true
+    }
+
+    // Runs the election of a replica whose master failed: after a delay it asks the
+    // masters for their vote in a new epoch, and takes the slots of its master over once
+    // the majority of them voted for it
+    fn failover(&mut self, now: u64, offset: u64, config: &Config) -> bool {
+        let master = self.nodes[&self.myself].master_id.clone();
+        let Some(master) = master.filter(|id| {
+            self.nodes.get(id).is_some_and(|node| node.fail) && self.count_slots(id) > 0
+        }) else {
+            self.election = None;
+            return false;
+        };
+        let auth_timeout = (self.node_timeout * 2).max(2000);
+
+        // A failed election is retried once it is well over
+        if self
+            .election
+            .as_ref()
+            .is_none_or(|election| now > election.start + auth_timeout * 2)
+        {
+            let rank = self
+                .nodes
+                .values()
+                .filter(|node| {
+                    !node.myself
+                        && node.master_id.as_deref() == Some(master.as_str())
+                        && node.offset > offset
+                })
+                .count() as u64;
+            let delay = FAILOVER_DELAY
+                + rand::rng().random_range(0..FAILOVER_DELAY)
+                + rank * FAILOVER_RANK_DELAY;
+            println!(
+                "Start of election delayed for {} milliseconds (rank #{}, offset {})",
+                delay, rank, offset
+            );
+            self.election = Some(Election {
+                start: now + delay,
+                requested: false,
+                epoch: 0,
+                votes: 0,
+            });
+            return false;
+        }
+
+        let quorum = self.quorum();
+        let election = self.election.as_mut().unwrap();
+        if now < election.start || now > election.start + auth_timeout {
+            return false;
+        }
+        if !election.requested {
+            self.current_epoch += 1;
+            election.requested = true;
+            election.epoch = self.current_epoch;
+            println!(
+                "Starting a failover election for epoch {}",
+                self.current_epoch
+            );
+            let message = self.message("AUTH_REQUEST", offset, vec![]);
+            self.broadcast(message);
+            return true;
+        }
+        if election.votes < quorum {
+            return false;
+        }
+
+        let epoch = election.epoch;
+        self.election = None;
+        println!("Failover election won, taking over the slots of {}", master);
+        let myself = self.myself.clone();
+        let me = self.myself_mut();
+        me.master_id = None;
+        me.config_epoch = epoch;
+        for owner in self.slots.iter_mut() {
+            if owner.as_deref() == Some(master.as_str()) {
+                *owner = Some(myself.clone());
+            }
+        }
+        replication::promote(config);
+        let message = self.message("PONG", offset, vec![]);
+        self.broadcast(message);
+        true
+    }
+}
+
+/// Cluster is the state of a server started with --cluster-enabled: the nodes it knows
+/// and which of them serves each slot
+#[derive(Clone)]
+pub struct Cluster {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl Cluster {
+    /// Loads the cluster config file at `path`, or starts a cluster of its own, made of a
+    /// new node serving no slot, when there is none
+    pub fn load(path: PathBuf, port: u16, node_timeout: u64) -> Result<Self> {
+        let mut state = State {
+            myself: String::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            node_timeout,
+            nodes: BTreeMap::new(),
+            slots: vec![None; CLUSTER_SLOTS as usize],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            reports: HashMap::new(),
+            voted: HashMap::new(),
+            election: None,
+            outbox: HashMap::new(),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(content) => parse_config_file(&mut state, &content, port).map_err(|err| {
+                anyhow!("Invalid cluster config file {}: {}", path.display(), err)
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let id = random_id();
+                let mut myself = Node::new(id.clone(), "127.0.0.1", port);
+                myself.myself = true;
+                println!("No cluster configuration found, I'm {}", id);
+                state.myself = id.clone();
+                state.nodes.insert(id, myself);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let cluster = Cluster {
+            path,
+            state: Arc::new(Mutex::new(state)),
+        };
+        cluster.save(&cluster.state.lock().unwrap())?;
+        Ok(cluster)
+    }
+
+    // Writes the cluster config file through a temporary file, so it is never left half
+    // written. Nodes still in handshake aren't part of the cluster yet.
+    fn save(&self, state: &State) -> Result<()> {
+        let mut content = String::new();
+        for node in state.nodes.values().filter(|node| !node.handshake) {
+            content.push_str(&state.node_line(node));
+            content.push('\n');
+        }
+        content.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            state.current_epoch, state.last_vote_epoch
+        ));
+        let temp = self
+            .path
+            .with_file_name(format!("temp-cluster-{}.conf", std::process::id()));
+        std::fs::write(&temp, content)
+            .and_then(|_| std::fs::rename(&temp, &self.path))
+            .map_err(|err| {
+                let _ = std::fs::remove_file(&temp);
+                anyhow!("Failed saving the cluster config file: {}", err)
+            })
+    }
+
+    // Saves a change learned from the bus, where there is nobody to report a failure to
+    fn persist(&self, state: &State) {
+        if let Err(err) = self.save(state) {
+            eprintln!("{}", err);
+        }
+    }
+
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().myself.clone()
+    }
+
+    /// Checks this node can serve a command on `keys`: they all have to hash to the same
+    /// slot, served by this node. A slot served by another node is answered with MOVED.
+    /// While a slot is resharded, keys already moved out are answered with ASK, and the
+    /// node importing the slot serves the clients which sent ASKING first.
+    pub fn route(&self, keys: &[&str], asking: bool, db: &RwLock<MemDB<Data>>) -> Result<()> {
+        let Some(slot) = same_slot(keys)? else {
+            return Ok(());
+        };
+        let (migrating, importing) = {
+            let state = self.state.lock().unwrap();
+            if !state.is_ok() {
+                return Err(anyhow!("CLUSTERDOWN The cluster is down"));
+            }
+            let Some(owner) = state.slots[slot as usize].as_ref() else {
+                return Err(anyhow!("CLUSTERDOWN Hash slot not served"));
+            };
+            let importing = asking && state.importing.contains_key(&slot);
+            if *owner != state.myself && !importing {
+                let node = &state.nodes[owner];
+                return Err(anyhow!("MOVED {} {}:{}", slot, node.ip, node.port));
+            }
+            let migrating = state
+                .migrating
+                .get(&slot)
+                .filter(|_| *owner == state.myself)
+                .and_then(|target| state.nodes.get(target))
+                .map(|target| format!("{}:{}", target.ip, target.port));
+            (migrating, importing)
+        };
+        if migrating.is_none() && !importing {
+            return Ok(());
+        }
+
+        let missing = {
+            let db = db.read().map_err(|_| anyhow!("Unable to acquire lock"))?;
+            keys.iter().filter(|key| !db.exists(key)).count()
+        };
+        if let Some(target) = migrating
+            && missing > 0
+        {
+            return Err(anyhow!("ASK {} {}", slot, target));
+        }
+        if importing && keys.len() > 1 && missing > 0 {
+            return Err(anyhow!(
+                "TRYAGAIN Multiple keys request during rehashing of slot"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Makes this node serve `slots`, which must not be served by any node yet
+    pub fn add_slots(&self, slots: &[u16]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_unique(slots)?;
+        if let Some(slot) = slots
+            .iter()
+            .find(|slot| state.slots[**slot as usize].is_some())
+        {
+            return Err(anyhow!("ERR Slot {} is already busy", slot));
+        }
+        let myself = state.myself.clone();
+        for slot in slots {
+            state.slots[*slot as usize] = Some(myself.clone());
+            state.importing.remove(slot);
+        }
+        self.save(&state)
+    }
+
+    /// Forgets who serves `slots`, which must all be served
+    pub fn del_slots(&self, slots: &[u16]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_unique(slots)?;
+        if let Some(slot) = slots
+            .iter()
+            .find(|slot| state.slots[**slot as usize].is_none())
+        {
+            return Err(anyhow!("ERR Slot {} is already unassigned", slot));
+        }
+        for slot in slots {
+            state.slots[*slot as usize] = None;
+        }
+        self.save(&state)
+    }
+
+    /// Starts a handshake with the node at `ip` and `port`, which joins the cluster once
+    /// it answers
+    pub fn meet(&self, ip: &str, port: u16, bus_port: u16) -> Result<()> {
+        if ip.parse::<IpAddr>().is_err() {
+            return Err(anyhow!(
+                "ERR Invalid node address specified: {}:{}",
+                ip,
+                port
+            ));
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .start_handshake(ip, port, bus_port);
+        Ok(())
+    }
+
+    /// Makes this node a replica of the master `id`. A master has to be empty first.
+    pub fn replicate(&self, id: &str, empty: bool, config: &Config) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if id == state.myself {
+            return Err(anyhow!("ERR Can't replicate myself"));
+        }
+        let Some(node) = state.nodes.get(id).filter(|node| !node.handshake) else {
+            return Err(anyhow!("ERR Unknown node {}", id));
+        };
+        if !node.is_master() {
+            return Err(anyhow!("ERR I can only replicate a master, not a replica."));
+        }
+        if state.nodes[&state.myself].is_master()
+            && (state.count_slots(&state.myself) > 0 || !empty)
+        {
+            return Err(anyhow!(
+                "ERR To set a master the node must be empty and without assigned slots."
+            ));
+        }
+        state.follow(id, config);
+        self.save(&state)
+    }
+
+    /// CLUSTER SETSLOT slot MIGRATING node: keys of the slot which are missing are asked
+    /// for to `id` from now on
+    pub fn set_migrating(&self, slot: u16, id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_master(&state)?;
+        if state.slots[slot as usize].as_deref() != Some(state.myself.as_str()) {
+            return Err(anyhow!("ERR I'm not the owner of hash slot {}", slot));
+        }
+        check_target(&state, id)?;
+        state.migrating.insert(slot, id.to_string());
+        self.save(&state)
+    }
+
+    /// CLUSTER SETSLOT slot IMPORTING node: clients sending ASKING are served keys of the
+    /// slot `id` is moving here
+    pub fn set_importing(&self, slot: u16, id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_master(&state)?;
+        if state.slots[slot as usize].as_deref() == Some(state.myself.as_str()) {
+            return Err(anyhow!("ERR I'm already the owner of hash slot {}", slot));
+        }
+        check_target(&state, id)?;
+        state.importing.insert(slot, id.to_string());
+        self.save(&state)
+    }
+
+    /// CLUSTER SETSLOT slot STABLE: the slot is no longer resharded
+    pub fn set_stable(&self, slot: u16) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_master(&state)?;
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        self.save(&state)
+    }
+
+    /// CLUSTER SETSLOT slot NODE node: ends a resharding, `id` serves the slot from now
+    /// on. The node taking the slot in takes a new config epoch for its claim to win.
+    pub fn set_slot_node(&self, slot: u16, id: &str, holds_keys: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        check_master(&state)?;
+        let Some(node) = state.nodes.get(id).filter(|node| !node.handshake) else {
+            return Err(anyhow!("ERR Unknown node {}", id));
+        };
+        if !node.is_master() {
+            return Err(anyhow!("ERR Target node is not a master"));
+        }
+        let mine = state.slots[slot as usize].as_deref() == Some(state.myself.as_str());
+        if mine && id != state.myself && holds_keys {
+            return Err(anyhow!(
+                "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                slot
+            ));
+        }
+        if id != state.myself {
+            state.migrating.remove(&slot);
+        } else if state.importing.remove(&slot).is_some() {
+            state.current_epoch += 1;
+            let epoch = state.current_epoch;
+            state.myself_mut().config_epoch = epoch;
+            println!("New config epoch {} after importing slot {}", epoch, slot);
+        }
+        state.slots[slot as usize] = Some(id.to_string());
+        self.save(&state)
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.state.lock().unwrap().nodes.values().cloned().collect()
+    }
+
+    /// The slots served by `id` as ranges of consecutive slots
+    pub fn ranges_of(&self, id: &str) -> Vec<(u16, u16)> {
+        self.state.lock().unwrap().ranges_of(id)
+    }
+
+    /// Every range of consecutive slots served by the same master, in slot order
+    pub fn ranges(&self) -> Vec<(u16, u16, String)> {
+        let state = self.state.lock().unwrap();
+        let mut ranges: Vec<(u16, u16, String)> = Vec::new();
+        for slot in 0..CLUSTER_SLOTS {
+            let Some(owner) = &state.slots[slot as usize] else {
+                continue;
+            };
+            match ranges.last_mut() {
+                Some((_, end, id)) if *end + 1 == slot && id == owner => *end = slot,
+                _ => ranges.push((slot, slot, owner.clone())),
+            }
+        }
+        ranges
+    }
+
+    /// What CLUSTER NODES shows, a line per node
+    pub fn nodes_text(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut text = String::new();
+        for node in state.nodes.values() {
+            text.push_str(&state.node_line(node));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// What CLUSTER INFO shows
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let owners: Vec<&Node> = state
+            .slots
+            .iter()
+            .filter_map(|owner| owner.as_ref().and_then(|id| state.nodes.get(id)))
+            .collect();
+        let pfail = owners.iter().filter(|node| node.pfail).count();
+        let fail = owners.iter().filter(|node| node.fail).count();
+        let size = state
+            .nodes
+            .values()
+            .filter(|node| node.is_master() && state.count_slots(&node.id) > 0)
+            .count();
+        let my_epoch = state.nodes[&state.myself].config_epoch;
+        [
+            format!(
+                "cluster_state:{}",
+                if state.is_ok() { "ok" } else { "fail" }
+            ),
+            format!("cluster_slots_assigned:{}", owners.len()),
+            format!("cluster_slots_ok:{}", owners.len() - pfail - fail),
+            format!("cluster_slots_pfail:{}", pfail),
+            format!("cluster_slots_fail:{}", fail),
+            format!("cluster_known_nodes:{}", state.nodes.len()),
+            format!("cluster_size:{}", size),
+            format!("cluster_current_epoch:{}", state.current_epoch),
+            format!("cluster_my_epoch:{}", my_epoch),
+        ]
+        .iter()
+        .map(|line| format!("{}\r\n", line))
+        .collect()
+    }
+
+    // The IDs of the other nodes, each of which gets a link
+    fn peers(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .nodes
+            .values()
+            .filter(|node| !node.myself)
+            .map(|node| node.id.clone())
+            .collect()
+    }
+
+    // The bus address of a node, None once it is forgotten
+    fn link(&self, id: &str) -> Option<MasterAddr> {
+        let state = self.state.lock().unwrap();
+        state.nodes.get(id).map(|node| MasterAddr {
+            host: node.ip.clone(),
+            port: node.bus_port,
+        })
+    }
+
+    fn set_connected(&self, id: &str, connected: bool) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id) {
+            node.connected = connected;
+        }
+    }
+
+    // Notes a ping is on its way to a node, the time it is unanswered for is counted from
+    // the first one
+    fn pinging(&self, id: &str) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id)
+            && node.ping_sent == 0
+        {
+            node.ping_sent = now_ms();
+        }
+    }
+
+    // The messages to send a node: those waiting for it, along with a ping when `ping` is
+    // set, a MEET to nodes in handshake
+    fn outgoing(&self, id: &str, ping: bool, offset: u64) -> Vec<Vec<String>> {
+        let mut state = self.state.lock().unwrap();
+        let mut messages = state.outbox.remove(id).unwrap_or_default();
+        if ping && let Some(node) = state.nodes.get(id) {
+            let kind = if node.handshake { "MEET" } else { "PING" };
+            messages.push(state.message(kind, offset, vec![]));
+        }
+        messages
+    }
+
+    /// Processes a message received on the bus, returning the PONG a PING or MEET is
+    /// answered with. `link` is the node whose link the message came from, when it is the
+    /// answer to a message of this node.
+    fn process(
+        &self,
+        argv: &[String],
+        ip: &str,
+        link: Option<&str>,
+        config: &Config,
+    ) -> Result<Option<Vec<String>>> {
+        let header = parse_header(argv)?;
+        let now = now_ms();
+        let offset = config.replication.master.offset();
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+
+        // The answer to a MEET tells the ID of the node met, it goes by it from then on
+        if let Some(link) = link
+            && header.kind == "PONG"
+            && state.nodes.get(link).is_some_and(|node| node.handshake)
+        {
+            let met = state.nodes.remove(link).unwrap();
+            state.outbox.remove(link);
+            if !state.nodes.contains_key(&header.sender) {
+                println!("Handshake with node {} completed", header.sender);
+                let mut node = Node::new(header.sender.clone(), &met.ip, header.port);
+                node.bus_port = header.bus_port;
+                state.nodes.insert(node.id.clone(), node);
+            }
+            changed = true;
+        }
+        if header.kind == "MEET"
+            && header.sender != state.myself
+            && !state.nodes.contains_key(&header.sender)
+        {
+            println!("Met node {} at {}:{}", header.sender, ip, header.port);
+            let mut node = Node::new(header.sender.clone(), ip, header.port);
+            node.bus_port = header.bus_port;
+            state.nodes.insert(node.id.clone(), node);
+            changed = true;
+        }
+        if header.current_epoch > state.current_epoch {
+            state.current_epoch = header.current_epoch;
+            changed = true;
+        }
+
+        // Nodes which aren't part of the cluster are only answered
+        let reply = matches!(header.kind.as_str(), "PING" | "MEET")
+            .then(|| state.message("PONG", offset, vec![]));
+        if header.sender == state.myself
+            || state
+                .nodes
+                .get(&header.sender)
+                .is_none_or(|node| node.handshake)
+        {
+            if changed {
+                self.persist(&state);
+            }
+            return Ok(reply);
+        }
+
+        let sender = state.nodes.get_mut(&header.sender).unwrap();
+        sender.offset = header.offset;
+        if sender.master_id != header.master_id {
+            sender.master_id = header.master_id.clone();
+            changed = true;
+        }
+        if header.master_id.is_none() && sender.config_epoch != header.config_epoch {
+            sender.config_epoch = header.config_epoch;
+            changed = true;
+        }
+        if header.kind == "PONG" {
+            sender.ping_sent = 0;
+            sender.pong_recv = now;
+            sender.pfail = false;
+            changed |= state.clear_failure(&header.sender, now);
+        }
+
+        if header.master_id.is_none() {
+            changed |= state.claim(
+                &header.sender,
+                header.config_epoch,
+                &header.slots,
+                offset,
+                config,
+            );
+            // Two masters can't share a config epoch, the one with the smaller ID keeps it
+            let me = &state.nodes[&state.myself];
+            if me.is_master()
+                && me.config_epoch == header.config_epoch
+                && header.sender > state.myself
+            {
+                state.current_epoch += 1;
+                let epoch = state.current_epoch;
+                state.myself_mut().config_epoch = epoch;
+                println!(
+                    "Config epoch collision with {}, moving to {}",
+                    header.sender, epoch
+                );
+                changed = true;
+            }
+        }
+
+        for entry in &header.gossip {
+            if entry.id == state.myself {
+                continue;
+            }
+            if state
+                .nodes
+                .get(&entry.id)
+                .is_some_and(|node| !node.handshake)
+            {
+                // Only masters decide whether a node failed
+                if header.master_id.is_none() {
+                    let reports = state.reports.entry(entry.id.clone()).or_default();
+                    if entry.failing {
+                        reports.insert(header.sender.clone(), now);
+                    } else {
+                        reports.remove(&header.sender);
+                    }
+                }
+                changed |= state.mark_as_failing(&entry.id, now, offset);
+            } else if !state.nodes.contains_key(&entry.id) {
+                state.start_handshake(&entry.ip, entry.port, entry.bus_port);
+            }
+        }
+
+        match header.kind.as_str() {
+            "FAIL" => {
+                if let Some(id) = header.extra.first()
+                    && *id != state.myself
+                    && let Some(node) = state.nodes.get_mut(id)
+                    && !node.fail
+                {
+                    println!("FAIL message received from {} about {}", header.sender, id);
+                    node.pfail = false;
+                    node.fail = true;
+                    node.fail_time = now;
+                    changed = true;
+                }
+            }
+            "UPDATE" => {
+                if let [id, epoch, slots] = &header.extra[..]
+                    && let Ok(epoch) = epoch.parse::<u64>()
+                    && let Some(node) = state.nodes.get_mut(id)
+                    && node.config_epoch < epoch
+                {
+                    node.config_epoch = epoch;
+                    let slots = decode_slots(slots)?;
+                    state.claim(id, epoch, &slots, offset, config);
+                    changed = true;
+                }
+            }
+            "AUTH_REQUEST" if state.vote(&header, now) => {
+                let message = state.message("AUTH_ACK", offset, vec![]);
+                state.send(&header.sender, message);
+                changed = true;
+            }
+            "AUTH_ACK" => {
+                let serves = state.count_slots(&header.sender) > 0;
+                if let Some(election) = state.election.as_mut()
+                    && election.requested
+                    && header.master_id.is_none()
+                    && serves
+                    && header.current_epoch >= election.epoch
+                {
+                    election.votes += 1;
+                }
+            }
+            _ => {}
+        }
+
+        if changed {
+            self.persist(&state);
+        }
+        Ok(reply)
+    }
+
+    // Runs every TIMER_PERIOD: suspects the nodes which didn't answer a ping within the
+    // node timeout, marks them as failed once the masters agree, and runs the election of
+    // a replica whose master failed
+    fn tick(&self, config: &Config) {
+        let now = now_ms();
+        let offset = config.replication.master.offset();
+        let mut state = self.state.lock().unwrap();
+        let timeout = state.node_timeout;
+        let mut changed = false;
+
+        // Nodes met which never answered are given up on
+        state.nodes.retain(|_, node| {
+            !node.handshake || now.saturating_sub(node.ctime) < timeout.max(1000)
+        });
+
+        let mut suspected = Vec::new();
+        for node in state.nodes.values_mut() {
+            if node.myself || node.handshake {
+                continue;
+            }
+            if !node.pfail
+                && !node.fail
+                && node.ping_sent != 0
+                && now.saturating_sub(node.ping_sent) > timeout
+            {
+                println!("*** NODE {} possibly failing", node.id);
+                node.pfail = true;
+            }
+            if node.pfail {
+                suspected.push(node.id.clone());
+            }
+        }
+        for id in suspected {
+            changed |= state.mark_as_failing(&id, now, offset);
+        }
+        changed |= state.failover(now, offset, config);
+
+        if changed {
+            self.persist(&state);
+        }
+    }
+}
+
+// Every message starts with what the sender knows about itself: the message type, its ID,
+// ports, master, epochs, replication offset and slots, then gossip about other nodes
+struct Header {
+    kind: String,
+    sender: String,
+    port: u16,
+    bus_port: u16,
+    master_id: Option<String>,
+    current_epoch: u64,
+    config_epoch: u64,
+    offset: u64,
+    slots: Vec<u16>,
+    gossip: Vec<Gossip>,
+    // What FAIL and UPDATE messages are about
+    extra: Vec<String>,
+}
+
+struct Gossip {
+    id: String,
+    ip: String,
+    port: u16,
+    bus_port: u16,
+    // failing is set when the sender suspects or knows the node failed
+    failing: bool,
+}
+
+fn parse_header(argv: &[String]) -> Result<Header> {
+    if argv.len() < HEADER_FIELDS {
+        return Err(anyhow!("truncated cluster bus message"));
+    }
+    let number = |field: &String| {
+        field
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid cluster bus message field '{}'", field))
+    };
+    let end = HEADER_FIELDS + number(&argv[9])? as usize * GOSSIP_FIELDS;
+    if argv.len() < end {
+        return Err(anyhow!("truncated cluster bus message"));
+    }
+    let gossip = argv[HEADER_FIELDS..end]
+        .chunks(GOSSIP_FIELDS)
+        .map(|fields| {
+            Ok(Gossip {
+                id: fields[0].clone(),
+                ip: fields[1].clone(),
+                port: number(&fields[2])? as u16,
+                bus_port: number(&fields[3])? as u16,
+                failing: fields[4] != "ok",
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Header {
+        kind: argv[0].to_uppercase(),
+        sender: argv[1].clone(),
+        port: number(&argv[2])? as u16,
+        bus_port: number(&argv[3])? as u16,
+        master_id: (argv[4] != "-").then(|| argv[4].clone()),
+        current_epoch: number(&argv[5])?,
+        config_epoch: number(&argv[6])?,
+        offset: number(&argv[7])?,
+        slots: decode_slots(&argv[8])?,
+        gossip,
+        extra: argv[end..].to_vec(),
+    })
+}
+
+// Slots travel as comma separated ranges, "-" standing for none
+fn encode_slots(ranges: &[(u16, u16)]) -> String {
+    if ranges.is_empty() {
+        return "-".to_string();
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_slots(value: &str) -> Result<Vec<u16>> {
+    if value == "-" {
+        return Ok(Vec::new());
+    }
+    let mut slots = Vec::new();
+    for range in value.split(',') {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        slots.extend(parse_slot(start)?..=parse_slot(end)?);
+    }
+    Ok(slots)
+}
+
+// The slot all `keys` hash to, None without keys. Keys of different slots can't be served
+// together, no node would hold all of them.
+fn same_slot(keys: &[&str]) -> Result<Option<u16>> {
+    let mut slots = keys.iter().map(|key| key_hash_slot(key.as_bytes()));
+    let Some(slot) = slots.next() else {
+        return Ok(None);
+    };
+    if slots.any(|other| other != slot) {
+        return Err(anyhow!(
+            "CROSSSLOT Keys in request don't hash to the same slot"
+        ));
+    }
+    Ok(Some(slot))
+}
+
+fn check_unique(slots: &[u16]) -> Result<()> {
+    let mut seen = vec![false; CLUSTER_SLOTS as usize];
+    for slot in slots {
+        if std::mem::replace(&mut seen[*slot as usize], true) {
+            return Err(anyhow!("ERR Slot {} specified multiple times", slot));
+        }
+    }
+    Ok(())
+}
+
+fn check_master(state: &State) -> Result<()> {
+    if !state.nodes[&state.myself].is_master() {
+        return Err(anyhow!("ERR Please use SETSLOT only with masters."));
+    }
+    Ok(())
+}
+
+// The node a slot is resharded with has to be another master
+fn check_target(state: &State, id: &str) -> Result<()> {
+    let Some(node) = state.nodes.get(id).filter(|node| !node.handshake) else {
+        return Err(anyhow!("ERR I don't know about node {}", id));
+    };
+    if node.myself || !node.is_master() {
+        return Err(anyhow!("ERR Target node is not a master"));
+    }
+    Ok(())
+}
+
+/// Parses a slot number given to a CLUSTER subcommand
+pub fn parse_slot(value: &str) -> Result<u16> {
+    value
+        .parse::<u16>()
+        .ok()
+        .filter(|slot| *slot < CLUSTER_SLOTS)
+        .ok_or_else(|| anyhow!("ERR Invalid or out of range slot"))
+}
+
+// Node IDs are 40 random hex characters
+fn random_id() -> String {
+    let mut rng = rand::rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.random_range(0..16), 16).unwrap())
+        .collect()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Reads the cluster config file, a CLUSTER NODES line per node followed by the epochs
+fn parse_config_file(state: &mut State, content: &str, port: u16) -> Result<()> {
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "vars" {
+            for pair in fields[1..].chunks(2) {
+                match pair {
+                    ["currentEpoch", epoch] => state.current_epoch = epoch.parse()?,
+                    ["lastVoteEpoch", epoch] => state.last_vote_epoch = epoch.parse()?,
+                    _ => {}
+                }
+            }
+            continue;
+        }
+        if fields.len() < 8 {
+            return Err(anyhow!("unexpected line '{}'", line));
+        }
+
+        let (addr, bus_port) = fields[1]
+            .split_once('@')
+            .ok_or_else(|| anyhow!("unexpected address '{}'", fields[1]))?;
+        let (ip, node_port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("unexpected address '{}'", fields[1]))?;
+        let mut node = Node::new(fields[0].to_string(), ip, node_port.parse()?);
+        node.bus_port = bus_port.split(',').next().unwrap_or_default().parse()?;
+        let flags: Vec<&str> = fields[2].split(',').collect();
+        node.myself = flags.contains(&"myself");
+        node.fail = flags.contains(&"fail");
+        node.fail_time = node.ctime;
+        if flags.contains(&"slave") && fields[3] != "-" {
+            node.master_id = Some(fields[3].to_string());
+        }
+        node.ping_sent = fields[4].parse()?;
+        node.pong_recv = fields[5].parse()?;
+        node.config_epoch = fields[6].parse()?;
+        if node.myself {
+            // The node may have been started on another port since
+            node.port = port;
+            node.bus_port = port + BUS_PORT_OFFSET;
+            node.ping_sent = 0;
+            state.myself = node.id.clone();
+        }
+
+        for range in &fields[8..] {
+            // Slots being resharded, as [slot->-target] or [slot-<-source]
+            if let Some(entry) = range
+                .strip_prefix('[')
+                .and_then(|entry| entry.strip_suffix(']'))
+            {
+                if let Some((slot, target)) = entry.split_once("->-") {
+                    state
+                        .migrating
+                        .insert(parse_slot(slot)?, target.to_string());
+                } else if let Some((slot, source)) = entry.split_once("-<-") {
+                    state
+                        .importing
+                        .insert(parse_slot(slot)?, source.to_string());
+                }
+                continue;
+            }
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            for slot in parse_slot(start)?..=parse_slot(end)? {
+                state.slots[slot as usize] = Some(node.id.clone());
+            }
+        }
+        state.nodes.insert(node.id.clone(), node);
+    }
+    if state.myself.is_empty() {
+        return Err(anyhow!("no node is flagged myself"));
+    }
+    Ok(())
+}
+
+/// Runs the cluster side of a node: a link to every other node, pinging it every second
+/// and delivering the messages queued for it, and the checks of `Cluster::tick`
+pub async fn cluster_cycle(config: Config) {
+    let Some(cluster) = config.cluster.clone() else {
+        return;
+    };
+    let mut linked = HashSet::new();
+    let mut interval = tokio::time::interval(TIMER_PERIOD);
+    loop {
+        interval.tick().await;
+        let peers = cluster.peers();
+        for id in &peers {
+            if linked.insert(id.clone()) {
+                tokio::spawn(node_link(cluster.clone(), id.clone(), config.clone()));
+            }
+        }
+        linked.retain(|id| peers.contains(id));
+        cluster.tick(&config);
+    }
+}
+
+/// Answers the messages other nodes send on the cluster bus
+pub async fn serve_bus(listener: TcpListener, config: Config) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let config = config.clone();
+        tokio::spawn(async move {
+            let _ = bus_connection(Connection::new(stream, addr), &config).await;
+        });
+    }
+}
+
+async fn bus_connection(mut connection: Connection, config: &Config) -> Result<()> {
+    let Some(cluster) = &config.cluster else {
+        return Ok(());
+    };
+    let ip = connection.addr().ip().to_string();
+    loop {
+        let request = connection.parse().await?;
+        let mut argv = vec![request.name];
+        argv.extend(request.args);
+        let reply = match cluster.process(&argv, &ip, None, config) {
+            Ok(Some(pong)) => message_frame(&pong),
+            Ok(None) => RespFrame::SimpleString("OK".to_string()),
+            Err(err) => RespFrame::Error(format!("ERR {}", err)),
+        };
+        connection.write(reply).await?;
+    }
+}
+
+// Talks to a node until it is forgotten, connecting again whenever the link is lost
+async fn node_link(cluster: Cluster, id: String, config: Config) {
+    while let Some(addr) = cluster.link(&id) {
+        if talk(&cluster, &id, &addr, &config).await.is_err() {
+            cluster.set_connected(&id, false);
+        }
+        tokio::time::sleep(PING_PERIOD).await;
+    }
+}
+
+async fn talk(cluster: &Cluster, id: &str, addr: &MasterAddr, config: &Config) -> Result<()> {
+    // A node which can't be reached counts as not answering the ping
+    cluster.pinging(id);
+    let timeout = Duration::from_millis(cluster.state.lock().unwrap().node_timeout);
+    let stream = tokio::time::timeout(
+        PING_PERIOD,
+        TcpStream::connect((addr.host.as_str(), addr.port)),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out connecting to {}:{}", addr.host, addr.port))??;
+    let peer = stream.peer_addr()?;
+    let mut connection = Connection::new(stream, peer);
+    cluster.set_connected(id, true);
+
+    let mut pinged: Option<Instant> = None;
+    while cluster.link(id).as_ref() == Some(addr) {
+        let ping = pinged.is_none_or(|at| at.elapsed() >= PING_PERIOD);
+        if ping {
+            pinged = Some(Instant::now());
+            cluster.pinging(id);
+        }
+        for message in cluster.outgoing(id, ping, config.replication.master.offset()) {
+            connection.write(message_frame(&message)).await?;
+            let reply = tokio::time::timeout(timeout, connection.read_reply())
+                .await
+                .map_err(|_| anyhow!("Timed out waiting for {}", id))??;
+            if let RespFrame::Array(items) = reply {
+                let argv: Vec<String> = items
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        RespFrame::BulkString(field) => Some(field),
+                        _ => None,
+                    })
+                    .collect();
+                cluster.process(&argv, &addr.host, Some(id), config)?;
+            }
+        }
+        tokio::time::sleep(TIMER_PERIOD).await;
+    }
+    Ok(())
+}
+
+fn message_frame(message: &[String]) -> RespFrame {
+    RespFrame::Array(
+        message
+            .iter()
+            .map(|field| RespFrame::BulkString(field.clone()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp::commands::structs::Value;
+
+    const MYSELF: &str = "1111111111111111111111111111111111111111";
+    const OTHER: &str = "2222222222222222222222222222222222222222";
+
+    // A cluster of two masters loaded from its config file, this node serving the first
+    // half of the slots and the other node, on port 7001, the second half
+    fn two_masters(name: &str) -> (Cluster, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("cluster-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nodes.conf");
+        let content = format!(
+            "{} 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-8191\n\
+             {} 127.0.0.1:7001@17001 master - 0 0 2 connected 8192-16383\n\
+             vars currentEpoch 2 lastVoteEpoch 0\n",
+            MYSELF, OTHER
+        );
+        std::fs::write(&path, content).unwrap();
+        let cluster = Cluster::load(path, 7000, DEFAULT_NODE_TIMEOUT).unwrap();
+        (cluster, dir)
+    }
+
+    fn route(cluster: &Cluster, keys: &[&str], asking: bool, db: &RwLock<MemDB<Data>>) -> String {
+        match cluster.route(keys, asking, db) {
+            Ok(()) => "OK".to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    // The slots CLUSTER KEYSLOT gives for the same keys, and the hash tag rules of the Redis
+    // Cluster specification
+    #[test]
+    fn hashes_keys_to_slots_like_redis() {
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"bar"), 5061);
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+        assert_eq!(key_hash_slot(b"123456789"), 12739);
+
+        // Only what the first {...} holds is hashed, so related keys share a slot
+        assert_eq!(key_hash_slot(b"{user1000}.following"), 3443);
+        assert_eq!(key_hash_slot(b"{user1000}.followers"), 3443);
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        // Neither an empty tag nor an unclosed one count, the whole key is hashed
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), 8363);
+        assert_eq!(key_hash_slot(b"foo{bar"), 15278);
+
+        assert_eq!(
+            same_slot(&["{user1000}.following", "{user1000}.followers"]).unwrap(),
+            Some(3443)
+        );
+        assert_eq!(same_slot(&[]).unwrap(), None);
+        assert!(
+            same_slot(&["foo", "bar"])
+                .unwrap_err()
+                .to_string()
+                .starts_with("CROSSSLOT")
+        );
+    }
+
+    #[test]
+    fn redirects_keys_of_slots_served_elsewhere() {
+        let (cluster, dir) = two_masters("redirects");
+        let db = RwLock::new(MemDB::new());
+
+        // bar hashes to 5061, served here, and foo to 12182, served by the other node
+        assert_eq!(route(&cluster, &["bar"], false, &db), "OK");
+        assert_eq!(
+            route(&cluster, &["foo"], false, &db),
+            "MOVED 12182 127.0.0.1:7001"
+        );
+        assert_eq!(
+            route(&cluster, &["foo"], true, &db),
+            "MOVED 12182 127.0.0.1:7001"
+        );
+        assert!(route(&cluster, &["foo", "bar"], false, &db).starts_with("CROSSSLOT"));
+
+        // A slot being moved out is still served for the keys it holds, the others are
+        // asked for to the node taking it in
+        cluster.set_migrating(5061, OTHER).unwrap();
+        assert_eq!(
+            route(&cluster, &["bar"], false, &db),
+            "ASK 5061 127.0.0.1:7001"
+        );
+        db.write().unwrap().set(
+            "bar".to_string(),
+            Data {
+                value: Value::String(b"1".to_vec()),
+                expires_at: None,
+            },
+        );
+        assert_eq!(route(&cluster, &["bar"], false, &db), "OK");
+        assert_eq!(
+            route(&cluster, &["{bar}other", "bar"], false, &db),
+            "ASK 5061 127.0.0.1:7001"
+        );
+
+        // A slot being taken in is only served to the clients which sent ASKING
+        cluster.set_importing(12182, OTHER).unwrap();
+        assert_eq!(
+            route(&cluster, &["foo"], false, &db),
+            "MOVED 12182 127.0.0.1:7001"
+        );
+        assert_eq!(route(&cluster, &["foo"], true, &db), "OK");
+        assert!(route(&cluster, &["foo", "{foo}2"], true, &db).starts_with("TRYAGAIN"));
+
+        // Once the slot is assigned here it is served to every client
+        cluster.set_slot_node(12182, MYSELF, false).unwrap();
+        assert_eq!(route(&cluster, &["foo"], false, &db), "OK");
+        cluster.set_slot_node(5061, OTHER, false).unwrap();
+        assert_eq!(
+            route(&cluster, &["bar"], false, &db),
+            "MOVED 5061 127.0.0.1:7001"
+        );
+
+        // Keys of a slot nobody serves are refused
+        cluster.del_slots(&[12182]).unwrap();
+        assert_eq!(
+            route(&cluster, &["bar"], false, &db),
+            "CLUSTERDOWN The cluster is down"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,26 @@
 use std::{
-    fmt::Display,
+    fmt::{self, Display},
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    aof::{self, Aof},
+    cluster::Cluster,
+    functions::Functions,
+    mem::Databases,
+    notify::{self, Notifier},
+    pubsub::PubSub,
+    replication::{DisklessLoad, LinkStatus, Master, MasterAddr, Upstream},
+    resp::commands::structs::Data,
+    scripting::Scripting,
+    sentinel::Sentinel,
+    snapshot::Persistence,
 };
 
 #[derive(Clone, PartialEq)]
@@ -17,8 +34,54 @@ pub struct Config {
     pub server: ServerInfo,
     pub replication: ReplicationInfo,
     pub stats: StatsInfo,
+    pub pubsub: PubSub,
+    pub notifier: Notifier,
+    pub scripting: Scripting,
+    pub functions: Functions,
+    pub persistence: Persistence,
+    pub aof: Aof,
+    // sentinel is set when the server runs with --sentinel, it then holds no data
+    pub sentinel: Option<Sentinel>,
+    // cluster is set when the server runs with --cluster-enabled yes
+    pub cluster: Option<Cluster>,
+    pub databases: Arc<Databases<Data>>,
+    // propagated_db is the database the commands handed to the AOF and the replicas apply
+    // to, a SELECT goes out first when the next one is for another. None makes it go out
+    // anyway, for a stream which starts over.
+    propagated_db: Arc<Mutex<Option<usize>>>,
 }
 
+// How many databases there are unless --databases says otherwise
+pub const DEFAULT_DATABASES: usize = 16;
+
+// Redis won't keep a smaller replication backlog, a smaller size is raised to it
+const MIN_BACKLOG_SIZE: usize = 16 * 1024;
+
+// Parameters which can be read and changed at runtime through CONFIG GET / CONFIG SET
+pub const PARAMETERS: &[&str] = &[
+    "notify-keyspace-events",
+    "busy-reply-threshold",
+    "lua-time-limit",
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "appenddirname",
+    "appendfsync",
+    "aof-load-truncated",
+    "aof-use-rdb-preamble",
+    "repl-backlog-size",
+    "replica-read-only",
+    "slave-read-only",
+    "repl-diskless-sync",
+    "repl-diskless-sync-delay",
+    "repl-diskless-load",
+    "replica-priority",
+    "slave-priority",
+    "databases",
+];
+
 #[derive(Clone)]
 pub struct ServerInfo {
     pub redis_version: String,
@@ -26,58 +89,122 @@ pub struct ServerInfo {
     pub arch_bits: String,
     pub tcp_port: u32,
 }
-impl ServerInfo {
-    fn to_string(&self) -> String {
+impl Display for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = String::new();
         builder.push_str("# Server\r\n");
         builder.push_str(&format!("redis_version: {}\r\n", self.redis_version));
         builder.push_str(&format!("os: {}\r\n", self.os));
         builder.push_str(&format!("arch_bits: {}\r\n", self.arch_bits));
         builder.push_str(&format!("tcp_port: {}\r\n", self.tcp_port));
-        builder
+        f.write_str(&builder)
     }
 }
 
 #[derive(Clone)]
 pub struct ReplicationInfo {
-    pub role: Role,
-    pub master_host: Option<String>,
-    pub master_port: Option<u32>,
-    pub connected_slaves: Arc<AtomicUsize>,
-    pub master_replid: String,
-    pub master_repl_offset: u64,
+    // upstream is the master this server replicates, it is a master itself without one
+    pub upstream: Upstream,
+    // master holds the replication ID and offset, along with the replicas fed from them
+    pub master: Master,
+    // read_only is replica-read-only, clients of a replica can't write while it is set
+    pub read_only: Arc<AtomicBool>,
+    // priority is replica-priority, sentinels promote the replica with the lowest one first
+    // and never one with 0
+    pub priority: Arc<AtomicUsize>,
 }
+
 impl ReplicationInfo {
-    fn to_string(&self) -> String {
+    pub fn role(&self) -> Role {
+        match self.upstream.addr() {
+            Some(_) => Role::Replica,
+            None => Role::Master,
+        }
+    }
+
+    /// Whether writes from clients are refused, the master's stream is applied anyway
+    pub fn is_read_only(&self) -> bool {
+        self.role() == Role::Replica && self.read_only.load(Ordering::Relaxed)
+    }
+}
+
+impl Display for ReplicationInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = String::new();
         builder.push_str("# Replication\r\n");
-        builder.push_str(&format!(
-            "role:{}\r\n",
-            match self.role {
-                Role::Master => "master",
-                Role::Replica => "slave",
-            }
-        ));
 
-        if self.role == Role::Master {
+        if let Some(addr) = self.upstream.addr() {
+            let status = self.upstream.status();
+            builder.push_str("role:slave\r\n");
+            builder.push_str(&format!("master_host:{}\r\n", addr.host));
+            builder.push_str(&format!("master_port:{}\r\n", addr.port));
+            builder.push_str(&format!(
+                "master_link_status:{}\r\n",
+                if status == LinkStatus::Connected {
+                    "up"
+                } else {
+                    "down"
+                }
+            ));
+            builder.push_str(&format!(
+                "master_last_io_seconds_ago:{}\r\n",
+                self.upstream
+                    .last_io_seconds()
+                    .map_or(-1, |seconds| seconds as i64)
+            ));
+            builder.push_str(&format!(
+                "master_sync_in_progress:{}\r\n",
+                (status == LinkStatus::Sync) as u8
+            ));
+            builder.push_str(&format!("slave_repl_offset:{}\r\n", self.master.offset()));
+            builder.push_str(&format!(
+                "slave_read_only:{}\r\n",
+                self.read_only.load(Ordering::Relaxed) as u8
+            ));
+            builder.push_str(&format!(
+                "slave_priority:{}\r\n",
+                self.priority.load(Ordering::Relaxed)
+            ));
+        } else {
+            builder.push_str("role:master\r\n");
+        }
+
+        let replicas = self.master.replicas();
+        builder.push_str(&format!("connected_slaves:{}\r\n", replicas.len()));
+        for (index, replica) in replicas.iter().enumerate() {
             builder.push_str(&format!(
-                "connected_slaves:{}\r\n",
-                self.connected_slaves.load(Ordering::Relaxed)
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                index,
+                replica.ip,
+                replica.port,
+                replica.state.name(),
+                replica.offset,
+                replica.lag
             ));
         }
 
-        builder.push_str(&format!("master_replid:{}\r\n", self.master_replid));
+        builder.push_str(&format!("master_replid:{}\r\n", self.master.replid()));
+        builder.push_str(&format!("master_replid2:{}\r\n", self.master.replid2()));
+        builder.push_str(&format!("master_repl_offset:{}\r\n", self.master.offset()));
         builder.push_str(&format!(
-            "master_repl_offset:{}\r\n",
-            self.master_repl_offset
+            "second_repl_offset:{}\r\n",
+            self.master.second_offset()
         ));
 
-        if let (Some(host), Some(port)) = (&self.master_host, self.master_port) {
-            builder.push_str(&format!("master_host:{}\r\n", host));
-            builder.push_str(&format!("master_port:{}\r\n", port));
-        }
+        let backlog = self.master.backlog_range();
+        builder.push_str(&format!(
+            "repl_backlog_active:{}\r\n",
+            backlog.is_some() as u8
+        ));
+        builder.push_str(&format!(
+            "repl_backlog_size:{}\r\n",
+            self.master.backlog_size()
+        ));
+        let (first, histlen) = backlog.unwrap_or((0, 0));
+        builder.push_str(&format!("repl_backlog_first_byte_offset:{}\r\n", first));
+        builder.push_str(&format!("repl_backlog_histlen:{}\r\n", histlen));
 
-        builder
+        f.write_str(&builder)
     }
 }
 
@@ -86,26 +213,20 @@ pub struct StatsInfo {
     pub total_connections_received: Arc<AtomicUsize>,
     pub total_commands_processed: Arc<AtomicUsize>,
 }
-impl StatsInfo {
-    fn to_string(&self) -> String {
-        format!(
-            "total_connections_received:{}\r\n",
-            self.total_connections_received.load(Ordering::Relaxed)
-        ) + &format!(
-            "total_commands_processed:{}\r\n",
+impl Display for StatsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n",
+            self.total_connections_received.load(Ordering::Relaxed),
             self.total_commands_processed.load(Ordering::Relaxed)
         )
     }
 }
 
 impl Config {
-    pub fn new(
-        role: Role,
-        port: u32,
-        master_host: Option<String>,
-        master_port: Option<u32>,
-    ) -> Self {
-        let connected_slaves = Arc::new(AtomicUsize::new(0));
+    pub fn new(port: u32, replicaof: Option<MasterAddr>) -> Self {
+        let pubsub = PubSub::new();
         Config {
             server: ServerInfo {
                 redis_version: "0.1.0".to_string(),
@@ -118,57 +239,267 @@ impl Config {
                 tcp_port: port,
             },
             replication: ReplicationInfo {
-                role,
-                master_host,
-                master_port,
-                connected_slaves,
-                master_replid: generate_random_alphanumeric(40),
-                master_repl_offset: 0,
+                upstream: Upstream::new(replicaof),
+                master: Master::new(generate_random_alphanumeric(40)),
+                read_only: Arc::new(AtomicBool::new(true)),
+                priority: Arc::new(AtomicUsize::new(100)),
             },
             stats: StatsInfo {
                 total_connections_received: Arc::new(AtomicUsize::new(0)),
                 total_commands_processed: Arc::new(AtomicUsize::new(0)),
             },
+            notifier: Notifier::new(pubsub.clone()),
+            pubsub,
+            scripting: Scripting::new(),
+            functions: Functions::new(),
+            persistence: Persistence::new(),
+            aof: Aof::new(),
+            sentinel: None,
+            cluster: None,
+            databases: Arc::new(Databases::new(DEFAULT_DATABASES)),
+            propagated_db: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Returns the current value of a parameter listed in PARAMETERS
+    pub fn get_parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "notify-keyspace-events" => Some(notify::flags_to_string(self.notifier.flags())),
+            "busy-reply-threshold" | "lua-time-limit" => {
+                Some(self.scripting.busy_threshold().to_string())
+            }
+            "dir" => Some(self.persistence.dir()),
+            "dbfilename" => Some(self.persistence.dbfilename()),
+            "save" => Some(self.persistence.save_rules()),
+            "appendonly" => Some(yes_no(self.aof.enabled())),
+            "appendfilename" => Some(self.aof.filename()),
+            "appenddirname" => Some(self.aof.dirname()),
+            "appendfsync" => Some(self.aof.fsync_policy()),
+            "aof-load-truncated" => Some(yes_no(self.aof.load_truncated())),
+            "aof-use-rdb-preamble" => Some(yes_no(self.aof.use_rdb_preamble())),
+            "repl-backlog-size" => Some(self.replication.master.backlog_size().to_string()),
+            "replica-read-only" | "slave-read-only" => {
+                Some(yes_no(self.replication.read_only.load(Ordering::Relaxed)))
+            }
+            "repl-diskless-sync" => Some(yes_no(self.replication.master.diskless_sync())),
+            "repl-diskless-sync-delay" => {
+                Some(self.replication.master.diskless_sync_delay().to_string())
+            }
+            "repl-diskless-load" => {
+                Some(self.replication.upstream.diskless_load().name().to_string())
+            }
+            "replica-priority" | "slave-priority" => Some(
+                self.replication
+                    .priority
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+            "databases" => Some(self.databases.len().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Changes a parameter listed in PARAMETERS, failing when the value is invalid
+    pub fn set_parameter(&self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "notify-keyspace-events" => self.notifier.set_flags(notify::parse_flags(value)?),
+            "busy-reply-threshold" | "lua-time-limit" => {
+                self.scripting.set_busy_threshold(parse_number(value)?)
+            }
+            "dir" => self.persistence.set_dir(value),
+            "dbfilename" => self.persistence.set_dbfilename(value)?,
+            "save" => self.persistence.set_save_rules(value)?,
+            "appendonly" => self.aof.set_enabled(parse_yes_no(value)?),
+            "appendfilename" | "appenddirname" | "databases" => {
+                return Err(anyhow!("can't set immutable config"));
+            }
+            "appendfsync" => self.aof.set_fsync_policy(value)?,
+            "aof-load-truncated" => self.aof.set_load_truncated(parse_yes_no(value)?),
+            "aof-use-rdb-preamble" => self.aof.set_use_rdb_preamble(parse_yes_no(value)?),
+            "repl-backlog-size" => self
+                .replication
+                .master
+                .set_backlog_size(parse_memory(value)?.max(MIN_BACKLOG_SIZE)),
+            "replica-read-only" | "slave-read-only" => self
+                .replication
+                .read_only
+                .store(parse_yes_no(value)?, Ordering::Relaxed),
+            "repl-diskless-sync" => self
+                .replication
+                .master
+                .set_diskless_sync(parse_yes_no(value)?),
+            "repl-diskless-sync-delay" => self
+                .replication
+                .master
+                .set_diskless_sync_delay(parse_number(value)?),
+            "repl-diskless-load" => self
+                .replication
+                .upstream
+                .set_diskless_load(DisklessLoad::parse(value)?),
+            "replica-priority" | "slave-priority" => self
+                .replication
+                .priority
+                .store(parse_number(value)?, Ordering::Relaxed),
+            _ => return Err(anyhow!("Unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// Hands the commands which changed database `db` over to the AOF and the replicas.
+    /// Several commands are wrapped in MULTI/EXEC so they are applied all or nothing, those of
+    /// another database following a SELECT of their own.
+    pub fn propagate(&self, db: usize, mut commands: Vec<Vec<Vec<u8>>>) {
+        if commands.is_empty() {
+            return;
+        }
+        if commands.len() > 1 {
+            commands.insert(0, vec![b"MULTI".to_vec()]);
+            commands.push(vec![b"EXEC".to_vec()]);
+        }
+        // Held until the commands are fed, so they follow their SELECT in both streams
+        let mut propagated_db = self.propagated_db.lock().unwrap();
+        if *propagated_db != Some(db) {
+            commands.insert(0, vec![b"SELECT".to_vec(), db.to_string().into_bytes()]);
+        }
+        *propagated_db = Some(db);
+        for argv in commands.iter_mut() {
+            absolute_expiry(argv);
+            // A transaction may switch database along the way, the stream ends up on the last one
+            if argv.len() == 2
+                && argv[0].eq_ignore_ascii_case(b"SELECT")
+                && let Some(index) = std::str::from_utf8(&argv[1])
+                    .ok()
+                    .and_then(|index| index.parse().ok())
+            {
+                *propagated_db = Some(index);
+            }
+        }
+        let payload = aof::encode_commands(&commands);
+        self.aof.feed(&payload);
+        // A replica forwards the stream of its master as it was received instead
+        if self.replication.role() == Role::Master {
+            self.replication.master.feed(&payload);
+            self.aof.advance(self.replication.master.offset());
+            self.replication.master.wake_waiters();
+        }
+    }
+
+    /// Makes the next propagated command go out after a SELECT, for a stream starting over
+    /// from a copy of the dataset: a replica which loaded it, or a new AOF incr file
+    pub fn reset_propagated_db(&self) {
+        *self.propagated_db.lock().unwrap() = None;
+    }
+
     pub fn increment_connections(&self) {
-        self.replication
-            .connected_slaves
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         self.stats
             .total_connections_received
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
+}
 
-    pub fn decrement_connections(&self) {
-        self.replication
-            .connected_slaves
-            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-    }
-
-    //to_string returns a String representation of the entire config according to the RESP3 spec of INFO command (https://redis.io/docs/latest/commands/info/)
-    pub fn to_string(&self) -> String {
+//to_string returns a String representation of the entire config according to the RESP3 spec of INFO command (https://redis.io/docs/latest/commands/info/)
+impl Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = String::new();
         builder.push_str(&self.server.to_string());
-        builder.push_str(&self.replication.to_string());
+        // A sentinel has no dataset to persist or replicate
+        if let Some(sentinel) = &self.sentinel {
+            builder.push_str(&sentinel.to_string());
+        } else {
+            builder.push_str(&self.persistence.to_string());
+            builder.push_str(&self.aof.to_string());
+            builder.push_str(&self.replication.to_string());
+        }
+        builder.push_str(&format!(
+            "# Cluster\r\ncluster_enabled:{}\r\n",
+            self.cluster.is_some() as u8
+        ));
         builder.push_str(&self.stats.to_string());
 
-        builder
+        f.write_str(&builder)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("argument couldn't be parsed into an integer"))
+}
+
+// Parses a size in bytes, which may carry a unit like 512kb or 1mb as in redis.conf
+fn parse_memory(value: &str) -> Result<usize> {
+    let value = value.to_lowercase();
+    let (digits, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("argument must be a memory value")),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|digits| digits.checked_mul(unit))
+        .ok_or_else(|| anyhow!("argument must be a memory value"))
+}
+
+/// Parses the yes/no value of a boolean parameter
+pub fn parse_yes_no(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("argument must be 'yes' or 'no'")),
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+// Relative expiry times are rewritten as absolute ones, a command replayed later must not
+// push the expiry further out. SET key value EX|PX ttl becomes SET key value PXAT timestamp.
+fn absolute_expiry(argv: &mut [Vec<u8>]) {
+    if argv.len() != 5 || !argv[0].eq_ignore_ascii_case(b"set") {
+        return;
     }
+    let unit = match argv[3].to_ascii_lowercase().as_slice() {
+        b"ex" => 1000,
+        b"px" => 1,
+        _ => return,
+    };
+    let Some(ttl) = std::str::from_utf8(&argv[4])
+        .ok()
+        .and_then(|ttl| ttl.parse::<u64>().ok())
+    else {
+        return;
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    argv[3] = b"PXAT".to_vec();
+    argv[4] = (now_ms + ttl * unit).to_string().into_bytes();
 }
 
 /// Generates a random alphanumeric string of the specified length
-fn generate_random_alphanumeric(length: usize) -> String {
+pub fn generate_random_alphanumeric(length: usize) -> String {
     use rand::Rng;
 
     const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 
-    let mut rng = rand::thread_rng();
+    let mut rng = rand::rng();
 
     (0..length)
         .map(|_| {
-            let idx = rng.gen_range(0..CHARSET.len());
+            let idx = rng.random_range(0..CHARSET.len());
             CHARSET[idx] as char
         })
         .collect()
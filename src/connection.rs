@@ -1,60 +1,209 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use anyhow::{Result, anyhow};
-use bytes::BytesMut;
+use bytes::{Buf, Bytes, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::TcpStream,
 };
 
-use crate::resp::{commands::Command, frame::RespFrame, parser::parse_resp};
+use crate::resp::{
+    frame::RespFrame,
+    parser::{Request, parse_reply, parse_request},
+};
+
+// A client whose unparsed requests grow past this is disconnected, like client-query-buffer-limit
+const QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+
+// Source of the unique ids handed to every connection
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct Connection {
+    id: u64,
     stream: BufWriter<TcpStream>,
     addr: SocketAddr,
+    // Bytes read from the socket which are not yet parsed into a command.
+    // Holds partial requests across reads and the remainder of pipelined requests.
+    buffer: BytesMut,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream, addr: SocketAddr) -> Connection {
         Connection {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
             stream: BufWriter::new(stream),
             addr,
+            //TODO: For MVP, 4KB is more than enough, but we should let users configure it based on requirement
+            buffer: BytesMut::with_capacity(4 * 1024),
         }
     }
 
-    pub async fn parse(&mut self) -> Result<Box<dyn Command>> {
-        //TODO: For MVP, 4KB is more than enough, but we should let users configure it based on requirement
-        let mut buf = BytesMut::with_capacity(4 * 1024);
-        let bytes_read = self.stream.read_buf(&mut buf).await?;
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
 
-        if bytes_read == 0 {
-            return Err(anyhow!(
-                "Connection closed by {}:{}",
-                self.addr.ip(),
-                self.addr.port()
-            ));
+    pub async fn parse(&mut self) -> Result<Request> {
+        self.parse_raw().await.map(|(request, _)| request)
+    }
+
+    /// Parses the next request along with the bytes it was sent as
+    pub async fn parse_raw(&mut self) -> Result<(Request, Bytes)> {
+        loop {
+            match parse_request(&self.buffer) {
+                Ok(Some((request, consumed))) => {
+                    return Ok((request, self.buffer.split_to(consumed).freeze()));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    // The stream can't be resynchronised after a protocol error, so drop what we have
+                    self.buffer.clear();
+                    return Err(err);
+                }
+            }
+            if self.buffer.len() > QUERY_BUFFER_LIMIT {
+                self.buffer.clear();
+                return Err(anyhow!("Query buffer limit reached"));
+            }
+            self.fill().await?;
         }
+    }
 
-        let data = std::str::from_utf8(&buf)?;
+    /// Reads the next reply of the server on the other end, when this side is the client
+    pub async fn read_reply(&mut self) -> Result<RespFrame> {
+        loop {
+            match parse_reply(&self.buffer) {
+                Ok(Some((frame, consumed))) => {
+                    self.buffer.advance(consumed);
+                    return Ok(frame);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    self.buffer.clear();
+                    return Err(err);
+                }
+            }
+            self.fill().await?;
+        }
+    }
 
-        let command = parse_resp(data)?;
+    /// Reads a single line, like the status replies to the replication handshake
+    pub async fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer.split_to(end + 2);
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            self.fill().await?;
+        }
+    }
 
-        Ok(command)
+    /// Reads exactly `len` bytes, like the RDB file sent to a replica
+    pub async fn read_exact(&mut self, len: usize) -> Result<Bytes> {
+        while self.buffer.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(len).freeze())
     }
 
-    pub async fn write(&mut self, frame: RespFrame) -> Result<()> {
-        self.stream.write_all(&frame.encode()).await?;
-        self.stream.flush().await?;
-        Ok(())
+    /// Reads up to `mark` and drops it, returning what came before, like an RDB file sent
+    /// between EOF marks
+    pub async fn read_until(&mut self, mark: &[u8]) -> Result<Bytes> {
+        let mut scanned = 0;
+        loop {
+            if let Some(position) = self.buffer[scanned..]
+                .windows(mark.len())
+                .position(|window| window == mark)
+            {
+                let data = self.buffer.split_to(scanned + position).freeze();
+                self.buffer.advance(mark.len());
+                return Ok(data);
+            }
+            // The mark may have only partly arrived
+            scanned = self.buffer.len().saturating_sub(mark.len() - 1);
+            self.fill().await?;
+        }
     }
 
-    pub async fn write_result(
+    /// Hands what was read to `consume` until it is done, reading more as needed. It returns
+    /// how many bytes it used, those left over are given again once more arrived. A large item
+    /// is only parsed again once the buffer doubled, or once the socket has nothing more for now.
+    pub async fn consume<T>(
         &mut self,
-        result: std::result::Result<RespFrame, anyhow::Error>,
-    ) -> Result<()> {
-        match result {
-            Ok(frame) => self.write(frame).await,
-            Err(err) => self.write(RespFrame::Error(err.to_string())).await,
+        mut consume: impl FnMut(&[u8]) -> Result<(usize, Option<T>)>,
+    ) -> Result<T> {
+        let mut wanted = 0;
+        let mut parsed = None;
+        loop {
+            if self.buffer.len() >= wanted && parsed != Some(self.buffer.len()) {
+                let (used, done) = consume(&self.buffer)?;
+                self.buffer.advance(used);
+                if let Some(done) = done {
+                    return Ok(done);
+                }
+                wanted = self.buffer.len() * 2;
+                parsed = Some(self.buffer.len());
+            }
+            // What already arrived is taken without waiting. Once the socket is drained, the
+            // bytes read since the last attempt may complete the item, only then we block.
+            match self.stream.get_ref().try_read_buf(&mut self.buffer) {
+                Ok(0) => return Err(self.closed_error()),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if parsed == Some(self.buffer.len()) {
+                        self.fill().await?;
+                    } else {
+                        wanted = 0;
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
         }
     }
+
+    /// Resolves once the client disconnects, with the reason. What it sends in the meantime is
+    /// kept for the next parse, so a client blocked on something else can still be watched.
+    pub async fn closed(&mut self) -> anyhow::Error {
+        loop {
+            if self.buffer.len() > QUERY_BUFFER_LIMIT {
+                return anyhow!("Query buffer limit reached");
+            }
+            if let Err(err) = self.fill().await {
+                return err;
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+        if bytes_read == 0 {
+            return Err(self.closed_error());
+        }
+        Ok(())
+    }
+
+    fn closed_error(&self) -> anyhow::Error {
+        anyhow!(
+            "Connection closed by {}:{}",
+            self.addr.ip(),
+            self.addr.port()
+        )
+    }
+
+    pub async fn write(&mut self, frame: RespFrame) -> Result<()> {
+        self.write_raw(&frame.encode()).await
+    }
+
+    /// Writes bytes which are already encoded, like the replication stream
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,333 @@
+use std::hash::{BuildHasher, RandomState};
+
+use rand::Rng;
+
+// The table never shrinks below this many buckets
+const MIN_BUCKETS: usize = 4;
+
+// Empty buckets a rehash step skips at most before giving up, so a sparse table doesn't make
+// a single write slow
+const REHASH_EMPTY_VISITS: usize = 10;
+
+type Bucket<T> = Vec<(String, T)>;
+
+// Dict is the hash table behind a database. Keys are chained in a power of two number of
+// buckets, which lets SCAN walk it with a cursor that survives the table growing or
+// shrinking between two calls.
+//
+// Like in Redis, resizing is incremental: the new table is allocated next to the old one and
+// every write moves one more bucket over, so no single command pays for rehashing the whole
+// keyspace. Until it is done, keys are looked up in both tables and new ones go to the new table.
+pub struct Dict<T> {
+    // tables[0] is the table in use, tables[1] the one it is being rehashed into
+    tables: [Vec<Bucket<T>>; 2],
+    // rehash_index is the next bucket of tables[0] to move while rehashing, those below it are
+    // already empty
+    rehash_index: Option<usize>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<T> Default for Dict<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Dict<T> {
+    pub fn new() -> Self {
+        Dict {
+            tables: [Self::empty_buckets(MIN_BUCKETS), Vec::new()],
+            rehash_index: None,
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &str) -> Option<&T> {
+        self.in_use().find_map(|table| {
+            self.tables[table][self.bucket_of(table, key)]
+                .iter()
+                .find(|(other, _)| other == key)
+                .map(|(_, value)| value)
+        })
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        let (table, bucket, position) = self.position(key)?;
+        Some(&mut self.tables[table][bucket][position].1)
+    }
+
+    // insert returns the value the key held before, if any
+    pub fn insert(&mut self, key: String, value: T) -> Option<T> {
+        self.rehash_step();
+        if let Some((table, bucket, position)) = self.position(&key) {
+            let (_, old) = &mut self.tables[table][bucket][position];
+            return Some(std::mem::replace(old, value));
+        }
+        // While rehashing new keys go to the new table, so the old one only ever empties
+        let table = if self.rehash_index.is_some() { 1 } else { 0 };
+        let bucket = self.bucket_of(table, &key);
+        self.tables[table][bucket].push((key, value));
+        self.len += 1;
+        // Grow once there are more keys than buckets, so chains stay short
+        if self.rehash_index.is_none() && self.len > self.tables[0].len() {
+            self.start_resize(self.tables[0].len() * 2);
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        self.rehash_step();
+        let (table, bucket, position) = self.position(key)?;
+        let (_, value) = self.tables[table][bucket].swap_remove(position);
+        self.len -= 1;
+        // Shrink once the table is mostly empty, like Redis does below a 1/8 fill
+        let size = self.tables[0].len();
+        if self.rehash_index.is_none() && size > MIN_BUCKETS && self.len < size / 8 {
+            self.start_resize((self.len * 2).next_power_of_two().max(MIN_BUCKETS));
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &T)> {
+        self.tables
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    /// Calls `visit` with the keys of the bucket `cursor` points at and returns the cursor of
+    /// the next one, 0 once the whole table was visited.
+    ///
+    /// The cursor counts up with its bits reversed, so the high bits of the bucket index are
+    /// incremented first. When the table doubles, bucket i splits into i and i + size, which
+    /// share the low bits already walked; when it halves, the two buckets that merge share them
+    /// too. Either way the buckets left to visit still hold every key not yet returned, so a
+    /// key present for the whole iteration is always returned, possibly more than once.
+    ///
+    /// While rehashing, the bucket of the smaller table is visited along with every bucket of
+    /// the larger one it expands to, so the keys being moved between them are not missed.
+    pub fn scan(&self, cursor: u64, mut visit: impl FnMut(&String, &T)) -> u64 {
+        let mut visit_bucket = |bucket: &Bucket<T>| {
+            for (key, value) in bucket {
+                visit(key, value);
+            }
+        };
+
+        if self.rehash_index.is_none() {
+            let mask = (self.tables[0].len() - 1) as u64;
+            visit_bucket(&self.tables[0][(cursor & mask) as usize]);
+            return next_cursor(cursor, mask);
+        }
+
+        let (small, large) = if self.tables[0].len() <= self.tables[1].len() {
+            (&self.tables[0], &self.tables[1])
+        } else {
+            (&self.tables[1], &self.tables[0])
+        };
+        let (small_mask, large_mask) = ((small.len() - 1) as u64, (large.len() - 1) as u64);
+        visit_bucket(&small[(cursor & small_mask) as usize]);
+        // The buckets of the larger table sharing the low bits of the cursor, walked in the
+        // order of the bits the smaller mask doesn't cover
+        let mut cursor = cursor;
+        loop {
+            visit_bucket(&large[(cursor & large_mask) as usize]);
+            cursor = next_cursor(cursor, large_mask);
+            if cursor & (small_mask ^ large_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+
+    // random picks a key from a random non empty bucket
+    pub fn random(&self) -> Option<(&String, &T)> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut rng = rand::rng();
+        // The buckets of the old table below rehash_index are known to be empty
+        let skipped = self.rehash_index.unwrap_or(0);
+        let old = self.tables[0].len() - skipped;
+        let candidates = old + self.tables[1].len();
+        loop {
+            let index = rng.random_range(0..candidates);
+            let bucket = if index < old {
+                &self.tables[0][skipped + index]
+            } else {
+                &self.tables[1][index - old]
+            };
+            if !bucket.is_empty() {
+                let (key, value) = &bucket[rng.random_range(0..bucket.len())];
+                return Some((key, value));
+            }
+        }
+    }
+
+    // in_use lists the tables holding keys, the new one only while rehashing
+    fn in_use(&self) -> std::ops::Range<usize> {
+        0..if self.rehash_index.is_some() { 2 } else { 1 }
+    }
+
+    fn bucket_of(&self, table: usize, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize & (self.tables[table].len() - 1)
+    }
+
+    // position finds where the key is stored as its table, bucket and index in the bucket
+    fn position(&self, key: &str) -> Option<(usize, usize, usize)> {
+        self.in_use().find_map(|table| {
+            let bucket = self.bucket_of(table, key);
+            self.tables[table][bucket]
+                .iter()
+                .position(|(other, _)| other == key)
+                .map(|position| (table, bucket, position))
+        })
+    }
+
+    fn start_resize(&mut self, size: usize) {
+        self.tables[1] = Self::empty_buckets(size);
+        self.rehash_index = Some(0);
+    }
+
+    // rehash_step moves the next non empty bucket of the old table to the new one, and
+    // makes the new table the one in use once the old one is empty
+    fn rehash_step(&mut self) {
+        let Some(mut index) = self.rehash_index else {
+            return;
+        };
+        let mut empty_visits = 0;
+        while index < self.tables[0].len() && self.tables[0][index].is_empty() {
+            index += 1;
+            empty_visits += 1;
+            if empty_visits == REHASH_EMPTY_VISITS {
+                break;
+            }
+        }
+        if index < self.tables[0].len() && !self.tables[0][index].is_empty() {
+            for (key, value) in std::mem::take(&mut self.tables[0][index]) {
+                let bucket = self.bucket_of(1, &key);
+                self.tables[1][bucket].push((key, value));
+            }
+            index += 1;
+        }
+
+        if index < self.tables[0].len() {
+            self.rehash_index = Some(index);
+        } else {
+            self.tables[0] = std::mem::take(&mut self.tables[1]);
+            self.rehash_index = None;
+        }
+    }
+
+    fn empty_buckets(size: usize) -> Vec<Bucket<T>> {
+        (0..size).map(|_| Vec::new()).collect()
+    }
+}
+
+// next_cursor increments the cursor from its high bits down, setting the bits above the mask
+// so the increment carries past them
+fn next_cursor(cursor: u64, mask: u64) -> u64 {
+    (cursor | !mask)
+        .reverse_bits()
+        .wrapping_add(1)
+        .reverse_bits()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // scan_all walks the whole table, calling `between` after every call to scan
+    fn scan_all(
+        dict: &mut Dict<usize>,
+        mut between: impl FnMut(&mut Dict<usize>),
+    ) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(key.clone());
+            });
+            if cursor == 0 {
+                return seen;
+            }
+            between(dict);
+        }
+    }
+
+    #[test]
+    fn rehashes_a_step_at_a_time() {
+        let mut dict = Dict::new();
+        for i in 0..1000 {
+            dict.insert(format!("key:{}", i), i);
+            // Every key stays reachable while the tables are being swapped
+            assert_eq!(dict.get(&format!("key:{}", i / 2)), Some(&(i / 2)));
+        }
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.iter().count(), 1000);
+        assert_eq!(dict.insert("key:7".to_string(), 70), Some(7));
+        assert_eq!(dict.len(), 1000);
+
+        for i in 0..990 {
+            let value = if i == 7 { 70 } else { i };
+            assert_eq!(dict.remove(&format!("key:{}", i)), Some(value));
+        }
+        assert_eq!(dict.len(), 10);
+        assert!(dict.get("key:0").is_none());
+        assert_eq!(dict.get("key:995"), Some(&995));
+        assert!(
+            dict.random()
+                .is_some_and(|(key, _)| key >= &"key:990".to_string())
+        );
+
+        // The shrink to a quarter of the table completes after enough writes
+        for _ in 0..100 {
+            dict.insert("key:995".to_string(), 995);
+        }
+        assert!(dict.rehash_index.is_none());
+        assert!(dict.tables[0].len() <= 256);
+        assert_eq!(dict.iter().count(), 10);
+    }
+
+    #[test]
+    fn scan_returns_every_key_across_a_resize() {
+        let mut dict = Dict::new();
+        for i in 0..100 {
+            dict.insert(format!("kept:{}", i), i);
+        }
+        let kept: HashSet<String> = (0..100).map(|i| format!("kept:{}", i)).collect();
+
+        // The table grows between the calls, several times and while still rehashing
+        let mut added = 0;
+        let seen = scan_all(&mut dict, |dict| {
+            for _ in 0..50 {
+                if added < 5000 {
+                    dict.insert(format!("added:{}", added), added);
+                    added += 1;
+                }
+            }
+        });
+        assert!(kept.is_subset(&seen));
+        assert!(added > 1000);
+
+        // Then it shrinks back between the calls
+        let mut removed = 0;
+        let seen = scan_all(&mut dict, |dict| {
+            for _ in 0..200 {
+                dict.remove(&format!("added:{}", removed));
+                removed += 1;
+            }
+        });
+        assert!(kept.is_subset(&seen));
+        assert!(removed >= added);
+
+        assert_eq!(scan_all(&mut dict, |_| {}).len(), dict.len());
+    }
+}
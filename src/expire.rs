@@ -0,0 +1,77 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Config, Role},
+    mem::{Databases, MemDB},
+    notify::NOTIFY_EXPIRED,
+    resp::commands::structs::Data,
+    transaction::try_exclusively,
+};
+
+// Expired keys are hidden from reads as soon as their TTL passes, but they are only removed
+// from the store here. Removing them is also what sends the expired keyspace event.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+// Like Redis, each round tests this many random keys among those with an expiry, and keeps
+// going while more than ACTIVE_EXPIRE_REPEAT_PERCENT of them had expired. A cycle stops once
+// it used a quarter of the interval, so it never holds the databases for long.
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_REPEAT_PERCENT: usize = 25;
+const ACTIVE_EXPIRE_TIME_BUDGET: Duration = Duration::from_millis(25);
+
+/// Periodically removes expired keys, runs for as long as the server does
+pub async fn active_expire_cycle(dbs: Arc<Databases<Data>>, config: Config) {
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Replicas leave expiring keys to their master, which streams a DEL for each
+        if config.replication.role() == Role::Replica {
+            continue;
+        }
+        // The cycle is skipped while a transaction or a script holds the databases
+        try_exclusively(|| {
+            let deadline = Instant::now() + ACTIVE_EXPIRE_TIME_BUDGET;
+            for db in dbs.all() {
+                if let Ok(mut db) = db.write() {
+                    remove_expired(&mut db, deadline, &config);
+                }
+            }
+        });
+    }
+}
+
+fn remove_expired(db: &mut MemDB<Data>, deadline: Instant, config: &Config) {
+    while Instant::now() < deadline {
+        let mut sampled = db.sample_volatile(ACTIVE_EXPIRE_SAMPLE);
+        if sampled.is_empty() {
+            return;
+        }
+        sampled.sort_unstable();
+        sampled.dedup();
+
+        let tested = sampled.len();
+        let mut expired = 0;
+        for key in sampled {
+            if !db
+                .get(&key)
+                .is_ok_and(|data| data.is_some_and(Data::expired))
+            {
+                continue;
+            }
+            expired += 1;
+            db.remove(&key);
+            config
+                .notifier
+                .notify(NOTIFY_EXPIRED, "expired", &key, db.index());
+            // The AOF only learns about the expiry through an explicit delete
+            config.propagate(db.index(), vec![vec![b"DEL".to_vec(), key.into_bytes()]]);
+        }
+
+        if expired * 100 <= tested * ACTIVE_EXPIRE_REPEAT_PERCENT {
+            return;
+        }
+    }
+}
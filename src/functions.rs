@@ -0,0 +1,525 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use mlua::{Function, HookTriggers, Lua, MultiValue, RegistryKey, Table, Value};
+
+use crate::{
+    glob,
+    rdb::{self, RDB_OPCODE_FUNCTION2},
+    scripting::{PROTECT_GLOBALS, new_vm},
+};
+
+// Flags a function can be registered with
+const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+// How long the code of a library may run while it is being loaded
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+// Library is the code given to FUNCTION LOAD along with the functions it registered
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: Vec<FunctionInfo>,
+}
+
+// What FUNCTION RESTORE does with the libraries already loaded
+pub enum RestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+// Functions holds the loaded libraries. Only their code is kept here, a library is loaded in
+// the functions interpreter the first time one of its functions is called.
+#[derive(Clone)]
+pub struct Functions {
+    libraries: Arc<Mutex<BTreeMap<String, Library>>>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Functions {
+            libraries: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Loads a library, returning its name. An existing library with the same name is only
+    /// replaced when asked to, and its functions can't clash with the ones of other libraries.
+    pub fn load(&self, code: &str, replace: bool) -> Result<String> {
+        let library = compile_library(code)?;
+        let mut libraries = self.libraries.lock().unwrap();
+        if !replace && libraries.contains_key(&library.name) {
+            return Err(anyhow!("ERR Library '{}' already exists", library.name));
+        }
+        check_clashes(&libraries, &library)?;
+
+        let name = library.name.clone();
+        libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        self.libraries
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or(anyhow!("ERR Library not found"))
+    }
+
+    pub fn flush(&self) {
+        self.libraries.lock().unwrap().clear();
+    }
+
+    /// Returns the libraries, or only the ones whose name matches the pattern
+    pub fn list(&self, pattern: Option<&str>) -> Vec<Library> {
+        self.libraries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|library| {
+                pattern.is_none_or(|pattern| {
+                    glob::matches(pattern.as_bytes(), library.name.as_bytes())
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Finds a function, returning the code of its library along with it
+    pub fn find(&self, name: &str) -> Option<(String, FunctionInfo)> {
+        self.libraries.lock().unwrap().values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| (library.code.clone(), function.clone()))
+        })
+    }
+
+    /// Serializes every library the way FUNCTION DUMP does, in a payload sealed like DUMP's
+    pub fn dump(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for library in self.libraries.lock().unwrap().values() {
+            payload.push(RDB_OPCODE_FUNCTION2);
+            rdb::write_string(&mut payload, library.code.as_bytes());
+        }
+        rdb::seal_payload(payload)
+    }
+
+    /// Loads the libraries of a FUNCTION DUMP payload. Nothing changes if any of them fails.
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<()> {
+        let mut reader = rdb::Reader::new(rdb::open_payload(payload)?);
+        let mut restored = Vec::new();
+        while !reader.is_empty() {
+            if reader.read_u8()? != RDB_OPCODE_FUNCTION2 {
+                return Err(anyhow!("ERR given type is not a function"));
+            }
+            let code = String::from_utf8(reader.read_string()?)
+                .map_err(|_| anyhow!("ERR payload holds invalid library code"))?;
+            restored.push(compile_library(&code)?);
+        }
+
+        let mut libraries = self.libraries.lock().unwrap();
+        let mut merged = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            RestorePolicy::Append | RestorePolicy::Replace => libraries.clone(),
+        };
+        for library in restored {
+            match policy {
+                RestorePolicy::Append if merged.contains_key(&library.name) => {
+                    return Err(anyhow!("ERR Library {} already exists", library.name));
+                }
+                _ => {
+                    merged.remove(&library.name);
+                }
+            }
+            check_clashes(&merged, &library)?;
+            merged.insert(library.name.clone(), library);
+        }
+        *libraries = merged;
+        Ok(())
+    }
+}
+
+/// Runs the code of a library in the interpreter, returning the functions it registered
+/// through redis.register_function
+pub fn load_library<'lua>(
+    lua: &'lua Lua,
+    code: &str,
+) -> Result<Vec<(FunctionInfo, Function<'lua>)>> {
+    let (_, body) = parse_metadata(code)?;
+    let registered: Arc<Mutex<Vec<(FunctionInfo, RegistryKey)>>> = Arc::default();
+
+    let register = {
+        let registered = Arc::clone(&registered);
+        lua.create_function(move |lua, args: MultiValue| {
+            let (info, callback) = register_args(args)?;
+            let mut registered = registered.lock().unwrap();
+            if registered.iter().any(|(other, _)| other.name == info.name) {
+                return Err(mlua::Error::RuntimeError(
+                    "Function already exists in the library".to_string(),
+                ));
+            }
+            registered.push((info, lua.create_registry_value(callback)?));
+            Ok(())
+        })
+        .map_err(|err| anyhow!("ERR {}", err))?
+    };
+
+    let started = Instant::now();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(100_000),
+        move |_, _| {
+            if started.elapsed() > LOAD_TIMEOUT {
+                return Err(mlua::Error::RuntimeError(
+                    "FUNCTION LOAD timeout".to_string(),
+                ));
+            }
+            Ok(())
+        },
+    );
+
+    let result = (|| -> mlua::Result<()> {
+        let redis: Table = lua.globals().get("redis")?;
+        redis.set("register_function", register)?;
+        lua.load(PROTECT_GLOBALS).exec()?;
+        lua.load(body).set_name("@user_function").exec()?;
+        // Functions can only be registered while the library loads
+        redis.set("register_function", Value::Nil)
+    })();
+    lua.remove_hook();
+    result.map_err(|err| anyhow!("ERR Error registering functions: {}", load_error(&err)))?;
+
+    let registered = std::mem::take(&mut *registered.lock().unwrap());
+    if registered.is_empty() {
+        return Err(anyhow!("ERR No functions registered"));
+    }
+    registered
+        .into_iter()
+        .map(|(info, key)| {
+            let callback = lua
+                .registry_value::<Function>(&key)
+                .map_err(|err| anyhow!("ERR {}", err))?;
+            Ok((info, callback))
+        })
+        .collect()
+}
+
+// Loads the library in a throwaway interpreter to check it and learn what it registers
+fn compile_library(code: &str) -> Result<Library> {
+    let (name, _) = parse_metadata(code)?;
+    let lua = new_vm().map_err(|err| anyhow!("ERR {}", err))?;
+    let functions = load_library(&lua, code)?
+        .into_iter()
+        .map(|(info, _)| info)
+        .collect();
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions,
+    })
+}
+
+fn check_clashes(libraries: &BTreeMap<String, Library>, library: &Library) -> Result<()> {
+    for function in &library.functions {
+        let clash = libraries
+            .values()
+            .filter(|other| other.name != library.name)
+            .any(|other| other.functions.iter().any(|f| f.name == function.name));
+        if clash {
+            return Err(anyhow!("ERR Function {} already exists", function.name));
+        }
+    }
+    Ok(())
+}
+
+// Parses the `#!lua name=<library>` line a library starts with, returning the library name and
+// the code with the line blanked so the Lua line numbers still match
+fn parse_metadata(code: &str) -> Result<(String, String)> {
+    let Some(shebang) = code.strip_prefix("#!") else {
+        return Err(anyhow!("ERR Missing library metadata"));
+    };
+    let (line, rest) = shebang.split_once('\n').unwrap_or((shebang, ""));
+
+    let mut parts = line.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(anyhow!("ERR Engine '{}' not found", engine));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(anyhow!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let name = name.ok_or(anyhow!("ERR Library name was not given"))?;
+    if !is_valid_name(&name) {
+        return Err(anyhow!(
+            "ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long"
+        ));
+    }
+    Ok((name, format!("\n{}", rest)))
+}
+
+// redis.register_function(name, callback) or redis.register_function{function_name=...,
+// callback=..., flags={...}, description=...}
+fn register_args(args: MultiValue) -> mlua::Result<(FunctionInfo, Function)> {
+    let invalid = |message: &str| mlua::Error::RuntimeError(message.to_string());
+    let mut args = args.into_iter();
+    let (name, callback, flags, description) = match (args.next(), args.next(), args.next()) {
+        (Some(Value::Table(table)), None, None) => (
+            table.get::<_, Value>("function_name")?,
+            table.get::<_, Value>("callback")?,
+            table.get::<_, Value>("flags")?,
+            table.get::<_, Value>("description")?,
+        ),
+        (Some(name), Some(callback), None) => (name, callback, Value::Nil, Value::Nil),
+        _ => {
+            return Err(invalid(
+                "wrong number of arguments to redis.register_function",
+            ));
+        }
+    };
+
+    let name = match name {
+        Value::String(name) if is_valid_name(name.to_str()?) => name.to_str()?.to_string(),
+        Value::String(_) => {
+            return Err(invalid(
+                "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+            ));
+        }
+        _ => {
+            return Err(invalid(
+                "function_name argument given to redis.register_function must be a string",
+            ));
+        }
+    };
+    let Value::Function(callback) = callback else {
+        return Err(invalid(
+            "callback argument given to redis.register_function must be a function",
+        ));
+    };
+
+    let flags = match flags {
+        Value::Nil => Vec::new(),
+        Value::Table(flags) => {
+            let mut parsed = Vec::new();
+            for flag in flags.sequence_values::<String>() {
+                let flag = flag.map_err(|_| invalid("unknown flag given"))?;
+                if !FUNCTION_FLAGS.contains(&flag.as_str()) {
+                    return Err(invalid("unknown flag given"));
+                }
+                parsed.push(flag);
+            }
+            parsed
+        }
+        _ => {
+            return Err(invalid(
+                "flags argument to redis.register_function must be a table representing function flags",
+            ));
+        }
+    };
+    let description = match description {
+        Value::Nil => None,
+        Value::String(description) => Some(description.to_str()?.to_string()),
+        _ => {
+            return Err(invalid(
+                "description given to redis.register_function must be a string",
+            ));
+        }
+    };
+
+    Ok((
+        FunctionInfo {
+            name,
+            description,
+            flags,
+        },
+        callback,
+    ))
+}
+
+// The message of an error raised while loading a library, without the Lua traceback
+fn load_error(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => load_error(cause),
+        mlua::Error::RuntimeError(message) | mlua::Error::SyntaxError { message, .. } => message
+            .split("\nstack traceback:")
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        err => err.to_string(),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::RestorePolicy;
+    use crate::{
+        config::Config,
+        mem::MemDB,
+        resp::{
+            commands::structs::Data,
+            parser::{Request, parse_command},
+        },
+    };
+
+    // The encoded reply, or the error, of a command
+    fn run(config: &Config, db: &RwLock<MemDB<Data>>, args: &[&str]) -> String {
+        let argv = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        match parse_command(Request::new(argv)).and_then(|command| command.execute(db, config)) {
+            Ok(reply) => String::from_utf8(reply.encode()).unwrap(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    const LIBRARY: &str = "#!lua name=mylib
+        redis.register_function('setkey', function(keys, args)
+            return redis.call('SET', keys[1], args[1])
+        end)
+        redis.register_function{
+            function_name = 'getkey',
+            callback = function(keys) return redis.call('GET', keys[1]) end,
+            flags = {'no-writes'},
+        }";
+
+    #[test]
+    fn loaded_functions_are_called_by_name() {
+        let config = Config::new(6379, None);
+        let db = config.databases.all()[0];
+        let run = |args: &[&str]| run(&config, db, args);
+
+        assert_eq!(run(&["FUNCTION", "LOAD", LIBRARY]), "$5\r\nmylib\r\n");
+        assert_eq!(run(&["FCALL", "setkey", "1", "k", "v"]), "+OK\r\n");
+        assert_eq!(run(&["FCALL_RO", "getkey", "1", "k"]), "$1\r\nv\r\n");
+        // FCALL_RO only runs the functions flagged no-writes
+        assert_eq!(
+            run(&["FCALL_RO", "setkey", "1", "k", "w"]),
+            "ERR Can not execute a script with write flag using *_ro command."
+        );
+        assert_eq!(run(&["FCALL", "missing", "0"]), "ERR Function not found");
+        assert_eq!(
+            run(&["FCALL", "setkey", "2", "k"]),
+            "ERR Number of keys can't be greater than number of args"
+        );
+
+        // A library is only replaced when asked to, and the new code is what runs next
+        assert_eq!(
+            run(&["FUNCTION", "LOAD", LIBRARY]),
+            "ERR Library 'mylib' already exists"
+        );
+        let replaced = LIBRARY.replace("args[1])", "args[1] .. '!')");
+        assert_eq!(
+            run(&["FUNCTION", "LOAD", "REPLACE", &replaced]),
+            "$5\r\nmylib\r\n"
+        );
+        run(&["FCALL", "setkey", "1", "k", "v"]);
+        assert_eq!(run(&["FCALL_RO", "getkey", "1", "k"]), "$2\r\nv!\r\n");
+
+        // Function names are unique across libraries
+        let other = "#!lua name=other\nredis.register_function('getkey', function() end)";
+        assert_eq!(
+            run(&["FUNCTION", "LOAD", other]),
+            "ERR Function getkey already exists"
+        );
+        assert_eq!(
+            run(&["FUNCTION", "LOAD", "return 1"]),
+            "ERR Missing library metadata"
+        );
+        assert_eq!(
+            run(&["FUNCTION", "LOAD", "#!lua name=empty\nreturn 1"]),
+            "ERR No functions registered"
+        );
+
+        assert_eq!(run(&["FUNCTION", "DELETE", "mylib"]), "+OK\r\n");
+        assert_eq!(
+            run(&["FCALL_RO", "getkey", "1", "k"]),
+            "ERR Function not found"
+        );
+    }
+
+    #[test]
+    fn dumped_libraries_restore_by_policy() {
+        let config = Config::new(6379, None);
+        let db = config.databases.all()[0];
+        config.functions.load(LIBRARY, false).unwrap();
+        let payload = config.functions.dump();
+
+        let other = "#!lua name=other\nredis.register_function('other', function() end)";
+        config.functions.load(other, false).unwrap();
+        let names = || -> Vec<String> {
+            config
+                .functions
+                .list(None)
+                .into_iter()
+                .map(|library| library.name)
+                .collect()
+        };
+
+        // APPEND refuses a library already loaded and leaves everything as it was
+        assert!(
+            config
+                .functions
+                .restore(&payload, RestorePolicy::Append)
+                .is_err_and(|err| err.to_string() == "ERR Library mylib already exists")
+        );
+        assert_eq!(names(), ["mylib", "other"]);
+        config
+            .functions
+            .restore(&payload, RestorePolicy::Replace)
+            .unwrap();
+        assert_eq!(names(), ["mylib", "other"]);
+        config
+            .functions
+            .restore(&payload, RestorePolicy::Flush)
+            .unwrap();
+        assert_eq!(names(), ["mylib"]);
+        assert_eq!(
+            run(&config, db, &["FCALL", "setkey", "1", "k", "v"]),
+            "+OK\r\n"
+        );
+
+        let mut corrupt = payload.clone();
+        corrupt[2] ^= 1;
+        assert!(
+            config
+                .functions
+                .restore(&corrupt, RestorePolicy::Flush)
+                .is_err()
+        );
+        assert_eq!(names(), ["mylib"]);
+    }
+}
@@ -0,0 +1,402 @@
+// Geohash helpers mirroring geohash.c and geohash_helper.c from Redis, so that scores,
+// distances and the cells scanned by GEOSEARCH are the same as in Redis.
+//
+// Positions are stored as 52 bit interleaved geohashes (26 bits per axis) in a sorted set.
+// Latitude bits live in the even positions and longitude bits in the odd ones.
+
+use std::f64::consts::PI;
+
+pub const GEO_STEP_MAX: u8 = 26;
+pub const GEO_LAT_MIN: f64 = -85.05112878;
+pub const GEO_LAT_MAX: f64 = 85.05112878;
+pub const GEO_LONG_MIN: f64 = -180.0;
+pub const GEO_LONG_MAX: f64 = 180.0;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+const GEO_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct GeoHashBits {
+    pub bits: u64,
+    pub step: u8,
+}
+
+impl GeoHashBits {
+    fn zero() -> Self {
+        GeoHashBits { bits: 0, step: 0 }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+
+    // Returns the (min, max) score range covered by this cell once aligned to 52 bits.
+    // The max is exclusive.
+    pub fn score_range(&self) -> (f64, f64) {
+        let shift = 52 - self.step as u32 * 2;
+        let min = self.bits << shift;
+        let max = (self.bits + 1) << shift;
+        (min as f64, max as f64)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Range {
+    min: f64,
+    max: f64,
+}
+
+const LONG_RANGE: Range = Range {
+    min: GEO_LONG_MIN,
+    max: GEO_LONG_MAX,
+};
+const LAT_RANGE: Range = Range {
+    min: GEO_LAT_MIN,
+    max: GEO_LAT_MAX,
+};
+
+struct Area {
+    longitude: Range,
+    latitude: Range,
+}
+
+// Spreads the 32 bits of x so that they occupy the even bits of the result
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+// Inverse of spread, collects the even bits of x
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x >> 16)) & 0x0000_0000_FFFF_FFFF;
+    x as u32
+}
+
+fn encode(
+    long_range: Range,
+    lat_range: Range,
+    longitude: f64,
+    latitude: f64,
+    step: u8,
+) -> GeoHashBits {
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min);
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min);
+    let lat_offset = (lat_offset * (1u64 << step) as f64) as u32;
+    let long_offset = (long_offset * (1u64 << step) as f64) as u32;
+    GeoHashBits {
+        bits: spread(lat_offset) | (spread(long_offset) << 1),
+        step,
+    }
+}
+
+fn decode(long_range: Range, lat_range: Range, hash: GeoHashBits) -> Area {
+    let lat_cell = squash(hash.bits) as f64;
+    let long_cell = squash(hash.bits >> 1) as f64;
+    let cells = (1u64 << hash.step) as f64;
+    let lat_scale = lat_range.max - lat_range.min;
+    let long_scale = long_range.max - long_range.min;
+
+    Area {
+        latitude: Range {
+            min: lat_range.min + (lat_cell / cells) * lat_scale,
+            max: lat_range.min + ((lat_cell + 1.0) / cells) * lat_scale,
+        },
+        longitude: Range {
+            min: long_range.min + (long_cell / cells) * long_scale,
+            max: long_range.min + ((long_cell + 1.0) / cells) * long_scale,
+        },
+    }
+}
+
+/// Returns whether the pair is inside the area that can be indexed
+pub fn valid_position(longitude: f64, latitude: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+}
+
+/// Encodes the position as the 52 bit score stored in the sorted set
+pub fn encode_score(longitude: f64, latitude: f64) -> f64 {
+    encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX).bits as f64
+}
+
+/// Decodes a 52 bit score back to the (longitude, latitude) at the centre of its cell
+pub fn decode_score(score: f64) -> (f64, f64) {
+    let area = decode(
+        LONG_RANGE,
+        LAT_RANGE,
+        GeoHashBits {
+            bits: score as u64,
+            step: GEO_STEP_MAX,
+        },
+    );
+    let longitude =
+        ((area.longitude.min + area.longitude.max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+/// Returns the standard 11 character geohash for a score, as reported by GEOHASH.
+/// The standard encoding uses a latitude range of -90..90 instead of the Mercator limits.
+pub fn standard_geohash(score: f64) -> String {
+    let (longitude, latitude) = decode_score(score);
+    let hash = encode(
+        LONG_RANGE,
+        Range {
+            min: -90.0,
+            max: 90.0,
+        },
+        longitude,
+        latitude,
+        GEO_STEP_MAX,
+    );
+
+    (0..11)
+        .map(|i| {
+            // Only 52 bits are available, so the last character is always the first of the alphabet
+            let idx = if i == 10 {
+                0
+            } else {
+                (hash.bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            GEO_ALPHABET[idx as usize] as char
+        })
+        .collect()
+}
+
+fn deg_rad(deg: f64) -> f64 {
+    deg * (PI / 180.0)
+}
+
+fn rad_deg(rad: f64) -> f64 {
+    rad / (PI / 180.0)
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+/// Haversine distance in meters between two (longitude, latitude) positions
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let lon1r = deg_rad(lon1);
+    let lon2r = deg_rad(lon2);
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    // When the longitudes are practically the same we can skip the expensive math
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let lat1r = deg_rad(lat1);
+    let lat2r = deg_rad(lat2);
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+/// The area searched by GEOSEARCH, with sizes in meters
+#[derive(Clone, Copy)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// Returns the distance from the centre in meters if the position is inside the shape
+    pub fn contains(&self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match self {
+            Shape::Radius(radius) => {
+                let d = distance(center.0, center.1, point.0, point.1);
+                (d <= *radius).then_some(d)
+            }
+            Shape::Box { width, height } => {
+                // The latitude distance is cheaper to compute, so it is checked first
+                if lat_distance(point.1, center.1) > height / 2.0 {
+                    return None;
+                }
+                if distance(point.0, point.1, center.0, point.1) > width / 2.0 {
+                    return None;
+                }
+                Some(distance(center.0, center.1, point.0, point.1))
+            }
+        }
+    }
+
+    // Returns (min_lon, min_lat, max_lon, max_lat) for the shape around the centre
+    fn bounding_box(&self, longitude: f64, latitude: f64) -> [f64; 4] {
+        let (height, width) = match self {
+            Shape::Radius(radius) => (*radius, *radius),
+            Shape::Box { width, height } => (height / 2.0, width / 2.0),
+        };
+
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude + lat_delta).cos());
+        let long_delta_bottom =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude - lat_delta).cos());
+        // The hemispheres are mirrored, so the widest edge is the one closer to the equator
+        let long_delta = if latitude < 0.0 {
+            long_delta_bottom
+        } else {
+            long_delta_top
+        };
+
+        [
+            longitude - long_delta,
+            latitude - lat_delta,
+            longitude + long_delta,
+            latitude + lat_delta,
+        ]
+    }
+
+    fn radius(&self) -> f64 {
+        match self {
+            Shape::Radius(radius) => *radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        }
+    }
+}
+
+fn estimate_steps_by_radius(mut range_meters: f64, latitude: f64) -> u8 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    // Make sure the range is included in most of the base cases
+    step -= 2;
+
+    // Cells get narrower towards the poles, so a wider area is needed there
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u8
+}
+
+fn move_x(hash: &mut GeoHashBits, d: i8) {
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0x5555_5555_5555_5555u64 >> (64 - hash.step as u32 * 2);
+    let x = if d > 0 {
+        x.wrapping_add(zz + 1)
+    } else {
+        (x | zz).wrapping_sub(zz + 1)
+    };
+    let x = x & (0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step as u32 * 2));
+    hash.bits = x | y;
+}
+
+fn move_y(hash: &mut GeoHashBits, d: i8) {
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step as u32 * 2);
+    let y = if d > 0 {
+        y.wrapping_add(zz + 1)
+    } else {
+        (y | zz).wrapping_sub(zz + 1)
+    };
+    let y = y & (0x5555_5555_5555_5555u64 >> (64 - hash.step as u32 * 2));
+    hash.bits = x | y;
+}
+
+fn neighbor(hash: GeoHashBits, dx: i8, dy: i8) -> GeoHashBits {
+    let mut cell = hash;
+    if dx != 0 {
+        move_x(&mut cell, dx);
+    }
+    if dy != 0 {
+        move_y(&mut cell, dy);
+    }
+    cell
+}
+
+/// Returns the cells to scan for a search, in the order Redis scans them:
+/// centre, north, south, east, west, north east, north west, south east, south west.
+/// Cells which can't hold matches are left out.
+pub fn search_cells(shape: &Shape, longitude: f64, latitude: f64) -> Vec<GeoHashBits> {
+    let [min_lon, min_lat, max_lon, max_lat] = shape.bounding_box(longitude, latitude);
+    let mut steps = estimate_steps_by_radius(shape.radius(), latitude);
+
+    let neighbors_of = |hash: GeoHashBits| -> [GeoHashBits; 9] {
+        [
+            hash,
+            neighbor(hash, 0, 1),
+            neighbor(hash, 0, -1),
+            neighbor(hash, 1, 0),
+            neighbor(hash, -1, 0),
+            neighbor(hash, 1, 1),
+            neighbor(hash, -1, 1),
+            neighbor(hash, 1, -1),
+            neighbor(hash, -1, -1),
+        ]
+    };
+
+    let mut hash = encode(LONG_RANGE, LAT_RANGE, longitude, latitude, steps);
+    let mut cells = neighbors_of(hash);
+
+    // Check if the step is enough at the limits of the covered area. Sometimes the cells
+    // don't cover the whole bounding box, in which case a coarser step is used.
+    let north = decode(LONG_RANGE, LAT_RANGE, cells[1]);
+    let south = decode(LONG_RANGE, LAT_RANGE, cells[2]);
+    let east = decode(LONG_RANGE, LAT_RANGE, cells[3]);
+    let west = decode(LONG_RANGE, LAT_RANGE, cells[4]);
+    let decrease_step = north.latitude.max < max_lat
+        || south.latitude.min > min_lat
+        || east.longitude.max < max_lon
+        || west.longitude.min > min_lon;
+
+    if steps > 1 && decrease_step {
+        steps -= 1;
+        hash = encode(LONG_RANGE, LAT_RANGE, longitude, latitude, steps);
+        cells = neighbors_of(hash);
+    }
+
+    // Exclude the cells which are entirely outside the bounding box
+    if steps >= 2 {
+        let area = decode(LONG_RANGE, LAT_RANGE, hash);
+        if area.latitude.min < min_lat {
+            for i in [2, 7, 8] {
+                cells[i] = GeoHashBits::zero();
+            }
+        }
+        if area.latitude.max > max_lat {
+            for i in [1, 5, 6] {
+                cells[i] = GeoHashBits::zero();
+            }
+        }
+        if area.longitude.min < min_lon {
+            for i in [4, 8, 6] {
+                cells[i] = GeoHashBits::zero();
+            }
+        }
+        if area.longitude.max > max_lon {
+            for i in [3, 7, 5] {
+                cells[i] = GeoHashBits::zero();
+            }
+        }
+    }
+
+    // With huge radiuses adjacent cells can be the same one, which would report duplicates
+    let mut result: Vec<GeoHashBits> = Vec::with_capacity(9);
+    for cell in cells {
+        if cell.is_zero() || result.last() == Some(&cell) {
+            continue;
+        }
+        result.push(cell);
+    }
+    result
+}
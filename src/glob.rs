@@ -0,0 +1,146 @@
+// Glob style pattern matching with the same rules as stringmatchlen in Redis:
+//   *      matches any sequence of characters
+//   ?      matches a single character
+//   [abc]  matches one of the characters, [^abc] negates, [a-z] matches a range
+//   \x     matches x literally
+//
+// Every element but * matches exactly one character, so when the rest of the pattern fails
+// only the last star needs to take one more character and retry. That keeps matching at
+// O(pattern * string) whatever the number of stars, unlike backtracking into every one.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where the pattern resumes after the last star, and where the string resumed last time
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            // Collapse consecutive stars
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            star = Some((p, s));
+            continue;
+        }
+        if p < pattern.len()
+            && let Some(next) = match_one(pattern, p, string[s])
+        {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((resume, tried)) => {
+                p = resume;
+                s = tried + 1;
+                star = Some((resume, s));
+            }
+            None => return false,
+        }
+    }
+
+    // Trailing stars match the empty string
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// match_one matches the pattern element at `p` against a single character, returning where
+// the next element starts
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let mut i = p + 1;
+            let not = pattern.get(i) == Some(&b'^');
+            if not {
+                i += 1;
+            }
+
+            let mut matched = false;
+            loop {
+                match &pattern[i..] {
+                    [b'\\', escaped, ..] => {
+                        matched |= *escaped == c;
+                        i += 2;
+                    }
+                    [b']', ..] => {
+                        i += 1;
+                        break;
+                    }
+                    // No closing bracket, the end of the pattern closes it
+                    [] => break,
+                    [start, b'-', end, ..] => {
+                        let (lo, hi) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        matched |= (lo..=hi).contains(&c);
+                        i += 3;
+                    }
+                    [other, ..] => {
+                        matched |= *other == c;
+                        i += 1;
+                    }
+                }
+            }
+            (matched != not).then_some(i)
+        }
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        other => (other == c).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::matches;
+
+    #[test]
+    fn matches_like_redis() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello world", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hbllo", true),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("news.*", "news.tech", true),
+            ("*.tech", "news.tech", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+            ("key:[0-9]*", "key:1abc", true),
+            ("foo[", "foo", false),
+            ("foo[ab", "fooa", true),
+            ("abc**", "abc", true),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                matches(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{} against {}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    #[test]
+    fn many_stars_stay_fast() {
+        let pattern = format!("{}b", "a*".repeat(40));
+        let string = "a".repeat(10_000);
+        let started = Instant::now();
+        assert!(!matches(pattern.as_bytes(), string.as_bytes()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,554 @@
+// HyperLogLog implementation which is byte compatible with the one in Redis.
+//
+// The value is a plain string starting with a 16 byte header:
+//   "HYLL" | encoding (1 byte) | unused (3 bytes) | cached cardinality (8 bytes, little endian)
+// followed by the registers in either the dense or the sparse encoding.
+// The most significant bit of the last cardinality byte marks the cached cardinality as stale.
+//
+// Dense: 16384 registers of 6 bits each, packed starting from the least significant bit.
+// Sparse: a run length encoding of the registers made of three opcodes
+//   ZERO  00xxxxxx          -> 1..64 registers set to 0
+//   XZERO 01xxxxxx yyyyyyyy -> 1..16384 registers set to 0
+//   VAL   1vvvvvxx          -> 1..4 registers set to the value 1..32
+//
+// See https://github.com/redis/redis/blob/unstable/src/hyperloglog.c for the reference implementation.
+
+use anyhow::{Result, anyhow};
+
+const HLL_P: u32 = 14;
+const HLL_Q: usize = 64 - HLL_P as usize;
+pub const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = (HLL_REGISTERS - 1) as u64;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HLL_MAGIC: &[u8; 4] = b"HYLL";
+
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+// Same default as the `hll-sparse-max-bytes` directive in Redis
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+pub const WRONGTYPE_ERR: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+pub const INVALIDOBJ_ERR: &str = "INVALIDOBJ Corrupted HLL object detected";
+
+// Sparse opcode helpers
+fn is_zero(op: u8) -> bool {
+    op & 0xc0 == 0x00
+}
+
+fn is_xzero(op: u8) -> bool {
+    op & 0xc0 == 0x40
+}
+
+fn zero_len(op: u8) -> usize {
+    (op & 0x3f) as usize + 1
+}
+
+fn xzero_len(op: u8, next: u8) -> usize {
+    ((((op & 0x3f) as usize) << 8) | next as usize) + 1
+}
+
+fn val_value(op: u8) -> u8 {
+    ((op >> 2) & 0x1f) + 1
+}
+
+fn val_len(op: u8) -> usize {
+    (op & 0x3) as usize + 1
+}
+
+fn zero_op(len: usize) -> u8 {
+    (len - 1) as u8
+}
+
+fn xzero_op(len: usize) -> [u8; 2] {
+    let len = len - 1;
+    [(len >> 8) as u8 | 0x40, (len & 0xff) as u8]
+}
+
+fn val_op(value: u8, len: usize) -> u8 {
+    (((value - 1) << 2) | (len as u8 - 1)) | 0x80
+}
+
+// Appends the shortest zero opcode able to describe `len` registers
+fn push_zero_run(seq: &mut Vec<u8>, len: usize) {
+    if len > HLL_SPARSE_ZERO_MAX_LEN {
+        seq.extend(xzero_op(len));
+    } else {
+        seq.push(zero_op(len));
+    }
+}
+
+// Dense register helpers
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = registers[byte] as u16;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> fb) | (b1 << (8 - fb))) & HLL_REGISTER_MAX as u16) as u8
+}
+
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let v = value as u16;
+    let mask = HLL_REGISTER_MAX as u16;
+
+    registers[byte] &= !((mask << fb) as u8);
+    registers[byte] |= (v << fb) as u8;
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next &= !((mask >> (8 - fb)) as u8);
+        *next |= (v >> (8 - fb)) as u8;
+    }
+}
+
+// MurmurHash2, 64 bit version (MurmurHash64A), as used by Redis to hash HLL elements
+fn murmurhash64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate().rev() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// Returns the register index for the element and the length of the 000..1 pattern in its hash
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let mut hash = murmurhash64a(element, 0xadc8_3b19);
+    let index = (hash & HLL_P_MASK) as usize;
+    hash >>= HLL_P;
+    // Make sure the loop terminates
+    hash |= 1 << HLL_Q;
+
+    let mut bit: u64 = 1;
+    let mut count: u8 = 1;
+    while hash & bit == 0 {
+        count += 1;
+        bit <<= 1;
+    }
+    (index, count)
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+// Estimates the cardinality from the register histogram using the improved estimator
+// by Otmar Ertl (https://arxiv.org/abs/1702.01284), the same one Redis uses.
+fn estimate(histogram: &[usize; 64]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let mut z = m * tau((m - histogram[HLL_Q + 1] as f64) / m);
+    for j in (1..=HLL_Q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (HLL_ALPHA_INF * m * m / z).round() as u64
+}
+
+/// Computes the cardinality of raw (one byte per register) registers, as used when merging HLLs
+pub fn count_registers(registers: &[u8]) -> u64 {
+    let mut histogram = [0usize; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+    estimate(&histogram)
+}
+
+/// A HyperLogLog backed by the exact bytes Redis stores for it
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Creates an empty HLL using the sparse encoding: a single XZERO opcode covering every register
+    pub fn new() -> Self {
+        let mut bytes = Vec::with_capacity(HLL_HDR_SIZE + 2);
+        bytes.extend(HLL_MAGIC);
+        bytes.push(HLL_SPARSE);
+        bytes.extend([0u8; 11]);
+        bytes.extend(xzero_op(HLL_SPARSE_XZERO_MAX_LEN));
+        HyperLogLog { bytes }
+    }
+
+    /// Wraps the bytes of a string value, validating that they hold a HLL
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() < HLL_HDR_SIZE || &bytes[..4] != HLL_MAGIC || bytes[4] > HLL_SPARSE {
+            return Err(anyhow!(WRONGTYPE_ERR));
+        }
+        if bytes[4] == HLL_DENSE && bytes.len() != HLL_DENSE_SIZE {
+            return Err(anyhow!(WRONGTYPE_ERR));
+        }
+        Ok(HyperLogLog { bytes })
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_dense(&self) -> bool {
+        self.bytes[4] == HLL_DENSE
+    }
+
+    fn cached_cardinality(&self) -> Option<u64> {
+        if self.bytes[15] & 0x80 != 0 {
+            return None;
+        }
+        Some(u64::from_le_bytes(self.bytes[8..16].try_into().unwrap()))
+    }
+
+    fn set_cached_cardinality(&mut self, card: u64) {
+        self.bytes[8..16].copy_from_slice(&card.to_le_bytes());
+    }
+
+    pub fn invalidate_cache(&mut self) {
+        self.bytes[15] |= 0x80;
+    }
+
+    /// Adds the element, returning whether any register was updated
+    pub fn add(&mut self, element: &[u8]) -> Result<bool> {
+        let (index, count) = pattern_len(element);
+        self.set(index, count)
+    }
+
+    /// Sets the register to `count` if it is greater than the current value
+    pub fn set(&mut self, index: usize, count: u8) -> Result<bool> {
+        if self.is_dense() {
+            return Ok(self.dense_set(index, count));
+        }
+        self.sparse_set(index, count)
+    }
+
+    fn dense_set(&mut self, index: usize, count: u8) -> bool {
+        let registers = &mut self.bytes[HLL_HDR_SIZE..];
+        if count > dense_get(registers, index) {
+            dense_set(registers, index, count);
+            return true;
+        }
+        false
+    }
+
+    // Port of hllSparseSet: splits the opcode covering the register into up to three opcodes
+    // and then merges adjacent VAL opcodes, so the resulting bytes match what Redis produces.
+    fn sparse_set(&mut self, index: usize, count: u8) -> Result<bool> {
+        if count > HLL_SPARSE_VAL_MAX_VALUE {
+            return self.promote_and_set(index, count);
+        }
+
+        let end = self.bytes.len();
+        let mut p = HLL_HDR_SIZE;
+        let mut first = 0;
+        let mut prev: Option<usize> = None;
+        let mut span = 0;
+        let mut oplen = 1;
+
+        // Step 1: find the opcode covering the register
+        while p < end {
+            let op = self.bytes[p];
+            oplen = 1;
+            if is_zero(op) {
+                span = zero_len(op);
+            } else if is_xzero(op) {
+                span = xzero_len(op, *self.bytes.get(p + 1).ok_or(anyhow!(INVALIDOBJ_ERR))?);
+                oplen = 2;
+            } else {
+                span = val_len(op);
+            }
+            if index < first + span {
+                break;
+            }
+            prev = Some(p);
+            p += oplen;
+            first += span;
+        }
+        if span == 0 || p >= end {
+            return Err(anyhow!(INVALIDOBJ_ERR));
+        }
+
+        let op = self.bytes[p];
+        let last = first + span - 1;
+
+        // Step 2: registers which can be updated in place
+        if !is_zero(op) && !is_xzero(op) {
+            if val_value(op) >= count {
+                return Ok(false);
+            }
+            if span == 1 {
+                self.bytes[p] = val_op(count, 1);
+                self.merge_values(prev.unwrap_or(HLL_HDR_SIZE));
+                return Ok(true);
+            }
+        }
+        if is_zero(op) && span == 1 {
+            self.bytes[p] = val_op(count, 1);
+            self.merge_values(prev.unwrap_or(HLL_HDR_SIZE));
+            return Ok(true);
+        }
+
+        // Step 3: split the opcode into a sequence of up to three opcodes
+        let mut seq: Vec<u8> = Vec::with_capacity(5);
+        if is_zero(op) || is_xzero(op) {
+            if index != first {
+                push_zero_run(&mut seq, index - first);
+            }
+            seq.push(val_op(count, 1));
+            if index != last {
+                push_zero_run(&mut seq, last - index);
+            }
+        } else {
+            let current = val_value(op);
+            if index != first {
+                seq.push(val_op(current, index - first));
+            }
+            seq.push(val_op(count, 1));
+            if index != last {
+                seq.push(val_op(current, last - index));
+            }
+        }
+
+        if seq.len() > oplen && self.bytes.len() + seq.len() - oplen > HLL_SPARSE_MAX_BYTES {
+            return self.promote_and_set(index, count);
+        }
+        self.bytes.splice(p..p + oplen, seq);
+
+        // Step 4: merge adjacent values if possible
+        self.merge_values(prev.unwrap_or(HLL_HDR_SIZE));
+        Ok(true)
+    }
+
+    // Scans up to 5 opcodes starting at `p`, merging adjacent VAL opcodes holding the same value
+    fn merge_values(&mut self, mut p: usize) {
+        let mut scanlen = 5;
+        while p < self.bytes.len() && scanlen > 0 {
+            scanlen -= 1;
+            let op = self.bytes[p];
+            if is_xzero(op) {
+                p += 2;
+                continue;
+            } else if is_zero(op) {
+                p += 1;
+                continue;
+            }
+
+            if let Some(next) = self.bytes.get(p + 1).copied()
+                && !is_zero(next)
+                && !is_xzero(next)
+                && val_value(op) == val_value(next)
+            {
+                let len = val_len(op) + val_len(next);
+                if len <= HLL_SPARSE_VAL_MAX_LEN {
+                    self.bytes[p + 1] = val_op(val_value(op), len);
+                    self.bytes.remove(p);
+                    // Try to merge the just merged value with the one on its right
+                    continue;
+                }
+            }
+            p += 1;
+        }
+    }
+
+    fn promote_and_set(&mut self, index: usize, count: u8) -> Result<bool> {
+        self.make_dense()?;
+        Ok(self.dense_set(index, count))
+    }
+
+    /// Converts the sparse representation to the dense one, keeping the header
+    pub fn make_dense(&mut self) -> Result<()> {
+        if self.is_dense() {
+            return Ok(());
+        }
+        let registers = self.registers()?;
+
+        let mut dense = vec![0u8; HLL_DENSE_SIZE];
+        dense[..HLL_HDR_SIZE].copy_from_slice(&self.bytes[..HLL_HDR_SIZE]);
+        dense[4] = HLL_DENSE;
+        for (index, value) in registers.iter().enumerate() {
+            if *value != 0 {
+                dense_set(&mut dense[HLL_HDR_SIZE..], index, *value);
+            }
+        }
+        self.bytes = dense;
+        Ok(())
+    }
+
+    /// Decodes the registers into one byte per register
+    pub fn registers(&self) -> Result<Vec<u8>> {
+        let data = &self.bytes[HLL_HDR_SIZE..];
+        if self.is_dense() {
+            return Ok((0..HLL_REGISTERS).map(|i| dense_get(data, i)).collect());
+        }
+
+        let mut registers = Vec::with_capacity(HLL_REGISTERS);
+        let mut p = 0;
+        while p < data.len() {
+            let op = data[p];
+            if is_zero(op) {
+                registers.resize(registers.len() + zero_len(op), 0);
+                p += 1;
+            } else if is_xzero(op) {
+                let next = *data.get(p + 1).ok_or(anyhow!(INVALIDOBJ_ERR))?;
+                registers.resize(registers.len() + xzero_len(op, next), 0);
+                p += 2;
+            } else {
+                registers.resize(registers.len() + val_len(op), val_value(op));
+                p += 1;
+            }
+            if registers.len() > HLL_REGISTERS {
+                return Err(anyhow!(INVALIDOBJ_ERR));
+            }
+        }
+        if registers.len() != HLL_REGISTERS {
+            return Err(anyhow!(INVALIDOBJ_ERR));
+        }
+        Ok(registers)
+    }
+
+    /// Returns the cardinality, using and refreshing the cached value in the header.
+    /// The boolean is true when the cache was refreshed and the bytes need to be stored back.
+    pub fn count(&mut self) -> Result<(u64, bool)> {
+        if let Some(card) = self.cached_cardinality() {
+            return Ok((card, false));
+        }
+        let card = count_registers(&self.registers()?);
+        self.set_cached_cardinality(card);
+        Ok((card, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Registers Redis sets for these elements: MurmurHash64A with the 0xadc83b19 seed
+    const ELEMENTS: &[(&[u8], usize, u8)] = &[
+        (b"a", 12711, 2),
+        (b"b", 15780, 1),
+        (b"c", 8436, 1),
+        (b"", 5938, 2),
+        (b"hyperloglog", 599, 3),
+        (b"the quick brown fox jumps", 7253, 2),
+    ];
+
+    fn header(encoding: u8, cache: [u8; 8]) -> Vec<u8> {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.extend([encoding, 0, 0, 0]);
+        bytes.extend(cache);
+        bytes
+    }
+
+    #[test]
+    fn hashes_elements_like_redis() {
+        for (element, index, count) in ELEMENTS {
+            assert_eq!(pattern_len(element), (*index, *count), "{:?}", element);
+        }
+    }
+
+    #[test]
+    fn matches_the_redis_sparse_encoding() {
+        // What PFADD with no element stores
+        let mut expected = header(HLL_SPARSE, [0; 8]);
+        expected.extend([0x7f, 0xff]);
+        assert_eq!(HyperLogLog::new().into_bytes(), expected);
+
+        // PFADD hll a b c
+        let mut hll = HyperLogLog::new();
+        for element in [b"a", b"b", b"c"] {
+            assert!(hll.add(element).unwrap());
+        }
+        assert!(!hll.add(b"a").unwrap());
+        hll.invalidate_cache();
+        let mut expected = header(HLL_SPARSE, [0, 0, 0, 0, 0, 0, 0, 0x80]);
+        // XZERO 8436, VAL 1, XZERO 4274, VAL 2, XZERO 3068, VAL 1, XZERO 603
+        expected.extend([
+            0x60, 0xf3, 0x80, 0x50, 0xb1, 0x84, 0x4b, 0xfb, 0x80, 0x42, 0x5a,
+        ]);
+        assert_eq!(hll.bytes, expected);
+
+        // PFCOUNT caches the cardinality in the header
+        assert_eq!(hll.count().unwrap(), (3, true));
+        assert_eq!(hll.bytes[8..16], [3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(hll.count().unwrap(), (3, false));
+    }
+
+    #[test]
+    fn matches_the_redis_dense_encoding() {
+        let mut hll = HyperLogLog::new();
+        for element in [b"a", b"b", b"c"] {
+            hll.add(element).unwrap();
+        }
+        hll.make_dense().unwrap();
+        hll.invalidate_cache();
+
+        let bytes = hll.into_bytes();
+        assert_eq!(bytes.len(), HLL_DENSE_SIZE);
+        assert_eq!(bytes[..16], header(HLL_DENSE, [0, 0, 0, 0, 0, 0, 0, 0x80]));
+        // Registers are 6 bits packed from the least significant bit: register 12711 starts
+        // at bit 2 of byte 9533, 15780 and 8436 on a byte boundary
+        let mut registers = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        registers[9533] = 2 << 2;
+        registers[11835] = 1;
+        registers[6327] = 1;
+        assert_eq!(bytes[HLL_HDR_SIZE..], registers);
+
+        let mut hll = HyperLogLog::from_bytes(bytes).unwrap();
+        assert_eq!(hll.count().unwrap().0, 3);
+    }
+}
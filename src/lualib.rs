@@ -0,0 +1,1320 @@
+// The libraries Redis preloads in its Lua interpreter besides the standard ones, ported from
+// the C modules it bundles: cjson (lua-cjson 2.1), cmsgpack (lua_cmsgpack 0.4), bit (LuaBitOp
+// 1.0) and struct (lua_struct 0.2). Scripts written for Redis rely on their exact output.
+use mlua::{LightUserData, Lua, MultiValue, Table, Value, Variadic};
+
+// Nested tables past this depth are refused by cjson, and encoded as nil by cmsgpack past its own
+const JSON_MAX_DEPTH: usize = 1000;
+const MSGPACK_MAX_NESTING: usize = 16;
+// cmsgpack.unpack gives up on input nesting deeper than this rather than overflow the stack
+const MSGPACK_MAX_DECODE_DEPTH: usize = 1000;
+// Largest integer struct packs, and the alignment `!` stands for on its own
+const STRUCT_MAX_INT_SIZE: usize = 32;
+const STRUCT_MAX_ALIGN: usize = 8;
+
+/// Sets the cjson, cmsgpack, bit and struct globals
+pub fn register(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    globals.set("cjson", cjson(lua)?)?;
+    globals.set("cmsgpack", cmsgpack(lua)?)?;
+    globals.set("bit", bit(lua)?)?;
+    globals.set("struct", lua_struct(lua)?)?;
+    Ok(())
+}
+
+fn error<T>(message: impl Into<String>) -> mlua::Result<T> {
+    Err(mlua::Error::RuntimeError(message.into()))
+}
+
+// Lua 5.1 only has doubles, integral ones reach Rust as integers
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Number(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Formats a number like printf's %.<precision>g
+pub fn format_g(n: f64, precision: usize) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    if exponent < -4 || exponent >= precision as i32 {
+        let mantissa = strip_fraction_zeros(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+        strip_fraction_zeros(&format!("{:.*}", decimals, n)).to_string()
+    }
+}
+
+fn strip_fraction_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "boolean",
+        Value::LightUserData(_) | Value::UserData(_) => "userdata",
+        Value::Integer(_) | Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        _ => "userdata",
+    }
+}
+
+// cjson
+
+fn cjson(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cjson = lua.create_table()?;
+    cjson.set(
+        "encode",
+        lua.create_function(|lua, value: Value| {
+            let mut json = Vec::new();
+            json_encode(&value, 1, &mut json)?;
+            lua.create_string(&json)
+        })?,
+    )?;
+    cjson.set(
+        "decode",
+        lua.create_function(|lua, json: mlua::String| {
+            let mut decoder = JsonDecoder {
+                lua,
+                input: json.as_bytes(),
+                pos: 0,
+            };
+            let value = decoder.value(1)?;
+            decoder.skip_whitespace();
+            if decoder.pos < decoder.input.len() {
+                return decoder.fail("the end");
+            }
+            Ok(value)
+        })?,
+    )?;
+    // Decoded JSON nulls are this light userdata, which encodes back to null
+    cjson.set(
+        "null",
+        Value::LightUserData(LightUserData(std::ptr::null_mut())),
+    )?;
+    Ok(cjson)
+}
+
+fn json_encode(value: &Value, depth: usize, json: &mut Vec<u8>) -> mlua::Result<()> {
+    match value {
+        Value::Nil => json.extend_from_slice(b"null"),
+        Value::LightUserData(data) if data.0.is_null() => json.extend_from_slice(b"null"),
+        Value::Boolean(true) => json.extend_from_slice(b"true"),
+        Value::Boolean(false) => json.extend_from_slice(b"false"),
+        Value::Integer(_) | Value::Number(_) => json_number(value, json)?,
+        Value::String(s) => json_string(s.as_bytes(), json),
+        Value::Table(table) => {
+            if depth > JSON_MAX_DEPTH {
+                return error(format!("Cannot serialise, excessive nesting ({})", depth));
+            }
+            match json_array_length(table)? {
+                Some(len) if len > 0 => {
+                    json.push(b'[');
+                    for i in 1..=len {
+                        if i > 1 {
+                            json.push(b',');
+                        }
+                        json_encode(&table.raw_get::<_, Value>(i)?, depth + 1, json)?;
+                    }
+                    json.push(b']');
+                }
+                _ => {
+                    json.push(b'{');
+                    let mut first = true;
+                    for pair in table.clone().pairs::<Value, Value>() {
+                        let (key, value) = pair?;
+                        if !first {
+                            json.push(b',');
+                        }
+                        first = false;
+                        match &key {
+                            Value::String(s) => json_string(s.as_bytes(), json),
+                            Value::Integer(_) | Value::Number(_) => {
+                                json.push(b'"');
+                                json_number(&key, json)?;
+                                json.push(b'"');
+                            }
+                            _ => {
+                                return error(
+                                    "Cannot serialise table: table key must be a number or string",
+                                );
+                            }
+                        }
+                        json.push(b':');
+                        json_encode(&value, depth + 1, json)?;
+                    }
+                    json.push(b'}');
+                }
+            }
+        }
+        other => {
+            return error(format!(
+                "Cannot serialise {}: type not supported",
+                type_name(other)
+            ));
+        }
+    }
+    Ok(())
+}
+
+// A table is an array when all its keys are integers from 1, the length being the largest.
+// Like lua-cjson's defaults, arrays with more than half holes past index 10 are refused.
+fn json_array_length(table: &Table) -> mlua::Result<Option<usize>> {
+    let (mut max, mut items) = (0usize, 0usize);
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        match number(&key) {
+            Some(k) if k.floor() == k && k >= 1.0 => {
+                max = max.max(k as usize);
+                items += 1;
+            }
+            _ => return Ok(None),
+        }
+    }
+    if max > items * 2 && max > 10 {
+        return error("Cannot serialise table: excessively sparse array");
+    }
+    Ok(Some(max))
+}
+
+fn json_number(value: &Value, json: &mut Vec<u8>) -> mlua::Result<()> {
+    let n = number(value).unwrap_or_default();
+    if !n.is_finite() {
+        return error("Cannot serialise number: must not be NaN or Inf");
+    }
+    json.extend_from_slice(format_g(n, 14).as_bytes());
+    Ok(())
+}
+
+fn json_string(s: &[u8], json: &mut Vec<u8>) {
+    json.push(b'"');
+    for &c in s {
+        match c {
+            b'"' => json.extend_from_slice(b"\\\""),
+            b'\\' => json.extend_from_slice(b"\\\\"),
+            b'/' => json.extend_from_slice(b"\\/"),
+            b'\x08' => json.extend_from_slice(b"\\b"),
+            b'\x0c' => json.extend_from_slice(b"\\f"),
+            b'\n' => json.extend_from_slice(b"\\n"),
+            b'\r' => json.extend_from_slice(b"\\r"),
+            b'\t' => json.extend_from_slice(b"\\t"),
+            c if c < 0x20 || c == 0x7f => {
+                json.extend_from_slice(format!("\\u{:04x}", c).as_bytes())
+            }
+            c => json.push(c),
+        }
+    }
+    json.push(b'"');
+}
+
+struct JsonDecoder<'lua, 'a> {
+    lua: &'lua Lua,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'lua> JsonDecoder<'lua, '_> {
+    fn fail<T>(&self, expected: &str) -> mlua::Result<T> {
+        let found = match self.input.get(self.pos) {
+            None => "T_END",
+            Some(_) => "invalid token",
+        };
+        error(format!(
+            "Expected {} but found {} at character {}",
+            expected,
+            found,
+            self.pos + 1
+        ))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, text: &[u8], value: Value<'lua>) -> mlua::Result<Value<'lua>> {
+        if !self.input[self.pos..].starts_with(text) {
+            return self.fail("value");
+        }
+        self.pos += text.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> mlua::Result<Value<'lua>> {
+        self.skip_whitespace();
+        match self.input.get(self.pos) {
+            Some(b'{') | Some(b'[') if depth > JSON_MAX_DEPTH => error(format!(
+                "Found too many nested data structures ({}) at character {}",
+                depth,
+                self.pos + 1
+            )),
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => Ok(Value::String(self.lua.create_string(&self.string()?)?)),
+            Some(b't') => self.literal(b"true", Value::Boolean(true)),
+            Some(b'f') => self.literal(b"false", Value::Boolean(false)),
+            Some(b'n') => self.literal(
+                b"null",
+                Value::LightUserData(LightUserData(std::ptr::null_mut())),
+            ),
+            Some(c) if *c == b'-' || c.is_ascii_digit() => self.number(),
+            _ => self.fail("value"),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> mlua::Result<Value<'lua>> {
+        let table = self.lua.create_table()?;
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.input.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.input.get(self.pos) != Some(&b'"') {
+                return self.fail("object key string");
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.input.get(self.pos) != Some(&b':') {
+                return self.fail("colon");
+            }
+            self.pos += 1;
+            let value = self.value(depth + 1)?;
+            table.raw_set(self.lua.create_string(&key)?, value)?;
+
+            self.skip_whitespace();
+            match self.input.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Table(table));
+                }
+                _ => return self.fail("comma or object end"),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> mlua::Result<Value<'lua>> {
+        let table = self.lua.create_table()?;
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.input.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(Value::Table(table));
+        }
+        for i in 1.. {
+            let value = self.value(depth + 1)?;
+            table.raw_set(i, value)?;
+            self.skip_whitespace();
+            match self.input.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return self.fail("comma or array end"),
+            }
+        }
+        Ok(Value::Table(table))
+    }
+
+    fn string(&mut self) -> mlua::Result<Vec<u8>> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut decoded = Vec::new();
+        loop {
+            let Some(&c) = self.input.get(self.pos) else {
+                self.pos = start;
+                return self.fail("string end");
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(decoded),
+                b'\\' => {
+                    let Some(&escaped) = self.input.get(self.pos) else {
+                        return self.fail("escape");
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => decoded.push(escaped),
+                        b'b' => decoded.push(b'\x08'),
+                        b'f' => decoded.push(b'\x0c'),
+                        b'n' => decoded.push(b'\n'),
+                        b'r' => decoded.push(b'\r'),
+                        b't' => decoded.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            let mut buffer = [0; 4];
+                            decoded.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                        }
+                        _ => {
+                            self.pos -= 2;
+                            return self.fail("valid escape");
+                        }
+                    }
+                }
+                c => decoded.push(c),
+            }
+        }
+    }
+
+    // Reads the 4 hex digits after \u, and the low surrogate following a high one
+    fn unicode_escape(&mut self) -> mlua::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return self.fail("low surrogate");
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return self.fail("low surrogate");
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.fail("valid unicode escape"),
+        }
+    }
+
+    fn hex4(&mut self) -> mlua::Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        match digits {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => self.fail("unicode escape"),
+        }
+    }
+
+    fn number(&mut self) -> mlua::Result<Value<'lua>> {
+        let start = self.pos;
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, b'-' | b'+' | b'.' | b'e' | b'E'))
+        {
+            self.pos += 1;
+        }
+        let parsed = std::str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .and_then(|text| text.parse::<f64>().ok());
+        match parsed {
+            Some(n) => Ok(Value::Number(n)),
+            None => {
+                self.pos = start;
+                self.fail("value")
+            }
+        }
+    }
+}
+
+// cmsgpack
+
+fn cmsgpack(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cmsgpack = lua.create_table()?;
+    cmsgpack.set(
+        "pack",
+        lua.create_function(|lua, values: MultiValue| {
+            if values.is_empty() {
+                return error("MessagePack pack needs input.");
+            }
+            let mut packed = Vec::new();
+            for value in values {
+                msgpack_encode(&value, 0, &mut packed)?;
+            }
+            lua.create_string(&packed)
+        })?,
+    )?;
+    cmsgpack.set(
+        "unpack",
+        lua.create_function(|lua, data: mlua::String| {
+            let (_, values) = msgpack_unpack(lua, data.as_bytes(), 0, 0)?;
+            Ok(MultiValue::from_vec(values))
+        })?,
+    )?;
+    cmsgpack.set(
+        "unpack_one",
+        lua.create_function(|lua, (data, offset): (mlua::String, Option<i64>)| {
+            msgpack_unpack_from(lua, data.as_bytes(), 1, offset.unwrap_or(0))
+        })?,
+    )?;
+    cmsgpack.set(
+        "unpack_limit",
+        lua.create_function(
+            |lua, (data, limit, offset): (mlua::String, i64, Option<i64>)| {
+                msgpack_unpack_from(lua, data.as_bytes(), limit, offset.unwrap_or(0))
+            },
+        )?,
+    )?;
+    Ok(cmsgpack)
+}
+
+fn msgpack_encode(value: &Value, level: usize, out: &mut Vec<u8>) -> mlua::Result<()> {
+    match value {
+        Value::Boolean(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Integer(_) | Value::Number(_) => {
+            let n = number(value).unwrap_or_default();
+            if n.is_finite() && (n as i64) as f64 == n {
+                msgpack_int(n as i64, out);
+            } else if (n as f32) as f64 == n || n.is_nan() {
+                out.push(0xca);
+                out.extend_from_slice(&(n as f32).to_be_bytes());
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            let bytes = s.as_bytes();
+            let len = bytes.len();
+            if len < 32 {
+                out.push(0xa0 | len as u8);
+            } else if len <= 0xff {
+                out.extend_from_slice(&[0xd9, len as u8]);
+            } else if len <= 0xffff {
+                out.push(0xda);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                out.push(0xdb);
+                out.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+            out.extend_from_slice(bytes);
+        }
+        // Too deeply nested tables are encoded as nil
+        Value::Table(table) if level < MSGPACK_MAX_NESTING => match msgpack_array_length(table)? {
+            Some(len) => {
+                msgpack_header(len, 0x90, 0xdc, 0xdd, out);
+                for i in 1..=len {
+                    msgpack_encode(&table.raw_get::<_, Value>(i)?, level + 1, out)?;
+                }
+            }
+            None => {
+                let pairs = table
+                    .clone()
+                    .pairs::<Value, Value>()
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                msgpack_header(pairs.len(), 0x80, 0xde, 0xdf, out);
+                for (key, value) in pairs {
+                    msgpack_encode(&key, level + 1, out)?;
+                    msgpack_encode(&value, level + 1, out)?;
+                }
+            }
+        },
+        _ => out.push(0xc0),
+    }
+    Ok(())
+}
+
+fn msgpack_int(n: i64, out: &mut Vec<u8>) {
+    if n >= 0 {
+        if n <= 127 {
+            out.push(n as u8);
+        } else if n <= 0xff {
+            out.extend_from_slice(&[0xcc, n as u8]);
+        } else if n <= 0xffff {
+            out.push(0xcd);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else if n <= 0xffff_ffff {
+            out.push(0xce);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        } else {
+            out.push(0xcf);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    } else if n >= -32 {
+        out.push(n as u8);
+    } else if n >= -128 {
+        out.extend_from_slice(&[0xd0, n as u8]);
+    } else if n >= -32768 {
+        out.push(0xd1);
+        out.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n >= -2147483648 {
+        out.push(0xd2);
+        out.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn msgpack_header(len: usize, fix: u8, short: u8, long: u8, out: &mut Vec<u8>) {
+    if len <= 15 {
+        out.push(fix | len as u8);
+    } else if len <= 0xffff {
+        out.push(short);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(long);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+// A table is packed as an array when its keys are exactly 1 to n, an empty one included
+fn msgpack_array_length(table: &Table) -> mlua::Result<Option<usize>> {
+    let (mut max, mut count) = (0usize, 0usize);
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        match number(&key) {
+            Some(k) if k.floor() == k && k >= 1.0 => {
+                max = max.max(k as usize);
+                count += 1;
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok((max == count).then_some(max))
+}
+
+// unpack_one and unpack_limit return the offset of the next object first, -1 once the input
+// was all read
+fn msgpack_unpack_from<'lua>(
+    lua: &'lua Lua,
+    data: &[u8],
+    limit: i64,
+    offset: i64,
+) -> mlua::Result<MultiValue<'lua>> {
+    if offset < 0 || limit < 0 {
+        return error(format!(
+            "Invalid request to unpack with offset of {} and limit of {}.",
+            offset, limit
+        ));
+    }
+    if offset as usize > data.len() {
+        return error(format!(
+            "Start offset {} greater than input length {}.",
+            offset,
+            data.len()
+        ));
+    }
+    let (end, mut values) = msgpack_unpack(lua, data, offset as usize, limit as usize)?;
+    let next = if end == data.len() { -1 } else { end as i64 };
+    values.insert(0, Value::Integer(next));
+    Ok(MultiValue::from_vec(values))
+}
+
+// Decodes up to `limit` objects starting at `offset`, all of them when the limit is 0
+fn msgpack_unpack<'lua>(
+    lua: &'lua Lua,
+    data: &[u8],
+    offset: usize,
+    limit: usize,
+) -> mlua::Result<(usize, Vec<Value<'lua>>)> {
+    let mut decoder = MsgpackDecoder {
+        lua,
+        input: data,
+        pos: offset,
+    };
+    let mut values = Vec::new();
+    while decoder.pos < data.len() && (limit == 0 || values.len() < limit) {
+        values.push(decoder.value(0)?);
+    }
+    Ok((decoder.pos, values))
+}
+
+struct MsgpackDecoder<'lua, 'a> {
+    lua: &'lua Lua,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'lua> MsgpackDecoder<'lua, '_> {
+    fn take(&mut self, len: usize) -> mlua::Result<&[u8]> {
+        match self.input.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => error("Missing bytes in input."),
+        }
+    }
+
+    fn uint(&mut self, size: usize) -> mlua::Result<u64> {
+        Ok(self
+            .take(size)?
+            .iter()
+            .fold(0u64, |n, byte| (n << 8) | *byte as u64))
+    }
+
+    // Two's complement integer of `size` bytes
+    fn int(&mut self, size: usize) -> mlua::Result<i64> {
+        let n = self.uint(size)?;
+        let shift = 64 - size as u32 * 8;
+        Ok(((n << shift) as i64) >> shift)
+    }
+
+    fn value(&mut self, depth: usize) -> mlua::Result<Value<'lua>> {
+        if depth > MSGPACK_MAX_DECODE_DEPTH {
+            return error("Too deep nesting in input.");
+        }
+        let tag = self.take(1)?[0];
+        Ok(match tag {
+            0x00..=0x7f => Value::Number(tag as f64),
+            0x80..=0x8f => self.map((tag & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.array((tag & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.string((tag & 0x1f) as usize)?,
+            0xc0 => Value::Nil,
+            0xc2 => Value::Boolean(false),
+            0xc3 => Value::Boolean(true),
+            0xc4 | 0xd9 => {
+                let len = self.uint(1)? as usize;
+                self.string(len)?
+            }
+            0xc5 | 0xda => {
+                let len = self.uint(2)? as usize;
+                self.string(len)?
+            }
+            0xc6 | 0xdb => {
+                let len = self.uint(4)? as usize;
+                self.string(len)?
+            }
+            0xca => Value::Number(f32::from_bits(self.uint(4)? as u32) as f64),
+            0xcb => Value::Number(f64::from_bits(self.uint(8)?)),
+            0xcc => Value::Number(self.uint(1)? as f64),
+            0xcd => Value::Number(self.uint(2)? as f64),
+            0xce => Value::Number(self.uint(4)? as f64),
+            0xcf => Value::Number(self.uint(8)? as f64),
+            0xd0 => Value::Number(self.int(1)? as f64),
+            0xd1 => Value::Number(self.int(2)? as f64),
+            0xd2 => Value::Number(self.int(4)? as f64),
+            0xd3 => Value::Number(self.int(8)? as f64),
+            0xdc => {
+                let len = self.uint(2)? as usize;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.uint(4)? as usize;
+                self.array(len, depth)?
+            }
+            0xde => {
+                let len = self.uint(2)? as usize;
+                self.map(len, depth)?
+            }
+            0xdf => {
+                let len = self.uint(4)? as usize;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::Number((tag as i8) as f64),
+            _ => return error("Bad data format in input."),
+        })
+    }
+
+    fn string(&mut self, len: usize) -> mlua::Result<Value<'lua>> {
+        let lua = self.lua;
+        let bytes = self.take(len)?;
+        Ok(Value::String(lua.create_string(bytes)?))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> mlua::Result<Value<'lua>> {
+        let table = self.lua.create_table()?;
+        for i in 1..=len {
+            table.raw_set(i, self.value(depth + 1)?)?;
+        }
+        Ok(Value::Table(table))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> mlua::Result<Value<'lua>> {
+        let table = self.lua.create_table()?;
+        for _ in 0..len {
+            let key = self.value(depth + 1)?;
+            let value = self.value(depth + 1)?;
+            if key == Value::Nil {
+                return error("Bad data format in input.");
+            }
+            table.raw_set(key, value)?;
+        }
+        Ok(Value::Table(table))
+    }
+}
+
+// bit
+
+// Numbers are taken modulo 2^32 after rounding, the way LuaBitOp does it for doubles
+fn tobit(n: f64) -> i32 {
+    (n + 6755399441055744.0).to_bits() as u32 as i32
+}
+
+fn bit(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let bit = lua.create_table()?;
+    bit.set("tobit", lua.create_function(|_, x: f64| Ok(tobit(x)))?)?;
+    bit.set("bnot", lua.create_function(|_, x: f64| Ok(!tobit(x)))?)?;
+    let fold = |op: fn(i32, i32) -> i32| {
+        lua.create_function(move |_, (x, rest): (f64, Variadic<f64>)| {
+            Ok(rest.iter().fold(tobit(x), |acc, y| op(acc, tobit(*y))))
+        })
+    };
+    bit.set("band", fold(|a, b| a & b)?)?;
+    bit.set("bor", fold(|a, b| a | b)?)?;
+    bit.set("bxor", fold(|a, b| a ^ b)?)?;
+    let shift = |op: fn(i32, u32) -> i32| {
+        lua.create_function(move |_, (x, n): (f64, f64)| Ok(op(tobit(x), tobit(n) as u32 & 31)))
+    };
+    bit.set("lshift", shift(|x, n| ((x as u32) << n) as i32)?)?;
+    bit.set("rshift", shift(|x, n| ((x as u32) >> n) as i32)?)?;
+    bit.set("arshift", shift(|x, n| x >> n)?)?;
+    bit.set("rol", shift(|x, n| (x as u32).rotate_left(n) as i32)?)?;
+    bit.set("ror", shift(|x, n| (x as u32).rotate_right(n) as i32)?)?;
+    bit.set(
+        "bswap",
+        lua.create_function(|_, x: f64| Ok(tobit(x).swap_bytes()))?,
+    )?;
+    bit.set(
+        "tohex",
+        lua.create_function(|_, (x, n): (f64, Option<f64>)| {
+            let mut n = n.map_or(8, tobit);
+            let digits: &[u8] = if n < 0 {
+                n = n.saturating_neg();
+                b"0123456789ABCDEF"
+            } else {
+                b"0123456789abcdef"
+            };
+            let n = n.min(8) as usize;
+            let mut value = tobit(x) as u32;
+            let mut hex = vec![0; n];
+            for c in hex.iter_mut().rev() {
+                *c = digits[(value & 15) as usize];
+                value >>= 4;
+            }
+            Ok(String::from_utf8(hex).unwrap_or_default())
+        })?,
+    )?;
+    Ok(bit)
+}
+
+// struct
+
+#[derive(Clone, Copy, PartialEq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+// The state a format string carries from one option to the next
+struct Header {
+    endian: Endian,
+    align: usize,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            endian: Endian::Little,
+            align: 1,
+        }
+    }
+}
+
+// Format is a struct format string being walked one option at a time
+struct Format<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+}
+
+impl Format<'_> {
+    fn next_option(&mut self) -> Option<u8> {
+        let opt = *self.fmt.get(self.pos)?;
+        self.pos += 1;
+        Some(opt)
+    }
+
+    fn number(&mut self, default: usize) -> mlua::Result<usize> {
+        if !self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            return Ok(default);
+        }
+        let mut n: usize = 0;
+        while let Some(digit) = self.fmt.get(self.pos).filter(|c| c.is_ascii_digit()) {
+            n = n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((digit - b'0') as usize))
+                .filter(|n| *n <= i32::MAX as usize)
+                .ok_or_else(|| mlua::Error::RuntimeError("integral size overflow".to_string()))?;
+            self.pos += 1;
+        }
+        Ok(n)
+    }
+
+    // The size in bytes of an option, 0 for those without a fixed one
+    fn size(&mut self, opt: u8) -> mlua::Result<usize> {
+        Ok(match opt {
+            b'b' | b'B' | b'x' => 1,
+            b'h' | b'H' => 2,
+            b'l' | b'L' | b'T' | b'd' => 8,
+            b'f' => 4,
+            b'c' => self.number(1)?,
+            b'i' | b'I' => {
+                let size = self.number(4)?;
+                if size > STRUCT_MAX_INT_SIZE {
+                    return error(format!(
+                        "integral size {} is larger than limit of {}",
+                        size, STRUCT_MAX_INT_SIZE
+                    ));
+                }
+                size
+            }
+            _ => 0,
+        })
+    }
+
+    // Options which only change the header: endianness, alignment and spaces
+    fn control(&mut self, opt: u8, header: &mut Header) -> mlua::Result<()> {
+        match opt {
+            b' ' => {}
+            b'>' => header.endian = Endian::Big,
+            b'<' | b'=' => header.endian = Endian::Little,
+            b'!' => {
+                let align = self.number(STRUCT_MAX_ALIGN)?;
+                if !align.is_power_of_two() {
+                    return error(format!("alignment {} is not a power of 2", align));
+                }
+                header.align = align;
+            }
+            opt => {
+                return error(format!(
+                    "bad argument #1 (invalid format option '{}')",
+                    opt as char
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Padding needed before an option of `size` bytes at offset `len`
+fn struct_padding(len: usize, header: &Header, opt: u8, size: usize) -> usize {
+    if size == 0 || opt == b'c' {
+        return 0;
+    }
+    let size = size.min(header.align);
+    (size - (len & (size - 1))) & (size - 1)
+}
+
+fn lua_struct(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let lua_struct = lua.create_table()?;
+    lua_struct.set(
+        "pack",
+        lua.create_function(|lua, (fmt, args): (mlua::String, Variadic<Value>)| {
+            lua.create_string(&struct_pack(fmt.as_bytes(), &args)?)
+        })?,
+    )?;
+    lua_struct.set(
+        "unpack",
+        lua.create_function(
+            |lua, (fmt, data, init): (mlua::String, mlua::String, Option<i64>)| {
+                struct_unpack(lua, fmt.as_bytes(), data.as_bytes(), init.unwrap_or(1))
+            },
+        )?,
+    )?;
+    lua_struct.set(
+        "size",
+        lua.create_function(|_, fmt: mlua::String| struct_size(fmt.as_bytes()))?,
+    )?;
+    Ok(lua_struct)
+}
+
+fn struct_pack(fmt: &[u8], args: &[Value]) -> mlua::Result<Vec<u8>> {
+    let mut format = Format { fmt, pos: 0 };
+    let mut header = Header::default();
+    let mut packed = Vec::new();
+    let mut args = args.iter();
+    let mut arg = 1;
+    let mut next_arg = || {
+        arg += 1;
+        args.next().cloned().unwrap_or(Value::Nil)
+    };
+    let number_arg = |value: Value| match number(&value) {
+        Some(n) => Ok(n),
+        None => match &value {
+            Value::String(s) => s
+                .to_str()
+                .ok()
+                .and_then(|s| s.trim().parse::<f64>().ok())
+                .ok_or_else(|| {
+                    mlua::Error::RuntimeError("bad argument (number expected, got string)".into())
+                }),
+            other => error(format!(
+                "bad argument (number expected, got {})",
+                type_name(other)
+            )),
+        },
+    };
+
+    while let Some(opt) = format.next_option() {
+        let mut size = format.size(opt)?;
+        let padding = struct_padding(packed.len(), &header, opt, size);
+        packed.resize(packed.len() + padding, 0);
+        match opt {
+            b'b' | b'B' | b'h' | b'H' | b'l' | b'L' | b'T' | b'i' | b'I' => {
+                let n = number_arg(next_arg())?;
+                let mut value = if n < 0.0 { n as i64 as u64 } else { n as u64 };
+                let mut bytes = vec![0u8; size];
+                for i in 0..size {
+                    let at = if header.endian == Endian::Little {
+                        i
+                    } else {
+                        size - 1 - i
+                    };
+                    bytes[at] = (value & 0xff) as u8;
+                    value >>= 8;
+                }
+                packed.extend_from_slice(&bytes);
+            }
+            b'x' => packed.push(0),
+            b'f' => {
+                let n = number_arg(next_arg())? as f32;
+                packed.extend_from_slice(&match header.endian {
+                    Endian::Little => n.to_le_bytes(),
+                    Endian::Big => n.to_be_bytes(),
+                });
+            }
+            b'd' => {
+                let n = number_arg(next_arg())?;
+                packed.extend_from_slice(&match header.endian {
+                    Endian::Little => n.to_le_bytes(),
+                    Endian::Big => n.to_be_bytes(),
+                });
+            }
+            b'c' | b's' => {
+                let value = next_arg();
+                let s = match &value {
+                    Value::String(s) => s.as_bytes().to_vec(),
+                    Value::Integer(_) | Value::Number(_) => {
+                        format_g(number(&value).unwrap_or_default(), 14).into_bytes()
+                    }
+                    other => {
+                        return error(format!(
+                            "bad argument #{} to 'pack' (string expected, got {})",
+                            arg,
+                            type_name(other)
+                        ));
+                    }
+                };
+                if size == 0 {
+                    size = s.len();
+                }
+                if s.len() < size {
+                    return error(format!(
+                        "bad argument #{} to 'pack' (string too short)",
+                        arg
+                    ));
+                }
+                packed.extend_from_slice(&s[..size]);
+                if opt == b's' {
+                    packed.push(0);
+                }
+            }
+            opt => format.control(opt, &mut header)?,
+        }
+    }
+    Ok(packed)
+}
+
+fn struct_unpack<'lua>(
+    lua: &'lua Lua,
+    fmt: &[u8],
+    data: &[u8],
+    init: i64,
+) -> mlua::Result<MultiValue<'lua>> {
+    if init <= 0 {
+        return error("bad argument #3 to 'unpack' (offset must be 1 or greater)");
+    }
+    let too_short = || error("bad argument #2 to 'unpack' (data string too short)");
+    let mut format = Format { fmt, pos: 0 };
+    let mut header = Header::default();
+    let mut pos = init as usize - 1;
+    let mut values: Vec<Value> = Vec::new();
+
+    while let Some(opt) = format.next_option() {
+        let mut size = format.size(opt)?;
+        pos += struct_padding(pos, &header, opt, size);
+        if size > data.len() || pos > data.len() - size {
+            return too_short();
+        }
+        match opt {
+            b'b' | b'B' | b'h' | b'H' | b'l' | b'L' | b'T' | b'i' | b'I' => {
+                let bytes = &data[pos..pos + size];
+                let mut value: u64 = 0;
+                let ordered: Box<dyn Iterator<Item = &u8>> = match header.endian {
+                    Endian::Big => Box::new(bytes.iter()),
+                    Endian::Little => Box::new(bytes.iter().rev()),
+                };
+                for byte in ordered {
+                    value = (value << 8) | *byte as u64;
+                }
+                let n = if opt.is_ascii_lowercase() {
+                    let mask = u64::MAX.checked_shl(size as u32 * 8 - 1).unwrap_or(0);
+                    if value & mask != 0 {
+                        value |= mask;
+                    }
+                    value as i64 as f64
+                } else {
+                    value as f64
+                };
+                values.push(Value::Number(n));
+            }
+            b'x' => {}
+            b'f' => {
+                let bytes: [u8; 4] = data[pos..pos + 4].try_into().unwrap_or_default();
+                let n = match header.endian {
+                    Endian::Little => f32::from_le_bytes(bytes),
+                    Endian::Big => f32::from_be_bytes(bytes),
+                };
+                values.push(Value::Number(n as f64));
+            }
+            b'd' => {
+                let bytes: [u8; 8] = data[pos..pos + 8].try_into().unwrap_or_default();
+                let n = match header.endian {
+                    Endian::Little => f64::from_le_bytes(bytes),
+                    Endian::Big => f64::from_be_bytes(bytes),
+                };
+                values.push(Value::Number(n));
+            }
+            b'c' => {
+                // c0 takes its length from the number unpacked right before it
+                if size == 0 {
+                    let Some(len) = values.pop().as_ref().and_then(number) else {
+                        return error("format 'c0' needs a previous size");
+                    };
+                    size = len as usize;
+                    if size > data.len() || pos > data.len() - size {
+                        return too_short();
+                    }
+                }
+                values.push(Value::String(lua.create_string(&data[pos..pos + size])?));
+            }
+            b's' => {
+                let Some(len) = data[pos..].iter().position(|c| *c == 0) else {
+                    return error("unfinished string in data");
+                };
+                values.push(Value::String(lua.create_string(&data[pos..pos + len])?));
+                size = len + 1;
+            }
+            opt => format.control(opt, &mut header)?,
+        }
+        pos += size;
+    }
+    values.push(Value::Integer(pos as i64 + 1));
+    Ok(MultiValue::from_vec(values))
+}
+
+fn struct_size(fmt: &[u8]) -> mlua::Result<usize> {
+    let mut format = Format { fmt, pos: 0 };
+    let mut header = Header::default();
+    let mut pos = 0;
+    while let Some(opt) = format.next_option() {
+        let size = format.size(opt)?;
+        pos += struct_padding(pos, &header, opt, size);
+        match opt {
+            b's' => return error("bad argument #1 to 'size' (options 's' have undefined sizes)"),
+            b'c' if size == 0 => {
+                return error("bad argument #1 to 'size' (options 'c0' have undefined sizes)");
+            }
+            b'b' | b'B' | b'h' | b'H' | b'l' | b'L' | b'T' | b'i' | b'I' | b'x' | b'f' | b'd'
+            | b'c' => {}
+            opt => format.control(opt, &mut header)?,
+        }
+        pos += size;
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm() -> Lua {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+        lua
+    }
+
+    fn eval_bytes(lua: &Lua, code: &str) -> Vec<u8> {
+        lua.load(code)
+            .eval::<mlua::String>()
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn formats_like_printf_g() {
+        assert_eq!(format_g(1.0, 14), "1");
+        assert_eq!(format_g(0.1, 14), "0.1");
+        assert_eq!(format_g(1.5e20, 14), "1.5e+20");
+        assert_eq!(format_g(1e-5, 14), "1e-05");
+        assert_eq!(format_g(-123456.789, 14), "-123456.789");
+        assert_eq!(format_g(1.0 / 3.0, 14), "0.33333333333333");
+    }
+
+    #[test]
+    fn cjson_encodes_like_lua_cjson() {
+        let lua = vm();
+        let cases = [
+            ("cjson.encode({1, 2, 'three'})", "[1,2,\"three\"]"),
+            ("cjson.encode({})", "{}"),
+            ("cjson.encode({a = {b = true}})", "{\"a\":{\"b\":true}}"),
+            ("cjson.encode('a/\"b\"\\n')", "\"a\\/\\\"b\\\"\\n\""),
+            ("cjson.encode(0.5)", "0.5"),
+            ("cjson.encode(cjson.null)", "null"),
+            ("cjson.encode({[1] = 'a', [3] = 'c'})", "[\"a\",null,\"c\"]"),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(eval_bytes(&lua, code), expected.as_bytes(), "{}", code);
+        }
+        assert!(
+            lua.load("cjson.encode({[1] = 1, [100] = 2})")
+                .exec()
+                .is_err()
+        );
+        assert!(lua.load("cjson.encode(function() end)").exec().is_err());
+    }
+
+    #[test]
+    fn cjson_decodes() {
+        let lua = vm();
+        let decoded: String = lua
+            .load(
+                r#"
+                local t = cjson.decode([[{"a": [1, 2.5, "x\u00e9\ud83d\ude00"], "b": null, "c": false}]])
+                return tostring(t.a[1]) .. ',' .. tostring(t.a[2]) .. ',' .. t.a[3] .. ','
+                    .. tostring(t.b == cjson.null) .. ',' .. tostring(t.c)
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(decoded, "1,2.5,xé😀,true,false");
+        assert!(lua.load("cjson.decode('{\"a\": }')").exec().is_err());
+        assert!(lua.load("cjson.decode('[1] x')").exec().is_err());
+    }
+
+    #[test]
+    fn cmsgpack_matches_known_encodings() {
+        let lua = vm();
+        let cases: [(&str, &[u8]); 8] = [
+            ("cmsgpack.pack(1)", &[0x01]),
+            ("cmsgpack.pack(-1)", &[0xff]),
+            ("cmsgpack.pack(300)", &[0xcd, 0x01, 0x2c]),
+            ("cmsgpack.pack(-200)", &[0xd1, 0xff, 0x38]),
+            ("cmsgpack.pack(0.5)", &[0xca, 0x3f, 0x00, 0x00, 0x00]),
+            ("cmsgpack.pack('abc')", &[0xa3, b'a', b'b', b'c']),
+            (
+                "cmsgpack.pack({1, 2}, true, nil)",
+                &[0x92, 0x01, 0x02, 0xc3, 0xc0],
+            ),
+            ("cmsgpack.pack({a = 1})", &[0x81, 0xa1, b'a', 0x01]),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(eval_bytes(&lua, code), expected, "{}", code);
+        }
+    }
+
+    #[test]
+    fn cmsgpack_round_trips() {
+        let lua = vm();
+        let result: String = lua
+            .load(
+                r#"
+                local a, b, c = cmsgpack.unpack(cmsgpack.pack({x = {1, 2, 3}}, 0.1, 'str'))
+                local next, one = cmsgpack.unpack_one(cmsgpack.pack(7, 8))
+                local last, two = cmsgpack.unpack_one(cmsgpack.pack(7, 8), next)
+                return table.concat(a.x, ',') .. ';' .. b .. ';' .. c .. ';'
+                    .. next .. ':' .. one .. ';' .. last .. ':' .. two
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(result, "1,2,3;0.1;str;1:7;-1:8");
+        assert!(lua.load("cmsgpack.unpack('\\205\\001')").exec().is_err());
+    }
+
+    #[test]
+    fn bit_operations_wrap_to_32_bits() {
+        let lua = vm();
+        let result: String = lua
+            .load(
+                r#"
+                return table.concat({
+                    bit.tobit(0xffffffff), bit.band(0xff, 0x0f, 0x3), bit.bor(1, 2, 4),
+                    bit.bxor(5, 1), bit.bnot(0), bit.lshift(1, 31), bit.rshift(-1, 28),
+                    bit.arshift(-16, 2), bit.rol(0x80000001, 1), bit.tohex(255),
+                    bit.tohex(-1, -4), bit.bswap(0x01020304),
+                }, ',')
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(
+            result,
+            "-1,3,7,4,-1,-2147483648,15,-4,3,000000ff,FFFF,67305985"
+        );
+    }
+
+    #[test]
+    fn struct_packs_like_lua_struct() {
+        let lua = vm();
+        assert_eq!(
+            eval_bytes(&lua, "struct.pack('>hI2b', 1, 258, -1)"),
+            &[0x00, 0x01, 0x01, 0x02, 0xff]
+        );
+        assert_eq!(
+            eval_bytes(&lua, "struct.pack('<i', -2)"),
+            &[0xfe, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            eval_bytes(&lua, "struct.pack('!4bi', 1, 2)"),
+            &[0x01, 0, 0, 0, 0x02, 0, 0, 0]
+        );
+        assert_eq!(
+            eval_bytes(&lua, "struct.pack('sc2', 'ab', 'xyz')"),
+            b"ab\0xy"
+        );
+
+        let result: String = lua
+            .load(
+                r#"
+                local a, s, c, next = struct.unpack('>hsBc0', struct.pack('>hsB', -2, 'hi', 3) .. 'xyz')
+                return table.concat({a, s, c, next, struct.size('!4bi'), struct.size('>d')}, ',')
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(result, "-2,hi,xyz,10,8,8");
+        assert!(lua.load("struct.unpack('i', 'ab')").exec().is_err());
+        assert!(lua.load("struct.size('s')").exec().is_err());
+    }
+}
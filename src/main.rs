@@ -1,8 +1,28 @@
 #![allow(unused_imports)]
+mod aof;
+mod cluster;
 mod config;
 mod connection;
+mod dict;
+mod expire;
+mod functions;
+mod geo;
+mod glob;
+mod hll;
+mod lualib;
 mod mem;
+mod notify;
+mod pubsub;
+mod rdb;
+mod replication;
 mod resp;
+mod scripting;
+mod sentinel;
+mod session;
+mod snapshot;
+mod slot;
+mod transaction;
+mod zset;
 
 use std::sync::{Arc, Mutex, RwLock};
 
@@ -12,15 +32,16 @@ use clap::Parser;
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::{
-    config::{Config, Role},
+    cluster::Cluster,
+    config::Config,
     connection::Connection,
-    resp::{
-        commands::{Command, list, structs::Value},
-        parser::parse_resp,
-    },
+    replication::MasterAddr,
+    resp::commands::{Command, list, structs::Value},
+    sentinel::Sentinel,
+    session::Session,
 };
 use crate::{
-    mem::MemDB,
+    mem::{Databases, MemDB},
     resp::{commands::structs::Data, frame::RespFrame},
 };
 
@@ -33,6 +54,83 @@ struct Args {
 
     #[arg(short, long)]
     replicaof: Option<String>,
+
+    #[arg(long, default_value = "")]
+    notify_keyspace_events: String,
+
+    #[arg(long, default_value = ".")]
+    dir: String,
+
+    #[arg(long, default_value = "dump.rdb")]
+    dbfilename: String,
+
+    // How many databases clients can SELECT from
+    #[arg(long, default_value_t = config::DEFAULT_DATABASES)]
+    databases: usize,
+
+    // Save rules as "<seconds> <changes> ...", an empty string disables automatic saves
+    #[arg(long)]
+    save: Option<String>,
+
+    #[arg(long, default_value = "no")]
+    appendonly: String,
+
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    #[arg(long, default_value = "appendonlydir")]
+    appenddirname: String,
+
+    #[arg(long, default_value = "everysec")]
+    appendfsync: String,
+
+    #[arg(long, default_value = "yes")]
+    aof_load_truncated: String,
+
+    #[arg(long, default_value = "yes")]
+    aof_use_rdb_preamble: String,
+
+    #[arg(long, default_value = "1mb")]
+    repl_backlog_size: String,
+
+    #[arg(long, default_value = "yes")]
+    replica_read_only: String,
+
+    #[arg(long, default_value = "no")]
+    repl_diskless_sync: String,
+
+    #[arg(long, default_value = "5")]
+    repl_diskless_sync_delay: String,
+
+    #[arg(long, default_value = "disabled")]
+    repl_diskless_load: String,
+
+    #[arg(long, default_value = "100")]
+    replica_priority: String,
+
+    // Runs as a sentinel, monitoring the masters given with --sentinel-monitor
+    #[arg(long)]
+    sentinel: bool,
+
+    // A master to monitor as "<name> <host> <port> <quorum>", like SENTINEL MONITOR
+    #[arg(long)]
+    sentinel_monitor: Vec<String>,
+
+    // A setting of a monitored master as "<name> <option> <value>", like SENTINEL SET
+    #[arg(long)]
+    sentinel_set: Vec<String>,
+
+    // Runs as a cluster node, serving the hash slots assigned to it
+    #[arg(long, default_value = "no")]
+    cluster_enabled: String,
+
+    // Where the node keeps the cluster state, relative to --dir
+    #[arg(long, default_value = "nodes.conf")]
+    cluster_config_file: String,
+
+    // Milliseconds a node may not answer for before it is suspected to fail
+    #[arg(long, default_value_t = cluster::DEFAULT_NODE_TIMEOUT)]
+    cluster_node_timeout: u64,
 }
 
 #[tokio::main]
@@ -40,14 +138,41 @@ async fn main() -> Result<()> {
     println!("Logs from your program will appear here!");
 
     let args = Args::parse();
-    let config = parse_config(&args);
+    let config = parse_config(&args)?;
 
     let listener_url = format!("127.0.0.1:{}", args.port);
 
     println!("Listening on {}", listener_url);
 
     let listener = TcpListener::bind(listener_url).await?;
-    let db = Arc::new(RwLock::new(MemDB::<Data>::new()));
+    let dbs = Arc::clone(&config.databases);
+    // A sentinel keeps no dataset, it only monitors
+    if let Some(sentinel) = &config.sentinel {
+        tokio::spawn(sentinel::sentinel_cycle(sentinel.clone()));
+    } else {
+        // With the AOF on the dataset comes from it, the RDB file is only used to create it
+        if !config.aof.enabled() || !config.aof.load(&dbs.all(), &config)? {
+            config.persistence.load(&dbs.all(), &config)?;
+        }
+        if config.aof.enabled() {
+            config.persistence.mark_saved(&dbs.all())?;
+            config.aof.start(&dbs.all(), &config)?;
+        }
+
+        tokio::spawn(expire::active_expire_cycle(Arc::clone(&dbs), config.clone()));
+        tokio::spawn(snapshot::save_cycle(Arc::clone(&dbs), config.clone()));
+        tokio::spawn(aof::aof_cycle(Arc::clone(&dbs), config.clone()));
+        tokio::spawn(replication::replica_cycle(Arc::clone(&dbs), config.clone()));
+    }
+    if config.cluster.is_some() {
+        let bus = TcpListener::bind(format!(
+            "127.0.0.1:{}",
+            args.port + cluster::BUS_PORT_OFFSET as u32
+        ))
+        .await?;
+        tokio::spawn(cluster::serve_bus(bus, config.clone()));
+        tokio::spawn(cluster::cluster_cycle(config.clone()));
+    }
 
     loop {
         let (stream, addr) = listener.accept().await?;
@@ -55,58 +180,182 @@ async fn main() -> Result<()> {
 
         let connection = Connection::new(stream, addr);
 
-        let db_clone = Arc::clone(&db);
+        let dbs_clone = Arc::clone(&dbs);
         let config_clone = config.clone();
         config_clone.increment_connections();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(connection, db_clone, config_clone.clone()).await {
+            if let Err(e) = handle_connection(connection, dbs_clone, config_clone).await {
                 eprintln!("Error handling connection: {}", e);
             }
-            config_clone.decrement_connections();
         });
     }
 }
 
-fn parse_config(args: &Args) -> Config {
-    let role: Role;
-    let mut master_host: Option<String> = None;
-    let mut master_port: Option<u32> = None;
-
+fn parse_config(args: &Args) -> Result<Config> {
+    let mut replicaof = None;
     if let Some(replicaof_str) = &args.replicaof {
-        role = Role::Replica;
-
         // Parse "host port" format
         let parts: Vec<&str> = replicaof_str.split_whitespace().collect();
-        if parts.len() == 2 {
-            master_host = Some(parts[0].to_string());
-            master_port = parts[1].parse().ok();
+        match parts[..] {
+            [host, port] if port.parse::<u16>().is_ok() => {
+                replicaof = Some(MasterAddr {
+                    host: host.to_string(),
+                    port: port.parse()?,
+                });
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Invalid --replicaof '{}', expected \"host port\"",
+                    replicaof_str
+                ));
+            }
         }
-    } else {
-        role = Role::Master
     }
 
-    Config::new(role, args.port, master_host, master_port)
+    let mut config = Config::new(args.port, replicaof);
+    if args.databases == 0 {
+        return Err(anyhow::anyhow!("Invalid --databases 0, there has to be at least one"));
+    }
+    config.databases = Arc::new(Databases::new(args.databases));
+    config.set_parameter("notify-keyspace-events", &args.notify_keyspace_events)?;
+    config.set_parameter("dir", &args.dir)?;
+    config.set_parameter("dbfilename", &args.dbfilename)?;
+    if let Some(save) = &args.save {
+        config.set_parameter("save", save)?;
+    }
+    config.aof.set_filename(&args.appendfilename)?;
+    config.aof.set_dirname(&args.appenddirname)?;
+    config.set_parameter("appendfsync", &args.appendfsync)?;
+    config.set_parameter("aof-load-truncated", &args.aof_load_truncated)?;
+    config.set_parameter("aof-use-rdb-preamble", &args.aof_use_rdb_preamble)?;
+    config.set_parameter("repl-backlog-size", &args.repl_backlog_size)?;
+    config.set_parameter("replica-read-only", &args.replica_read_only)?;
+    config.set_parameter("repl-diskless-sync", &args.repl_diskless_sync)?;
+    config.set_parameter("repl-diskless-sync-delay", &args.repl_diskless_sync_delay)?;
+    config.set_parameter("repl-diskless-load", &args.repl_diskless_load)?;
+    config.set_parameter("replica-priority", &args.replica_priority)?;
+    if args.sentinel {
+        config.sentinel = Some(parse_sentinel(args, &config)?);
+    }
+    if config::parse_yes_no(&args.cluster_enabled)? {
+        let path = std::path::Path::new(&args.dir).join(&args.cluster_config_file);
+        config.cluster = Some(Cluster::load(
+            path,
+            args.port as u16,
+            args.cluster_node_timeout,
+        )?);
+    }
+    config
+        .aof
+        .set_enabled(config::parse_yes_no(&args.appendonly)?);
+    Ok(config)
+}
+
+fn parse_sentinel(args: &Args, config: &Config) -> Result<Sentinel> {
+    let sentinel = Sentinel::new(args.port, config.pubsub.clone());
+    for monitor in &args.sentinel_monitor {
+        let parts: Vec<&str> = monitor.split_whitespace().collect();
+        let [name, host, port, quorum] = parts[..] else {
+            return Err(anyhow::anyhow!(
+                "Invalid --sentinel-monitor '{}', expected \"name host port quorum\"",
+                monitor
+            ));
+        };
+        let addr = MasterAddr {
+            host: host.to_string(),
+            port: port
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid port in --sentinel-monitor '{}'", monitor))?,
+        };
+        let quorum = quorum
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid quorum in --sentinel-monitor '{}'", monitor))?;
+        sentinel.monitor(name, addr, quorum)?;
+    }
+    for set in &args.sentinel_set {
+        let parts: Vec<&str> = set.split_whitespace().collect();
+        let [name, option, value] = parts[..] else {
+            return Err(anyhow::anyhow!(
+                "Invalid --sentinel-set '{}', expected \"name option value\"",
+                set
+            ));
+        };
+        sentinel.set(name, option, value)?;
+    }
+    Ok(sentinel)
 }
 
 async fn handle_connection(
     mut connection: Connection,
-    db: Arc<RwLock<MemDB<Data>>>,
+    dbs: Arc<Databases<Data>>,
     config: Config,
+) -> Result<()> {
+    let dbs = dbs.all();
+    let mut session = Session::new(connection.id(), connection.addr(), &config);
+    let result = serve(&mut connection, &mut session, &dbs, &config).await;
+    session.close(&dbs);
+    result
+}
+
+async fn serve(
+    connection: &mut Connection,
+    session: &mut Session,
+    dbs: &[&RwLock<MemDB<Data>>],
+    config: &Config,
 ) -> Result<()> {
     loop {
-        match connection.parse().await {
+        // Subscribed clients get messages pushed to them while we wait for their next request
+        let request = tokio::select! {
+            request = connection.parse() => request,
+            message = session.next_message() => {
+                let Some(message) = message else {
+                    println!("Closing {}, it fell behind on pub/sub messages", connection.addr());
+                    return Ok(());
+                };
+                connection.write(message).await?;
+                continue;
+            }
+        };
+
+        match request {
             Err(err) => {
                 if err.to_string().starts_with("Connection closed") {
                     println!("{}", err);
                     return Ok(());
                 }
                 println!("Error parsing command: {}", err);
-                let _ = connection.write(RespFrame::Error(err.to_string())).await; //TODO: How to handle errors here??
+                // The rest of what the client sent can't be told apart from garbage, so like
+                // Redis the connection is closed after replying
+                let _ = connection.write(RespFrame::Error(format!("ERR {}", err))).await;
+                return Ok(());
             }
-            std::result::Result::Ok(command) => {
-                command.validate()?;
-                let result = command.execute(db.as_ref(), &config);
-                connection.write_result(result).await?;
+            std::result::Result::Ok(request) => {
+                // A running script holds the database, waiting for it must not hold up the worker thread
+                let frames = if config.scripting.is_running() {
+                    tokio::task::block_in_place(|| session.handle(request, dbs, config))
+                } else {
+                    session.handle(request, dbs, config)
+                };
+                for frame in frames {
+                    connection.write(frame).await?;
+                }
+                if let Some(wait) = session.take_wait() {
+                    // WAIT may block forever, a client which goes away meanwhile is dropped
+                    let reply = tokio::select! {
+                        reply = replication::wait(wait, config) => reply,
+                        err = connection.closed() => {
+                            println!("{}", err);
+                            return Ok(());
+                        }
+                    };
+                    connection.write(reply).await?;
+                }
+                if let Some(sync) = session.take_sync() {
+                    return replication::serve_replica(connection, sync, dbs, config).await;
+                }
+                if session.is_closing() {
+                    return Ok(());
+                }
             }
         }
     }
@@ -1,16 +1,43 @@
-use std::{collections::HashMap, sync::{Arc, RwLock}, time::Instant};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, RwLock}, time::Instant};
+
+use crate::dict::Dict;
 
 use anyhow::{Error, Ok, Result};
     
 
+// Values stored in a MemDB tell whether they carry an expiry, so the keys which do can be sampled
+pub trait Expires {
+    fn expires(&self) -> bool;
+}
+
 pub struct MemDB<T> {
-    store: HashMap<String, T>
+    // index is the number clients SELECT the database by
+    index: usize,
+    store: Dict<T>,
+    // volatile holds the keys of the store which have an expiry, the ones expiry is sampled from
+    volatile: Dict<()>,
+    // watchers maps the keys clients called WATCH on to those clients. Once a watched key is
+    // modified its clients move to dirty, which makes their next EXEC fail.
+    watchers: HashMap<String, HashSet<u64>>,
+    dirty: HashSet<u64>,
+    // changes counts the writes since the store was created, snapshots compare it against
+    // the count at the last save
+    changes: u64
 }
 
-impl<T> MemDB<T> {
+impl<T: Expires> MemDB<T> {
     pub fn new() -> Self {
+        Self::with_index(0)
+    }
+
+    pub fn with_index(index: usize) -> Self {
         MemDB {
-            store: HashMap::new()
+            index,
+            store: Dict::new(),
+            volatile: Dict::new(),
+            watchers: HashMap::new(),
+            dirty: HashSet::new(),
+            changes: 0
         }
     }
 
@@ -26,7 +53,145 @@ impl<T> MemDB<T> {
 
     }
 
+    // get_mut gives access to a value in place without it counting as a write, for what a
+    // value caches about itself and which doesn't change what it holds
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        self.store.get_mut(key)
+    }
+
     pub fn set(&mut self, key: String, data: T) {
+        self.touch(&key);
+        self.changes += 1;
+        if data.expires() {
+            self.volatile.insert(key.clone(), ());
+        } else {
+            self.volatile.remove(&key);
+        }
         self.store.insert(key, data);
     }
-}
\ No newline at end of file
+
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        let removed = self.store.remove(key);
+        if removed.is_some() {
+            self.volatile.remove(key);
+            self.touch(key);
+            self.changes += 1;
+        }
+        removed
+    }
+
+    // clear removes every key, clients watching any of them see it as modified. The keys are
+    // handed back, so a large store can be dropped somewhere else.
+    pub fn clear(&mut self) -> Dict<T> {
+        self.changes += self.store.len() as u64;
+        self.touch_all();
+        self.volatile = Dict::new();
+        std::mem::take(&mut self.store)
+    }
+
+    // swap exchanges the keys of two databases, which keep their index and watchers. Every
+    // client watching a key in either of them sees it as modified.
+    pub fn swap(&mut self, other: &mut MemDB<T>) {
+        self.changes += self.store.len() as u64 + other.store.len() as u64;
+        other.changes += self.store.len() as u64 + other.store.len() as u64;
+        std::mem::swap(&mut self.store, &mut other.store);
+        std::mem::swap(&mut self.volatile, &mut other.volatile);
+        self.touch_all();
+        other.touch_all();
+    }
+
+    pub fn watch(&mut self, key: &str, client: u64) {
+        self.watchers.entry(key.to_string()).or_default().insert(client);
+    }
+
+    pub fn unwatch(&mut self, keys: &[String], client: u64) {
+        for key in keys {
+            if let Some(clients) = self.watchers.get_mut(key) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    self.watchers.remove(key);
+                }
+            }
+        }
+        self.dirty.remove(&client);
+    }
+
+    // is_dirty is true when one of the keys the client watches was modified since WATCH
+    pub fn is_dirty(&self, client: u64) -> bool {
+        self.dirty.contains(&client)
+    }
+
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(clients) = self.watchers.remove(key) {
+            self.dirty.extend(clients);
+        }
+    }
+
+    fn touch_all(&mut self) {
+        let watchers = std::mem::take(&mut self.watchers);
+        self.dirty.extend(watchers.into_values().flatten());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &T)> {
+        self.store.iter()
+    }
+
+    // scan visits the keys of one bucket of the store, see Dict::scan
+    pub fn scan(&self, cursor: u64, visit: impl FnMut(&String, &T)) -> u64 {
+        self.store.scan(cursor, visit)
+    }
+
+    pub fn random(&self) -> Option<(&String, &T)> {
+        self.store.random()
+    }
+
+    // sample_volatile picks up to `count` random keys among those with an expiry, a key may
+    // come up more than once
+    pub fn sample_volatile(&self, count: usize) -> Vec<String> {
+        (0..count)
+            .map_while(|_| self.volatile.random())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+// Databases are the numbered keyspaces a client picks from with SELECT, each behind its own lock
+pub struct Databases<T> {
+    dbs: Vec<RwLock<MemDB<T>>>
+}
+
+impl<T: Expires> Databases<T> {
+    pub fn new(count: usize) -> Self {
+        Databases {
+            dbs: (0..count).map(|index| RwLock::new(MemDB::with_index(index))).collect()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn all(&self) -> Vec<&RwLock<MemDB<T>>> {
+        self.dbs.iter().collect()
+    }
+
+    // with_current lists every database with `current` in place of the one sharing its index.
+    // Code given exclusive access to a database works on a private copy while the real lock is
+    // held, so it must keep going through the lock it was handed.
+    pub fn with_current<'a>(&'a self, current: &'a RwLock<MemDB<T>>) -> Vec<&'a RwLock<MemDB<T>>> {
+        let index = current.read().map(|db| db.index).ok();
+        self.dbs
+            .iter()
+            .enumerate()
+            .map(|(i, db)| if Some(i) == index { current } else { db })
+            .collect()
+    }
+}
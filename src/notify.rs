@@ -0,0 +1,258 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::pubsub::PubSub;
+
+// Keyspace event classes, one bit per character of notify-keyspace-events
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_NEW: u32 = 1 << 12; // n
+// A is an alias for g$lshzxet, key miss and new key events have to be asked for explicitly
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM;
+
+const CLASSES: &[(char, u32)] = &[
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+];
+
+/// Parses the notify-keyspace-events value, an empty string disables notifications
+pub fn parse_flags(classes: &str) -> Result<u32> {
+    let mut flags = 0;
+    for c in classes.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            _ => CLASSES
+                .iter()
+                .find(|(class, _)| *class == c)
+                .map(|(_, flag)| *flag)
+                .ok_or(anyhow!(
+                    "Invalid event class character. Use 'Ag$lshzxeKEtmn'."
+                ))?,
+        };
+    }
+    Ok(flags)
+}
+
+/// Formats the flags back the way CONFIG GET shows them, using A when every class is set
+pub fn flags_to_string(flags: u32) -> String {
+    let mut classes = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        classes.push('A');
+    } else {
+        for (class, flag) in CLASSES {
+            if flags & flag != 0 {
+                classes.push(*class);
+            }
+        }
+    }
+    for (class, flag) in [
+        ('K', NOTIFY_KEYSPACE),
+        ('E', NOTIFY_KEYEVENT),
+        ('m', NOTIFY_KEY_MISS),
+        ('n', NOTIFY_NEW),
+    ] {
+        if flags & flag != 0 {
+            classes.push(class);
+        }
+    }
+    classes
+}
+
+// Notifier publishes keyspace events over pub/sub. Notifications are disabled by default,
+// every write command calls notify and it drops the events of classes which aren't enabled.
+#[derive(Clone)]
+pub struct Notifier {
+    flags: Arc<AtomicU32>,
+    pubsub: PubSub,
+}
+
+impl Notifier {
+    pub fn new(pubsub: PubSub) -> Self {
+        Notifier {
+            flags: Arc::new(AtomicU32::new(0)),
+            pubsub,
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flags(&self, flags: u32) {
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    /// Publishes `event` on __keyspace@<db>__:<key> and `key` on __keyevent@<db>__:<event>,
+    /// depending on which of K and E are enabled
+    pub fn notify(&self, class: u32, event: &str, key: &str, db: usize) {
+        let flags = self.flags();
+        if flags & class == 0 {
+            return;
+        }
+
+        if flags & NOTIFY_KEYSPACE != 0 {
+            self.pubsub
+                .publish(&format!("__keyspace@{}__:{}", db, key), event.as_bytes());
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.pubsub
+                .publish(&format!("__keyevent@{}__:{}", db, event), key.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        pubsub::{Kind, Message, Subscriber},
+        resp::parser::{Request, parse_command},
+    };
+
+    fn run(config: &Config, db: usize, args: &[&str]) {
+        let argv = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        let command = parse_command(Request::new(argv)).unwrap();
+        command.execute(config.databases.all()[db], config).unwrap();
+    }
+
+    // A client subscribed to every keyspace and keyevent channel
+    fn subscriber(config: &Config) -> Subscriber {
+        let mut subscriber = Subscriber::new(1, config.pubsub.clone());
+        subscriber.subscribe(Kind::Pattern, &["__key*__:*".to_string()], false);
+        subscriber
+    }
+
+    // The channel and payload of the next `count` messages the subscriber gets
+    async fn received(subscriber: &mut Subscriber, count: usize) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        for _ in 0..count {
+            match subscriber.recv().await {
+                Some(Message::Pattern {
+                    channel, payload, ..
+                }) => messages.push((channel, String::from_utf8(payload).unwrap())),
+                _ => panic!("expected a pattern message"),
+            }
+        }
+        messages
+    }
+
+    fn messages(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(channel, payload)| (channel.to_string(), payload.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn channels_name_the_database_of_the_key() {
+        let config = Config::new(6379, None);
+        config.notifier.set_flags(parse_flags("KEA").unwrap());
+        let mut subscriber = subscriber(&config);
+
+        run(&config, 3, &["SET", "k", "v"]);
+        // MOVE reports the key leaving one database and entering the other
+        run(&config, 3, &["MOVE", "k", "5"]);
+        run(&config, 5, &["DEL", "k"]);
+        assert_eq!(
+            received(&mut subscriber, 8).await,
+            messages(&[
+                ("__keyspace@3__:k", "set"),
+                ("__keyevent@3__:set", "k"),
+                ("__keyspace@3__:k", "move_from"),
+                ("__keyevent@3__:move_from", "k"),
+                ("__keyspace@5__:k", "move_to"),
+                ("__keyevent@5__:move_to", "k"),
+                ("__keyspace@5__:k", "del"),
+                ("__keyevent@5__:del", "k"),
+            ])
+        );
+    }
+
+    #[test]
+    fn flags_parse_and_format_like_redis() {
+        assert_eq!(parse_flags("").unwrap(), 0);
+        assert_eq!(
+            parse_flags("Kl$").unwrap(),
+            NOTIFY_KEYSPACE | NOTIFY_LIST | NOTIFY_STRING
+        );
+        // A doesn't cover key miss and new key events
+        assert_eq!(parse_flags("A").unwrap(), NOTIFY_ALL);
+        assert_eq!(
+            parse_flags("A").unwrap() & (NOTIFY_KEY_MISS | NOTIFY_NEW),
+            0
+        );
+        assert!(parse_flags("KEQ").is_err());
+
+        assert_eq!(flags_to_string(parse_flags("Elg").unwrap()), "glE");
+        assert_eq!(flags_to_string(parse_flags("g$lshzxetKE").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("nmAK").unwrap()), "AKmn");
+        assert_eq!(flags_to_string(0), "");
+    }
+
+    #[tokio::test]
+    async fn only_enabled_classes_are_published() {
+        let config = Config::new(6379, None);
+        let mut subscriber = subscriber(&config);
+
+        // Disabled by default
+        run(&config, 0, &["SET", "k", "v"]);
+        // Only list events, and only on the keyspace channel
+        config.notifier.set_flags(parse_flags("Kl").unwrap());
+        run(&config, 0, &["SET", "k", "v"]);
+        run(&config, 0, &["RPUSH", "list", "a"]);
+        run(&config, 0, &["GET", "missing"]);
+        // Key miss and new key events once asked for, on the keyevent channel
+        config.notifier.set_flags(parse_flags("Emn$").unwrap());
+        run(&config, 0, &["SET", "k", "v"]);
+        run(&config, 0, &["SET", "fresh", "v"]);
+        run(&config, 0, &["GET", "missing"]);
+        run(&config, 0, &["RPUSH", "list", "b"]);
+        config.pubsub.publish("__keyspace@0__:end", b"end");
+
+        assert_eq!(
+            received(&mut subscriber, 6).await,
+            messages(&[
+                ("__keyspace@0__:list", "rpush"),
+                ("__keyevent@0__:set", "k"),
+                ("__keyevent@0__:new", "fresh"),
+                ("__keyevent@0__:set", "fresh"),
+                ("__keyevent@0__:keymiss", "missing"),
+                ("__keyspace@0__:end", "end"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use tokio::sync::mpsc::{Receiver, Sender, channel, error::TrySendError};
+
+use crate::{glob, resp::frame::RespFrame, slot::key_hash_slot};
+
+// Message is what the hub delivers to a subscribed connection
+pub enum Message {
+    Channel {
+        channel: String,
+        payload: Vec<u8>,
+    },
+    Pattern {
+        pattern: String,
+        channel: String,
+        payload: Vec<u8>,
+    },
+    Shard {
+        channel: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Encodes the message as a push frame for RESP3 clients, or as a plain array for RESP2 clients
+    pub fn into_frame(self, resp3: bool) -> RespFrame {
+        let items = match self {
+            Message::Channel { channel, payload } => vec![
+                RespFrame::BulkString("message".to_string()),
+                RespFrame::BulkString(channel),
+                RespFrame::BulkBytes(payload),
+            ],
+            Message::Pattern {
+                pattern,
+                channel,
+                payload,
+            } => vec![
+                RespFrame::BulkString("pmessage".to_string()),
+                RespFrame::BulkString(pattern),
+                RespFrame::BulkString(channel),
+                RespFrame::BulkBytes(payload),
+            ],
+            Message::Shard { channel, payload } => vec![
+                RespFrame::BulkString("smessage".to_string()),
+                RespFrame::BulkString(channel),
+                RespFrame::BulkBytes(payload),
+            ],
+        };
+        push_frame(items, resp3)
+    }
+}
+
+pub fn push_frame(items: Vec<RespFrame>, resp3: bool) -> RespFrame {
+    if resp3 {
+        RespFrame::Push(items)
+    } else {
+        RespFrame::Array(items)
+    }
+}
+
+// Kind is the namespace a subscription lives in. Shard channels are kept apart from
+// classic channels, so PUBLISH and SPUBLISH never reach each other's subscribers.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    fn subscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Shard => "ssubscribe",
+        }
+    }
+
+    fn unsubscribe_reply(&self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+// Messages a subscriber may have waiting before it is disconnected for falling behind, the
+// counterpart of the pubsub class of client-output-buffer-limit in Redis
+const MAX_PENDING_MESSAGES: usize = 8192;
+
+// Outbox is the hub's end of a subscriber's message queue
+#[derive(Clone)]
+struct Outbox {
+    sender: Sender<Message>,
+    // Set once the queue was found full, the connection is then closed
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    // deliver queues the message without ever waiting on a slow subscriber
+    fn deliver(&self, message: Message) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+type Subscribers = HashMap<u64, Outbox>;
+
+#[derive(Default)]
+struct Subscriptions {
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+    // Shard channels are grouped by the hash slot of the channel name, like keys are
+    shard_channels: HashMap<u16, HashMap<String, Subscribers>>,
+}
+
+impl Subscriptions {
+    fn table(&mut self, kind: Kind, name: &str) -> &mut HashMap<String, Subscribers> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => self
+                .shard_channels
+                .entry(key_hash_slot(name.as_bytes()))
+                .or_default(),
+        }
+    }
+}
+
+// PubSub is the hub every connection registers its subscriptions with.
+// It is cloned into every connection through the Config, like the other shared state.
+#[derive(Clone, Default)]
+pub struct PubSub {
+    inner: Arc<Mutex<Subscriptions>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delivers the payload to every subscriber of the channel and of the patterns matching it.
+    /// Returns the number of clients that received the message.
+    pub fn publish(&self, channel: &str, payload: &[u8]) -> usize {
+        let subscriptions = self.inner.lock().unwrap();
+        let mut receivers = 0;
+
+        if let Some(subscribers) = subscriptions.channels.get(channel) {
+            for outbox in subscribers.values() {
+                let message = Message::Channel {
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                if outbox.deliver(message) {
+                    receivers += 1;
+                }
+            }
+        }
+
+        for (pattern, subscribers) in &subscriptions.patterns {
+            if !glob::matches(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            for outbox in subscribers.values() {
+                let message = Message::Pattern {
+                    pattern: pattern.clone(),
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                if outbox.deliver(message) {
+                    receivers += 1;
+                }
+            }
+        }
+
+        receivers
+    }
+
+    /// Delivers the payload to the subscribers of the shard channel.
+    /// Patterns don't apply to shard channels.
+    pub fn spublish(&self, channel: &str, payload: &[u8]) -> usize {
+        let subscriptions = self.inner.lock().unwrap();
+        let Some(subscribers) = subscriptions
+            .shard_channels
+            .get(&key_hash_slot(channel.as_bytes()))
+            .and_then(|slot| slot.get(channel))
+        else {
+            return 0;
+        };
+
+        subscribers
+            .values()
+            .filter(|outbox| {
+                let message = Message::Shard {
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                outbox.deliver(message)
+            })
+            .count()
+    }
+
+    fn subscribe(&self, kind: Kind, client: &Subscriber, name: &str) {
+        let mut subscriptions = self.inner.lock().unwrap();
+        subscriptions
+            .table(kind, name)
+            .entry(name.to_string())
+            .or_default()
+            .insert(client.id, client.outbox.clone());
+    }
+
+    fn unsubscribe(&self, kind: Kind, client_id: u64, name: &str) {
+        let mut subscriptions = self.inner.lock().unwrap();
+        let table = subscriptions.table(kind, name);
+        if let Some(subscribers) = table.get_mut(name) {
+            subscribers.remove(&client_id);
+            if subscribers.is_empty() {
+                table.remove(name);
+            }
+        }
+        if kind == Kind::Shard {
+            let slot = key_hash_slot(name.as_bytes());
+            if subscriptions
+                .shard_channels
+                .get(&slot)
+                .is_some_and(|channels| channels.is_empty())
+            {
+                subscriptions.shard_channels.remove(&slot);
+            }
+        }
+    }
+
+    /// Returns the channels with at least one subscriber, optionally filtered by a glob pattern
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let subscriptions = self.inner.lock().unwrap();
+        filter_names(subscriptions.channels.keys(), pattern)
+    }
+
+    /// Returns the shard channels with at least one subscriber, optionally filtered by a glob pattern
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let subscriptions = self.inner.lock().unwrap();
+        filter_names(
+            subscriptions.shard_channels.values().flat_map(|c| c.keys()),
+            pattern,
+        )
+    }
+
+    /// Returns the number of subscribers of the channel, patterns are not counted
+    pub fn numsub(&self, channel: &str) -> usize {
+        let subscriptions = self.inner.lock().unwrap();
+        subscriptions.channels.get(channel).map_or(0, |s| s.len())
+    }
+
+    /// Returns the number of subscribers of the shard channel
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        let subscriptions = self.inner.lock().unwrap();
+        subscriptions
+            .shard_channels
+            .get(&key_hash_slot(channel.as_bytes()))
+            .and_then(|slot| slot.get(channel))
+            .map_or(0, |s| s.len())
+    }
+
+    /// Returns the number of unique patterns clients are subscribed to
+    pub fn numpat(&self) -> usize {
+        self.inner.lock().unwrap().patterns.len()
+    }
+}
+
+fn filter_names<'a>(names: impl Iterator<Item = &'a String>, pattern: Option<&str>) -> Vec<String> {
+    names
+        .filter(|name| pattern.is_none_or(|p| glob::matches(p.as_bytes(), name.as_bytes())))
+        .cloned()
+        .collect()
+}
+
+// Subscriber is the per connection side of pub/sub. It remembers what the connection is
+// subscribed to and owns the receiving end of the messages the hub delivers to it.
+pub struct Subscriber {
+    id: u64,
+    hub: PubSub,
+    outbox: Outbox,
+    receiver: Receiver<Message>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    shard_channels: BTreeSet<String>,
+}
+
+impl Subscriber {
+    pub fn new(id: u64, hub: PubSub) -> Self {
+        let (sender, receiver) = channel(MAX_PENDING_MESSAGES);
+        Subscriber {
+            id,
+            hub,
+            outbox: Outbox {
+                sender,
+                overflowed: Arc::new(AtomicBool::new(false)),
+            },
+            receiver,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+        }
+    }
+
+    /// A connection with at least one subscription is in subscriber mode
+    pub fn is_subscribed(&self) -> bool {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len() > 0
+    }
+
+    fn set(&mut self, kind: Kind) -> &mut BTreeSet<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    // The count in the replies covers classic channels and patterns together,
+    // while shard channels are counted on their own
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::Shard => self.shard_channels.len(),
+        }
+    }
+
+    /// Waits for the next message published to one of the subscriptions. Returns None once
+    /// messages were dropped because too many were pending, the client must then be disconnected.
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.outbox.overflowed.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.receiver.recv().await
+    }
+
+    fn reply(&self, kind: Kind, action: &str, name: Option<&str>, resp3: bool) -> RespFrame {
+        push_frame(
+            vec![
+                RespFrame::BulkString(action.to_string()),
+                match name {
+                    Some(name) => RespFrame::BulkString(name.to_string()),
+                    None => RespFrame::NullBulkString,
+                },
+                RespFrame::Integer(self.count(kind) as i64),
+            ],
+            resp3,
+        )
+    }
+
+    /// Handles (P|S)SUBSCRIBE, returning one confirmation per channel
+    pub fn subscribe(&mut self, kind: Kind, names: &[String], resp3: bool) -> Vec<RespFrame> {
+        names
+            .iter()
+            .map(|name| {
+                if self.set(kind).insert(name.clone()) {
+                    self.hub.subscribe(kind, self, name);
+                }
+                self.reply(kind, kind.subscribe_reply(), Some(name), resp3)
+            })
+            .collect()
+    }
+
+    /// Handles (P|S)UNSUBSCRIBE. Without names every subscription of that kind is dropped.
+    pub fn unsubscribe(&mut self, kind: Kind, names: &[String], resp3: bool) -> Vec<RespFrame> {
+        let names: Vec<String> = if names.is_empty() {
+            self.set(kind).iter().cloned().collect()
+        } else {
+            names.to_vec()
+        };
+
+        if names.is_empty() {
+            return vec![self.reply(kind, kind.unsubscribe_reply(), None, resp3)];
+        }
+
+        let replies = names
+            .iter()
+            .map(|name| {
+                if self.set(kind).remove(name) {
+                    self.hub.unsubscribe(kind, self.id, name);
+                }
+                self.reply(kind, kind.unsubscribe_reply(), Some(name), resp3)
+            })
+            .collect();
+        self.discard_pending();
+        replies
+    }
+
+    /// Drops every subscription, used by RESET and when the connection closes
+    pub fn clear(&mut self) {
+        for kind in [Kind::Channel, Kind::Pattern, Kind::Shard] {
+            for name in std::mem::take(self.set(kind)) {
+                self.hub.unsubscribe(kind, self.id, &name);
+            }
+        }
+        self.discard_pending();
+    }
+
+    // Messages still queued once the last subscription is gone must not leak into a later subscription
+    fn discard_pending(&mut self) {
+        if !self.is_subscribed() {
+            while self.receiver.try_recv().is_ok() {}
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    // The kind, channel and payload of the messages waiting for the subscriber
+    fn pending(subscriber: &mut Subscriber) -> Vec<(&'static str, String, String)> {
+        let mut messages = Vec::new();
+        while let Ok(message) = subscriber.receiver.try_recv() {
+            messages.push(match message {
+                Message::Channel { channel, payload } => ("message", channel, payload),
+                Message::Pattern {
+                    channel, payload, ..
+                } => ("pmessage", channel, payload),
+                Message::Shard { channel, payload } => ("smessage", channel, payload),
+            });
+        }
+        messages
+            .into_iter()
+            .map(|(kind, channel, payload)| (kind, channel, String::from_utf8(payload).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn shard_channels_are_apart_from_classic_channels() {
+        let hub = PubSub::new();
+        let mut classic = Subscriber::new(1, hub.clone());
+        let mut sharded = Subscriber::new(2, hub.clone());
+        classic.subscribe(Kind::Channel, &names(&["news"]), false);
+        classic.subscribe(Kind::Pattern, &names(&["*"]), false);
+        // The count of a shard subscription doesn't include the classic ones
+        let mut replies = sharded.subscribe(Kind::Shard, &names(&["news", "{news}.sport"]), false);
+        assert_eq!(
+            replies.pop().unwrap().encode(),
+            b"*3\r\n$10\r\nssubscribe\r\n$12\r\n{news}.sport\r\n:2\r\n"
+        );
+
+        // Neither PUBLISH nor SPUBLISH reaches the other namespace, and patterns only
+        // apply to classic channels
+        assert_eq!(hub.publish("news", b"classic"), 2);
+        assert_eq!(hub.spublish("news", b"sharded"), 1);
+        assert_eq!(hub.spublish("weather", b"nobody"), 0);
+        assert_eq!(
+            pending(&mut classic),
+            vec![
+                ("message", "news".to_string(), "classic".to_string()),
+                ("pmessage", "news".to_string(), "classic".to_string()),
+            ]
+        );
+        assert_eq!(
+            pending(&mut sharded),
+            vec![("smessage", "news".to_string(), "sharded".to_string())]
+        );
+
+        assert_eq!(hub.channels(None), names(&["news"]));
+        let mut shard_channels = hub.shard_channels(None);
+        shard_channels.sort();
+        assert_eq!(shard_channels, names(&["news", "{news}.sport"]));
+        assert_eq!(hub.shard_channels(Some("{*")), names(&["{news}.sport"]));
+        assert_eq!((hub.numsub("news"), hub.shard_numsub("news")), (1, 1));
+        assert_eq!(hub.shard_numsub("{news}.sport"), 1);
+    }
+
+    #[test]
+    fn shard_channels_are_grouped_by_slot() {
+        let hub = PubSub::new();
+        let mut subscriber = Subscriber::new(1, hub.clone());
+        // Both names hash to the slot of "news"
+        subscriber.subscribe(Kind::Shard, &names(&["news", "{news}.sport"]), false);
+        {
+            let subscriptions = hub.inner.lock().unwrap();
+            assert_eq!(subscriptions.shard_channels.len(), 1);
+            assert_eq!(
+                subscriptions.shard_channels[&key_hash_slot(b"news")].len(),
+                2
+            );
+        }
+
+        subscriber.unsubscribe(Kind::Shard, &names(&["news"]), false);
+        assert_eq!(hub.shard_channels(None), names(&["{news}.sport"]));
+        // SUNSUBSCRIBE without names leaves the classic subscriptions alone, and the slot
+        // goes away with its last channel
+        subscriber.subscribe(Kind::Channel, &names(&["news"]), false);
+        let mut replies = subscriber.unsubscribe(Kind::Shard, &[], false);
+        assert_eq!(
+            replies.remove(0).encode(),
+            b"*3\r\n$12\r\nsunsubscribe\r\n$12\r\n{news}.sport\r\n:0\r\n"
+        );
+        assert!(hub.inner.lock().unwrap().shard_channels.is_empty());
+        assert!(subscriber.is_subscribed());
+        assert_eq!(hub.spublish("{news}.sport", b"gone"), 0);
+        assert_eq!(hub.publish("news", b"still here"), 1);
+    }
+}
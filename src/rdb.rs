@@ -0,0 +1,1039 @@
+mod encodings;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    resp::commands::structs::{Data, Value},
+    zset::SortedSet,
+};
+
+// RDB format version written in dumps and snapshots
+pub const RDB_VERSION: u16 = 11;
+// Newest format version which can be loaded, the one of Redis 7.4 which adds hash field expiry
+const RDB_MAX_LOAD_VERSION: u16 = 12;
+
+// Value types
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const RDB_TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const RDB_TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const RDB_TYPE_HASH_METADATA: u8 = 24;
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// Opcodes, which share the byte announcing the type of the next key
+const RDB_OPCODE_SLOT_INFO: u8 = 244;
+pub const RDB_OPCODE_FUNCTION2: u8 = 245;
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 246;
+const RDB_OPCODE_MODULE_AUX: u8 = 247;
+const RDB_OPCODE_IDLE: u8 = 248;
+const RDB_OPCODE_FREQ: u8 = 249;
+const RDB_OPCODE_AUX: u8 = 250;
+const RDB_OPCODE_RESIZEDB: u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+const RDB_OPCODE_EXPIRETIME: u8 = 253;
+const RDB_OPCODE_SELECTDB: u8 = 254;
+const RDB_OPCODE_EOF: u8 = 255;
+
+// Special string encodings, flagged by the two top bits of the length being set
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+// Quicklist nodes hold either a single element or a listpack of them
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+
+// Module values are a sequence of typed fields ending with EOF
+const RDB_MODULE_OPCODE_EOF: u64 = 0;
+const RDB_MODULE_OPCODE_SINT: u64 = 1;
+const RDB_MODULE_OPCODE_UINT: u64 = 2;
+const RDB_MODULE_OPCODE_FLOAT: u64 = 3;
+const RDB_MODULE_OPCODE_DOUBLE: u64 = 4;
+const RDB_MODULE_OPCODE_STRING: u64 = 5;
+
+// Lookup table for CRC-64/Jones (reflected, poly 0xad93d23594c935a9), the checksum Redis
+// appends to RDB files and DUMP payloads
+const CRC64_TABLE: [u64; 256] = {
+    const POLY: u64 = 0x95ac9329ac4bc9b5; // 0xad93d23594c935a9 bit reversed
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues the CRC64 of the bytes before `data`, start from 0
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+pub fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    write_length(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+// Reader walks an RDB encoded buffer
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(Truncated)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Reads a length, returning whether it is a special string encoding instead
+    pub fn read_length_with_encoding(&mut self) -> Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => Ok((
+                (((first & 0x3f) as u64) << 8) | self.read_u8()? as u64,
+                false,
+            )),
+            2 => match first {
+                0x80 => {
+                    let bytes = self.read_bytes(4)?;
+                    Ok((u32::from_be_bytes(bytes.try_into()?) as u64, false))
+                }
+                0x81 => {
+                    let bytes = self.read_bytes(8)?;
+                    Ok((u64::from_be_bytes(bytes.try_into()?), false))
+                }
+                _ => Err(anyhow!("unknown RDB length encoding {:#x}", first)),
+            },
+            _ => Ok(((first & 0x3f) as u64, true)),
+        }
+    }
+
+    pub fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_with_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(anyhow!("unexpected encoded string in place of a length")),
+        }
+    }
+
+    pub fn read_string(&mut self) -> Result<Vec<u8>> {
+        let (len, encoded) = self.read_length_with_encoding()?;
+        if !encoded {
+            return Ok(self.read_bytes(len as usize)?.to_vec());
+        }
+
+        let value = match len as u8 {
+            RDB_ENC_INT8 => self.read_u8()? as i8 as i64,
+            RDB_ENC_INT16 => i16::from_le_bytes(self.read_bytes(2)?.try_into()?) as i64,
+            RDB_ENC_INT32 => i32::from_le_bytes(self.read_bytes(4)?.try_into()?) as i64,
+            RDB_ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                return encodings::lzf_decompress(compressed, len);
+            }
+            encoding => return Err(anyhow!("unknown RDB string encoding {}", encoding)),
+        };
+        Ok(value.to_string().into_bytes())
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into()?))
+    }
+
+    // Doubles of the old ZSET type are stored as text, with special lengths for NaN and infinities
+    fn read_double_string(&mut self) -> Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => std::str::from_utf8(self.read_bytes(len as usize)?)?
+                .parse()
+                .map_err(|_| anyhow!("invalid double value")),
+        }
+    }
+
+    // Keys and members are kept as text, binary ones are refused rather than altered
+    fn read_text(&mut self) -> Result<String> {
+        String::from_utf8(self.read_string()?)
+            .map_err(|_| anyhow!("binary keys and members aren't supported, found invalid UTF-8"))
+    }
+
+    // Skips a stream value, laid out as rdbLoadObject reads it for the given type
+    fn skip_stream(&mut self, kind: u8) -> Result<()> {
+        // Listpacks of entries along with the ID of their master entry
+        for _ in 0..self.read_length()? {
+            self.read_string()?;
+            self.read_string()?;
+        }
+        // Length and last ID, then the first ID, max deleted ID and entries added
+        let fields = if kind == RDB_TYPE_STREAM_LISTPACKS {
+            3
+        } else {
+            8
+        };
+        for _ in 0..fields {
+            self.read_length()?;
+        }
+        for _ in 0..self.read_length()? {
+            // Consumer group name, last delivered ID and entries read
+            self.read_string()?;
+            let fields = if kind == RDB_TYPE_STREAM_LISTPACKS {
+                2
+            } else {
+                3
+            };
+            for _ in 0..fields {
+                self.read_length()?;
+            }
+            // Pending entries: raw ID, delivery time and delivery count
+            for _ in 0..self.read_length()? {
+                self.read_bytes(16)?;
+                self.read_u64()?;
+                self.read_length()?;
+            }
+            for _ in 0..self.read_length()? {
+                // Consumer name, seen time, active time and the raw IDs of its pending entries
+                self.read_string()?;
+                self.read_u64()?;
+                if kind == RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_u64()?;
+                }
+                for _ in 0..self.read_length()? {
+                    self.read_bytes(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Skips the fields a module saved for a key or as aux data
+    fn skip_module_value(&mut self) -> Result<()> {
+        loop {
+            match self.read_length()? {
+                RDB_MODULE_OPCODE_EOF => return Ok(()),
+                RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => {
+                    self.read_length()?;
+                }
+                RDB_MODULE_OPCODE_FLOAT => {
+                    self.read_bytes(4)?;
+                }
+                RDB_MODULE_OPCODE_DOUBLE => {
+                    self.read_bytes(8)?;
+                }
+                RDB_MODULE_OPCODE_STRING => {
+                    self.read_string()?;
+                }
+                opcode => return Err(anyhow!("unknown module value opcode {}", opcode)),
+            }
+        }
+    }
+}
+
+/// Appends the RDB version and checksum trailer DUMP payloads end with
+pub fn seal_payload(mut payload: Vec<u8>) -> Vec<u8> {
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Checks the trailer of a DUMP payload, returning the data before it
+pub fn open_payload(payload: &[u8]) -> Result<&[u8]> {
+    if payload.len() < 10 {
+        return Err(anyhow!("ERR DUMP payload version or checksum are wrong"));
+    }
+    let (data, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into()?);
+    // A zero checksum means the producer didn't compute one
+    if version > RDB_VERSION || (crc != 0 && crc != crc64(0, &payload[..payload.len() - 8])) {
+        return Err(anyhow!("ERR DUMP payload version or checksum are wrong"));
+    }
+    Ok(data)
+}
+
+// Snapshot is what an RDB file holds: the keys of every database along with their values
+// and expiry, and the code of the function libraries
+#[derive(Default)]
+pub struct Snapshot {
+    // entries are (database index, key, data)
+    pub entries: Vec<(usize, String, Data)>,
+    pub libraries: Vec<String>,
+}
+
+/// Serializes a snapshot into a complete RDB file. Expiry times are stored as absolute
+/// unix timestamps in milliseconds, which is why keys already expired are left out.
+pub fn encode(snapshot: &Snapshot, redis_version: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+
+    let ctime = unix_millis(SystemTime::now()) / 1000;
+    for (key, value) in [
+        ("redis-ver", redis_version.to_string()),
+        ("redis-bits", usize::BITS.to_string()),
+        ("ctime", ctime.to_string()),
+        ("used-mem", "0".to_string()),
+        ("aof-base", "0".to_string()),
+    ] {
+        out.push(RDB_OPCODE_AUX);
+        write_string(&mut out, key.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+
+    for code in &snapshot.libraries {
+        out.push(RDB_OPCODE_FUNCTION2);
+        write_string(&mut out, code.as_bytes());
+    }
+
+    let now = Instant::now();
+    let now_ms = unix_millis(SystemTime::now());
+    let mut live: Vec<&(usize, String, Data)> = snapshot
+        .entries
+        .iter()
+        .filter(|(_, _, data)| !data.expired())
+        .collect();
+    live.sort_by_key(|(db, _, _)| *db);
+
+    for (i, (db, key, data)) in live.iter().enumerate() {
+        // Each database starts with its index and sizes
+        if i == 0 || live[i - 1].0 != *db {
+            let keys: Vec<_> = live.iter().filter(|(other, _, _)| other == db).collect();
+            let expires = keys
+                .iter()
+                .filter(|(_, _, data)| data.expires_at.is_some())
+                .count();
+            out.push(RDB_OPCODE_SELECTDB);
+            write_length(&mut out, *db as u64);
+            out.push(RDB_OPCODE_RESIZEDB);
+            write_length(&mut out, keys.len() as u64);
+            write_length(&mut out, expires as u64);
+        }
+
+        if let Some(expires_at) = data.expires_at {
+            let at = now_ms + expires_at.saturating_duration_since(now).as_millis() as u64;
+            out.push(RDB_OPCODE_EXPIRETIME_MS);
+            out.extend_from_slice(&at.to_le_bytes());
+        }
+        out.push(value_type(&data.value));
+        write_string(&mut out, key.as_bytes());
+        write_value(&mut out, &data.value);
+    }
+
+    out.push(RDB_OPCODE_EOF);
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+// The RDB type of a value, written before its key
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => RDB_TYPE_STRING,
+        Value::List(_) => RDB_TYPE_LIST,
+        Value::SortedSet(_) => RDB_TYPE_ZSET_2,
+        Value::Set(_) => RDB_TYPE_SET,
+        Value::Hash(_) => RDB_TYPE_HASH,
+    }
+}
+
+// Writes a value in the encoding of its RDB type
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(value) => write_string(out, value),
+        Value::List(items) => {
+            write_length(out, items.len() as u64);
+            for item in items {
+                write_string(out, item.as_bytes());
+            }
+        }
+        Value::SortedSet(zset) => {
+            write_length(out, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                write_string(out, member.as_bytes());
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Set(members) => {
+            write_length(out, members.len() as u64);
+            for member in members {
+                write_string(out, member.as_bytes());
+            }
+        }
+        Value::Hash(fields) => {
+            write_length(out, fields.len() as u64);
+            for (field, value) in fields {
+                write_string(out, field.as_bytes());
+                write_string(out, value.as_bytes());
+            }
+        }
+    }
+}
+
+/// Serializes a value the way DUMP does: its RDB type and encoding, then the trailer
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let mut payload = vec![value_type(value)];
+    write_value(&mut payload, value);
+    seal_payload(payload)
+}
+
+/// Parses a DUMP payload back into the value of `key`
+pub fn restore_value(payload: &[u8], key: &str) -> Result<Value> {
+    let mut reader = Reader::new(open_payload(payload)?);
+    let kind = reader.read_u8()?;
+    let value = read_value(&mut reader, kind, key, unix_millis(SystemTime::now()))
+        .map_err(|_| anyhow!("ERR Bad data format"))?;
+    if !reader.is_empty() {
+        return Err(anyhow!("ERR Bad data format"));
+    }
+    Ok(value)
+}
+
+/// Parses an RDB file, keys which expired in the meantime are dropped. Every encoding
+/// Redis writes can be read, up to the hash field expiry types of Redis 7.4.
+pub fn decode(data: &[u8]) -> Result<Snapshot> {
+    decode_prefix(data).map(|(snapshot, _)| snapshot)
+}
+
+/// Parses an RDB file found at the start of the data, returning it along with its length.
+/// AOF files begin with one when they were written with an RDB preamble.
+pub fn decode_prefix(data: &[u8]) -> Result<(Snapshot, usize)> {
+    let mut decoder = Decoder::new();
+    match decoder.feed(data)? {
+        (len, Some(snapshot)) => Ok((snapshot, len)),
+        (_, None) => Err(Truncated.into()),
+    }
+}
+
+// Truncated is the error of a read past the end of the data, which for a file arriving
+// piece by piece only means the rest has to be waited for
+#[derive(Debug)]
+struct Truncated;
+
+impl Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected end of RDB data")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+// Record is what reading the next record of an RDB file ended with
+enum Record {
+    More,
+    // End holds the length of the EOF opcode and checksum
+    End(usize),
+}
+
+/// Decoder parses an RDB file record by record as it arrives, so a file read from a socket
+/// is never held whole: only the records not yet complete are kept by the caller.
+pub struct Decoder {
+    version: Option<u16>,
+    snapshot: Snapshot,
+    db: usize,
+    expires_at_ms: Option<u64>,
+    // crc covers every byte consumed so far
+    crc: u64,
+    now: Instant,
+    now_ms: u64,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            version: None,
+            snapshot: Snapshot::default(),
+            db: 0,
+            expires_at_ms: None,
+            crc: 0,
+            now: Instant::now(),
+            now_ms: unix_millis(SystemTime::now()),
+        }
+    }
+
+    /// Parses the complete records at the start of the data, returning how many bytes they
+    /// took, and the snapshot once the end of the file was reached. The bytes left over have
+    /// to be given again, followed by more.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(usize, Option<Snapshot>)> {
+        let mut consumed = 0;
+        if self.version.is_none() {
+            if data.len() < 9 {
+                // Only a signature can be refused before the whole header arrived
+                if !b"REDIS".starts_with(&data[..data.len().min(5)]) {
+                    return Err(anyhow!("wrong signature, not an RDB file"));
+                }
+                return Ok((0, None));
+            }
+            self.version = Some(read_header(data)?);
+            self.crc = crc64(0, &data[..9]);
+            consumed = 9;
+        }
+
+        loop {
+            let mut reader = Reader::new(&data[consumed..]);
+            match self.read_record(&mut reader) {
+                Ok(Record::More) => {
+                    self.crc = crc64(self.crc, &data[consumed..consumed + reader.pos]);
+                    consumed += reader.pos;
+                }
+                Ok(Record::End(len)) => {
+                    let snapshot = std::mem::take(&mut self.snapshot);
+                    return Ok((consumed + len, Some(snapshot)));
+                }
+                Err(err) if err.is::<Truncated>() => return Ok((consumed, None)),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Reads one record. Decoder state only changes once the record was read whole, so one
+    // cut short can be read again from its start.
+    fn read_record(&mut self, reader: &mut Reader) -> Result<Record> {
+        let kind = reader.read_u8()?;
+        match kind {
+            RDB_OPCODE_EOF => {
+                // Version 5 introduced the checksum, a zero one means it was disabled when saving
+                if self.version.is_some_and(|version| version >= 5) {
+                    let crc_start = reader.pos;
+                    let crc = reader.read_u64()?;
+                    let expected = crc64(self.crc, &reader.data[..crc_start]);
+                    if crc != 0 && crc != expected {
+                        return Err(anyhow!("wrong RDB checksum"));
+                    }
+                }
+                return Ok(Record::End(reader.pos));
+            }
+            RDB_OPCODE_AUX => {
+                reader.read_string()?;
+                reader.read_string()?;
+            }
+            RDB_OPCODE_SELECTDB => {
+                self.db = reader.read_length()? as usize;
+            }
+            RDB_OPCODE_RESIZEDB => {
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            RDB_OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.read_length()?;
+                }
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                self.expires_at_ms = Some(reader.read_u64()?);
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?);
+                self.expires_at_ms = Some(secs as u64 * 1000);
+            }
+            // Eviction hints for the next key, there is no eviction here
+            RDB_OPCODE_IDLE => {
+                reader.read_length()?;
+            }
+            RDB_OPCODE_FREQ => {
+                reader.read_u8()?;
+            }
+            RDB_OPCODE_FUNCTION2 => {
+                let code = String::from_utf8(reader.read_string()?)
+                    .map_err(|_| anyhow!("function library code isn't valid UTF-8"))?;
+                self.snapshot.libraries.push(code);
+            }
+            RDB_OPCODE_FUNCTION_PRE_GA => {
+                return Err(anyhow!("pre-release function format not supported"));
+            }
+            // Data modules keep outside of keys, skipped as no module is loaded
+            RDB_OPCODE_MODULE_AUX => {
+                reader.read_length()?;
+                if reader.read_length()? != RDB_MODULE_OPCODE_UINT {
+                    return Err(anyhow!("invalid module aux data"));
+                }
+                reader.read_length()?;
+                reader.skip_module_value()?;
+            }
+            // Streams have no counterpart here, the key is dropped rather than failing the load
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                let key = reader.read_string()?;
+                reader.skip_stream(kind)?;
+                self.expires_at_ms = None;
+                eprintln!(
+                    "Skipping key '{}' of the RDB file, streams aren't supported",
+                    String::from_utf8_lossy(&key)
+                );
+            }
+            kind => {
+                let key = reader.read_text()?;
+                let value = read_value(reader, kind, &key, self.now_ms)?;
+                let expires_at = match self.expires_at_ms.take() {
+                    Some(at) if at <= self.now_ms => return Ok(Record::More),
+                    Some(at) => Some(self.now + Duration::from_millis(at - self.now_ms)),
+                    None => None,
+                };
+                self.snapshot
+                    .entries
+                    .push((self.db, key, Data { value, expires_at }));
+            }
+        }
+        Ok(Record::More)
+    }
+}
+
+// Checks the signature and returns the format version
+fn read_header(data: &[u8]) -> Result<u16> {
+    if data.len() < 9 || &data[..5] != b"REDIS" {
+        return Err(anyhow!("wrong signature, not an RDB file"));
+    }
+    let version: u16 = std::str::from_utf8(&data[5..9])?
+        .parse()
+        .map_err(|_| anyhow!("invalid RDB version"))?;
+    if version == 0 || version > RDB_MAX_LOAD_VERSION {
+        return Err(anyhow!("can't handle RDB format version {}", version));
+    }
+    Ok(version)
+}
+
+fn read_value(reader: &mut Reader, kind: u8, key: &str, now_ms: u64) -> Result<Value> {
+    let text = |entries: Vec<Vec<u8>>| -> Result<Vec<String>> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                String::from_utf8(entry).map_err(|_| {
+                    anyhow!("key '{}' holds binary members, which aren't supported", key)
+                })
+            })
+            .collect()
+    };
+
+    let value = match kind {
+        RDB_TYPE_STRING => Value::String(reader.read_string()?),
+        RDB_TYPE_LIST | RDB_TYPE_SET => {
+            let len = reader.read_length()?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(reader.read_text()?);
+            }
+            if kind == RDB_TYPE_LIST {
+                Value::List(items)
+            } else {
+                Value::Set(items.into_iter().collect())
+            }
+        }
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+            let len = reader.read_length()?;
+            let mut zset = SortedSet::new();
+            for _ in 0..len {
+                let member = reader.read_text()?;
+                let score = if kind == RDB_TYPE_ZSET {
+                    reader.read_double_string()?
+                } else {
+                    f64::from_le_bytes(reader.read_bytes(8)?.try_into()?)
+                };
+                zset.insert(member, score);
+            }
+            Value::SortedSet(zset)
+        }
+        RDB_TYPE_HASH => {
+            let len = reader.read_length()?;
+            let mut fields = HashMap::new();
+            for _ in 0..len {
+                fields.insert(reader.read_text()?, reader.read_text()?);
+            }
+            Value::Hash(fields)
+        }
+        RDB_TYPE_LIST_ZIPLIST => {
+            Value::List(text(encodings::ziplist_entries(&reader.read_string()?)?)?)
+        }
+        RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+            let nodes = reader.read_length()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                if kind == RDB_TYPE_LIST_QUICKLIST {
+                    items.extend(encodings::ziplist_entries(&reader.read_string()?)?);
+                    continue;
+                }
+                let container = reader.read_length()?;
+                let node = reader.read_string()?;
+                if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                    items.push(node);
+                } else {
+                    items.extend(encodings::listpack_entries(&node)?);
+                }
+            }
+            Value::List(text(items)?)
+        }
+        RDB_TYPE_SET_INTSET => Value::Set(
+            text(encodings::intset_entries(&reader.read_string()?)?)?
+                .into_iter()
+                .collect(),
+        ),
+        RDB_TYPE_SET_LISTPACK => Value::Set(
+            text(encodings::listpack_entries(&reader.read_string()?)?)?
+                .into_iter()
+                .collect::<HashSet<_>>(),
+        ),
+        RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+            let blob = reader.read_string()?;
+            let entries = if kind == RDB_TYPE_ZSET_ZIPLIST {
+                encodings::ziplist_entries(&blob)?
+            } else {
+                encodings::listpack_entries(&blob)?
+            };
+            let mut zset = SortedSet::new();
+            for pair in text(entries)?.chunks(2) {
+                let [member, score] = pair else {
+                    return Err(anyhow!("sorted set of key '{}' is missing a score", key));
+                };
+                let score = score
+                    .parse()
+                    .map_err(|_| anyhow!("invalid score in sorted set of key '{}'", key))?;
+                zset.insert(member.clone(), score);
+            }
+            Value::SortedSet(zset)
+        }
+        RDB_TYPE_HASH_ZIPMAP | RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+            let blob = reader.read_string()?;
+            let entries = match kind {
+                RDB_TYPE_HASH_ZIPMAP => encodings::zipmap_entries(&blob)?,
+                RDB_TYPE_HASH_ZIPLIST => encodings::ziplist_entries(&blob)?,
+                _ => encodings::listpack_entries(&blob)?,
+            };
+            Value::Hash(pairs(text(entries)?, key)?)
+        }
+        // Hashes with field expiry keep their fields, those already expired are dropped and
+        // the others lose their TTL as fields can't expire here
+        RDB_TYPE_HASH_METADATA | RDB_TYPE_HASH_METADATA_PRE_GA => {
+            let min_expire = if kind == RDB_TYPE_HASH_METADATA {
+                reader.read_u64()?
+            } else {
+                0
+            };
+            let len = reader.read_length()?;
+            let mut fields = HashMap::new();
+            for _ in 0..len {
+                let mut ttl = reader.read_length()?;
+                if kind == RDB_TYPE_HASH_METADATA && ttl != 0 {
+                    ttl = ttl + min_expire - 1;
+                }
+                let (field, value) = (reader.read_text()?, reader.read_text()?);
+                if ttl == 0 || ttl > now_ms {
+                    fields.insert(field, value);
+                }
+            }
+            Value::Hash(fields)
+        }
+        RDB_TYPE_HASH_LISTPACK_EX | RDB_TYPE_HASH_LISTPACK_EX_PRE_GA => {
+            if kind == RDB_TYPE_HASH_LISTPACK_EX {
+                reader.read_u64()?;
+            }
+            let entries = text(encodings::listpack_entries(&reader.read_string()?)?)?;
+            let mut fields = HashMap::new();
+            for triplet in entries.chunks(3) {
+                let [field, value, ttl] = triplet else {
+                    return Err(anyhow!("hash of key '{}' is missing a field TTL", key));
+                };
+                let ttl: u64 = ttl.parse().unwrap_or_default();
+                if ttl == 0 || ttl > now_ms {
+                    fields.insert(field.clone(), value.clone());
+                }
+            }
+            Value::Hash(fields)
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            return Err(anyhow!(
+                "key '{}' holds a stream, which isn't supported",
+                key
+            ));
+        }
+        RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => {
+            let id = reader.read_length()?;
+            return Err(anyhow!(
+                "key '{}' holds a value of module type '{}', modules aren't supported",
+                key,
+                module_type_name(id)
+            ));
+        }
+        kind => {
+            return Err(anyhow!("key '{}' has unknown RDB value type {}", key, kind));
+        }
+    };
+    Ok(value)
+}
+
+// Pairs up the fields and values of a hash blob
+fn pairs(entries: Vec<String>, key: &str) -> Result<HashMap<String, String>> {
+    entries
+        .chunks(2)
+        .map(|pair| match pair {
+            [field, value] => Ok((field.clone(), value.clone())),
+            _ => Err(anyhow!("hash of key '{}' is missing a value", key)),
+        })
+        .collect()
+}
+
+// Module type ids hold the 9 character type name in their upper 54 bits
+fn module_type_name(id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..9)
+        .map(|i| CHARSET[((id >> (64 - 6 * (i + 1))) & 63) as usize] as char)
+        .collect()
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A comparable rendering of a value, with the unordered collections sorted
+    fn describe(value: &Value) -> String {
+        match value {
+            Value::String(s) => format!("string {:?}", s),
+            Value::List(items) => format!("list {:?}", items),
+            Value::Set(members) => {
+                let mut members: Vec<_> = members.iter().collect();
+                members.sort();
+                format!("set {:?}", members)
+            }
+            Value::Hash(fields) => {
+                let mut fields: Vec<_> = fields.iter().collect();
+                fields.sort();
+                format!("hash {:?}", fields)
+            }
+            Value::SortedSet(zset) => format!("zset {:?}", zset.iter().collect::<Vec<_>>()),
+        }
+    }
+
+    fn rdb(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(RDB_OPCODE_EOF);
+        // A zero checksum is accepted as disabled
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), 1.5);
+        zset.insert("b".to_string(), -2.0);
+        let values = [
+            Value::String(b"\x00binary\xff".to_vec()),
+            Value::List(vec!["x".to_string(), "y".to_string(), "x".to_string()]),
+            Value::Set(["1", "two"].into_iter().map(String::from).collect()),
+            Value::Hash([("f".to_string(), "v".to_string())].into_iter().collect()),
+            Value::SortedSet(zset),
+        ];
+        let in_an_hour = Instant::now() + Duration::from_secs(3600);
+        let snapshot = Snapshot {
+            entries: values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let expires_at = (i == 0).then_some(in_an_hour);
+                    (i % 2, format!("key{}", i), Data { value, expires_at })
+                })
+                .collect(),
+            libraries: vec!["#!lua name=lib\nredis.register_function('f', function() end)".into()],
+        };
+
+        let mut decoded = decode(&encode(&snapshot, "7.4.0")).unwrap();
+        // Keys are written database by database
+        decoded.entries.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(decoded.libraries, snapshot.libraries);
+        assert_eq!(decoded.entries.len(), snapshot.entries.len());
+        for (expected, got) in snapshot.entries.iter().zip(&decoded.entries) {
+            assert_eq!((expected.0, &expected.1), (got.0, &got.1));
+            assert_eq!(describe(&expected.2.value), describe(&got.2.value));
+            assert_eq!(expected.2.expires_at.is_some(), got.2.expires_at.is_some());
+        }
+        let ttl = decoded.entries[0].2.expires_at.unwrap() - Instant::now();
+        assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn dump_payload_round_trips() {
+        let value = Value::List(vec!["a".to_string(), "b".to_string()]);
+        let payload = dump_value(&value);
+        let restored = restore_value(&payload, "key").unwrap();
+        assert_eq!(describe(&restored), describe(&value));
+
+        let mut corrupted = payload.clone();
+        corrupted[2] ^= 1;
+        assert!(restore_value(&corrupted, "key").is_err());
+    }
+
+    #[test]
+    fn reads_redis_encodings() {
+        let mut body = vec![RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB, 2, 0];
+        body.extend_from_slice(&[RDB_TYPE_STRING, 3, b'f', b'o', b'o', 3, b'b', b'a', b'r']);
+        // An intset of int16 values 1 and 2
+        body.extend_from_slice(&[RDB_TYPE_SET_INTSET, 1, b's', 12]);
+        body.extend_from_slice(&[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 2, 0]);
+        // An integer encoded string
+        body.extend_from_slice(&[RDB_TYPE_STRING, 1, b'n', 0xc0, 42]);
+
+        let snapshot = decode(&rdb(&body)).unwrap();
+        let found: Vec<_> = snapshot
+            .entries
+            .iter()
+            .map(|(_, key, data)| format!("{} {}", key, describe(&data.value)))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "foo string [98, 97, 114]",
+                "s set [\"1\", \"2\"]",
+                "n string [52, 50]"
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_piece_by_piece() {
+        let snapshot = Snapshot {
+            entries: (0..50)
+                .map(|i| {
+                    let value = Value::String(vec![b'x'; i * 10]);
+                    let data = Data {
+                        value,
+                        expires_at: None,
+                    };
+                    (0, format!("key{}", i), data)
+                })
+                .collect(),
+            libraries: Vec::new(),
+        };
+        let data = encode(&snapshot, "7.4.0");
+
+        // Fed 7 bytes at a time, with the leftovers given again like a socket reader does
+        let mut decoder = Decoder::new();
+        let mut pending = Vec::new();
+        let mut decoded = None;
+        for chunk in data.chunks(7) {
+            pending.extend_from_slice(chunk);
+            let (used, done) = decoder.feed(&pending).unwrap();
+            pending.drain(..used);
+            if done.is_some() {
+                decoded = done;
+            }
+        }
+        assert!(pending.is_empty());
+        assert_eq!(decoded.unwrap().entries.len(), 50);
+
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(decode(&corrupted).is_err());
+        assert!(decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn skips_streams() {
+        let mut body = vec![RDB_TYPE_STREAM_LISTPACKS_3, 1, b'x'];
+        // One listpack, length 1, last ID 1-1, first ID, max deleted ID and entries added
+        body.extend_from_slice(&[1, 1, 0, 1, 0, 1, 1, 1, 1, 0, 0, 0, 1]);
+        // One group "g" with last ID 1-1, 1 entry read and one pending entry
+        body.extend_from_slice(&[1, 1, b'g', 1, 1, 1, 1]);
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&[0; 8]);
+        body.push(1);
+        // One consumer "c" with its seen and active times and the same pending entry
+        body.extend_from_slice(&[1, 1, b'c']);
+        body.extend_from_slice(&[0; 16]);
+        body.push(1);
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 1, b'k', 1, b'v']);
+
+        let snapshot = decode(&rdb(&body)).unwrap();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].1, "k");
+    }
+
+    #[test]
+    fn refuses_binary_keys_and_members() {
+        let key = rdb(&[RDB_TYPE_STRING, 1, 0xff, 1, b'v']);
+        assert!(decode(&key).is_err());
+
+        let member = rdb(&[RDB_TYPE_SET, 1, b's', 1, 2, 0xc3, 0x28]);
+        let err = decode(&member).err().unwrap().to_string();
+        assert!(err.contains("binary"), "{}", err);
+    }
+}
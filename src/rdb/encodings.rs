@@ -0,0 +1,212 @@
+// Decoders for the compact blobs Redis stores small collections in. Each blob is read from
+// the RDB file as a single string and holds its elements back to back.
+
+use anyhow::{Result, anyhow};
+
+/// Decompresses an LZF compressed string of `len` bytes
+pub fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let corrupt = || anyhow!("corrupt LZF compressed string");
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 1 << 5 {
+            // Literal run of ctrl + 1 bytes
+            let run = input.get(ip..ip + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+            continue;
+        }
+
+        // Back reference, the copy may overlap the bytes it produces
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(ip).ok_or_else(corrupt)? as usize;
+            ip += 1;
+        }
+        let offset = ((ctrl & 0x1f) << 8) + *input.get(ip).ok_or_else(corrupt)? as usize + 1;
+        ip += 1;
+        let start = out.len().checked_sub(offset).ok_or_else(corrupt)?;
+        for i in 0..run + 2 {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+/// Returns the entries of a ziplist
+pub fn ziplist_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let corrupt = || anyhow!("corrupt ziplist");
+    let count = u16::from_le_bytes(blob.get(8..10).ok_or_else(corrupt)?.try_into()?);
+    let mut pos = 10;
+    let mut entries = Vec::with_capacity(count as usize);
+    let byte = |pos: usize| blob.get(pos).copied().ok_or_else(corrupt);
+    let bytes = |pos: usize, len: usize| blob.get(pos..pos + len).ok_or_else(corrupt);
+
+    while byte(pos)? != 0xff {
+        // The length of the previous entry takes one byte, or five when it is 254 or more
+        pos += if byte(pos)? == 0xfe { 5 } else { 1 };
+
+        let encoding = byte(pos)?;
+        let entry = match encoding >> 6 {
+            0 => {
+                let len = (encoding & 0x3f) as usize;
+                pos += 1;
+                bytes(pos, len)?.to_vec()
+            }
+            1 => {
+                let len = (((encoding & 0x3f) as usize) << 8) | byte(pos + 1)? as usize;
+                pos += 2;
+                bytes(pos, len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(bytes(pos + 1, 4)?.try_into()?) as usize;
+                pos += 5;
+                bytes(pos, len)?.to_vec()
+            }
+            _ => {
+                pos += 1;
+                let (value, len) = match encoding {
+                    0xc0 => (i16::from_le_bytes(bytes(pos, 2)?.try_into()?) as i64, 2),
+                    0xd0 => (i32::from_le_bytes(bytes(pos, 4)?.try_into()?) as i64, 4),
+                    0xe0 => (i64::from_le_bytes(bytes(pos, 8)?.try_into()?), 8),
+                    0xf0 => {
+                        let b = bytes(pos, 3)?;
+                        (i32::from_le_bytes([0, b[0], b[1], b[2]]) as i64 >> 8, 3)
+                    }
+                    0xfe => (byte(pos)? as i8 as i64, 1),
+                    0xf1..=0xfd => ((encoding & 0x0f) as i64 - 1, 0),
+                    _ => return Err(anyhow!("unknown ziplist entry encoding {:#x}", encoding)),
+                };
+                pos += len;
+                entries.push(value.to_string().into_bytes());
+                continue;
+            }
+        };
+        pos += entry.len();
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Returns the entries of a listpack
+pub fn listpack_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let corrupt = || anyhow!("corrupt listpack");
+    let count = u16::from_le_bytes(blob.get(4..6).ok_or_else(corrupt)?.try_into()?);
+    let mut pos = 6;
+    let mut entries = Vec::with_capacity(count as usize);
+    let byte = |pos: usize| blob.get(pos).copied().ok_or_else(corrupt);
+    let bytes = |pos: usize, len: usize| blob.get(pos..pos + len).ok_or_else(corrupt);
+
+    loop {
+        let encoding = byte(pos)?;
+        if encoding == 0xff {
+            break;
+        }
+
+        // (entry size, payload), integers are signed and stored little endian
+        let (size, entry) = if encoding & 0x80 == 0 {
+            (1, (encoding as i64).to_string().into_bytes())
+        } else if encoding & 0xc0 == 0x80 {
+            let len = (encoding & 0x3f) as usize;
+            (1 + len, bytes(pos + 1, len)?.to_vec())
+        } else if encoding & 0xe0 == 0xc0 {
+            let raw = (((encoding & 0x1f) as i64) << 8) | byte(pos + 1)? as i64;
+            let value = if raw >= 1 << 12 { raw - (1 << 13) } else { raw };
+            (2, value.to_string().into_bytes())
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = (((encoding & 0x0f) as usize) << 8) | byte(pos + 1)? as usize;
+            (2 + len, bytes(pos + 2, len)?.to_vec())
+        } else if encoding == 0xf0 {
+            let len = u32::from_le_bytes(bytes(pos + 1, 4)?.try_into()?) as usize;
+            (5 + len, bytes(pos + 5, len)?.to_vec())
+        } else {
+            let (size, value) = match encoding {
+                0xf1 => (3, i16::from_le_bytes(bytes(pos + 1, 2)?.try_into()?) as i64),
+                0xf2 => {
+                    let b = bytes(pos + 1, 3)?;
+                    (4, i32::from_le_bytes([0, b[0], b[1], b[2]]) as i64 >> 8)
+                }
+                0xf3 => (5, i32::from_le_bytes(bytes(pos + 1, 4)?.try_into()?) as i64),
+                0xf4 => (9, i64::from_le_bytes(bytes(pos + 1, 8)?.try_into()?)),
+                _ => return Err(anyhow!("unknown listpack entry encoding {:#x}", encoding)),
+            };
+            (size, value.to_string().into_bytes())
+        };
+        pos += size + backlen_size(size);
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Each listpack entry ends with its own size, stored in 7 bit groups
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..128 => 1,
+        128..16384 => 2,
+        16384..2097152 => 3,
+        2097152..268435456 => 4,
+        _ => 5,
+    }
+}
+
+/// Returns the members of an intset
+pub fn intset_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let corrupt = || anyhow!("corrupt intset");
+    let width = u32::from_le_bytes(blob.get(0..4).ok_or_else(corrupt)?.try_into()?) as usize;
+    let count = u32::from_le_bytes(blob.get(4..8).ok_or_else(corrupt)?.try_into()?) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(anyhow!("unknown intset encoding {}", width));
+    }
+
+    let values = blob.get(8..8 + width * count).ok_or_else(corrupt)?;
+    Ok(values
+        .chunks(width)
+        .map(|value| {
+            let value = match width {
+                2 => i16::from_le_bytes([value[0], value[1]]) as i64,
+                4 => i32::from_le_bytes([value[0], value[1], value[2], value[3]]) as i64,
+                _ => i64::from_le_bytes(value.try_into().unwrap_or_default()),
+            };
+            value.to_string().into_bytes()
+        })
+        .collect())
+}
+
+/// Returns the fields and values of a zipmap, one after the other
+pub fn zipmap_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let corrupt = || anyhow!("corrupt zipmap");
+    let byte = |pos: usize| blob.get(pos).copied().ok_or_else(corrupt);
+    let mut pos = 1;
+    let mut entries = Vec::new();
+
+    while byte(pos)? != 0xff {
+        for is_value in [false, true] {
+            let len = match byte(pos)? {
+                254 => {
+                    let len = blob.get(pos + 1..pos + 5).ok_or_else(corrupt)?;
+                    pos += 5;
+                    u32::from_le_bytes(len.try_into()?) as usize
+                }
+                len => {
+                    pos += 1;
+                    len as usize
+                }
+            };
+            // Values are followed by unused bytes, their count is stored ahead of the value
+            let free = if is_value {
+                pos += 1;
+                byte(pos - 1)? as usize
+            } else {
+                0
+            };
+            entries.push(blob.get(pos..pos + len).ok_or_else(corrupt)?.to_vec());
+            pos += len + free;
+        }
+    }
+    Ok(entries)
+}
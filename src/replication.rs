@@ -0,0 +1,1686 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use tokio::{
+    net::TcpStream,
+    sync::{
+        Notify,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    time::Instant,
+};
+
+use crate::{
+    aof,
+    config::{Config, generate_random_alphanumeric},
+    connection::Connection,
+    mem::{Databases, MemDB},
+    rdb,
+    resp::{commands::structs::Data, frame::RespFrame, parser::Request},
+    session::Session,
+    snapshot::{restore, take_snapshot},
+    transaction::exclusively,
+};
+
+// How long a replica waits before connecting again once the link to its master is lost
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often a replica reports its offsets to its master without being asked
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const READONLY_ERR: &str = "READONLY You can't write against a read only replica.";
+
+// Size of the backlog until repl-backlog-size is set, as in Redis
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+// Bytes of the stream a replica may have waiting to be sent before it is dropped for falling
+// behind, the counterpart of the hard limit of the replica class of client-output-buffer-limit
+// in Redis
+const REPLICA_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+
+// Seconds a diskless transfer waits for more replicas until repl-diskless-sync-delay is set
+const DEFAULT_DISKLESS_SYNC_DELAY: u64 = 5;
+
+// Length of the mark around an RDB file sent without its size
+const EOF_MARK_LEN: usize = 40;
+
+// A replica which completed its handshake, it is sent every write from then on
+struct ReplicaLink {
+    id: u64,
+    // ip and port are where the replica listens, the port comes from REPLCONF listening-port
+    ip: String,
+    port: u16,
+    state: ReplicaState,
+    sender: UnboundedSender<Bytes>,
+    queued: Arc<Queued>,
+    // The offsets the replica last reported having processed and having synced to its AOF,
+    // and when it did
+    ack_offset: u64,
+    aof_offset: u64,
+    last_ack: Instant,
+}
+
+// Where a replica is in its sync, in the words of INFO
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplicaState {
+    // The dataset is being copied for it
+    WaitBgsave,
+    SendBulk,
+    Online,
+}
+
+impl ReplicaState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+// A replica as listed by INFO and ROLE, lag is the number of seconds since its last ACK
+pub struct ReplicaStatus {
+    pub ip: String,
+    pub port: u16,
+    pub state: ReplicaState,
+    pub offset: u64,
+    pub lag: u64,
+}
+
+// Backlog keeps the latest bytes of the replication stream, so a replica which lost its link
+// can be sent only what it missed instead of the whole dataset
+struct Backlog {
+    data: VecDeque<u8>,
+    // first is the offset of the oldest byte held, counted from 1 like PSYNC offsets
+    first: u64,
+}
+
+impl Backlog {
+    fn new(offset: u64) -> Self {
+        Backlog {
+            data: VecDeque::new(),
+            first: offset + 1,
+        }
+    }
+
+    fn push(&mut self, payload: &[u8], size: usize) {
+        self.data.extend(payload);
+        self.trim(size);
+    }
+
+    fn trim(&mut self, size: usize) {
+        if self.data.len() > size {
+            let excess = self.data.len() - size;
+            self.data.drain(..excess);
+            self.first += excess as u64;
+        }
+    }
+
+    // The bytes from `offset` on, if the backlog still holds all of them
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.first || offset > self.first + self.data.len() as u64 {
+            return None;
+        }
+        Some(
+            self.data
+                .range((offset - self.first) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+struct State {
+    replid: String,
+    // offset counts the bytes of write commands fed to the replication stream
+    offset: u64,
+    // replid2 is the ID this server used before the current one, a replica of that history
+    // can continue up to second_offset
+    replid2: String,
+    second_offset: Option<u64>,
+    // backlog is created along with the first replica, or once this server follows a master
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    replicas: Vec<ReplicaLink>,
+    // With diskless_sync, the RDB file of a full resync is sent straight from memory to the
+    // replicas which can read it without knowing its size. They wait in waiting for
+    // diskless_sync_delay seconds, so the replicas arriving together share one transfer.
+    diskless_sync: bool,
+    diskless_sync_delay: u64,
+    waiting: Vec<Waiting>,
+    // output_limit is how many bytes a replica may have waiting in its stream
+    output_limit: usize,
+}
+
+// A replica waiting for the next diskless transfer, it learns where its stream starts
+// through sender
+struct Waiting {
+    id: u64,
+    ip: String,
+    port: u16,
+    sender: oneshot::Sender<Start>,
+}
+
+// Master is the master side of replication: the replication ID and offset replicas sync
+// from, and the replicas the write stream is sent to
+#[derive(Clone)]
+pub struct Master {
+    state: Arc<Mutex<State>>,
+    // acked wakes the clients blocked in WAIT or WAITAOF whenever an offset they wait on moves
+    acked: Arc<Notify>,
+}
+
+impl Master {
+    pub fn new(replid: String) -> Self {
+        Master {
+            state: Arc::new(Mutex::new(State {
+                replid,
+                offset: 0,
+                replid2: "0".repeat(40),
+                second_offset: None,
+                backlog: None,
+                backlog_size: DEFAULT_BACKLOG_SIZE,
+                replicas: Vec::new(),
+                diskless_sync: false,
+                diskless_sync_delay: DEFAULT_DISKLESS_SYNC_DELAY,
+                waiting: Vec::new(),
+                output_limit: REPLICA_OUTPUT_LIMIT,
+            })),
+            acked: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn replid(&self) -> String {
+        self.state.lock().unwrap().replid.clone()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    pub fn replid2(&self) -> String {
+        self.state.lock().unwrap().replid2.clone()
+    }
+
+    // second_offset is reported as -1 while there is no previous ID
+    pub fn second_offset(&self) -> i64 {
+        self.state
+            .lock()
+            .unwrap()
+            .second_offset
+            .map_or(-1, |offset| offset as i64)
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.state.lock().unwrap().backlog_size
+    }
+
+    /// The offset of the first byte the backlog holds and how many it holds, if there is one
+    pub fn backlog_range(&self) -> Option<(u64, u64)> {
+        let state = self.state.lock().unwrap();
+        let backlog = state.backlog.as_ref()?;
+        Some((backlog.first, backlog.data.len() as u64))
+    }
+
+    pub fn set_backlog_size(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.backlog_size = size;
+        if let Some(backlog) = state.backlog.as_mut() {
+            backlog.trim(size);
+        }
+    }
+
+    pub fn diskless_sync(&self) -> bool {
+        self.state.lock().unwrap().diskless_sync
+    }
+
+    pub fn set_diskless_sync(&self, diskless_sync: bool) {
+        self.state.lock().unwrap().diskless_sync = diskless_sync;
+    }
+
+    pub fn diskless_sync_delay(&self) -> u64 {
+        self.state.lock().unwrap().diskless_sync_delay
+    }
+
+    pub fn set_diskless_sync_delay(&self, delay: u64) {
+        self.state.lock().unwrap().diskless_sync_delay = delay;
+    }
+
+    /// Takes over the replication ID and offset of the master this server replicates, after
+    /// a full sync. The history this server had before is gone.
+    pub fn follow(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.offset = offset;
+        state.replid2 = "0".repeat(40);
+        state.second_offset = None;
+        state.backlog = Some(Backlog::new(offset));
+    }
+
+    /// Switches to a new replication ID, keeping the current one as the secondary ID so
+    /// replicas which followed it can still continue from the backlog
+    pub fn shift_replid(&self, replid: String) {
+        let mut state = self.state.lock().unwrap();
+        state.replid2 = std::mem::replace(&mut state.replid, replid);
+        state.second_offset = Some(state.offset + 1);
+    }
+
+    /// Sends write commands, already encoded, to every replica. Replicas whose connection
+    /// is gone are dropped on the way, and so are those with more than the output limit
+    /// waiting to be sent: they connect again and continue from the backlog if it still
+    /// holds what they missed, or sync from scratch.
+    pub fn feed(&self, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.offset += payload.len() as u64;
+        let size = state.backlog_size;
+        if let Some(backlog) = state.backlog.as_mut() {
+            backlog.push(payload, size);
+        }
+        if state.replicas.is_empty() {
+            return;
+        }
+        let payload = Bytes::copy_from_slice(payload);
+        let limit = state.output_limit;
+        state.replicas.retain(|replica| {
+            let queued = replica
+                .queued
+                .bytes
+                .fetch_add(payload.len(), Ordering::Relaxed);
+            if queued + payload.len() > limit {
+                eprintln!(
+                    "Replica {}:{} dropped for overcoming the output buffer limit",
+                    replica.ip, replica.port
+                );
+                replica.queued.overflowed.store(true, Ordering::Relaxed);
+                return false;
+            }
+            replica.sender.send(payload.clone()).is_ok()
+        });
+    }
+
+    // Asks every replica for its offsets through the stream, they answer with REPLCONF ACK
+    fn request_acks(&self) {
+        if !self.state.lock().unwrap().replicas.is_empty() {
+            self.feed(&aof::encode_commands(&[vec![
+                b"REPLCONF".to_vec(),
+                b"GETACK".to_vec(),
+                b"*".to_vec(),
+            ]]));
+        }
+    }
+
+    pub fn wake_waiters(&self) {
+        self.acked.notify_waiters();
+    }
+
+    // Counts the replicas which processed, or synced to their AOF, everything up to `offset`
+    fn acknowledged(&self, offset: u64, aof: bool) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .replicas
+            .iter()
+            .filter(|replica| {
+                if aof {
+                    replica.aof_offset >= offset
+                } else {
+                    replica.ack_offset >= offset
+                }
+            })
+            .count()
+    }
+
+    fn acknowledge(&self, id: u64, offset: u64, aof_offset: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            replica.last_ack = Instant::now();
+            if let Some(aof_offset) = aof_offset {
+                replica.aof_offset = replica.aof_offset.max(aof_offset);
+            }
+        }
+        drop(state);
+        self.wake_waiters();
+    }
+
+    /// Drops every replica, they connect again and learn about the change of replication ID
+    /// or master through PSYNC
+    pub fn disconnect_replicas(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.clear();
+        state.waiting.clear();
+    }
+
+    pub fn replicas(&self) -> Vec<ReplicaStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .replicas
+            .iter()
+            .map(|replica| ReplicaStatus {
+                ip: replica.ip.clone(),
+                port: replica.port,
+                state: replica.state,
+                offset: replica.ack_offset,
+                lag: replica.last_ack.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    fn set_replica_state(&self, id: u64, replica_state: ReplicaState) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.state = replica_state;
+        }
+    }
+
+    // Adds a replica, returning where its stream starts along with it
+    fn register(&self, id: u64, ip: &str, port: u16) -> (String, u64, Stream) {
+        let mut state = self.state.lock().unwrap();
+        let receiver = add_replica(&mut state, id, ip, port, ReplicaState::WaitBgsave);
+        (state.replid.clone(), state.offset, receiver)
+    }
+
+    // Queues a replica for the next diskless transfer. Returns whether it is the first one,
+    // which then starts the transfer once the delay is over.
+    fn enqueue(&self, id: u64, ip: &str, port: u16) -> (bool, oneshot::Receiver<Start>) {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push(Waiting {
+            id,
+            ip: ip.to_string(),
+            port,
+            sender,
+        });
+        (state.waiting.len() == 1, receiver)
+    }
+
+    // Adds every replica waiting for a diskless transfer, their streams all start at the
+    // current offset
+    fn register_waiting(&self) -> (String, u64, Vec<(oneshot::Sender<Start>, Stream)>) {
+        let mut state = self.state.lock().unwrap();
+        let waiting = std::mem::take(&mut state.waiting);
+        let registered = waiting
+            .into_iter()
+            .map(|waiting| {
+                let stream = add_replica(
+                    &mut state,
+                    waiting.id,
+                    &waiting.ip,
+                    waiting.port,
+                    ReplicaState::WaitBgsave,
+                );
+                (waiting.sender, stream)
+            })
+            .collect();
+        (state.replid.clone(), state.offset, registered)
+    }
+
+    // Fails every replica waiting for a diskless transfer which can't happen, dropping its
+    // sender ends the wait. The next replica to arrive starts a new transfer.
+    fn abort_waiting(&self) {
+        self.state.lock().unwrap().waiting.clear();
+    }
+
+    // Adds a replica asking to continue from `offset` of the history `replid`, if the backlog
+    // still holds everything it missed. Returns the current ID and the missed bytes.
+    fn resume(
+        &self,
+        (id, ip, port): (u64, &str, u16),
+        replid: &str,
+        offset: u64,
+    ) -> Option<(String, Vec<u8>, Stream)> {
+        let mut state = self.state.lock().unwrap();
+        let same_history = replid == state.replid
+            || (replid == state.replid2
+                && state.second_offset.is_some_and(|second| offset <= second));
+        if !same_history {
+            return None;
+        }
+        let missed = state.backlog.as_ref()?.since(offset)?;
+        let receiver = add_replica(&mut state, id, ip, port, ReplicaState::Online);
+        Some((state.replid.clone(), missed, receiver))
+    }
+
+    fn unregister(&self, id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .replicas
+            .retain(|replica| replica.id != id);
+    }
+}
+
+// Queued is what a replica has waiting in its stream, shared by both ends of it
+#[derive(Default)]
+struct Queued {
+    bytes: AtomicUsize,
+    // Set once the replica was dropped for having too much waiting, what is left isn't sent
+    overflowed: AtomicBool,
+}
+
+// The write stream as a replica's connection receives it
+struct Stream {
+    receiver: UnboundedReceiver<Bytes>,
+    queued: Arc<Queued>,
+}
+
+impl Stream {
+    // recv returns the next writes to send, None once the replica was dropped
+    async fn recv(&mut self) -> Option<Bytes> {
+        if self.queued.overflowed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let payload = self.receiver.recv().await?;
+        self.queued
+            .bytes
+            .fetch_sub(payload.len(), Ordering::Relaxed);
+        Some(payload)
+    }
+}
+
+// Starts sending the stream to a replica, through the channel returned
+fn add_replica(
+    state: &mut State,
+    id: u64,
+    ip: &str,
+    port: u16,
+    replica_state: ReplicaState,
+) -> Stream {
+    if state.backlog.is_none() {
+        state.backlog = Some(Backlog::new(state.offset));
+    }
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(Queued::default());
+    state.replicas.push(ReplicaLink {
+        id,
+        ip: ip.to_string(),
+        port,
+        state: replica_state,
+        sender,
+        queued: queued.clone(),
+        ack_offset: 0,
+        aof_offset: 0,
+        last_ack: Instant::now(),
+    });
+    Stream { receiver, queued }
+}
+
+// The address of the master a replica follows
+#[derive(Clone, PartialEq)]
+pub struct MasterAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+// How far a replica got in following its master, in the words of ROLE
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    Connect,
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl LinkStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkStatus::Connect => "connect",
+            LinkStatus::Connecting => "connecting",
+            LinkStatus::Sync => "sync",
+            LinkStatus::Connected => "connected",
+        }
+    }
+}
+
+// How a replica loads the RDB file of a full resync, from repl-diskless-load
+#[derive(Clone, Copy, PartialEq)]
+pub enum DisklessLoad {
+    // The file is written to disk first, then loaded from there
+    Disabled,
+    // Loaded straight from the socket when there is no data to lose if the transfer fails
+    OnEmptyDb,
+    // Loaded straight from the socket, the current data is kept until the load succeeds
+    Swapdb,
+}
+
+impl DisklessLoad {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "disabled" => Ok(DisklessLoad::Disabled),
+            "on-empty-db" => Ok(DisklessLoad::OnEmptyDb),
+            "swapdb" => Ok(DisklessLoad::Swapdb),
+            _ => Err(anyhow!(
+                "argument(s) must be one of the following: disabled, on-empty-db, swapdb"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisklessLoad::Disabled => "disabled",
+            DisklessLoad::OnEmptyDb => "on-empty-db",
+            DisklessLoad::Swapdb => "swapdb",
+        }
+    }
+}
+
+// Upstream is the replica side of replication: the master this server follows, if any, and
+// the state of the link to it. The link is set up by replica_cycle, which follows every
+// change of master.
+#[derive(Clone)]
+pub struct Upstream {
+    addr: Arc<watch::Sender<Option<MasterAddr>>>,
+    link: Arc<Mutex<Link>>,
+    diskless_load: Arc<Mutex<DisklessLoad>>,
+}
+
+struct Link {
+    status: LinkStatus,
+    // last_io is when the master last sent something, while the link is up
+    last_io: Option<Instant>,
+    // db is the database the stream of the master applies to. It is kept across links, as a
+    // partial resync continues the stream where it stopped.
+    db: usize,
+}
+
+impl Upstream {
+    pub fn new(addr: Option<MasterAddr>) -> Self {
+        Upstream {
+            addr: Arc::new(watch::Sender::new(addr)),
+            link: Arc::new(Mutex::new(Link {
+                status: LinkStatus::Connect,
+                last_io: None,
+                db: 0,
+            })),
+            diskless_load: Arc::new(Mutex::new(DisklessLoad::Disabled)),
+        }
+    }
+
+    pub fn addr(&self) -> Option<MasterAddr> {
+        self.addr.borrow().clone()
+    }
+
+    /// Follows another master, or none once promoted. The current link is dropped.
+    pub fn set_addr(&self, addr: Option<MasterAddr>) {
+        self.set_status(LinkStatus::Connect);
+        self.addr.send_replace(addr);
+    }
+
+    pub fn diskless_load(&self) -> DisklessLoad {
+        *self.diskless_load.lock().unwrap()
+    }
+
+    pub fn set_diskless_load(&self, diskless_load: DisklessLoad) {
+        *self.diskless_load.lock().unwrap() = diskless_load;
+    }
+
+    pub fn status(&self) -> LinkStatus {
+        self.link.lock().unwrap().status
+    }
+
+    /// Seconds since the master last sent something, None while the link is down
+    pub fn last_io_seconds(&self) -> Option<u64> {
+        let link = self.link.lock().unwrap();
+        link.last_io.map(|last_io| last_io.elapsed().as_secs())
+    }
+
+    fn set_status(&self, status: LinkStatus) {
+        let mut link = self.link.lock().unwrap();
+        link.status = status;
+        link.last_io = match status {
+            LinkStatus::Connected => Some(Instant::now()),
+            _ => None,
+        };
+    }
+
+    fn touch(&self) {
+        self.link.lock().unwrap().last_io = Some(Instant::now());
+    }
+
+    fn stream_db(&self) -> usize {
+        self.link.lock().unwrap().db
+    }
+
+    fn set_stream_db(&self, db: usize) {
+        self.link.lock().unwrap().db = db;
+    }
+}
+
+/// Turns a replica into a master. It takes a new replication ID and keeps the one it
+/// followed as the secondary ID, so the other replicas of its master can continue from it.
+pub fn promote(config: &Config) {
+    config.replication.upstream.set_addr(None);
+    config
+        .replication
+        .master
+        .shift_replid(generate_random_alphanumeric(40));
+    config.replication.master.disconnect_replicas();
+    // The replicas of this server got the stream of its master until now
+    config.reset_propagated_db();
+    println!("MASTER MODE enabled");
+}
+
+/// Makes this server a replica of `addr`, the link is set up by replica_cycle
+pub fn replicate(config: &Config, addr: MasterAddr) {
+    println!("REPLICAOF {}:{} enabled", addr.host, addr.port);
+    config.replication.upstream.set_addr(Some(addr));
+    config.replication.master.disconnect_replicas();
+}
+
+// What a replica is sent before the stream: the dataset as an RDB file, already framed,
+// or the part of the stream it missed
+enum Payload {
+    Rdb(Vec<u8>),
+    Backlog(Vec<u8>),
+}
+
+// Start is how a replica's sync begins: the reply to its PSYNC, then the payload and the
+// stream
+struct Start {
+    reply: String,
+    payload: Payload,
+    stream: Stream,
+}
+
+enum Transfer {
+    Ready(Start),
+    // The replica waits for a diskless transfer, the leader being the one starting it
+    Diskless {
+        leader: bool,
+        receiver: oneshot::Receiver<Start>,
+    },
+}
+
+// Resync is what a replica gets in answer to PSYNC
+pub struct Resync {
+    id: u64,
+    transfer: Transfer,
+}
+
+// A replica as known ahead of its PSYNC: where it listens and whether it can read an RDB
+// file sent between EOF marks, from REPLCONF capa eof
+pub struct Peer {
+    pub ip: String,
+    pub port: u16,
+    pub eof: bool,
+}
+
+/// Answers PSYNC replid offset from the replica on connection `id`. A replica which follows
+/// the current history, or the previous one, continues from the backlog when it still holds
+/// what the replica missed. Any other gets a full resync, diskless when repl-diskless-sync
+/// is set and the replica supports it.
+pub fn psync(
+    id: u64,
+    peer: Peer,
+    replid: &str,
+    offset: &str,
+    dbs: &[&RwLock<MemDB<Data>>],
+    config: &Config,
+) -> Result<Resync> {
+    let master = &config.replication.master;
+    if let Ok(offset) = offset.parse::<u64>()
+        && let Some((replid, missed, stream)) =
+            master.resume((id, &peer.ip, peer.port), replid, offset)
+    {
+        let start = Start {
+            reply: format!("CONTINUE {}", replid),
+            payload: Payload::Backlog(missed),
+            stream,
+        };
+        return Ok(Resync {
+            id,
+            transfer: Transfer::Ready(start),
+        });
+    }
+
+    if peer.eof && master.diskless_sync() {
+        let (leader, receiver) = master.enqueue(id, &peer.ip, peer.port);
+        return Ok(Resync {
+            id,
+            transfer: Transfer::Diskless { leader, receiver },
+        });
+    }
+
+    // The dataset is copied and the replica registered while every other client is held
+    // off, so its stream starts exactly where the copy ends. The replica loads the copy in
+    // database 0, the stream has to select the database of the next write again.
+    let (snapshot, (replid, offset, stream)) = exclusively(|| {
+        let (snapshot, _) = take_snapshot(dbs, config)?;
+        let registered = master.register(id, &peer.ip, peer.port);
+        config.reset_propagated_db();
+        Ok::<_, anyhow::Error>((snapshot, registered))
+    })?;
+
+    // The RDB file is sent like a bulk string, without the trailing CRLF
+    let rdb = rdb::encode(&snapshot, &config.server.redis_version);
+    let mut payload = format!("${}\r\n", rdb.len()).into_bytes();
+    payload.extend_from_slice(&rdb);
+    let start = Start {
+        reply: format!("FULLRESYNC {} {}", replid, offset),
+        payload: Payload::Rdb(payload),
+        stream,
+    };
+    Ok(Resync {
+        id,
+        transfer: Transfer::Ready(start),
+    })
+}
+
+// Copies the dataset once for every replica waiting, and hands each its start. The RDB file
+// goes between two random marks instead of after its size, as when it is streamed while
+// being produced.
+fn diskless_transfer(dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<()> {
+    let master = &config.replication.master;
+    let copied = exclusively(|| {
+        let (snapshot, _) = take_snapshot(dbs, config)?;
+        let registered = master.register_waiting();
+        config.reset_propagated_db();
+        Ok::<_, anyhow::Error>((snapshot, registered))
+    });
+    let (snapshot, (replid, offset, registered)) = match copied {
+        Ok(copied) => copied,
+        Err(err) => {
+            master.abort_waiting();
+            return Err(err);
+        }
+    };
+
+    let mark = generate_random_alphanumeric(EOF_MARK_LEN);
+    let mut payload = format!("$EOF:{}\r\n", mark).into_bytes();
+    payload.extend_from_slice(&rdb::encode(&snapshot, &config.server.redis_version));
+    payload.extend_from_slice(mark.as_bytes());
+    for (sender, stream) in registered {
+        let _ = sender.send(Start {
+            reply: format!("FULLRESYNC {} {}", replid, offset),
+            payload: Payload::Rdb(payload.clone()),
+            stream,
+        });
+    }
+    Ok(())
+}
+
+/// Replies to the replica's PSYNC and sends it the RDB file or the missed bytes, then every
+/// write until it disconnects. What the replica sends back are its REPLCONF ACKs, anything
+/// else is dropped. A replica waiting for a diskless transfer gets its reply once the
+/// transfer starts, the first one to wait starts it after repl-diskless-sync-delay.
+pub async fn serve_replica(
+    connection: &mut Connection,
+    sync: Resync,
+    dbs: &[&RwLock<MemDB<Data>>],
+    config: &Config,
+) -> Result<()> {
+    let Resync { id, transfer } = sync;
+    let master = &config.replication.master;
+
+    let Start {
+        reply,
+        payload,
+        mut stream,
+    } = match transfer {
+        Transfer::Ready(start) => start,
+        Transfer::Diskless { leader, receiver } => {
+            if leader {
+                let delay = Duration::from_secs(master.diskless_sync_delay());
+                tokio::time::sleep(delay).await;
+                diskless_transfer(dbs, config)?;
+            }
+            receiver
+                .await
+                .map_err(|_| anyhow!("Diskless sync aborted"))?
+        }
+    };
+
+    let payload = match payload {
+        Payload::Rdb(rdb) => {
+            master.set_replica_state(id, ReplicaState::SendBulk);
+            rdb
+        }
+        Payload::Backlog(missed) => missed,
+    };
+    let mut result = connection.write(RespFrame::SimpleString(reply)).await;
+    if result.is_ok() {
+        result = connection.write_raw(&payload).await;
+    }
+    master.set_replica_state(id, ReplicaState::Online);
+
+    while result.is_ok() {
+        tokio::select! {
+            payload = stream.recv() => match payload {
+                Some(payload) => result = connection.write_raw(&payload).await,
+                None => break,
+            },
+            request = connection.parse() => match request {
+                Ok(request) => {
+                    if let Some((offset, aof_offset)) = parse_ack(&request) {
+                        master.acknowledge(id, offset, aof_offset);
+                    }
+                }
+                Err(err) if err.to_string().starts_with("Connection closed") => break,
+                Err(err) => result = Err(err),
+            },
+        }
+    }
+
+    master.unregister(id);
+    result.map_err(|err| anyhow!("Replica connection lost: {}", err))
+}
+
+// REPLCONF ACK offset [FACK aof_offset]
+fn parse_ack(request: &Request) -> Option<(u64, Option<u64>)> {
+    if request.name != "replconf" || !request.args.first()?.eq_ignore_ascii_case("ack") {
+        return None;
+    }
+    let offset = request.args.get(1)?.parse().ok()?;
+    let aof_offset = match request.args.get(2..) {
+        Some([option, aof_offset]) if option.eq_ignore_ascii_case("fack") => {
+            Some(aof_offset.parse().ok()?)
+        }
+        _ => None,
+    };
+    Some((offset, aof_offset))
+}
+
+// Wait is what a client blocked in WAIT or WAITAOF waits for: the replicas, and with
+// WAITAOF the local AOF, to have everything up to the offset of its last write
+pub struct Wait {
+    pub offset: u64,
+    pub local: bool,
+    pub replicas: usize,
+    // aof is set by WAITAOF, the replicas then have to have synced the writes to their AOF
+    pub aof: bool,
+    pub timeout: Option<Duration>,
+}
+
+/// Blocks until the replicas have acknowledged what the client waits for or the timeout
+/// fires, then replies with how many did. The replicas are asked for their offsets once.
+pub async fn wait(wait: Wait, config: &Config) -> RespFrame {
+    let master = &config.replication.master;
+    let deadline = wait.timeout.map(|timeout| Instant::now() + timeout);
+    let mut asked = false;
+    let (local, replicas) = loop {
+        let notified = master.acked.notified();
+        tokio::pin!(notified);
+        // Registered before checking, so an acknowledgement arriving in between isn't lost
+        notified.as_mut().enable();
+
+        let local = wait.local && config.aof.synced_offset() >= wait.offset;
+        let replicas = master.acknowledged(wait.offset, wait.aof);
+        if (local || !wait.local) && replicas >= wait.replicas {
+            break (local, replicas);
+        }
+        if !asked {
+            master.request_acks();
+            asked = true;
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    break (local, replicas);
+                }
+            }
+            None => notified.await,
+        }
+    };
+
+    if !wait.aof {
+        return RespFrame::Integer(replicas as i64);
+    }
+    RespFrame::Array(vec![
+        RespFrame::Integer(local as i64),
+        RespFrame::Integer(replicas as i64),
+    ])
+}
+
+/// Keeps a replica in sync with its master: the handshake, a partial or full resync, then
+/// the write stream, connecting again whenever the link is lost. Idles while this server is
+/// a master, and drops the link as soon as the master changes.
+pub async fn replica_cycle(dbs: Arc<Databases<Data>>, config: Config) {
+    let upstream = &config.replication.upstream;
+    let mut changes = upstream.addr.subscribe();
+    let dbs = dbs.all();
+    loop {
+        let Some(addr) = changes.borrow_and_update().clone() else {
+            let _ = changes.changed().await;
+            continue;
+        };
+        tokio::select! {
+            result = follow_master(&addr, &dbs, &config) => {
+                if let Err(err) = result {
+                    eprintln!("Replication with the master failed: {}", err);
+                }
+                upstream.set_status(LinkStatus::Connect);
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_INTERVAL) => {}
+                    _ = changes.changed() => {}
+                }
+            }
+            _ = changes.changed() => {}
+        }
+    }
+}
+
+async fn follow_master(
+    addr: &MasterAddr,
+    dbs: &[&RwLock<MemDB<Data>>],
+    config: &Config,
+) -> Result<()> {
+    let upstream = &config.replication.upstream;
+    upstream.set_status(LinkStatus::Connecting);
+    let stream = TcpStream::connect((addr.host.as_str(), addr.port)).await?;
+    let peer = stream.peer_addr()?;
+    let mut connection = Connection::new(stream, peer);
+
+    handshake(&mut connection, "PONG", &["PING"]).await?;
+    let port = config.server.tcp_port.to_string();
+    handshake(
+        &mut connection,
+        "OK",
+        &["REPLCONF", "listening-port", &port],
+    )
+    .await?;
+    handshake(
+        &mut connection,
+        "OK",
+        &["REPLCONF", "capa", "eof", "capa", "psync2"],
+    )
+    .await?;
+    // The replica asks to continue its own history, a master which doesn't share it
+    // answers with a full resync
+    let master = &config.replication.master;
+    let replid = master.replid();
+    let offset = (master.offset() + 1).to_string();
+    let reply = handshake(&mut connection, "", &["PSYNC", &replid, &offset]).await?;
+    let (replid, offset) = match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["FULLRESYNC", replid, offset] => (replid.to_string(), offset.parse::<u64>()?),
+        ["CONTINUE"] => return apply_stream(&mut connection, addr, dbs, config).await,
+        ["CONTINUE", new_replid] => {
+            // The master changed its ID since, after a failover, the old one is kept as the
+            // secondary ID
+            if new_replid != replid {
+                master.shift_replid(new_replid.to_string());
+            }
+            return apply_stream(&mut connection, addr, dbs, config).await;
+        }
+        _ => return Err(anyhow!("Unexpected reply to PSYNC: {}", reply)),
+    };
+
+    upstream.set_status(LinkStatus::Sync);
+    // Loading from memory keeps no copy on disk, so unless told otherwise the file is
+    // stored first, which leaves the dataset the master sent in the RDB file
+    let from_memory = match upstream.diskless_load() {
+        DisklessLoad::Disabled => false,
+        DisklessLoad::OnEmptyDb => dbs
+            .iter()
+            .all(|db| db.read().is_ok_and(|db| db.iter().next().is_none())),
+        DisklessLoad::Swapdb => true,
+    };
+
+    // The RDB file comes as a bulk string without the trailing CRLF, or between two EOF
+    // marks when the master sends it diskless
+    let header = connection.read_line().await?;
+    let (mark, len) = match header.strip_prefix("$EOF:") {
+        Some(mark) if mark.len() == EOF_MARK_LEN => (Some(mark.to_string()), None),
+        Some(_) => return Err(anyhow!("Unexpected RDB header from the master: {}", header)),
+        None => {
+            let len = header
+                .strip_prefix('$')
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("Unexpected RDB header from the master: {}", header))?;
+            (None, Some(len))
+        }
+    };
+
+    let snapshot = if from_memory {
+        // The file is parsed as it arrives, the keys being the only copy held
+        let mut decoder = rdb::Decoder::new();
+        let mut read = 0;
+        let snapshot = connection
+            .consume(|data| {
+                let available = len.map_or(data.len(), |len| data.len().min(len - read));
+                let (used, snapshot) = decoder.feed(&data[..available])?;
+                read += used;
+                if snapshot.is_none() && len == Some(read + available - used) {
+                    return Err(anyhow!("The RDB file from the master is truncated"));
+                }
+                Ok((used, snapshot))
+            })
+            .await?;
+        match (&mark, len) {
+            (Some(mark), _) if connection.read_exact(EOF_MARK_LEN).await? != mark.as_bytes() => {
+                return Err(anyhow!(
+                    "The RDB file from the master doesn't end with its mark"
+                ));
+            }
+            (_, Some(len)) if read != len => {
+                return Err(anyhow!(
+                    "The RDB file from the master is longer than its RDB data"
+                ));
+            }
+            _ => snapshot,
+        }
+    } else {
+        let payload = match (&mark, len) {
+            (Some(mark), _) => connection.read_until(mark.as_bytes()).await?,
+            (_, len) => connection.read_exact(len.unwrap_or_default()).await?,
+        };
+        let path = config.persistence.store(&payload)?;
+        rdb::decode(&std::fs::read(path)?)?
+    };
+
+    let keys = exclusively(|| {
+        for db in dbs {
+            db.write()
+                .map_err(|_| anyhow!("Unable to acquire lock"))?
+                .clear();
+        }
+        config.functions.flush();
+        config.scripting.reset_functions();
+        master.follow(replid, offset);
+        upstream.set_stream_db(0);
+        let keys = restore(snapshot, dbs, config)?;
+        if !from_memory {
+            config.persistence.mark_saved(dbs)?;
+        }
+        Ok::<_, anyhow::Error>(keys)
+    })?;
+    println!("Full sync with the master done, loaded {} keys", keys);
+    // The AOF has to start over from the dataset the master sent
+    if config.aof.enabled()
+        && let Err(err) = config.aof.rewrite(dbs, config)
+    {
+        eprintln!("Unable to rewrite the AOF after the full sync: {}", err);
+    }
+
+    apply_stream(&mut connection, addr, dbs, config).await
+}
+
+// Sends a handshake command and checks its reply starts with `expected`
+async fn handshake(connection: &mut Connection, expected: &str, argv: &[&str]) -> Result<String> {
+    let request = argv
+        .iter()
+        .map(|arg| RespFrame::BulkString(arg.to_string()))
+        .collect();
+    connection.write(RespFrame::Array(request)).await?;
+    let reply = connection.read_line().await?;
+    match reply.strip_prefix('+') {
+        Some(status) if status.starts_with(expected) => Ok(status.to_string()),
+        _ => Err(anyhow!("Unexpected reply to {}: {}", argv[0], reply)),
+    }
+}
+
+// Applies the commands the master streams without replying to them. Only REPLCONF GETACK is
+// answered, with the offset of everything processed before it. The offsets are also reported
+// once a second, so the master learns when the AOF got synced.
+async fn apply_stream(
+    connection: &mut Connection,
+    addr: &MasterAddr,
+    dbs: &[&RwLock<MemDB<Data>>],
+    config: &Config,
+) -> Result<()> {
+    let master = &config.replication.master;
+    let upstream = &config.replication.upstream;
+    upstream.set_status(LinkStatus::Connected);
+    let mut session = Session::master_link(
+        connection.id(),
+        connection.addr(),
+        upstream.stream_db(),
+        config,
+    );
+    let mut interval = tokio::time::interval(ACK_INTERVAL);
+    let result = loop {
+        let (request, raw) = tokio::select! {
+            parsed = connection.parse_raw() => match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => break Err(err),
+            },
+            _ = interval.tick() => {
+                if let Err(err) = connection.write(ack_frame(config)).await {
+                    break Err(err);
+                }
+                continue;
+            }
+        };
+
+        // Nothing more is applied once this server follows another master, or none
+        if upstream.addr().as_ref() != Some(addr) {
+            break Ok(());
+        }
+        upstream.touch();
+
+        if request.name == "replconf"
+            && request
+                .args
+                .first()
+                .is_some_and(|option| option.eq_ignore_ascii_case("getack"))
+        {
+            if let Err(err) = connection.write(ack_frame(config)).await {
+                break Err(err);
+            }
+        } else if config.scripting.is_running() {
+            tokio::task::block_in_place(|| session.handle(request, dbs, config));
+        } else {
+            session.handle(request, dbs, config);
+        }
+        master.feed(&raw);
+        config.aof.advance(master.offset());
+    };
+    upstream.set_stream_db(session.selected_db());
+    session.close(dbs);
+    result
+}
+
+// REPLCONF ACK offset FACK aof_offset
+fn ack_frame(config: &Config) -> RespFrame {
+    RespFrame::Array(
+        [
+            "REPLCONF".to_string(),
+            "ACK".to_string(),
+            config.replication.master.offset().to_string(),
+            "FACK".to_string(),
+            config.aof.synced_offset().to_string(),
+        ]
+        .into_iter()
+        .map(RespFrame::BulkString)
+        .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+    use crate::resp::commands::structs::Value;
+
+    fn string(value: &str) -> Data {
+        Data {
+            value: Value::String(value.as_bytes().to_vec()),
+            expires_at: None,
+        }
+    }
+
+    fn peer(port: u16, eof: bool) -> Peer {
+        Peer {
+            ip: "127.0.0.1".to_string(),
+            port,
+            eof,
+        }
+    }
+
+    // Serves the sync to a replica over a local connection, returning the replica's end
+    async fn serve(config: &Config, sync: Resync) -> (Connection, JoinHandle<Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let replica = TcpStream::connect(addr).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let config = config.clone();
+        let served = tokio::spawn(async move {
+            let mut connection = Connection::new(stream, peer);
+            let dbs = config.databases.all();
+            serve_replica(&mut connection, sync, &dbs, &config).await
+        });
+        (Connection::new(replica, addr), served)
+    }
+
+    // Makes the replica follow a master played by the test, returning the master's end of
+    // the link once the replica sent its PSYNC, along with the ID and offset it asked for
+    async fn follow(config: &Config) -> (Connection, Vec<String>, JoinHandle<Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = MasterAddr {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        replicate(config, addr.clone());
+        let config = config.clone();
+        let following = tokio::spawn(async move {
+            let dbs = config.databases.all();
+            follow_master(&addr, &dbs, &config).await
+        });
+
+        let (stream, peer) = listener.accept().await.unwrap();
+        let mut master = Connection::new(stream, peer);
+        for (name, reply) in [("ping", "PONG"), ("replconf", "OK"), ("replconf", "OK")] {
+            assert_eq!(master.parse().await.unwrap().name, name);
+            master
+                .write(RespFrame::SimpleString(reply.to_string()))
+                .await
+                .unwrap();
+        }
+        let psync = master.parse().await.unwrap();
+        assert_eq!(psync.name, "psync");
+        (master, psync.args, following)
+    }
+
+    fn commands(commands: &[&[&str]]) -> Vec<u8> {
+        let commands: Vec<Vec<Vec<u8>>> = commands
+            .iter()
+            .map(|args| args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+            .collect();
+        aof::encode_commands(&commands)
+    }
+
+    // The reply to a PSYNC, along with the missed bytes when the replica continues
+    fn answer(sync: Resync) -> (String, Option<Vec<u8>>) {
+        match sync.transfer {
+            Transfer::Ready(Start {
+                reply,
+                payload: Payload::Backlog(missed),
+                ..
+            }) => (reply, Some(missed)),
+            Transfer::Ready(Start { reply, .. }) => (reply, None),
+            Transfer::Diskless { .. } => ("diskless".to_string(), None),
+        }
+    }
+
+    // The offsets of the next REPLCONF ACK the replica sends
+    async fn ack(master: &mut Connection) -> Vec<String> {
+        let ack = master.parse().await.unwrap();
+        assert_eq!(ack.name, "replconf");
+        ack.args
+    }
+
+    #[tokio::test]
+    async fn drops_a_replica_past_the_output_limit() {
+        let master = Master::new("0".repeat(40));
+        master.state.lock().unwrap().output_limit = 100;
+        let (_, offset, mut reading) = master.register(1, "127.0.0.1", 6380);
+        let (_, _, mut stalled) = master.register(2, "127.0.0.1", 6381);
+        assert_eq!(offset, 0);
+        for _ in 0..10 {
+            master.feed(&[b'x'; 30]);
+            assert_eq!(reading.recv().await.unwrap().len(), 30);
+        }
+
+        // The replica which doesn't read was dropped once more than 100 bytes waited for it,
+        // and what was left in its stream isn't sent
+        let ports: Vec<u16> = master
+            .replicas()
+            .iter()
+            .map(|replica| replica.port)
+            .collect();
+        assert_eq!(ports, [6380]);
+        assert!(stalled.recv().await.is_none());
+        // The backlog still holds the writes it missed, to continue from once it is back
+        assert_eq!(master.backlog_range(), Some((1, 300)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn full_resync_sends_the_dataset_then_the_writes() {
+        let config = Config::new(6379, None);
+        let dbs = config.databases.all();
+        dbs[2]
+            .write()
+            .unwrap()
+            .set("before".to_string(), string("1"));
+        let replid = config.replication.master.replid();
+        // The stream of the replica starts at the offset the earlier writes reached
+        config.propagate(2, vec![vec![b"SET".to_vec(), b"x".to_vec(), b"y".to_vec()]]);
+        let offset = config.replication.master.offset();
+
+        let sync = psync(7, peer(6380, false), "?", "-1", &dbs, &config).unwrap();
+        let (mut replica, served) = serve(&config, sync).await;
+        assert_eq!(
+            replica.read_line().await.unwrap(),
+            format!("+FULLRESYNC {} {}", replid, offset)
+        );
+        let len: usize = replica.read_line().await.unwrap()[1..].parse().unwrap();
+        let snapshot = rdb::decode(&replica.read_exact(len).await.unwrap()).unwrap();
+        let keys: Vec<(usize, &str)> = snapshot
+            .entries
+            .iter()
+            .map(|(db, key, _)| (*db, key.as_str()))
+            .collect();
+        assert_eq!(keys, [(2, "before")]);
+
+        // The stream starts with a SELECT, the replica loaded the dataset in database 0
+        config.propagate(2, vec![vec![b"DEL".to_vec(), b"before".to_vec()]]);
+        let expected = aof::encode_commands(&[
+            vec![b"SELECT".to_vec(), b"2".to_vec()],
+            vec![b"DEL".to_vec(), b"before".to_vec()],
+        ]);
+        assert_eq!(replica.read_exact(expected.len()).await.unwrap(), expected);
+
+        // The replica's acknowledgements move its offset
+        let master = &config.replication.master;
+        let acked = master.offset();
+        replica
+            .write(RespFrame::Array(
+                ["REPLCONF", "ACK", &acked.to_string()]
+                    .map(|arg| RespFrame::BulkString(arg.to_string()))
+                    .to_vec(),
+            ))
+            .await
+            .unwrap();
+        while master.acknowledged(acked, false) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let replicas = master.replicas();
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].port, 6380);
+        assert_eq!(replicas[0].offset, acked);
+        assert_eq!(replicas[0].state.name(), "online");
+
+        // and the replica is dropped once its connection is gone
+        drop(replica);
+        served.await.unwrap().unwrap();
+        assert!(master.replicas().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replica_loads_the_dataset_then_applies_the_stream() {
+        let dir = std::env::temp_dir().join(format!("replica-sync-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::new(6379, None);
+        config.persistence.set_dir(dir.to_str().unwrap());
+        let dbs = config.databases.all();
+        dbs[0]
+            .write()
+            .unwrap()
+            .set("stale".to_string(), string("1"));
+        let own_replid = config.replication.master.replid();
+
+        // The replica asks to continue its own history first
+        let (mut master, psync, following) = follow(&config).await;
+        assert_eq!(psync, [own_replid, "1".to_string()]);
+
+        let replid = "a".repeat(40);
+        let snapshot = rdb::Snapshot {
+            entries: vec![(0, "synced".to_string(), string("1"))],
+            libraries: Vec::new(),
+        };
+        let rdb = rdb::encode(&snapshot, "7.2.0");
+        let mut payload = format!("+FULLRESYNC {} 100\r\n${}\r\n", replid, rdb.len()).into_bytes();
+        payload.extend_from_slice(&rdb);
+        let stream = commands(&[&["SELECT", "1"], &["SET", "k", "v"]]);
+        payload.extend_from_slice(&stream);
+        payload.extend_from_slice(&commands(&[&["REPLCONF", "GETACK", "*"]]));
+        master.write_raw(&payload).await.unwrap();
+
+        // GETACK is answered with the offset of what came before it
+        let offset = 100 + stream.len() as u64;
+        assert_eq!(
+            ack(&mut master).await[..2],
+            ["ACK".to_string(), offset.to_string()]
+        );
+        assert!(!dbs[0].read().unwrap().exists("stale"));
+        assert!(dbs[0].read().unwrap().exists("synced"));
+        assert!(dbs[1].read().unwrap().exists("k"));
+        // The replica took over the history of its master, and stored the file it sent
+        let replication = &config.replication;
+        assert_eq!(replication.master.replid(), replid);
+        assert!(replication.master.offset() >= offset);
+        assert_eq!(replication.upstream.status().name(), "connected");
+        assert!(dir.join("dump.rdb").exists());
+
+        drop(master);
+        assert!(following.await.unwrap().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn psync_continues_from_the_backlog() {
+        let config = Config::new(6379, None);
+        let dbs = config.databases.all();
+        let master = &config.replication.master;
+        let psync = |replid: &str, offset: u64| {
+            let sync = psync(
+                9,
+                peer(6380, false),
+                replid,
+                &offset.to_string(),
+                &dbs,
+                &config,
+            );
+            answer(sync.unwrap())
+        };
+        let set = |value: &str| {
+            let argv = vec![b"SET".to_vec(), b"k".to_vec(), value.as_bytes().to_vec()];
+            config.propagate(0, vec![argv]);
+        };
+
+        // The backlog starts along with the first replica
+        let replid = master.replid();
+        assert_eq!(psync(&replid, 1).1, None);
+        set("1");
+        let start = master.offset() + 1;
+        set("2");
+        set("3");
+        let missed = commands(&[&["SET", "k", "2"], &["SET", "k", "3"]]);
+        assert_eq!(
+            psync(&replid, start),
+            (format!("CONTINUE {}", replid), Some(missed))
+        );
+        assert_eq!(
+            psync(&replid, master.offset() + 1),
+            (format!("CONTINUE {}", replid), Some(Vec::new()))
+        );
+        // Anything the backlog doesn't hold, or another history, takes a full resync
+        let offset = master.offset();
+        let full = format!("FULLRESYNC {} {}", replid, offset);
+        assert_eq!(psync(&replid, offset + 2), (full.clone(), None));
+        assert_eq!(psync(&"b".repeat(40), start), (full.clone(), None));
+        master.set_backlog_size(10);
+        assert_eq!(psync(&replid, start), (full, None));
+        assert_eq!(master.backlog_range(), Some((offset - 9, 10)));
+        assert!(psync(&replid, offset - 9).1.is_some());
+
+        // Once promoted, the replicas of the previous history continue up to where it ended
+        master.set_backlog_size(1024);
+        promote(&config);
+        let promoted = master.replid();
+        set("4");
+        let continued = format!("CONTINUE {}", promoted);
+        assert_eq!(psync(&replid, offset + 1).0, continued);
+        assert_eq!(psync(&promoted, offset + 1).0, continued);
+        assert!(psync(&replid, offset + 2).0.starts_with("FULLRESYNC"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replica_continues_its_history_with_a_new_id() {
+        let config = Config::new(6379, None);
+        let master = &config.replication.master;
+        let replid = "a".repeat(40);
+        master.follow(replid.clone(), 100);
+        let (mut link, psync, following) = follow(&config).await;
+        assert_eq!(psync, [replid.clone(), "101".to_string()]);
+
+        // The master was promoted since, the replica keeps its offset along with the old ID
+        let promoted = "b".repeat(40);
+        let stream = commands(&[&["SET", "k", "v"]]);
+        let mut payload = format!("+CONTINUE {}\r\n", promoted).into_bytes();
+        payload.extend_from_slice(&stream);
+        payload.extend_from_slice(&commands(&[&["REPLCONF", "GETACK", "*"]]));
+        link.write_raw(&payload).await.unwrap();
+        let offset = 100 + stream.len() as u64;
+        assert_eq!(ack(&mut link).await[1], offset.to_string());
+        assert!(config.databases.all()[0].read().unwrap().exists("k"));
+        assert_eq!(master.replid(), promoted);
+        assert_eq!(master.replid2(), replid);
+        assert_eq!(master.second_offset(), 101);
+
+        drop(link);
+        assert!(following.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_counts_the_replicas_which_acknowledged() {
+        let config = Config::new(6379, None);
+        let master = config.replication.master.clone();
+        let (_, _, mut first) = master.register(1, "127.0.0.1", 6380);
+        let (_, _, _second) = master.register(2, "127.0.0.1", 6381);
+        config.propagate(0, vec![vec![b"DEL".to_vec(), b"k".to_vec()]]);
+        let offset = master.offset();
+        let waiting = |replicas: usize, aof: bool, timeout: Option<u64>| Wait {
+            offset,
+            local: aof,
+            replicas,
+            aof,
+            timeout: timeout.map(Duration::from_millis),
+        };
+
+        // Without acknowledgements WAIT times out, having asked the replicas for their offsets
+        let reply = wait(waiting(1, false, Some(50)), &config).await;
+        assert_eq!(reply.encode(), b":0\r\n");
+        let select_del = commands(&[&["SELECT", "0"], &["DEL", "k"]]);
+        assert_eq!(first.recv().await.unwrap(), select_del);
+        let getack = commands(&[&["REPLCONF", "GETACK", "*"]]);
+        assert_eq!(first.recv().await.unwrap(), getack);
+
+        // It returns as soon as enough replicas caught up, without waiting for the others
+        let acknowledging = master.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            acknowledging.acknowledge(1, offset - 1, None);
+            acknowledging.acknowledge(1, offset, None);
+        });
+        let reply = wait(waiting(1, false, None), &config).await;
+        assert_eq!(reply.encode(), b":1\r\n");
+        let reply = wait(waiting(2, false, Some(20)), &config).await;
+        assert_eq!(reply.encode(), b":1\r\n");
+        // An older acknowledgement doesn't move the offset back
+        master.acknowledge(1, 1, None);
+        assert_eq!(master.acknowledged(offset, false), 1);
+
+        // WAITAOF counts the replicas which synced their AOF, and the local AOF which is off
+        let reply = wait(waiting(1, true, Some(20)), &config).await;
+        assert_eq!(reply.encode(), b"*2\r\n:0\r\n:0\r\n");
+        master.acknowledge(2, offset, Some(offset));
+        let reply = wait(waiting(1, true, Some(20)), &config).await;
+        assert_eq!(reply.encode(), b"*2\r\n:0\r\n:1\r\n");
+    }
+
+    #[test]
+    fn info_lists_the_connected_replicas() {
+        let config = Config::new(6379, None);
+        let master = &config.replication.master;
+        // The lines of INFO replication about the replicas and the backlog
+        let info = || -> Vec<String> {
+            config
+                .replication
+                .to_string()
+                .lines()
+                .filter(|line| line.contains("slave") || line.starts_with("repl_backlog_active"))
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(info(), ["connected_slaves:0", "repl_backlog_active:0"]);
+
+        let _first = master.register(1, "127.0.0.1", 6380);
+        let _second = master.register(2, "10.0.0.2", 6381);
+        master.feed(b"0123456789");
+        master.set_replica_state(1, ReplicaState::Online);
+        master.acknowledge(1, 10, None);
+        assert_eq!(
+            info(),
+            [
+                "connected_slaves:2",
+                "slave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0",
+                "slave1:ip=10.0.0.2,port=6381,state=wait_bgsave,offset=0,lag=0",
+                "repl_backlog_active:1",
+            ]
+        );
+
+        // A replica is no longer counted once its connection is gone
+        master.unregister(1);
+        assert_eq!(
+            info(),
+            [
+                "connected_slaves:1",
+                "slave0:ip=10.0.0.2,port=6381,state=wait_bgsave,offset=0,lag=0",
+                "repl_backlog_active:1",
+            ]
+        );
+        master.disconnect_replicas();
+        assert_eq!(info()[0], "connected_slaves:0");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn diskless_sync_sends_one_copy_to_the_replicas_waiting() {
+        let config = Config::new(6379, None);
+        let dbs = config.databases.all();
+        dbs[0].write().unwrap().set("k".to_string(), string("v"));
+        let master = &config.replication.master;
+        master.set_diskless_sync(true);
+        master.set_diskless_sync_delay(0);
+
+        // Both replicas arrive before the transfer starts, the one which can't read a file
+        // between EOF marks is sent it from disk as usual
+        let first = psync(1, peer(6380, true), "?", "-1", &dbs, &config).unwrap();
+        let second = psync(2, peer(6381, true), "?", "-1", &dbs, &config).unwrap();
+        let legacy = psync(3, peer(6382, false), "?", "-1", &dbs, &config).unwrap();
+        assert!(matches!(
+            first.transfer,
+            Transfer::Diskless { leader: true, .. }
+        ));
+        assert!(matches!(
+            second.transfer,
+            Transfer::Diskless { leader: false, .. }
+        ));
+        assert!(answer(legacy).0.starts_with("FULLRESYNC"));
+
+        let (mut marks, mut replicas) = (Vec::new(), Vec::new());
+        for sync in [first, second] {
+            let (mut replica, _) = serve(&config, sync).await;
+            let reply = replica.read_line().await.unwrap();
+            assert_eq!(reply, format!("+FULLRESYNC {} 0", master.replid()));
+            let mark = replica.read_line().await.unwrap()["$EOF:".len()..].to_string();
+            assert_eq!(mark.len(), EOF_MARK_LEN);
+            let rdb = replica.read_until(mark.as_bytes()).await.unwrap();
+            assert_eq!(rdb::decode(&rdb).unwrap().entries.len(), 1);
+            marks.push(mark);
+            replicas.push(replica);
+        }
+        assert_eq!(marks[0], marks[1]);
+        let ports: Vec<u16> = master
+            .replicas()
+            .iter()
+            .map(|replica| replica.port)
+            .collect();
+        assert_eq!(ports, [6382, 6380, 6381]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replica_loads_a_diskless_transfer_from_memory() {
+        let dir = std::env::temp_dir().join(format!("replica-diskless-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::new(6379, None);
+        config.persistence.set_dir(dir.to_str().unwrap());
+        config
+            .replication
+            .upstream
+            .set_diskless_load(DisklessLoad::Swapdb);
+        let (mut link, _, following) = follow(&config).await;
+
+        let snapshot = rdb::Snapshot {
+            entries: vec![(3, "synced".to_string(), string("1"))],
+            libraries: Vec::new(),
+        };
+        let mark = "m".repeat(EOF_MARK_LEN);
+        let mut payload =
+            format!("+FULLRESYNC {} 0\r\n$EOF:{}\r\n", "a".repeat(40), mark).into_bytes();
+        payload.extend_from_slice(&rdb::encode(&snapshot, "7.2.0"));
+        payload.extend_from_slice(mark.as_bytes());
+        payload.extend_from_slice(&commands(&[&["REPLCONF", "GETACK", "*"]]));
+        link.write_raw(&payload).await.unwrap();
+
+        assert_eq!(ack(&mut link).await[1], "0");
+        assert!(config.databases.all()[3].read().unwrap().exists("synced"));
+        // Nothing was written to disk on the way
+        assert!(!dir.join("dump.rdb").exists());
+
+        drop(link);
+        assert!(following.await.unwrap().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    cluster::{self, Cluster, Node},
+    config::Config,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+    slot::key_hash_slot,
+};
+
+// CLUSTER implementation, only known to a server started with --cluster-enabled yes
+pub struct ClusterCommand {
+    args: Vec<String>,
+}
+
+impl ClusterCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for ClusterCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let Some(cluster) = &config.cluster else {
+            return Err(anyhow!("ERR This instance has cluster support disabled"));
+        };
+        let subcommand = self.args[0].to_lowercase();
+        let args = &self.args[1..];
+        let wrong_arity = || {
+            anyhow!(
+                "ERR wrong number of arguments for 'cluster|{}' command",
+                subcommand
+            )
+        };
+
+        match subcommand.as_str() {
+            "keyslot" => {
+                if args.len() != 1 {
+                    return Err(wrong_arity());
+                }
+                Ok(RespFrame::Integer(key_hash_slot(args[0].as_bytes()) as i64))
+            }
+            "myid" => {
+                if !args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                Ok(RespFrame::BulkString(cluster.myid()))
+            }
+            "info" => {
+                if !args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                Ok(RespFrame::BulkString(cluster.info()))
+            }
+            "nodes" => {
+                if !args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                Ok(RespFrame::BulkString(cluster.nodes_text()))
+            }
+            "slots" => {
+                if !args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                Ok(slots_frame(cluster))
+            }
+            "shards" => {
+                if !args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                Ok(shards_frame(cluster))
+            }
+            "addslots" | "delslots" => {
+                if args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                let slots = args
+                    .iter()
+                    .map(|slot| cluster::parse_slot(slot))
+                    .collect::<anyhow::Result<Vec<u16>>>()?;
+                if subcommand == "addslots" {
+                    cluster.add_slots(&slots)?;
+                } else {
+                    cluster.del_slots(&slots)?;
+                }
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            // The RANGE variants take pairs of first and last slots
+            "addslotsrange" | "delslotsrange" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(wrong_arity());
+                }
+                let mut slots = Vec::new();
+                for pair in args.chunks(2) {
+                    let (start, end) = (
+                        cluster::parse_slot(&pair[0])?,
+                        cluster::parse_slot(&pair[1])?,
+                    );
+                    if start > end {
+                        return Err(anyhow!(
+                            "ERR start slot number {} is greater than end slot number {}",
+                            start,
+                            end
+                        ));
+                    }
+                    slots.extend(start..=end);
+                }
+                if subcommand == "addslotsrange" {
+                    cluster.add_slots(&slots)?;
+                } else {
+                    cluster.del_slots(&slots)?;
+                }
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            // CLUSTER MEET ip port [cluster-bus-port]
+            "meet" => {
+                if args.len() != 2 && args.len() != 3 {
+                    return Err(wrong_arity());
+                }
+                let port = |value: &String| {
+                    value
+                        .parse::<u16>()
+                        .map_err(|_| anyhow!("ERR Invalid base port specified: {}", value))
+                };
+                let base = port(&args[1])?;
+                let bus = match args.get(2) {
+                    Some(bus) => port(bus)?,
+                    None => base
+                        .checked_add(cluster::BUS_PORT_OFFSET)
+                        .ok_or_else(|| anyhow!("ERR Invalid base port specified: {}", base))?,
+                };
+                cluster.meet(&args[0], base, bus)?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "replicate" => {
+                if args.len() != 1 {
+                    return Err(wrong_arity());
+                }
+                let empty = db
+                    .read()
+                    .map_err(|_| anyhow!("Unable to acquire lock"))?
+                    .iter()
+                    .all(|(_, data)| data.expired());
+                cluster.replicate(&args[0], empty, config)?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            // CLUSTER SETSLOT slot IMPORTING node | MIGRATING node | STABLE | NODE node
+            "setslot" => {
+                if args.len() < 2 {
+                    return Err(wrong_arity());
+                }
+                let slot = cluster::parse_slot(&args[0])?;
+                match (args[1].to_lowercase().as_str(), &args[2..]) {
+                    ("migrating", [id]) => cluster.set_migrating(slot, id)?,
+                    ("importing", [id]) => cluster.set_importing(slot, id)?,
+                    ("stable", []) => cluster.set_stable(slot)?,
+                    ("node", [id]) => {
+                        let holds_keys = !keys_in_slot(db, slot, 1)?.is_empty();
+                        cluster.set_slot_node(slot, id, holds_keys)?
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                        ));
+                    }
+                }
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "countkeysinslot" => {
+                if args.len() != 1 {
+                    return Err(wrong_arity());
+                }
+                let slot = cluster::parse_slot(&args[0])?;
+                Ok(RespFrame::Integer(
+                    keys_in_slot(db, slot, usize::MAX)?.len() as i64,
+                ))
+            }
+            "getkeysinslot" => {
+                if args.len() != 2 {
+                    return Err(wrong_arity());
+                }
+                let slot = cluster::parse_slot(&args[0])?;
+                let count = args[1]
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("ERR Invalid number of keys"))?;
+                Ok(RespFrame::Array(
+                    keys_in_slot(db, slot, count)?
+                        .into_iter()
+                        .map(RespFrame::BulkString)
+                        .collect(),
+                ))
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'cluster' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Up to `count` of the keys living in `slot`
+fn keys_in_slot(db: &RwLock<MemDB<Data>>, slot: u16, count: usize) -> anyhow::Result<Vec<String>> {
+    let d = db.read().map_err(|_| anyhow!("Unable to acquire lock"))?;
+    Ok(d.iter()
+        .filter(|(key, data)| !data.expired() && key_hash_slot(key.as_bytes()) == slot)
+        .take(count)
+        .map(|(key, _)| key.clone())
+        .collect())
+}
+
+// CLUSTER SLOTS lists each range of slots with its master first, then its replicas
+fn slots_frame(cluster: &Cluster) -> RespFrame {
+    let nodes = cluster.nodes();
+    let node_frame = |node: &Node| {
+        RespFrame::Array(vec![
+            RespFrame::BulkString(node.ip.clone()),
+            RespFrame::Integer(node.port as i64),
+            RespFrame::BulkString(node.id.clone()),
+        ])
+    };
+    RespFrame::Array(
+        cluster
+            .ranges()
+            .into_iter()
+            .map(|(start, end, owner)| {
+                let mut entry = vec![
+                    RespFrame::Integer(start as i64),
+                    RespFrame::Integer(end as i64),
+                ];
+                entry.extend(nodes.iter().filter(|node| node.id == owner).map(node_frame));
+                entry.extend(
+                    nodes
+                        .iter()
+                        .filter(|node| node.master_id.as_deref() == Some(owner.as_str()))
+                        .map(node_frame),
+                );
+                RespFrame::Array(entry)
+            })
+            .collect(),
+    )
+}
+
+// CLUSTER SHARDS describes each master with its replicas, and the slots they serve, as flat
+// lists of field names and values
+fn shards_frame(cluster: &Cluster) -> RespFrame {
+    let nodes = cluster.nodes();
+    let node_frame = |node: &Node| {
+        RespFrame::Array(vec![
+            RespFrame::BulkString("id".to_string()),
+            RespFrame::BulkString(node.id.clone()),
+            RespFrame::BulkString("port".to_string()),
+            RespFrame::Integer(node.port as i64),
+            RespFrame::BulkString("ip".to_string()),
+            RespFrame::BulkString(node.ip.clone()),
+            RespFrame::BulkString("endpoint".to_string()),
+            RespFrame::BulkString(node.ip.clone()),
+            RespFrame::BulkString("role".to_string()),
+            RespFrame::BulkString(
+                if node.is_master() {
+                    "master"
+                } else {
+                    "replica"
+                }
+                .to_string(),
+            ),
+            RespFrame::BulkString("health".to_string()),
+            RespFrame::BulkString(
+                if node.pfail || node.fail {
+                    "fail"
+                } else {
+                    "online"
+                }
+                .to_string(),
+            ),
+        ])
+    };
+    RespFrame::Array(
+        nodes
+            .iter()
+            .filter(|node| node.is_master())
+            .map(|master| {
+                let slots = cluster
+                    .ranges_of(&master.id)
+                    .into_iter()
+                    .flat_map(|(start, end)| {
+                        [
+                            RespFrame::Integer(start as i64),
+                            RespFrame::Integer(end as i64),
+                        ]
+                    })
+                    .collect();
+                let mut members = vec![node_frame(master)];
+                members.extend(
+                    nodes
+                        .iter()
+                        .filter(|node| node.master_id.as_deref() == Some(master.id.as_str()))
+                        .map(node_frame),
+                );
+                RespFrame::Array(vec![
+                    RespFrame::BulkString("slots".to_string()),
+                    RespFrame::Array(slots),
+                    RespFrame::BulkString("nodes".to_string()),
+                    RespFrame::Array(members),
+                ])
+            })
+            .collect(),
+    )
+}
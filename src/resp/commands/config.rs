@@ -0,0 +1,91 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::{Config, PARAMETERS},
+    glob,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// CONFIG GET / CONFIG SET implementation
+pub struct ConfigCommand {
+    args: Vec<String>,
+}
+
+impl ConfigCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for ConfigCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        match self.args[0].to_lowercase().as_str() {
+            "get" => {
+                // Every argument is a glob pattern matched against the parameter names
+                let mut values = Vec::new();
+                for name in PARAMETERS {
+                    let matched = self.args[1..].iter().any(|pattern| {
+                        glob::matches(pattern.to_lowercase().as_bytes(), name.as_bytes())
+                    });
+                    if matched && let Some(value) = config.get_parameter(name) {
+                        values.push(RespFrame::BulkString(name.to_string()));
+                        values.push(RespFrame::BulkString(value));
+                    }
+                }
+                Ok(RespFrame::Array(values))
+            }
+            "set" => {
+                for pair in self.args[1..].chunks(2) {
+                    let name = pair[0].to_lowercase();
+                    config.set_parameter(&name, &pair[1]).map_err(|err| {
+                        anyhow!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                            name,
+                            err
+                        )
+                    })?;
+                }
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let Some(subcommand) = self.args.first() else {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'config' command"
+            ));
+        };
+
+        match subcommand.to_lowercase().as_str() {
+            "get" if self.args.len() < 2 => Err(anyhow!(
+                "ERR wrong number of arguments for 'config|get' command"
+            )),
+            "set" if self.args.len() < 3 || !(self.args.len() - 1).is_multiple_of(2) => Err(
+                anyhow!("ERR wrong number of arguments for 'config|set' command"),
+            ),
+            "set" => {
+                for pair in self.args[1..].chunks(2) {
+                    if !PARAMETERS.contains(&pair[0].to_lowercase().as_str()) {
+                        return Err(anyhow!(
+                            "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                            pair[0]
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
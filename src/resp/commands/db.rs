@@ -0,0 +1,235 @@
+use std::sync::RwLock;
+
+use anyhow::{Error, Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    notify::NOTIFY_GENERIC,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// DBSIZE implementation
+pub struct DbSizeCommand {
+    args: Vec<String>,
+}
+
+impl DbSizeCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for DbSizeCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, _: &Config) -> anyhow::Result<RespFrame> {
+        let d = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let size = d.iter().filter(|(_, data)| !data.expired()).count();
+        Ok(RespFrame::Integer(size as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'dbsize' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// FLUSHDB implementation
+pub struct FlushDbCommand {
+    args: Vec<String>,
+}
+
+impl FlushDbCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for FlushDbCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, _: &Config) -> anyhow::Result<RespFrame> {
+        let mut d = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let keys = d.clear();
+        if is_async(&self.args) {
+            std::thread::spawn(move || drop(keys));
+        }
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        validate_flush_mode("flushdb", &self.args)
+    }
+}
+
+// FLUSHALL implementation
+pub struct FlushAllCommand {
+    args: Vec<String>,
+}
+
+impl FlushAllCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for FlushAllCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let mut flushed = Vec::new();
+        for db in config.databases.with_current(db) {
+            let mut d = db
+                .write()
+                .map_err(|_| Error::msg("Unable to acquire lock"))?;
+            flushed.push(d.clear());
+        }
+        if is_async(&self.args) {
+            std::thread::spawn(move || drop(flushed));
+        }
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        validate_flush_mode("flushall", &self.args)
+    }
+}
+
+// FLUSHDB and FLUSHALL take an optional ASYNC or SYNC
+fn validate_flush_mode(name: &str, args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [] => Ok(()),
+        [mode] if mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync") => Ok(()),
+        [_] => Err(anyhow!("ERR syntax error")),
+        _ => Err(anyhow!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )),
+    }
+}
+
+// With ASYNC the flushed keys are freed on another thread, the client gets its reply right away
+fn is_async(args: &[String]) -> bool {
+    args.first()
+        .is_some_and(|mode| mode.eq_ignore_ascii_case("async"))
+}
+
+// SWAPDB implementation
+pub struct SwapDbCommand {
+    args: Vec<String>,
+}
+
+impl SwapDbCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for SwapDbCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        if config.cluster.is_some() {
+            return Err(anyhow!("ERR SWAPDB is not allowed in cluster mode"));
+        }
+        let first: usize = self.args[0]
+            .parse()
+            .map_err(|_| anyhow!("ERR invalid first DB index"))?;
+        let second: usize = self.args[1]
+            .parse()
+            .map_err(|_| anyhow!("ERR invalid second DB index"))?;
+
+        let dbs = config.databases.with_current(db);
+        if first >= dbs.len() || second >= dbs.len() {
+            return Err(anyhow!("ERR DB index is out of range"));
+        }
+        if first == second {
+            return Ok(RespFrame::SimpleString("OK".to_string()));
+        }
+
+        let (low, high) = (first.min(second), first.max(second));
+        let mut low = dbs[low]
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let mut high = dbs[high]
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        low.swap(&mut high);
+
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 2 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'swapdb' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// MOVE implementation
+pub struct MoveCommand {
+    args: Vec<String>,
+}
+
+impl MoveCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for MoveCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        if config.cluster.is_some() {
+            return Err(anyhow!("ERR MOVE is not allowed in cluster mode"));
+        }
+        let key = &self.args[0];
+        let target: usize = self.args[1]
+            .parse()
+            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+
+        let dbs = config.databases.with_current(db);
+        let Some(target) = dbs.get(target) else {
+            return Err(anyhow!("ERR DB index is out of range"));
+        };
+        if std::ptr::eq(*target, db) {
+            return Err(anyhow!("ERR source and destination objects are the same"));
+        }
+
+        let mut source = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let mut target = target
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        if !source.exists(key) || target.exists(key) {
+            return Ok(RespFrame::Integer(0));
+        }
+        // The key keeps its value and expiry, only the database holding it changes
+        if let Some(data) = source.remove(key) {
+            target.set(key.clone(), data);
+        }
+        config
+            .notifier
+            .notify(NOTIFY_GENERIC, "move_from", key, source.index());
+        config
+            .notifier
+            .notify(NOTIFY_GENERIC, "move_to", key, target.index());
+
+        Ok(RespFrame::Integer(1))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 2 {
+            return Err(anyhow!("ERR wrong number of arguments for 'move' command"));
+        }
+        Ok(())
+    }
+}
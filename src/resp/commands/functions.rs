@@ -0,0 +1,230 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    functions::{Library, RestorePolicy},
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// FUNCTION LOAD / DELETE / FLUSH / LIST / DUMP / RESTORE / KILL implementation
+pub struct FunctionCommand {
+    args: Vec<String>,
+    // raw holds the arguments as received, RESTORE payloads are binary
+    raw: Vec<Vec<u8>>,
+}
+
+impl FunctionCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
+    }
+
+    // LIST [LIBRARYNAME pattern] [WITHCODE]
+    fn list(&self, config: &Config) -> anyhow::Result<RespFrame> {
+        let mut pattern = None;
+        let mut with_code = false;
+        let mut idx = 1;
+        while idx < self.args.len() {
+            match self.args[idx].to_lowercase().as_str() {
+                "withcode" if !with_code => with_code = true,
+                "libraryname" if pattern.is_none() && idx + 1 < self.args.len() => {
+                    idx += 1;
+                    pattern = Some(self.args[idx].as_str());
+                }
+                _ => return Err(anyhow!("ERR Unknown argument {}", self.args[idx])),
+            }
+            idx += 1;
+        }
+
+        let libraries = config
+            .functions
+            .list(pattern)
+            .into_iter()
+            .map(|library| library_frame(library, with_code))
+            .collect();
+        Ok(RespFrame::Array(libraries))
+    }
+}
+
+impl Command for FunctionCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let ok = RespFrame::SimpleString("OK".to_string());
+        match self.args[0].to_lowercase().as_str() {
+            "load" => {
+                let replace = self.args.len() == 3;
+                let code = self.args.last().unwrap();
+                Ok(RespFrame::BulkString(config.functions.load(code, replace)?))
+            }
+            "delete" => {
+                config.functions.delete(&self.args[1])?;
+                config.scripting.reset_functions();
+                Ok(ok)
+            }
+            // Libraries are dropped right away, ASYNC is accepted for compatibility
+            "flush" => {
+                config.functions.flush();
+                config.scripting.reset_functions();
+                Ok(ok)
+            }
+            "list" => self.list(config),
+            "dump" => Ok(RespFrame::BulkBytes(config.functions.dump())),
+            "restore" => {
+                let policy = match self.args.get(2).map(|policy| policy.to_lowercase()) {
+                    None => RestorePolicy::Append,
+                    Some(policy) if policy == "append" => RestorePolicy::Append,
+                    Some(policy) if policy == "replace" => RestorePolicy::Replace,
+                    Some(policy) if policy == "flush" => RestorePolicy::Flush,
+                    Some(_) => return Err(anyhow!("ERR Wrong restore policy given")),
+                };
+                config.functions.restore(&self.raw[1], policy)?;
+                config.scripting.reset_functions();
+                Ok(ok)
+            }
+            "kill" => {
+                config.scripting.kill()?;
+                Ok(ok)
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try FUNCTION HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let Some(subcommand) = self.args.first() else {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'function' command"
+            ));
+        };
+
+        let subcommand = subcommand.to_lowercase();
+        let valid = match subcommand.as_str() {
+            "load" => match self.args.len() {
+                2 => true,
+                3 if self.args[1].eq_ignore_ascii_case("replace") => true,
+                3 => return Err(anyhow!("ERR Unknown option given: {}", self.args[1])),
+                _ => false,
+            },
+            "delete" => self.args.len() == 2,
+            "restore" => (2..=3).contains(&self.args.len()),
+            "dump" | "kill" => self.args.len() == 1,
+            "flush" => match self.args.get(1) {
+                None => true,
+                Some(mode) if self.args.len() == 2 => {
+                    mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync")
+                }
+                Some(_) => false,
+            },
+            _ => true,
+        };
+        if !valid {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'function|{}' command",
+                subcommand
+            ));
+        }
+        Ok(())
+    }
+}
+
+// FCALL / FCALL_RO implementation
+pub struct FCallCommand {
+    args: Vec<String>,
+    // raw holds the keys and arguments as received, functions get them byte for byte
+    raw: Vec<Vec<u8>>,
+    // read_only is set for FCALL_RO, which only runs functions flagged no-writes
+    read_only: bool,
+}
+
+impl FCallCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>, read_only: bool) -> Self {
+        Self {
+            args,
+            raw,
+            read_only,
+        }
+    }
+
+    fn numkeys(&self) -> anyhow::Result<usize> {
+        let numkeys: i64 = self.args[1]
+            .parse()
+            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+        if numkeys < 0 {
+            return Err(anyhow!("ERR Number of keys can't be negative"));
+        }
+        if numkeys as usize > self.args.len() - 2 {
+            return Err(anyhow!(
+                "ERR Number of keys can't be greater than number of args"
+            ));
+        }
+        Ok(numkeys as usize)
+    }
+}
+
+impl Command for FCallCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let numkeys = self.numkeys()?;
+        let (keys, argv) = self.raw[2..].split_at(numkeys);
+        config
+            .scripting
+            .fcall(&self.args[0], self.read_only, keys, argv, db, config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 2 {
+            let name = if self.read_only { "fcall_ro" } else { "fcall" };
+            return Err(anyhow!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+        self.numkeys()?;
+        Ok(())
+    }
+}
+
+// A library as FUNCTION LIST shows it
+fn library_frame(library: Library, with_code: bool) -> RespFrame {
+    let functions = library
+        .functions
+        .into_iter()
+        .map(|function| {
+            RespFrame::Array(vec![
+                RespFrame::BulkString("name".to_string()),
+                RespFrame::BulkString(function.name),
+                RespFrame::BulkString("description".to_string()),
+                function
+                    .description
+                    .map_or(RespFrame::NullBulkString, RespFrame::BulkString),
+                RespFrame::BulkString("flags".to_string()),
+                RespFrame::Array(
+                    function
+                        .flags
+                        .into_iter()
+                        .map(RespFrame::BulkString)
+                        .collect(),
+                ),
+            ])
+        })
+        .collect();
+
+    let mut fields = vec![
+        RespFrame::BulkString("library_name".to_string()),
+        RespFrame::BulkString(library.name),
+        RespFrame::BulkString("engine".to_string()),
+        RespFrame::BulkString("LUA".to_string()),
+        RespFrame::BulkString("functions".to_string()),
+        RespFrame::Array(functions),
+    ];
+    if with_code {
+        fields.push(RespFrame::BulkString("library_code".to_string()));
+        fields.push(RespFrame::BulkString(library.code));
+    }
+    RespFrame::Array(fields)
+}
@@ -0,0 +1,815 @@
+use std::sync::RwLock;
+
+use anyhow::{Error, Ok, anyhow};
+
+use crate::{
+    geo::{self, Shape},
+    mem::MemDB,
+    notify::{NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_NEW, NOTIFY_ZSET},
+    resp::{
+        commands::{
+            Command,
+            structs::{Data, Value},
+        },
+        frame::RespFrame,
+    },
+    zset::SortedSet,
+};
+
+const WRONGTYPE_ERR: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+// Returns the sorted set stored at key, or None when the key doesn't exist or is expired
+fn get_zset<'a>(db: &'a MemDB<Data>, key: &str) -> anyhow::Result<Option<&'a SortedSet>> {
+    match db.get(key)? {
+        Some(data) if !data.expired() => match &data.value {
+            Value::SortedSet(zset) => Ok(Some(zset)),
+            _ => Err(anyhow!(WRONGTYPE_ERR)),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn parse_float(arg: &str) -> anyhow::Result<f64> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|f| !f.is_nan())
+        .ok_or(anyhow!("ERR value is not a valid float"))
+}
+
+// Returns the number of meters in the unit
+fn parse_unit(unit: &str) -> anyhow::Result<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(anyhow!(
+            "ERR unsupported unit provided. please use M, KM, FT, MI"
+        )),
+    }
+}
+
+fn parse_position(lon: &str, lat: &str) -> anyhow::Result<(f64, f64)> {
+    let longitude = parse_float(lon)?;
+    let latitude = parse_float(lat)?;
+    if !geo::valid_position(longitude, latitude) {
+        return Err(anyhow!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            longitude,
+            latitude
+        ));
+    }
+    Ok((longitude, latitude))
+}
+
+// Formats distances the way Redis replies with them
+fn format_distance(distance: f64) -> RespFrame {
+    RespFrame::BulkString(format!("{:.4}", distance))
+}
+
+// Formats coordinates with 17 decimals, dropping trailing zeros like Redis does for long doubles
+fn format_coordinate(value: f64) -> RespFrame {
+    let formatted = format!("{:.17}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    RespFrame::BulkString(trimmed.to_string())
+}
+
+fn position_frame(score: f64) -> RespFrame {
+    let (longitude, latitude) = geo::decode_score(score);
+    RespFrame::Array(vec![
+        format_coordinate(longitude),
+        format_coordinate(latitude),
+    ])
+}
+
+// GEOADD implementation
+pub struct GeoAddCommand {
+    args: Vec<String>,
+}
+
+struct GeoAddOptions {
+    nx: bool,
+    xx: bool,
+    ch: bool,
+    // index of the first longitude argument
+    start: usize,
+}
+
+impl GeoAddCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+
+    fn options(&self) -> anyhow::Result<GeoAddOptions> {
+        let mut options = GeoAddOptions {
+            nx: false,
+            xx: false,
+            ch: false,
+            start: 1,
+        };
+        while let Some(arg) = self.args.get(options.start) {
+            match arg.to_lowercase().as_str() {
+                "nx" => options.nx = true,
+                "xx" => options.xx = true,
+                "ch" => options.ch = true,
+                _ => break,
+            }
+            options.start += 1;
+        }
+
+        if options.nx && options.xx {
+            return Err(anyhow!(
+                "ERR XX and NX options at the same time are not compatible"
+            ));
+        }
+        let remaining = self.args.len() - options.start;
+        if remaining == 0 || !remaining.is_multiple_of(3) {
+            return Err(anyhow!(
+                "ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... "
+            ));
+        }
+        Ok(options)
+    }
+}
+
+impl Command for GeoAddCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let key = &self.args[0];
+        let options = self.options()?;
+
+        // Every position is validated before anything is written
+        let mut members: Vec<(String, f64)> = Vec::new();
+        for triple in self.args[options.start..].chunks(3) {
+            let (longitude, latitude) = parse_position(&triple[0], &triple[1])?;
+            members.push((triple[2].clone(), geo::encode_score(longitude, latitude)));
+        }
+
+        let mut db_write = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let (mut zset, expires_at, existed) = match db_write.get(key)? {
+            Some(data) if !data.expired() => match &data.value {
+                Value::SortedSet(zset) => (zset.clone(), data.expires_at, true),
+                _ => return Err(anyhow!(WRONGTYPE_ERR)),
+            },
+            _ => (SortedSet::new(), None, false),
+        };
+
+        let mut added = 0;
+        let mut changed = 0;
+        for (member, score) in members {
+            match zset.score(&member) {
+                Some(_) if options.nx => {}
+                None if options.xx => {}
+                Some(old) => {
+                    if old != score {
+                        zset.insert(member, score);
+                        changed += 1;
+                    }
+                }
+                None => {
+                    zset.insert(member, score);
+                    added += 1;
+                }
+            }
+        }
+
+        if !zset.is_empty() {
+            db_write.set(
+                key.to_string(),
+                Data {
+                    value: Value::SortedSet(zset),
+                    expires_at,
+                },
+            );
+        }
+
+        if added + changed > 0 {
+            if !existed {
+                config
+                    .notifier
+                    .notify(NOTIFY_NEW, "new", key, db_write.index());
+            }
+            config
+                .notifier
+                .notify(NOTIFY_ZSET, "zadd", key, db_write.index());
+        }
+
+        if options.ch {
+            Ok(RespFrame::Integer(added + changed))
+        } else {
+            Ok(RespFrame::Integer(added))
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 4 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geoadd' command"
+            ));
+        }
+        self.options()?;
+        Ok(())
+    }
+}
+
+// GEODIST implementation
+pub struct GeoDistCommand {
+    args: Vec<String>,
+}
+
+impl GeoDistCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for GeoDistCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let unit = match self.args.get(3) {
+            Some(unit) => parse_unit(unit)?,
+            None => 1.0,
+        };
+
+        let db_read = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let Some(zset) = get_zset(&db_read, &self.args[0])? else {
+            config
+                .notifier
+                .notify(NOTIFY_KEY_MISS, "keymiss", &self.args[0], db_read.index());
+            return Ok(RespFrame::NullBulkString);
+        };
+
+        let (Some(first), Some(second)) = (zset.score(&self.args[1]), zset.score(&self.args[2]))
+        else {
+            return Ok(RespFrame::NullBulkString);
+        };
+
+        let (lon1, lat1) = geo::decode_score(first);
+        let (lon2, lat2) = geo::decode_score(second);
+        Ok(format_distance(
+            geo::distance(lon1, lat1, lon2, lat2) / unit,
+        ))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 3 && self.args.len() != 4 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geodist' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// GEOHASH implementation
+pub struct GeoHashCommand {
+    args: Vec<String>,
+}
+
+impl GeoHashCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for GeoHashCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let db_read = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let zset = get_zset(&db_read, &self.args[0])?;
+        if zset.is_none() {
+            config
+                .notifier
+                .notify(NOTIFY_KEY_MISS, "keymiss", &self.args[0], db_read.index());
+        }
+
+        let hashes = self.args[1..]
+            .iter()
+            .map(|member| match zset.and_then(|z| z.score(member)) {
+                Some(score) => RespFrame::BulkString(geo::standard_geohash(score)),
+                None => RespFrame::NullBulkString,
+            })
+            .collect();
+        Ok(RespFrame::Array(hashes))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geohash' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// GEOPOS implementation
+pub struct GeoPosCommand {
+    args: Vec<String>,
+}
+
+impl GeoPosCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for GeoPosCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let db_read = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let zset = get_zset(&db_read, &self.args[0])?;
+        if zset.is_none() {
+            config
+                .notifier
+                .notify(NOTIFY_KEY_MISS, "keymiss", &self.args[0], db_read.index());
+        }
+
+        let positions = self.args[1..]
+            .iter()
+            .map(|member| match zset.and_then(|z| z.score(member)) {
+                Some(score) => position_frame(score),
+                None => RespFrame::NullArray,
+            })
+            .collect();
+        Ok(RespFrame::Array(positions))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geopos' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+enum Origin {
+    Member(String),
+    Position(f64, f64),
+}
+
+#[derive(PartialEq)]
+enum Sort {
+    None,
+    Asc,
+    Desc,
+}
+
+struct SearchOptions {
+    origin: Origin,
+    shape: Shape,
+    // number of meters in the unit used by the shape, distances are reported in the same unit
+    unit: f64,
+    sort: Sort,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+struct SearchResult {
+    member: String,
+    score: f64,
+    // distance from the origin in meters
+    distance: f64,
+}
+
+// Parses the GEOSEARCH arguments which follow the source key
+fn parse_search(args: &[String], store: bool) -> anyhow::Result<SearchOptions> {
+    let mut origin: Option<Origin> = None;
+    let mut shape: Option<(Shape, f64)> = None;
+    let mut options = SearchOptions {
+        origin: Origin::Position(0.0, 0.0),
+        shape: Shape::Radius(0.0),
+        unit: 1.0,
+        sort: Sort::None,
+        count: None,
+        any: false,
+        with_coord: false,
+        with_dist: false,
+        with_hash: false,
+        store_dist: false,
+    };
+
+    let syntax_err = || anyhow!("ERR syntax error");
+    let mut i = 0;
+    while i < args.len() {
+        let remaining = args.len() - i - 1;
+        match args[i].to_lowercase().as_str() {
+            "frommember" if remaining >= 1 => {
+                if origin.is_some() {
+                    return Err(anyhow!(
+                        "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                    ));
+                }
+                origin = Some(Origin::Member(args[i + 1].clone()));
+                i += 1;
+            }
+            "fromlonlat" if remaining >= 2 => {
+                if origin.is_some() {
+                    return Err(anyhow!(
+                        "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                    ));
+                }
+                let (longitude, latitude) = parse_position(&args[i + 1], &args[i + 2])?;
+                origin = Some(Origin::Position(longitude, latitude));
+                i += 2;
+            }
+            "byradius" if remaining >= 2 => {
+                if shape.is_some() {
+                    return Err(anyhow!(
+                        "ERR exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH"
+                    ));
+                }
+                let radius = parse_float(&args[i + 1])?;
+                if radius < 0.0 {
+                    return Err(anyhow!("ERR radius cannot be negative"));
+                }
+                let unit = parse_unit(&args[i + 2])?;
+                shape = Some((Shape::Radius(radius * unit), unit));
+                i += 2;
+            }
+            "bybox" if remaining >= 3 => {
+                if shape.is_some() {
+                    return Err(anyhow!(
+                        "ERR exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH"
+                    ));
+                }
+                let width = parse_float(&args[i + 1])?;
+                let height = parse_float(&args[i + 2])?;
+                if width < 0.0 || height < 0.0 {
+                    return Err(anyhow!("ERR height or width cannot be negative"));
+                }
+                let unit = parse_unit(&args[i + 3])?;
+                shape = Some((
+                    Shape::Box {
+                        width: width * unit,
+                        height: height * unit,
+                    },
+                    unit,
+                ));
+                i += 3;
+            }
+            "asc" => options.sort = Sort::Asc,
+            "desc" => options.sort = Sort::Desc,
+            "count" if remaining >= 1 => {
+                let count: i64 = args[i + 1]
+                    .parse()
+                    .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                if count <= 0 {
+                    return Err(anyhow!("ERR COUNT must be > 0"));
+                }
+                options.count = Some(count as usize);
+                i += 1;
+                if args.get(i + 1).map(|a| a.to_lowercase()) == Some("any".to_string()) {
+                    options.any = true;
+                    i += 1;
+                }
+            }
+            "withcoord" if !store => options.with_coord = true,
+            "withdist" if !store => options.with_dist = true,
+            "withhash" if !store => options.with_hash = true,
+            "storedist" if store => options.store_dist = true,
+            "any" => {
+                return Err(anyhow!("ERR the ANY argument requires COUNT argument"));
+            }
+            _ => return Err(syntax_err()),
+        }
+        i += 1;
+    }
+
+    let Some(origin) = origin else {
+        return Err(anyhow!(
+            "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+        ));
+    };
+    let Some((shape, unit)) = shape else {
+        return Err(anyhow!(
+            "ERR exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH"
+        ));
+    };
+    options.origin = origin;
+    options.shape = shape;
+    options.unit = unit;
+
+    // Like Redis, COUNT without ANY implies the closest matches are wanted
+    if options.count.is_some() && !options.any && options.sort == Sort::None {
+        options.sort = Sort::Asc;
+    }
+    Ok(options)
+}
+
+// Runs the search over the neighbouring geohash cells, returning the matches in the requested order
+fn search(zset: &SortedSet, options: &SearchOptions) -> anyhow::Result<Vec<SearchResult>> {
+    let (longitude, latitude) = match &options.origin {
+        Origin::Position(longitude, latitude) => (*longitude, *latitude),
+        Origin::Member(member) => match zset.score(member) {
+            Some(score) => geo::decode_score(score),
+            None => return Err(anyhow!("ERR could not decode requested zset member")),
+        },
+    };
+
+    let limit = if options.any { options.count } else { None };
+    let mut results: Vec<SearchResult> = Vec::new();
+    for cell in geo::search_cells(&options.shape, longitude, latitude) {
+        if limit.is_some_and(|limit| results.len() >= limit) {
+            break;
+        }
+        let (min, max) = cell.score_range();
+        for (member, score) in zset.range_by_score(min, max) {
+            if limit.is_some_and(|limit| results.len() >= limit) {
+                break;
+            }
+            if let Some(distance) = options
+                .shape
+                .contains((longitude, latitude), geo::decode_score(score))
+            {
+                results.push(SearchResult {
+                    member: member.to_string(),
+                    score,
+                    distance,
+                });
+            }
+        }
+    }
+
+    match options.sort {
+        Sort::Asc => results.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+        Sort::Desc => results.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+        Sort::None => {}
+    }
+    if let Some(count) = options.count {
+        results.truncate(count);
+    }
+    Ok(results)
+}
+
+// GEOSEARCH implementation
+pub struct GeoSearchCommand {
+    args: Vec<String>,
+}
+
+impl GeoSearchCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for GeoSearchCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let options = parse_search(&self.args[1..], false)?;
+
+        let db_read = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let Some(zset) = get_zset(&db_read, &self.args[0])? else {
+            config
+                .notifier
+                .notify(NOTIFY_KEY_MISS, "keymiss", &self.args[0], db_read.index());
+            return Ok(RespFrame::EmptyArray);
+        };
+
+        let with_extra = options.with_coord || options.with_dist || options.with_hash;
+        let frames = search(zset, &options)?
+            .into_iter()
+            .map(|result| {
+                let member = RespFrame::BulkString(result.member);
+                if !with_extra {
+                    return member;
+                }
+
+                let mut item = vec![member];
+                if options.with_dist {
+                    item.push(format_distance(result.distance / options.unit));
+                }
+                if options.with_hash {
+                    item.push(RespFrame::Integer(result.score as i64));
+                }
+                if options.with_coord {
+                    item.push(position_frame(result.score));
+                }
+                RespFrame::Array(item)
+            })
+            .collect();
+        Ok(RespFrame::Array(frames))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 6 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geosearch' command"
+            ));
+        }
+        parse_search(&self.args[1..], false)?;
+        Ok(())
+    }
+}
+
+// GEOSEARCHSTORE implementation
+pub struct GeoSearchStoreCommand {
+    args: Vec<String>,
+}
+
+impl GeoSearchStoreCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for GeoSearchStoreCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let dest = &self.args[0];
+        let options = parse_search(&self.args[2..], true)?;
+
+        let mut db_write = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let results = match get_zset(&db_write, &self.args[1])? {
+            Some(zset) => search(zset, &options)?,
+            None => Vec::new(),
+        };
+
+        let mut stored = SortedSet::new();
+        for result in results {
+            let score = if options.store_dist {
+                result.distance / options.unit
+            } else {
+                result.score
+            };
+            stored.insert(result.member, score);
+        }
+
+        let count = stored.len() as i64;
+        if stored.is_empty() {
+            if db_write.remove(dest).is_some_and(|data| !data.expired()) {
+                config
+                    .notifier
+                    .notify(NOTIFY_GENERIC, "del", dest, db_write.index());
+            }
+        } else {
+            let existed = db_write.exists(dest);
+            db_write.set(
+                dest.to_string(),
+                Data {
+                    value: Value::SortedSet(stored),
+                    expires_at: None,
+                },
+            );
+            if !existed {
+                config
+                    .notifier
+                    .notify(NOTIFY_NEW, "new", dest, db_write.index());
+            }
+            config
+                .notifier
+                .notify(NOTIFY_ZSET, "geosearchstore", dest, db_write.index());
+        }
+        Ok(RespFrame::Integer(count))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 7 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'geosearchstore' command"
+            ));
+        }
+        parse_search(&self.args[2..], true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn run(command: &dyn Command, db: &RwLock<MemDB<Data>>, config: &Config) -> Vec<String> {
+        command.validate().unwrap();
+        flatten(command.execute(db, config).unwrap())
+    }
+
+    // The strings and integers of a reply in order, nesting left out
+    fn flatten(frame: RespFrame) -> Vec<String> {
+        match frame {
+            RespFrame::BulkString(s) | RespFrame::SimpleString(s) => vec![s],
+            RespFrame::Integer(n) => vec![n.to_string()],
+            RespFrame::Array(frames) => frames.into_iter().flat_map(flatten).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    // The Sicily examples of the Redis documentation, with the replies Redis gives
+    #[test]
+    fn searches_like_redis() {
+        let db = RwLock::new(MemDB::new());
+        let config = Config::new(6379, None);
+        let added = run(
+            &GeoAddCommand::new(args(
+                "Sicily 13.361389 38.115556 Palermo 15.087269 37.502669 Catania",
+            )),
+            &db,
+            &config,
+        );
+        assert_eq!(added, ["2"]);
+
+        let dist = GeoDistCommand::new(args("Sicily Palermo Catania"));
+        assert_eq!(run(&dist, &db, &config), ["166274.1516"]);
+        let dist = GeoDistCommand::new(args("Sicily Palermo Catania km"));
+        assert_eq!(run(&dist, &db, &config), ["166.2742"]);
+
+        let hash = GeoHashCommand::new(args("Sicily Palermo Catania"));
+        assert_eq!(run(&hash, &db, &config), ["sqc8b49rny0", "sqdtr74hyu0"]);
+        let pos = GeoPosCommand::new(args("Sicily Palermo"));
+        assert_eq!(
+            run(&pos, &db, &config),
+            ["13.36138933897018433", "38.11555639549629859"]
+        );
+
+        let search = GeoSearchCommand::new(args(
+            "Sicily FROMLONLAT 15 37 BYRADIUS 200 km ASC WITHCOORD WITHDIST",
+        ));
+        assert_eq!(
+            run(&search, &db, &config),
+            [
+                "Catania",
+                "56.4413",
+                "15.08726745843887329",
+                "37.50266842333162032",
+                "Palermo",
+                "190.4424",
+                "13.36138933897018433",
+                "38.11555639549629859",
+            ]
+        );
+
+        run(
+            &GeoAddCommand::new(args(
+                "Sicily 12.758489 38.788135 edge1 17.241510 38.788135 edge2",
+            )),
+            &db,
+            &config,
+        );
+        // The box reaches further than the radius in its corners
+        let search = GeoSearchCommand::new(args(
+            "Sicily FROMLONLAT 15 37 BYBOX 400 400 km ASC WITHDIST",
+        ));
+        assert_eq!(
+            run(&search, &db, &config),
+            [
+                "Catania", "56.4413", "Palermo", "190.4424", "edge2", "279.7403", "edge1",
+                "279.7405",
+            ]
+        );
+        let search = GeoSearchCommand::new(args("Sicily FROMMEMBER Palermo BYRADIUS 200 km DESC"));
+        assert_eq!(run(&search, &db, &config), ["Catania", "edge1", "Palermo"]);
+        let search = GeoSearchCommand::new(args(
+            "Sicily FROMLONLAT 15 37 BYRADIUS 400 km ASC COUNT 1 ANY",
+        ));
+        assert_eq!(run(&search, &db, &config).len(), 1);
+
+        let store = GeoSearchStoreCommand::new(args(
+            "near Sicily FROMLONLAT 15 37 BYRADIUS 200 km STOREDIST",
+        ));
+        assert_eq!(run(&store, &db, &config), ["2"]);
+        let d = db.read().unwrap();
+        let zset = get_zset(&d, "near").unwrap().unwrap();
+        assert_eq!(format!("{:.4}", zset.score("Catania").unwrap()), "56.4413");
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::RwLock;
+
+use anyhow::{Error, Ok, anyhow};
+
+use crate::{
+    hll::{HLL_REGISTERS, HyperLogLog, WRONGTYPE_ERR, count_registers},
+    mem::MemDB,
+    notify::{NOTIFY_NEW, NOTIFY_STRING},
+    resp::{
+        commands::{
+            Command,
+            structs::{Data, Value},
+        },
+        frame::RespFrame,
+    },
+};
+
+// Returns the HLL stored at key, or None when the key doesn't exist or is expired
+fn load_hll(db: &MemDB<Data>, key: &str) -> anyhow::Result<Option<HyperLogLog>> {
+    match db.get(key)? {
+        Some(data) if !data.expired() => match &data.value {
+            Value::String(bytes) => Ok(Some(HyperLogLog::from_bytes(bytes.clone())?)),
+            _ => Err(anyhow!(WRONGTYPE_ERR)),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Stores the HLL back at key, keeping the expiry of an existing value
+fn store_hll(db: &mut MemDB<Data>, key: &str, hll: HyperLogLog) -> anyhow::Result<()> {
+    let expires_at = match db.get(key)? {
+        Some(data) if !data.expired() => data.expires_at,
+        _ => None,
+    };
+    db.set(
+        key.to_string(),
+        Data {
+            value: Value::String(hll.into_bytes()),
+            expires_at,
+        },
+    );
+    Ok(())
+}
+
+// PFADD implementation
+pub struct PfAddCommand {
+    args: Vec<String>,
+    // raw holds the elements as received, they are hashed byte for byte
+    raw: Vec<Vec<u8>>,
+}
+
+impl PfAddCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
+    }
+}
+
+impl Command for PfAddCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let key = &self.args[0];
+        let mut db_write = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let (mut hll, created) = match load_hll(&db_write, key)? {
+            Some(hll) => (hll, false),
+            None => (HyperLogLog::new(), true),
+        };
+        let mut updated = created;
+
+        for element in &self.raw[1..] {
+            if hll.add(element)? {
+                updated = true;
+            }
+        }
+
+        if updated {
+            hll.invalidate_cache();
+            store_hll(&mut db_write, key, hll)?;
+            if created {
+                config
+                    .notifier
+                    .notify(NOTIFY_NEW, "new", key, db_write.index());
+            }
+            config
+                .notifier
+                .notify(NOTIFY_STRING, "pfadd", key, db_write.index());
+        }
+
+        Ok(RespFrame::Integer(updated as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!("ERR wrong number of arguments for 'pfadd' command"));
+        }
+        Ok(())
+    }
+}
+
+// PFCOUNT implementation
+pub struct PfCountCommand {
+    args: Vec<String>,
+}
+
+impl PfCountCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for PfCountCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        _config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        if self.args.len() == 1 {
+            // The cached cardinality is refreshed in place, which is why this takes the write lock.
+            // The value still holds the same set, so it doesn't count as a write: the key isn't
+            // touched for WATCH nor propagated.
+            let key = &self.args[0];
+            let mut db_write = db
+                .write()
+                .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+            let Some(mut hll) = load_hll(&db_write, key)? else {
+                return Ok(RespFrame::Integer(0));
+            };
+            let (card, refreshed) = hll.count()?;
+            if refreshed && let Some(data) = db_write.get_mut(key) {
+                data.value = Value::String(hll.into_bytes());
+            }
+            return Ok(RespFrame::Integer(card as i64));
+        }
+
+        // Multiple keys are merged into a temporary set of registers
+        let db_read = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        let mut max = vec![0u8; HLL_REGISTERS];
+        for key in &self.args {
+            if let Some(hll) = load_hll(&db_read, key)? {
+                for (register, value) in max.iter_mut().zip(hll.registers()?) {
+                    *register = (*register).max(value);
+                }
+            }
+        }
+
+        Ok(RespFrame::Integer(count_registers(&max) as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'pfcount' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// PFMERGE implementation
+pub struct PfMergeCommand {
+    args: Vec<String>,
+}
+
+impl PfMergeCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for PfMergeCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let dest = &self.args[0];
+        let mut db_write = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        // The destination takes part in the merge as well
+        let mut max = vec![0u8; HLL_REGISTERS];
+        let mut use_dense = false;
+        for key in &self.args {
+            if let Some(hll) = load_hll(&db_write, key)? {
+                use_dense |= hll.is_dense();
+                for (register, value) in max.iter_mut().zip(hll.registers()?) {
+                    *register = (*register).max(value);
+                }
+            }
+        }
+
+        let existing = load_hll(&db_write, dest)?;
+        let created = existing.is_none();
+        let mut hll = existing.unwrap_or_else(HyperLogLog::new);
+        // Like Redis, the result is only dense when at least one of the inputs was dense
+        if use_dense {
+            hll.make_dense()?;
+        }
+        for (index, value) in max.into_iter().enumerate() {
+            if value != 0 {
+                hll.set(index, value)?;
+            }
+        }
+        hll.invalidate_cache();
+        store_hll(&mut db_write, dest, hll)?;
+
+        if created {
+            config
+                .notifier
+                .notify(NOTIFY_NEW, "new", dest, db_write.index());
+        }
+        // PFMERGE reports the same event as PFADD, like Redis does
+        config
+            .notifier
+            .notify(NOTIFY_STRING, "pfadd", dest, db_write.index());
+
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'pfmerge' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn bytes(db: &RwLock<MemDB<Data>>, key: &str) -> Vec<u8> {
+        match &db.read().unwrap().get(key).unwrap().unwrap().value {
+            Value::String(bytes) => bytes.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn counting_caches_without_modifying_the_key() {
+        let db = RwLock::new(MemDB::new());
+        let config = Config::new(6379, None);
+        let elements = ["hll", "a", "b", "c"];
+        PfAddCommand::new(
+            elements.iter().map(|arg| arg.to_string()).collect(),
+            elements.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
+        )
+        .execute(&db, &config)
+        .unwrap();
+        // The cardinality isn't cached yet, the most significant bit of its last byte says so
+        assert_eq!(bytes(&db, "hll")[15] & 0x80, 0x80);
+
+        db.write().unwrap().watch("hll", 1);
+        let changes = db.read().unwrap().changes();
+        let count = PfCountCommand::new(vec!["hll".to_string()])
+            .execute(&db, &config)
+            .unwrap();
+        assert!(matches!(count, RespFrame::Integer(3)));
+
+        assert_eq!(bytes(&db, "hll")[8..16], [3, 0, 0, 0, 0, 0, 0, 0]);
+        let db = db.read().unwrap();
+        assert!(!db.is_dirty(1));
+        assert_eq!(db.changes(), changes);
+    }
+}
@@ -11,10 +11,14 @@ impl InfoCommand {
 impl Command for InfoCommand {
     fn execute(
         &self,
-        _: &std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>,
+        db: &std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>,
         config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
-        let response = config.to_string();
+        let mut response = config.to_string();
+        // A sentinel holds no keys
+        if config.sentinel.is_none() {
+            response.push_str(&keyspace(&config.databases.with_current(db)));
+        }
         Ok(crate::resp::frame::RespFrame::BulkString(response))
     }
 
@@ -22,3 +26,26 @@ impl Command for InfoCommand {
         Ok(())
     }
 }
+
+// The keyspace section lists the keys and keys with an expiry of every database holding any
+fn keyspace(dbs: &[&std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>]) -> String {
+    let mut section = "# Keyspace\r\n".to_string();
+    for db in dbs {
+        let Ok(db) = db.read() else {
+            continue;
+        };
+        let live = db.iter().filter(|(_, data)| !data.expired());
+        let (keys, expires) = live.fold((0, 0), |(keys, expires), (_, data)| {
+            (keys + 1, expires + data.expires_at.is_some() as usize)
+        });
+        if keys > 0 {
+            section.push_str(&format!(
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                db.index(),
+                keys,
+                expires
+            ));
+        }
+    }
+    section
+}
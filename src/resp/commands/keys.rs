@@ -0,0 +1,207 @@
+use std::sync::RwLock;
+
+use anyhow::{Error, Ok, anyhow};
+use rand::seq::IteratorRandom;
+
+use crate::{
+    config::Config,
+    glob,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// COUNT when SCAN isn't given one
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// Buckets a single SCAN call walks at most whatever its COUNT, so the database isn't held
+// for long
+const MAX_SCAN_BUCKETS: usize = 1 << 20;
+
+// Types SCAN can filter on
+const TYPE_NAMES: &[&str] = &["string", "list", "zset", "set", "hash"];
+
+// Random picks RANDOMKEY tries before looking through every key, when most have expired
+const RANDOM_ATTEMPTS: usize = 100;
+
+// SCAN implementation
+pub struct ScanCommand {
+    args: Vec<String>,
+}
+
+// The options following the SCAN cursor
+struct ScanOptions<'a> {
+    pattern: Option<&'a str>,
+    count: usize,
+    kind: Option<String>,
+}
+
+impl ScanCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+
+    fn options(&self) -> anyhow::Result<ScanOptions<'_>> {
+        let mut options = ScanOptions {
+            pattern: None,
+            count: DEFAULT_SCAN_COUNT,
+            kind: None,
+        };
+        let mut args = self.args[1..].iter();
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| anyhow!("ERR syntax error"))?;
+            match option.to_lowercase().as_str() {
+                "match" => options.pattern = Some(value),
+                "count" => {
+                    options.count = value
+                        .parse()
+                        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                    if options.count < 1 {
+                        return Err(anyhow!("ERR syntax error"));
+                    }
+                }
+                "type" => {
+                    let kind = value.to_lowercase();
+                    if !TYPE_NAMES.contains(&kind.as_str()) {
+                        return Err(anyhow!("ERR unknown type name '{}'", value));
+                    }
+                    options.kind = Some(kind);
+                }
+                _ => return Err(anyhow!("ERR syntax error")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl Command for ScanCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, _: &Config) -> anyhow::Result<RespFrame> {
+        let mut cursor: u64 = self.args[0]
+            .parse()
+            .map_err(|_| anyhow!("ERR invalid cursor"))?;
+        let options = self.options()?;
+
+        let d = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        // COUNT is only a hint of how much work to do: buckets are visited until that many
+        // keys were collected, giving up after ten times as many buckets in a sparse table
+        let mut keys = Vec::new();
+        let mut buckets = options.count.saturating_mul(10).min(MAX_SCAN_BUCKETS);
+        loop {
+            cursor = d.scan(cursor, |key, data| {
+                if !data.expired() {
+                    keys.push((key.clone(), data.value.type_name()));
+                }
+            });
+            buckets -= 1;
+            if cursor == 0 || buckets == 0 || keys.len() >= options.count {
+                break;
+            }
+        }
+
+        // The filters apply after the buckets were walked, so a reply may hold fewer keys
+        // than COUNT, or none, while the iteration goes on
+        let keys = keys
+            .into_iter()
+            .filter(|(key, _)| {
+                options
+                    .pattern
+                    .is_none_or(|pattern| glob::matches(pattern.as_bytes(), key.as_bytes()))
+            })
+            .filter(|(_, kind)| options.kind.as_deref().is_none_or(|wanted| wanted == *kind))
+            .map(|(key, _)| RespFrame::BulkString(key))
+            .collect();
+
+        Ok(RespFrame::Array(vec![
+            RespFrame::BulkString(cursor.to_string()),
+            RespFrame::Array(keys),
+        ]))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!("ERR wrong number of arguments for 'scan' command"));
+        }
+        self.options()?;
+        Ok(())
+    }
+}
+
+// KEYS implementation
+pub struct KeysCommand {
+    args: Vec<String>,
+}
+
+impl KeysCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for KeysCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, _: &Config) -> anyhow::Result<RespFrame> {
+        let pattern = self.args[0].as_bytes();
+        let d = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let keys = d
+            .iter()
+            .filter(|(key, data)| !data.expired() && glob::matches(pattern, key.as_bytes()))
+            .map(|(key, _)| RespFrame::BulkString(key.clone()))
+            .collect();
+        Ok(RespFrame::Array(keys))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 1 {
+            return Err(anyhow!("ERR wrong number of arguments for 'keys' command"));
+        }
+        Ok(())
+    }
+}
+
+// RANDOMKEY implementation
+pub struct RandomKeyCommand {
+    args: Vec<String>,
+}
+
+impl RandomKeyCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for RandomKeyCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, _: &Config) -> anyhow::Result<RespFrame> {
+        let d = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let picked = (0..RANDOM_ATTEMPTS)
+            .map_while(|_| d.random())
+            .find(|(_, data)| !data.expired())
+            .or_else(|| {
+                d.iter()
+                    .filter(|(_, data)| !data.expired())
+                    .choose(&mut rand::rng())
+            });
+        Ok(match picked {
+            Some((key, _)) => RespFrame::BulkString(key.clone()),
+            None => RespFrame::NullBulkString,
+        })
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'randomkey' command"
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 use std::{
     sync::RwLock,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Error, Ok};
 
 use crate::{
     mem::MemDB,
+    notify::{NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_NEW, NOTIFY_STRING},
     resp::{
         commands::{
             Command,
@@ -22,7 +23,7 @@ pub struct GetCommand {
 
 impl GetCommand {
     pub fn new(args: Vec<String>) -> Self {
-        return Self { args };
+        Self { args }
     }
 }
 
@@ -30,7 +31,7 @@ impl Command for GetCommand {
     fn execute(
         &self,
         db: &RwLock<MemDB<Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
         let key = &self.args[0];
         let d = db
@@ -42,19 +43,25 @@ impl Command for GetCommand {
         match result {
             Some(value) => {
                 if value.expired() {
+                    config
+                        .notifier
+                        .notify(NOTIFY_KEY_MISS, "keymiss", key, d.index());
                     return Ok(RespFrame::NullBulkString);
                 }
 
                 match &value.value {
-                    Value::String(data) => {
-                        Ok(RespFrame::BulkString(String::from_utf8(data.clone())?))
-                    }
-                    Value::List(_) => {
-                        return Err(anyhow::anyhow!("GET command does not support List values"));
-                    }
+                    Value::String(data) => Ok(RespFrame::BulkBytes(data.clone())),
+                    _ => Err(anyhow::anyhow!(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                    )),
                 }
             }
-            None => Ok(RespFrame::Null),
+            None => {
+                config
+                    .notifier
+                    .notify(NOTIFY_KEY_MISS, "keymiss", key, d.index());
+                Ok(RespFrame::Null)
+            }
         }
     }
 
@@ -73,11 +80,13 @@ impl Command for GetCommand {
 
 pub struct SetCommand {
     args: Vec<String>,
+    // raw holds the arguments as received so binary values are stored byte for byte
+    raw: Vec<Vec<u8>>,
 }
 
 impl SetCommand {
-    pub fn new(args: Vec<String>) -> Self {
-        Self { args }
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
     }
 }
 
@@ -85,10 +94,10 @@ impl Command for SetCommand {
     fn execute(
         &self,
         db: &RwLock<MemDB<Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<RespFrame> {
         let key = self.args[0].clone();
-        let value = self.args[1].clone();
+        let value = self.raw[1].clone();
 
         let mut expires_at: Option<Instant> = None;
         if self.args.len() == 4 {
@@ -101,6 +110,9 @@ impl Command for SetCommand {
             expires_at = match expiry_type.as_str() {
                 "px" => timestamp.checked_add(Duration::from_millis(expiry_val)),
                 "ex" => timestamp.checked_add(Duration::from_secs(expiry_val)),
+                // Absolute unix times, a time in the past leaves the key already expired
+                "pxat" => Some(instant_at(expiry_val)),
+                "exat" => Some(instant_at(expiry_val.saturating_mul(1000))),
                 _ => None,
             };
         }
@@ -110,10 +122,23 @@ impl Command for SetCommand {
             .map_err(|_| Error::msg("Unable to acquire lock"))?;
 
         let data = Data {
-            value: Value::String(String::into_bytes(value.clone())),
+            value: Value::String(value),
             expires_at,
         };
-        d.set(key, data);
+        let existed = d.exists(&key);
+        d.set(key.clone(), data);
+
+        if !existed {
+            config.notifier.notify(NOTIFY_NEW, "new", &key, d.index());
+        }
+        config
+            .notifier
+            .notify(NOTIFY_STRING, "set", &key, d.index());
+        if expires_at.is_some() {
+            config
+                .notifier
+                .notify(NOTIFY_GENERIC, "expire", &key, d.index());
+        }
 
         Ok(RespFrame::SimpleString("OK".to_string()))
     }
@@ -136,3 +161,59 @@ impl Command for SetCommand {
         Ok(())
     }
 }
+
+// Converts a unix time in milliseconds to an Instant
+fn instant_at(unix_ms: u64) -> Instant {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    Instant::now() + Duration::from_millis(unix_ms.saturating_sub(now_ms))
+}
+
+// DEL implementation
+pub struct DelCommand {
+    args: Vec<String>,
+}
+
+impl DelCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for DelCommand {
+    fn execute(
+        &self,
+        db: &RwLock<MemDB<Data>>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<RespFrame> {
+        let mut d = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let mut deleted = 0;
+        for key in &self.args {
+            // Expired keys are dropped as well, but they don't count as deleted
+            if let Some(data) = d.remove(key)
+                && !data.expired()
+            {
+                config
+                    .notifier
+                    .notify(NOTIFY_GENERIC, "del", key, d.index());
+                deleted += 1;
+            }
+        }
+
+        Ok(RespFrame::Integer(deleted))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow::anyhow!(
+                "ERR wrong number of arguments for 'del' command"
+            ));
+        }
+        Ok(())
+    }
+}
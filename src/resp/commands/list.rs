@@ -1,11 +1,14 @@
 use anyhow::Ok;
 
-use crate::resp::{
-    commands::{
-        Command,
-        structs::{Data, Value},
+use crate::{
+    notify::{NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_LIST, NOTIFY_NEW},
+    resp::{
+        commands::{
+            Command,
+            structs::{Data, Value},
+        },
+        frame::RespFrame,
     },
-    frame::RespFrame,
 };
 
 // RPUSH implementaion
@@ -24,10 +27,10 @@ impl Command for ListPushCommand {
     fn execute(
         &self,
         db: &std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
         let key = &self.args[0];
-        let mut values: Vec<String> = self.args[1..].iter().map(|s| s.clone()).collect();
+        let mut values: Vec<String> = self.args[1..].to_vec();
 
         if self.reverse {
             values.reverse();
@@ -35,6 +38,7 @@ impl Command for ListPushCommand {
 
         let mut db_write = db.write().unwrap();
         let data = db_write.get(key)?;
+        let existed = data.is_some();
 
         let list_data = match data {
             Some(d) => match &d.value {
@@ -64,6 +68,16 @@ impl Command for ListPushCommand {
             },
         );
 
+        if !existed {
+            config
+                .notifier
+                .notify(NOTIFY_NEW, "new", key, db_write.index());
+        }
+        let event = if self.reverse { "lpush" } else { "rpush" };
+        config
+            .notifier
+            .notify(NOTIFY_LIST, event, key, db_write.index());
+
         let count = list_data.len() as i64;
 
         Ok(crate::resp::frame::RespFrame::Integer(count))
@@ -94,7 +108,7 @@ impl Command for ListGetCommand {
     fn execute(
         &self,
         db: &std::sync::RwLock<crate::mem::MemDB<Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
         let key = self.args[0].clone();
 
@@ -108,11 +122,11 @@ impl Command for ListGetCommand {
                     let total_items = items.len() as isize;
 
                     if start < 0 {
-                        start = total_items + start;
+                        start += total_items;
                     }
 
                     if end < 0 {
-                        end = total_items + end;
+                        end += total_items;
                     }
 
                     if start > end {
@@ -139,15 +153,18 @@ impl Command for ListGetCommand {
                         .iter()
                         .map(|item| crate::resp::frame::RespFrame::BulkString(item.clone()))
                         .collect();
-                    return Ok(crate::resp::frame::RespFrame::Array(resp_frames));
-                }
-                _ => {
-                    return Err(anyhow::anyhow!(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value"
-                    ));
+                    Ok(crate::resp::frame::RespFrame::Array(resp_frames))
                 }
+                _ => Err(anyhow::anyhow!(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value"
+                )),
             },
-            None => return Ok(crate::resp::frame::RespFrame::EmptyArray),
+            None => {
+                config
+                    .notifier
+                    .notify(NOTIFY_KEY_MISS, "keymiss", &key, db_read.index());
+                Ok(crate::resp::frame::RespFrame::EmptyArray)
+            }
         }
     }
 
@@ -183,7 +200,7 @@ impl Command for ListLengthCommand {
     fn execute(
         &self,
         db: &std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
         let key = &self.args[0];
 
@@ -198,7 +215,12 @@ impl Command for ListLengthCommand {
                     "WRONGTYPE Operation against a key holding the wrong kind of value"
                 )),
             },
-            None => Ok(crate::resp::frame::RespFrame::Integer(0)),
+            None => {
+                config
+                    .notifier
+                    .notify(NOTIFY_KEY_MISS, "keymiss", key, db_read.index());
+                Ok(crate::resp::frame::RespFrame::Integer(0))
+            }
         }
     }
 
@@ -226,7 +248,7 @@ impl Command for ListPopCommand {
     fn execute(
         &self,
         db: &std::sync::RwLock<crate::mem::MemDB<super::structs::Data>>,
-        _config: &crate::config::Config,
+        config: &crate::config::Config,
     ) -> anyhow::Result<crate::resp::frame::RespFrame> {
         let key = self.args[0].clone();
 
@@ -267,16 +289,27 @@ impl Command for ListPopCommand {
         }
 
         let remove_result = new_list.drain(remove_start..remove_end);
-        let popped_elements: Vec<RespFrame> =
-            remove_result.map(|s| RespFrame::BulkString(s)).collect();
-
-        db_write.set(
-            key,
-            Data {
-                value: Value::List(new_list),
-                expires_at,
-            },
-        );
+        let popped_elements: Vec<RespFrame> = remove_result.map(RespFrame::BulkString).collect();
+
+        config
+            .notifier
+            .notify(NOTIFY_LIST, "lpop", &key, db_write.index());
+
+        // Like Redis, a list is deleted once its last element is popped
+        if new_list.is_empty() {
+            db_write.remove(&key);
+            config
+                .notifier
+                .notify(NOTIFY_GENERIC, "del", &key, db_write.index());
+        } else {
+            db_write.set(
+                key,
+                Data {
+                    value: Value::List(new_list),
+                    expires_at,
+                },
+            );
+        }
 
         if popped_elements.len() == 1 {
             Ok(popped_elements[0].clone())
@@ -0,0 +1,478 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    aof,
+    config::Config,
+    mem::MemDB,
+    notify::NOTIFY_GENERIC,
+    rdb,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+        parser::parse_reply,
+    },
+    transaction::with_exclusive_access,
+};
+
+// RESTORE implementation, RESTORE-ASKING being the variant MIGRATE sends so that a node
+// still importing the slot of the key takes it in
+pub struct RestoreCommand {
+    args: Vec<String>,
+    raw: Vec<Vec<u8>>,
+}
+
+impl RestoreCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
+    }
+}
+
+impl Command for RestoreCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let key = &self.args[0];
+        let ttl = self.args[1]
+            .parse::<i64>()
+            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+        if ttl < 0 {
+            return Err(anyhow!("ERR Invalid TTL value, must be >= 0"));
+        }
+
+        let (mut replace, mut absolute) = (false, false);
+        let mut idx = 3;
+        while idx < self.args.len() {
+            match self.args[idx].to_lowercase().as_str() {
+                "replace" => replace = true,
+                "absttl" => absolute = true,
+                // Eviction hints, there is no eviction here
+                "idletime" | "freq" if idx + 1 < self.args.len() => {
+                    self.args[idx + 1]
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("ERR Invalid IDLETIME or FREQ value"))?;
+                    idx += 1;
+                }
+                _ => return Err(anyhow!("ERR syntax error")),
+            }
+            idx += 1;
+        }
+
+        let mut d = db.write().map_err(|_| anyhow!("Unable to acquire lock"))?;
+        if !replace && d.exists(key) {
+            return Err(anyhow!("BUSYKEY Target key name already exists."));
+        }
+        let value = rdb::restore_value(&self.raw[2], key)?;
+
+        let ttl = ttl as u64;
+        let expires_at = match (ttl, absolute) {
+            (0, _) => None,
+            (at, true) => {
+                let now_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or_default();
+                // A key whose expiry already passed is not restored at all
+                if at <= now_ms {
+                    d.remove(key);
+                    return Ok(RespFrame::SimpleString("OK".to_string()));
+                }
+                Some(Instant::now() + Duration::from_millis(at - now_ms))
+            }
+            (ttl, false) => Some(Instant::now() + Duration::from_millis(ttl)),
+        };
+        d.set(key.clone(), Data { value, expires_at });
+        config
+            .notifier
+            .notify(NOTIFY_GENERIC, "restore", key, d.index());
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 3 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'restore' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// MIGRATE implementation. Like in Redis the transfer blocks the client: keys are sent with
+// RESTORE-ASKING and only removed here once the target accepted them, and only when they were
+// left unchanged while the transfer was going on.
+pub struct MigrateCommand {
+    args: Vec<String>,
+}
+
+// What MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password]
+// [AUTH2 username password] [KEYS key [key ...]] asks for
+struct Migration {
+    host: String,
+    port: u16,
+    keys: Vec<String>,
+    db: u64,
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+    auth: Vec<String>,
+}
+
+impl MigrateCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+
+    fn migration(&self) -> anyhow::Result<Migration> {
+        let args = &self.args;
+        let integer = |arg: &String| {
+            arg.parse::<i64>()
+                .map_err(|_| anyhow!("ERR value is not an integer or out of range"))
+        };
+        let port = args[1]
+            .parse::<u16>()
+            .map_err(|_| anyhow!("ERR Invalid port"))?;
+        let db = integer(&args[3])?;
+        if db < 0 {
+            return Err(anyhow!("ERR DB index is out of range"));
+        }
+        // Like Redis, a timeout which isn't positive stands for a second
+        let timeout = integer(&args[4])?;
+        let timeout = Duration::from_millis(if timeout > 0 { timeout as u64 } else { 1000 });
+
+        let mut migration = Migration {
+            host: args[0].clone(),
+            port,
+            keys: vec![args[2].clone()],
+            db: db as u64,
+            timeout,
+            copy: false,
+            replace: false,
+            auth: Vec::new(),
+        };
+        let mut idx = 5;
+        while idx < args.len() {
+            match args[idx].to_lowercase().as_str() {
+                "copy" => migration.copy = true,
+                "replace" => migration.replace = true,
+                "auth" if idx + 1 < args.len() => {
+                    migration.auth = args[idx + 1..idx + 2].to_vec();
+                    idx += 1;
+                }
+                "auth2" if idx + 2 < args.len() => {
+                    migration.auth = args[idx + 1..idx + 3].to_vec();
+                    idx += 2;
+                }
+                "keys" => {
+                    if !args[2].is_empty() {
+                        return Err(anyhow!(
+                            "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                        ));
+                    }
+                    migration.keys = args[idx + 1..].to_vec();
+                    break;
+                }
+                _ => return Err(anyhow!("ERR syntax error")),
+            }
+            idx += 1;
+        }
+        Ok(migration)
+    }
+}
+
+impl Command for MigrateCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let migration = self.migration()?;
+
+        // The keys are dumped at once, the TTL sent being what is left of it
+        let dumps: Vec<(String, Option<Instant>, Vec<Vec<u8>>)> = {
+            let d = db.read().map_err(|_| anyhow!("Unable to acquire lock"))?;
+            let now = Instant::now();
+            migration
+                .keys
+                .iter()
+                .filter_map(|key| {
+                    let data = d.get(key).ok().flatten().filter(|data| !data.expired())?;
+                    let ttl = data
+                        .expires_at
+                        .map(|at| at.saturating_duration_since(now).as_millis().max(1) as u64)
+                        .unwrap_or(0);
+                    let mut argv = vec![
+                        b"RESTORE-ASKING".to_vec(),
+                        key.as_bytes().to_vec(),
+                        ttl.to_string().into_bytes(),
+                        rdb::dump_value(&data.value),
+                    ];
+                    if migration.replace {
+                        argv.push(b"REPLACE".to_vec());
+                    }
+                    Some((key.clone(), data.expires_at, argv))
+                })
+                .collect()
+        };
+        if dumps.is_empty() {
+            return Ok(RespFrame::SimpleString("NOKEY".to_string()));
+        }
+
+        let mut commands = Vec::new();
+        if !migration.auth.is_empty() {
+            let mut argv = vec![b"AUTH".to_vec()];
+            argv.extend(migration.auth.iter().map(|arg| arg.as_bytes().to_vec()));
+            commands.push(argv);
+        }
+        if migration.db != 0 {
+            commands.push(vec![
+                b"SELECT".to_vec(),
+                migration.db.to_string().into_bytes(),
+            ]);
+        }
+        let setup = commands.len();
+        commands.extend(dumps.iter().map(|(_, _, argv)| argv.clone()));
+        // The exchange blocks on the network, the worker thread is handed over meanwhile
+        let replies = tokio::task::block_in_place(|| exchange(&migration, &commands))?;
+
+        // The keys are ignored by the target when AUTH or SELECT failed
+        let (setup_replies, key_replies) = replies.split_at(setup);
+        if let Some(RespFrame::Error(err)) = setup_replies
+            .iter()
+            .find(|reply| matches!(reply, RespFrame::Error(_)))
+        {
+            return Err(anyhow!("ERR Target instance replied with error: {}", err));
+        }
+        let mut error = None;
+        let mut moved = Vec::new();
+        for (reply, dump) in key_replies.iter().zip(&dumps) {
+            match reply {
+                RespFrame::Error(err) => {
+                    error.get_or_insert(err.clone());
+                }
+                _ => moved.push(dump),
+            }
+        }
+
+        if !migration.copy && !moved.is_empty() {
+            with_exclusive_access(db, |db| -> anyhow::Result<()> {
+                let mut d = db.write().map_err(|_| anyhow!("Unable to acquire lock"))?;
+                let mut argv = vec![b"DEL".to_vec()];
+                for (key, expires_at, argv_sent) in moved {
+                    // A key written to since it was dumped keeps its new value here
+                    let unchanged = d.get(key).ok().flatten().is_some_and(|data| {
+                        data.expires_at == *expires_at
+                            && rdb::dump_value(&data.value) == argv_sent[3]
+                    });
+                    if unchanged && d.remove(key).is_some() {
+                        config
+                            .notifier
+                            .notify(NOTIFY_GENERIC, "del", key, d.index());
+                        argv.push(key.as_bytes().to_vec());
+                    }
+                }
+                if argv.len() > 1 {
+                    config.propagate(d.index(), vec![argv]);
+                }
+                Ok(())
+            })??;
+        }
+
+        match error {
+            Some(err) => Err(anyhow!("ERR Target instance replied with error: {}", err)),
+            None => Ok(RespFrame::SimpleString("OK".to_string())),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 5 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'migrate' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Sends the commands to the target in one go and reads a reply to each of them
+fn exchange(migration: &Migration, commands: &[Vec<Vec<u8>>]) -> anyhow::Result<Vec<RespFrame>> {
+    let addr = (migration.host.as_str(), migration.port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| anyhow!("IOERR error or timeout connecting to the client"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, migration.timeout)
+        .map_err(|_| anyhow!("IOERR error or timeout connecting to the client"))?;
+    stream.set_read_timeout(Some(migration.timeout))?;
+    stream.set_write_timeout(Some(migration.timeout))?;
+    stream
+        .write_all(&aof::encode_commands(commands))
+        .map_err(|_| anyhow!("IOERR error or timeout writing to target instance"))?;
+
+    let mut buffer = Vec::new();
+    let mut replies = Vec::new();
+    while replies.len() < commands.len() {
+        if let Some((reply, consumed)) = parse_reply(&buffer)? {
+            buffer.drain(..consumed);
+            replies.push(reply);
+            continue;
+        }
+        let mut chunk = [0u8; 4096];
+        match stream.read(&mut chunk) {
+            std::result::Result::Ok(read) if read > 0 => buffer.extend_from_slice(&chunk[..read]),
+            _ => return Err(anyhow!("IOERR error or timeout reading to target instance")),
+        }
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc, thread};
+
+    use super::*;
+    use crate::resp::{
+        commands::structs::Value,
+        parser::{Request, parse_command, parse_request},
+    };
+
+    fn string(value: &str, expires_at: Option<Instant>) -> Data {
+        Data {
+            value: Value::String(value.as_bytes().to_vec()),
+            expires_at,
+        }
+    }
+
+    fn get(db: &RwLock<MemDB<Data>>, key: &str) -> Option<(String, Option<Instant>)> {
+        let db = db.read().unwrap();
+        db.get(key).unwrap().map(|data| match &data.value {
+            Value::String(value) => (String::from_utf8_lossy(value).into_owned(), data.expires_at),
+            _ => unreachable!(),
+        })
+    }
+
+    // A target instance taking the `count` commands of one MIGRATE in, running them against
+    // `target`. `during` runs once they are received, while MIGRATE waits for the replies.
+    fn serve_target(
+        target: Arc<RwLock<MemDB<Data>>>,
+        count: usize,
+        during: impl FnOnce() + Send + 'static,
+    ) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let config = Config::new(6379, None);
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut buffer, mut requests) = (Vec::new(), Vec::new());
+            while requests.len() < count {
+                if let Some((request, consumed)) = parse_request(&buffer).unwrap() {
+                    buffer.drain(..consumed);
+                    requests.push(request);
+                    continue;
+                }
+                let mut chunk = [0u8; 4096];
+                let read = stream.read(&mut chunk).unwrap();
+                buffer.extend_from_slice(&chunk[..read]);
+            }
+            during();
+            for request in requests {
+                let reply = parse_command(Request::new(request.argv()))
+                    .and_then(|command| command.execute(&target, &config))
+                    .unwrap_or_else(|err| RespFrame::Error(err.to_string()));
+                stream.write_all(&reply.encode()).unwrap();
+            }
+        });
+        (port, handle)
+    }
+
+    fn migrate(args: &str, port: u16) -> Vec<String> {
+        args.replace("PORT", &port.to_string())
+            .split(' ')
+            .map(|arg| if arg == "\"\"" { "" } else { arg }.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn moves_keys_to_the_target() {
+        let config = Config::new(6379, None);
+        let source = RwLock::new(MemDB::new());
+        let expires_at = Instant::now() + Duration::from_secs(3600);
+        {
+            let mut db = source.write().unwrap();
+            db.set("a".to_string(), string("1", Some(expires_at)));
+            db.set("b".to_string(), string("2", None));
+            db.set("kept".to_string(), string("3", None));
+        }
+        let target = Arc::new(RwLock::new(MemDB::new()));
+
+        let (port, handle) = serve_target(target.clone(), 2, || {});
+        let command =
+            MigrateCommand::new(migrate("127.0.0.1 PORT \"\" 0 1000 KEYS a b missing", port));
+        command.validate().unwrap();
+        let reply = command.execute(&source, &config).unwrap();
+        handle.join().unwrap();
+        assert_eq!(reply.encode(), b"+OK\r\n");
+
+        assert_eq!(get(&source, "a"), None);
+        assert_eq!(get(&source, "b"), None);
+        assert!(get(&source, "kept").is_some());
+        let (value, ttl) = get(&target, "a").unwrap();
+        assert_eq!(value, "1");
+        // The TTL left is sent, so the key expires about when it would have here
+        assert!(ttl.is_some_and(|at| at <= expires_at && at + Duration::from_secs(5) > expires_at));
+        assert_eq!(get(&target, "b"), Some(("2".to_string(), None)));
+
+        // With COPY the key stays here as well, an existing one is only replaced on demand
+        let (port, handle) = serve_target(target.clone(), 1, || {});
+        let command = MigrateCommand::new(migrate("127.0.0.1 PORT kept 0 1000 COPY", port));
+        command.execute(&source, &config).unwrap();
+        handle.join().unwrap();
+        assert!(get(&source, "kept").is_some());
+        assert_eq!(get(&target, "kept"), Some(("3".to_string(), None)));
+
+        let (port, handle) = serve_target(target.clone(), 1, || {});
+        let command = MigrateCommand::new(migrate("127.0.0.1 PORT kept 0 1000", port));
+        let result = command.execute(&source, &config);
+        handle.join().unwrap();
+        assert!(result.is_err_and(|err| err.to_string().contains("BUSYKEY")));
+        assert!(get(&source, "kept").is_some());
+
+        let command = MigrateCommand::new(migrate("127.0.0.1 PORT missing 0 1000", port));
+        let reply = command.execute(&source, &config).unwrap();
+        assert_eq!(reply.encode(), b"+NOKEY\r\n");
+    }
+
+    #[test]
+    fn keeps_a_key_written_to_during_the_transfer() {
+        let config = Config::new(6379, None);
+        let source = Arc::new(RwLock::new(MemDB::new()));
+        {
+            let mut db = source.write().unwrap();
+            db.set("written".to_string(), string("old", None));
+            db.set("untouched".to_string(), string("1", None));
+        }
+        let target = Arc::new(RwLock::new(MemDB::new()));
+
+        // Another client writes the key once it was sent, before the target replies
+        let writer = source.clone();
+        let (port, handle) = serve_target(target.clone(), 2, move || {
+            writer
+                .write()
+                .unwrap()
+                .set("written".to_string(), string("new", None));
+        });
+        let command = MigrateCommand::new(migrate(
+            "127.0.0.1 PORT \"\" 0 1000 KEYS written untouched",
+            port,
+        ));
+        let reply = command.execute(&source, &config).unwrap();
+        handle.join().unwrap();
+        assert_eq!(reply.encode(), b"+OK\r\n");
+
+        // The new value stays, the target got the one sent
+        assert_eq!(get(&source, "written"), Some(("new".to_string(), None)));
+        assert_eq!(get(&target, "written"), Some(("old".to_string(), None)));
+        assert_eq!(get(&source, "untouched"), None);
+        assert_eq!(get(&target, "untouched"), Some(("1".to_string(), None)));
+    }
+}
@@ -1,9 +1,22 @@
+pub mod cluster;
 pub mod command;
+pub mod config;
+pub mod db;
 pub mod echo;
+pub mod functions;
+pub mod geo;
+pub mod hll;
 pub mod info;
+pub mod keys;
 pub mod kv;
 pub mod list;
+pub mod migrate;
+pub mod persistence;
 pub mod ping;
+pub mod pubsub;
+pub mod replication;
+pub mod scripting;
+pub mod sentinel;
 pub mod structs;
 
 pub use command::Command;
@@ -0,0 +1,134 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+    snapshot::BgSaveStatus,
+};
+
+// SAVE implementation
+pub struct SaveCommand {
+    args: Vec<String>,
+}
+
+impl SaveCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for SaveCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        config
+            .persistence
+            .save(&config.databases.with_current(db), config)?;
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!("ERR wrong number of arguments for 'save' command"));
+        }
+        Ok(())
+    }
+}
+
+// BGSAVE implementation
+pub struct BgSaveCommand {
+    args: Vec<String>,
+}
+
+impl BgSaveCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for BgSaveCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let schedule = !self.args.is_empty();
+        let reply = match config.persistence.bgsave(
+            &config.databases.with_current(db),
+            config,
+            schedule,
+        )? {
+            BgSaveStatus::Started => "Background saving started",
+            BgSaveStatus::Scheduled => "Background saving scheduled",
+        };
+        Ok(RespFrame::SimpleString(reply.to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        match self.args.as_slice() {
+            [] => Ok(()),
+            [option] if option.eq_ignore_ascii_case("schedule") => Ok(()),
+            [_] => Err(anyhow!("ERR syntax error")),
+            _ => Err(anyhow!(
+                "ERR wrong number of arguments for 'bgsave' command"
+            )),
+        }
+    }
+}
+
+// LASTSAVE implementation
+pub struct LastSaveCommand {
+    args: Vec<String>,
+}
+
+impl LastSaveCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for LastSaveCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        Ok(RespFrame::Integer(config.persistence.last_save() as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'lastsave' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// BGREWRITEAOF implementation
+pub struct BgRewriteAofCommand {
+    args: Vec<String>,
+}
+
+impl BgRewriteAofCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for BgRewriteAofCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        config
+            .aof
+            .rewrite(&config.databases.with_current(db), config)?;
+        Ok(RespFrame::SimpleString(
+            "Background append only file rewriting started".to_string(),
+        ))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'bgrewriteaof' command"
+            ));
+        }
+        Ok(())
+    }
+}
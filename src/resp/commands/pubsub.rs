@@ -0,0 +1,150 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// PUBLISH implementation
+pub struct PublishCommand {
+    args: Vec<String>,
+    // raw holds the message as received so binary payloads are delivered byte for byte
+    raw: Vec<Vec<u8>>,
+}
+
+impl PublishCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
+    }
+}
+
+impl Command for PublishCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let receivers = config.pubsub.publish(&self.args[0], &self.raw[1]);
+        Ok(RespFrame::Integer(receivers as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 2 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'publish' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// SPUBLISH implementation
+pub struct SPublishCommand {
+    args: Vec<String>,
+    // raw holds the message as received so binary payloads are delivered byte for byte
+    raw: Vec<Vec<u8>>,
+}
+
+impl SPublishCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>) -> Self {
+        Self { args, raw }
+    }
+}
+
+impl Command for SPublishCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let receivers = config.pubsub.spublish(&self.args[0], &self.raw[1]);
+        Ok(RespFrame::Integer(receivers as i64))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 2 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'spublish' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// PUBSUB CHANNELS / NUMSUB / NUMPAT / SHARDCHANNELS / SHARDNUMSUB implementation
+pub struct PubSubCommand {
+    args: Vec<String>,
+}
+
+impl PubSubCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for PubSubCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        match self.args[0].to_lowercase().as_str() {
+            "channels" => {
+                let channels = config
+                    .pubsub
+                    .channels(self.args.get(1).map(|s| s.as_str()))
+                    .into_iter()
+                    .map(RespFrame::BulkString)
+                    .collect();
+                Ok(RespFrame::Array(channels))
+            }
+            "numsub" => {
+                let mut counts = Vec::with_capacity((self.args.len() - 1) * 2);
+                for channel in &self.args[1..] {
+                    counts.push(RespFrame::BulkString(channel.clone()));
+                    counts.push(RespFrame::Integer(config.pubsub.numsub(channel) as i64));
+                }
+                Ok(RespFrame::Array(counts))
+            }
+            "numpat" => Ok(RespFrame::Integer(config.pubsub.numpat() as i64)),
+            "shardchannels" => {
+                let channels = config
+                    .pubsub
+                    .shard_channels(self.args.get(1).map(|s| s.as_str()))
+                    .into_iter()
+                    .map(RespFrame::BulkString)
+                    .collect();
+                Ok(RespFrame::Array(channels))
+            }
+            "shardnumsub" => {
+                let mut counts = Vec::with_capacity((self.args.len() - 1) * 2);
+                for channel in &self.args[1..] {
+                    counts.push(RespFrame::BulkString(channel.clone()));
+                    counts.push(RespFrame::Integer(
+                        config.pubsub.shard_numsub(channel) as i64
+                    ));
+                }
+                Ok(RespFrame::Array(counts))
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let Some(subcommand) = self.args.first() else {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'pubsub' command"
+            ));
+        };
+
+        let valid = match subcommand.to_lowercase().as_str() {
+            "channels" | "shardchannels" => self.args.len() <= 2,
+            "numpat" => self.args.len() == 1,
+            _ => true,
+        };
+        if !valid {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'pubsub|{}' command",
+                subcommand.to_lowercase()
+            ));
+        }
+        Ok(())
+    }
+}
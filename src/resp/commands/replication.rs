@@ -0,0 +1,207 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    replication::{self, LinkStatus, MasterAddr},
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// REPLICAOF / SLAVEOF implementation
+pub struct ReplicaOfCommand {
+    name: String,
+    args: Vec<String>,
+}
+
+impl ReplicaOfCommand {
+    pub fn new(name: String, args: Vec<String>) -> Self {
+        Self { name, args }
+    }
+}
+
+impl Command for ReplicaOfCommand {
+    fn execute(&self, _db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        if config.cluster.is_some() {
+            return Err(anyhow!("ERR REPLICAOF not allowed in cluster mode."));
+        }
+        let upstream = &config.replication.upstream;
+        let (host, port) = (&self.args[0], &self.args[1]);
+
+        // REPLICAOF NO ONE turns a replica into a master
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            if upstream.addr().is_some() {
+                replication::promote(config);
+            }
+            return Ok(RespFrame::SimpleString("OK".to_string()));
+        }
+
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("ERR Invalid master port"))?;
+        let addr = MasterAddr {
+            host: host.clone(),
+            port,
+        };
+        if upstream.addr().as_ref() == Some(&addr) {
+            return Ok(RespFrame::SimpleString(
+                "OK Already connected to specified master".to_string(),
+            ));
+        }
+        replication::replicate(config, addr);
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() != 2 {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for '{}' command",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+// ROLE implementation
+pub struct RoleCommand {
+    args: Vec<String>,
+}
+
+impl RoleCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for RoleCommand {
+    fn execute(&self, _db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        // A sentinel lists the masters it monitors
+        if let Some(sentinel) = &config.sentinel {
+            let names = sentinel
+                .names()
+                .into_iter()
+                .map(RespFrame::BulkString)
+                .collect();
+            return Ok(RespFrame::Array(vec![
+                RespFrame::BulkString("sentinel".to_string()),
+                RespFrame::Array(names),
+            ]));
+        }
+
+        let replication = &config.replication;
+        let offset = replication.master.offset() as i64;
+
+        // A master lists its replicas with the offset each acknowledged
+        let Some(addr) = replication.upstream.addr() else {
+            let replicas = replication
+                .master
+                .replicas()
+                .into_iter()
+                .map(|replica| {
+                    RespFrame::Array(vec![
+                        RespFrame::BulkString(replica.ip),
+                        RespFrame::BulkString(replica.port.to_string()),
+                        RespFrame::BulkString(replica.offset.to_string()),
+                    ])
+                })
+                .collect();
+            return Ok(RespFrame::Array(vec![
+                RespFrame::BulkString("master".to_string()),
+                RespFrame::Integer(offset),
+                RespFrame::Array(replicas),
+            ]));
+        };
+
+        // A replica only has an offset once it is in sync with its master
+        let status = replication.upstream.status();
+        Ok(RespFrame::Array(vec![
+            RespFrame::BulkString("slave".to_string()),
+            RespFrame::BulkString(addr.host),
+            RespFrame::Integer(addr.port as i64),
+            RespFrame::BulkString(status.name().to_string()),
+            RespFrame::Integer(if status == LinkStatus::Connected {
+                offset
+            } else {
+                -1
+            }),
+        ]))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.args.is_empty() {
+            return Err(anyhow!("ERR wrong number of arguments for 'role' command"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::Config, resp::parser::Request, session::Session};
+
+    // The replies a session sends back to a command, encoded one after the other
+    fn replies(session: &mut Session, config: &Config, args: &[&str]) -> String {
+        let argv = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        let dbs = config.databases.all();
+        session
+            .handle(Request::new(argv), &dbs, config)
+            .into_iter()
+            .map(|frame| String::from_utf8(frame.encode()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replicaof_switches_the_role_and_refuses_writes() {
+        let config = Config::new(6379, None);
+        let mut client = Session::new(1, "127.0.0.1:50000".parse().unwrap(), &config);
+        let mut send = |args: &[&str]| replies(&mut client, &config, args);
+        assert_eq!(send(&["ROLE"]), "*3\r\n$6\r\nmaster\r\n:0\r\n*0\r\n");
+        assert_eq!(send(&["SET", "a", "1"]), "+OK\r\n");
+        let replid = config.replication.master.replid();
+
+        assert_eq!(
+            send(&["REPLICAOF", "127.0.0.1", "70000"]),
+            "-ERR Invalid master port\r\n"
+        );
+        assert_eq!(send(&["REPLICAOF", "127.0.0.1", "6380"]), "+OK\r\n");
+        assert_eq!(
+            send(&["SLAVEOF", "127.0.0.1", "6380"]),
+            "+OK Already connected to specified master\r\n"
+        );
+        // The offset only shows once the link is up
+        assert_eq!(
+            send(&["ROLE"]),
+            "*5\r\n$5\r\nslave\r\n$9\r\n127.0.0.1\r\n:6380\r\n$7\r\nconnect\r\n:-1\r\n"
+        );
+
+        // Clients can read but not write, a write inside MULTI fails the transaction
+        let readonly = "-READONLY You can't write against a read only replica.\r\n";
+        assert_eq!(send(&["SET", "a", "2"]), readonly);
+        assert_eq!(send(&["GET", "a"]), "$1\r\n1\r\n");
+        send(&["MULTI"]);
+        assert_eq!(send(&["DEL", "a"]), readonly);
+        assert!(send(&["EXEC"]).starts_with("-EXECABORT"));
+        // while the master's stream is applied anyway
+        let mut link = Session::master_link(2, "127.0.0.1:6380".parse().unwrap(), 0, &config);
+        assert_eq!(replies(&mut link, &config, &["SET", "a", "3"]), "+OK\r\n");
+        assert_eq!(
+            send(&["CONFIG", "SET", "replica-read-only", "no"]),
+            "+OK\r\n"
+        );
+        assert_eq!(send(&["SET", "a", "4"]), "+OK\r\n");
+
+        // REPLICAOF NO ONE starts a new history, keeping the one followed as the secondary ID
+        assert_eq!(send(&["REPLICAOF", "NO", "ONE"]), "+OK\r\n");
+        assert!(send(&["ROLE"]).starts_with("*3\r\n$6\r\nmaster\r\n"));
+        let master = &config.replication.master;
+        assert_ne!(master.replid(), replid);
+        assert_eq!(master.replid2(), replid);
+        assert_eq!(send(&["REPLICAOF", "NO", "ONE"]), "+OK\r\n");
+        assert_eq!(master.replid2(), replid);
+    }
+}
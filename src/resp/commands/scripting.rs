@@ -0,0 +1,140 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// EVAL / EVALSHA implementation
+pub struct EvalCommand {
+    args: Vec<String>,
+    // raw holds the keys and arguments as received, scripts get them byte for byte
+    raw: Vec<Vec<u8>>,
+    // by_sha is set for EVALSHA, where the first argument is the SHA1 of a cached script
+    by_sha: bool,
+}
+
+impl EvalCommand {
+    pub fn new(args: Vec<String>, raw: Vec<Vec<u8>>, by_sha: bool) -> Self {
+        Self { args, raw, by_sha }
+    }
+
+    fn numkeys(&self) -> anyhow::Result<usize> {
+        let numkeys: i64 = self.args[1]
+            .parse()
+            .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+        if numkeys < 0 {
+            return Err(anyhow!("ERR Number of keys can't be negative"));
+        }
+        if numkeys as usize > self.args.len() - 2 {
+            return Err(anyhow!(
+                "ERR Number of keys can't be greater than number of args"
+            ));
+        }
+        Ok(numkeys as usize)
+    }
+}
+
+impl Command for EvalCommand {
+    fn execute(&self, db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let body = if self.by_sha {
+            config
+                .scripting
+                .get(&self.args[0])
+                .ok_or(anyhow!("NOSCRIPT No matching script. Please use EVAL."))?
+        } else {
+            self.args[0].clone()
+        };
+
+        let numkeys = self.numkeys()?;
+        let (keys, argv) = self.raw[2..].split_at(numkeys);
+        config.scripting.eval(&body, keys, argv, db, config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.len() < 2 {
+            let name = if self.by_sha { "evalsha" } else { "eval" };
+            return Err(anyhow!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+        self.numkeys()?;
+        Ok(())
+    }
+}
+
+// SCRIPT LOAD / EXISTS / FLUSH / KILL implementation
+pub struct ScriptCommand {
+    args: Vec<String>,
+}
+
+impl ScriptCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for ScriptCommand {
+    fn execute(&self, _: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        match self.args[0].to_lowercase().as_str() {
+            "load" => Ok(RespFrame::BulkString(config.scripting.load(&self.args[1])?)),
+            "exists" => {
+                let found = self.args[1..]
+                    .iter()
+                    .map(|sha| RespFrame::Integer(config.scripting.get(sha).is_some() as i64))
+                    .collect();
+                Ok(RespFrame::Array(found))
+            }
+            // Scripts are dropped right away, ASYNC is accepted for compatibility
+            "flush" => {
+                config.scripting.flush();
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "kill" => {
+                config.scripting.kill()?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try SCRIPT HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let Some(subcommand) = self.args.first() else {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'script' command"
+            ));
+        };
+
+        let subcommand = subcommand.to_lowercase();
+        let valid = match subcommand.as_str() {
+            "load" => self.args.len() == 2,
+            "exists" => self.args.len() >= 2,
+            "kill" => self.args.len() == 1,
+            "flush" => match self.args.get(1) {
+                None => true,
+                Some(mode) if self.args.len() == 2 => {
+                    mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync")
+                }
+                Some(_) => false,
+            },
+            _ => true,
+        };
+        if !valid {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'script|{}' command",
+                subcommand
+            ));
+        }
+        Ok(())
+    }
+}
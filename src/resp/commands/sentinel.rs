@@ -0,0 +1,173 @@
+use std::sync::RwLock;
+
+use anyhow::{Ok, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    replication::MasterAddr,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+};
+
+// SENTINEL implementation, only known to a server started with --sentinel
+pub struct SentinelCommand {
+    args: Vec<String>,
+}
+
+impl SentinelCommand {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+}
+
+impl Command for SentinelCommand {
+    fn execute(&self, _db: &RwLock<MemDB<Data>>, config: &Config) -> anyhow::Result<RespFrame> {
+        let Some(sentinel) = &config.sentinel else {
+            return Err(anyhow!("ERR unknown command 'sentinel'"));
+        };
+        let subcommand = self.args[0].to_lowercase();
+        let args = &self.args[1..];
+        let arity = |count: usize| {
+            if args.len() != count {
+                return Err(anyhow!(
+                    "ERR wrong number of arguments for 'sentinel|{}' command",
+                    subcommand
+                ));
+            }
+            Ok(())
+        };
+
+        match subcommand.as_str() {
+            "masters" => {
+                arity(0)?;
+                Ok(RespFrame::Array(
+                    sentinel.masters().into_iter().map(fields_frame).collect(),
+                ))
+            }
+            "master" => {
+                arity(1)?;
+                Ok(fields_frame(sentinel.master(&args[0])?))
+            }
+            "replicas" | "slaves" => {
+                arity(1)?;
+                Ok(RespFrame::Array(
+                    sentinel
+                        .replicas(&args[0])?
+                        .into_iter()
+                        .map(fields_frame)
+                        .collect(),
+                ))
+            }
+            "sentinels" => {
+                arity(1)?;
+                Ok(RespFrame::Array(
+                    sentinel
+                        .sentinels(&args[0])?
+                        .into_iter()
+                        .map(fields_frame)
+                        .collect(),
+                ))
+            }
+            "get-master-addr-by-name" => {
+                arity(1)?;
+                Ok(match sentinel.master_addr(&args[0]) {
+                    Some(addr) => RespFrame::Array(vec![
+                        RespFrame::BulkString(addr.host),
+                        RespFrame::BulkString(addr.port.to_string()),
+                    ]),
+                    None => RespFrame::NullArray,
+                })
+            }
+            // SENTINEL is-master-down-by-addr ip port current-epoch runid, sent by the
+            // other sentinels
+            "is-master-down-by-addr" => {
+                arity(4)?;
+                let addr = MasterAddr {
+                    host: args[0].clone(),
+                    port: args[1]
+                        .parse()
+                        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?,
+                };
+                let epoch = args[2]
+                    .parse()
+                    .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                let (down, leader, leader_epoch) =
+                    sentinel.is_master_down_by_addr(&addr, epoch, &args[3]);
+                Ok(RespFrame::Array(vec![
+                    RespFrame::Integer(down as i64),
+                    RespFrame::BulkString(leader.unwrap_or("*".to_string())),
+                    RespFrame::Integer(leader_epoch as i64),
+                ]))
+            }
+            "monitor" => {
+                arity(4)?;
+                let addr = MasterAddr {
+                    host: args[1].clone(),
+                    port: args[2]
+                        .parse()
+                        .map_err(|_| anyhow!("ERR Invalid port number"))?,
+                };
+                let quorum = args[3]
+                    .parse()
+                    .map_err(|_| anyhow!("ERR Invalid quorum number"))?;
+                sentinel.monitor(&args[0], addr, quorum)?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "remove" => {
+                arity(1)?;
+                sentinel.remove(&args[0])?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "set" => {
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(anyhow!(
+                        "ERR wrong number of arguments for 'sentinel|set' command"
+                    ));
+                }
+                for pair in args[1..].chunks(2) {
+                    sentinel.set(&args[0], &pair[0], &pair[1])?;
+                }
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "failover" => {
+                arity(1)?;
+                sentinel.failover(&args[0])?;
+                Ok(RespFrame::SimpleString("OK".to_string()))
+            }
+            "ckquorum" => {
+                arity(1)?;
+                Ok(RespFrame::SimpleString(sentinel.ckquorum(&args[0])?))
+            }
+            "myid" => {
+                arity(0)?;
+                Ok(RespFrame::BulkString(sentinel.myid().to_string()))
+            }
+            _ => Err(anyhow!(
+                "ERR unknown subcommand '{}'. Try SENTINEL HELP.",
+                self.args[0]
+            )),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.args.is_empty() {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for 'sentinel' command"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// An instance is described as a flat list of field names and values, as in Redis
+fn fields_frame(fields: Vec<(String, String)>) -> RespFrame {
+    RespFrame::Array(
+        fields
+            .into_iter()
+            .flat_map(|(name, value)| [RespFrame::BulkString(name), RespFrame::BulkString(value)])
+            .collect(),
+    )
+}
@@ -1,4 +1,12 @@
-use std::{collections::VecDeque, time::Instant};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Instant,
+};
+
+use crate::{
+    mem::{Expires, MemDB},
+    zset::SortedSet,
+};
 
 type BinaryString = Vec<u8>;
 
@@ -6,12 +14,30 @@ type BinaryString = Vec<u8>;
 pub enum Value {
     String(BinaryString),
     List(Vec<String>),
+    SortedSet(SortedSet),
+    // Sets and hashes come from RDB files written by Redis, there are no commands for them yet
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
+}
+
+impl Value {
+    /// The name Redis gives the type, as filtered on by SCAN TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::SortedSet(_) => "zset",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+        }
+    }
 }
 
 // Data wraps over Value with extra metadata
+#[derive(Clone)]
 pub struct Data {
     pub value: Value,
-    pub expires_at: Option<Instant>
+    pub expires_at: Option<Instant>,
 }
 
 impl Data {
@@ -21,4 +47,17 @@ impl Data {
             None => false,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Expires for Data {
+    fn expires(&self) -> bool {
+        self.expires_at.is_some()
+    }
+}
+
+impl MemDB<Data> {
+    /// Returns true when the key holds a value which hasn't expired yet
+    pub fn exists(&self, key: &str) -> bool {
+        matches!(self.get(key), Ok(Some(data)) if !data.expired())
+    }
+}
@@ -1,12 +1,15 @@
-use std::fmt::format;
-
 #[derive(Clone)]
 pub enum RespFrame {
     SimpleString(String),
     Error(String),
     Integer(i64),
     BulkString(String),
+    // BulkBytes is a bulk string whose content is not guaranteed to be valid UTF-8
+    BulkBytes(Vec<u8>),
     Array(Vec<RespFrame>),
+    // Map and Push only exist in RESP3, they must not be sent to RESP2 clients
+    Map(Vec<(RespFrame, RespFrame)>),
+    Push(Vec<RespFrame>),
     EmptyArray,
     Null,
     NullBulkString,
@@ -16,18 +19,18 @@ pub enum RespFrame {
 const CRLF: &str = "\r\n";
 
 impl RespFrame {
-    pub fn encode(self: Self) -> Vec<u8> {
+    pub fn encode(self) -> Vec<u8> {
         match self {
             RespFrame::SimpleString(s) => format!("+{}{}", s, CRLF).into_bytes(),
             RespFrame::Error(err) => format!("-{}{}", err, CRLF).into_bytes(),
-            RespFrame::Integer(num) => {
-                if num >= 0 {
-                    format!(":{}{}", num, CRLF).into_bytes()
-                } else {
-                    format!(":-{}\r\n", num).into_bytes()
-                }
-            }
+            RespFrame::Integer(num) => format!(":{}{}", num, CRLF).into_bytes(),
             RespFrame::BulkString(s) => format!("${}{}{}{}", s.len(), CRLF, s, CRLF).into_bytes(),
+            RespFrame::BulkBytes(bytes) => {
+                let mut encoded = format!("${}{}", bytes.len(), CRLF).into_bytes();
+                encoded.extend(bytes);
+                encoded.extend(CRLF.as_bytes());
+                encoded
+            }
             RespFrame::Null => format!("_{}", CRLF).into_bytes(),
             RespFrame::NullBulkString => format!("$-1{}", CRLF).into_bytes(),
             RespFrame::NullArray => format!("*-1{}", CRLF).into_bytes(),
@@ -39,6 +42,21 @@ impl RespFrame {
                 }
                 encoded
             }
+            RespFrame::Map(entries) => {
+                let mut encoded = format!("%{}{}", entries.len(), CRLF).into_bytes();
+                for (key, value) in entries {
+                    encoded.extend(key.encode());
+                    encoded.extend(value.encode());
+                }
+                encoded
+            }
+            RespFrame::Push(items) => {
+                let mut encoded = format!(">{}{}", items.len(), CRLF).into_bytes();
+                for item in items {
+                    encoded.extend(item.encode());
+                }
+                encoded
+            }
         }
     }
 }
@@ -5,4 +5,3 @@ pub mod parser;
 pub mod commands;
 
 use frame::RespFrame;
-use parser::parse_resp;
\ No newline at end of file
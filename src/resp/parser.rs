@@ -1,74 +1,311 @@
-use std::pin::Pin;
-
 use anyhow::{Error, Ok, Result};
 
-use crate::resp::commands::{Command, GetCommand, Ping, echo::Echo, kv::SetCommand};
+use crate::resp::commands::{
+    Command, GetCommand, Ping,
+    cluster::ClusterCommand,
+    config::ConfigCommand,
+    db::{DbSizeCommand, FlushAllCommand, FlushDbCommand, MoveCommand, SwapDbCommand},
+    echo::Echo,
+    functions::{FCallCommand, FunctionCommand},
+    geo::{
+        GeoAddCommand, GeoDistCommand, GeoHashCommand, GeoPosCommand, GeoSearchCommand,
+        GeoSearchStoreCommand,
+    },
+    hll::{PfAddCommand, PfCountCommand, PfMergeCommand},
+    keys::{KeysCommand, RandomKeyCommand, ScanCommand},
+    kv::{DelCommand, SetCommand},
+    migrate::{MigrateCommand, RestoreCommand},
+    persistence::{BgRewriteAofCommand, BgSaveCommand, LastSaveCommand, SaveCommand},
+    pubsub::{PubSubCommand, PublishCommand, SPublishCommand},
+    replication::{ReplicaOfCommand, RoleCommand},
+    scripting::{EvalCommand, ScriptCommand},
+    sentinel::SentinelCommand,
+};
+use crate::resp::frame::RespFrame;
+
+// Request is a parsed client request before it is turned into a Command
+pub struct Request {
+    // name is the lowercased command name
+    pub name: String,
+    pub args: Vec<String>,
+    // raw holds the arguments as received, for commands which need them byte for byte
+    pub raw: Vec<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(mut raw: Vec<Vec<u8>>) -> Self {
+        let name = String::from_utf8_lossy(&raw.remove(0)).to_lowercase();
+        let args = raw
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        Request { name, args, raw }
+    }
+
+    /// The command name followed by its arguments, the way it is written to the AOF
+    pub fn argv(&self) -> Vec<Vec<u8>> {
+        let mut argv = Vec::with_capacity(self.raw.len() + 1);
+        argv.push(self.name.as_bytes().to_vec());
+        argv.extend(self.raw.iter().cloned());
+        argv
+    }
+}
+
+// Bulk strings longer than this are refused, like proto-max-bulk-len in Redis
+pub const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
 
-// RESP parser that returns appropriate Command implementations
-pub fn parse_resp(input: &str) -> Result<Box<dyn Command>> {
-    let input = input.trim_end();
-    // Check if input is empty
+// Longest line allowed for the header of an array or a bulk string
+const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
+// The count of an array comes from the peer, so only this many slots are allocated up front
+const MAX_PREALLOCATED_ITEMS: usize = 1024;
+
+// parse_request reads a single RESP array of bulk strings from the input in a binary safe way.
+// Bulk strings are read by their declared length, so arguments may contain CRLF or non UTF-8 bytes.
+// Returns None when the input does not yet hold a complete request, otherwise the request along
+// with the number of bytes it consumed from the input.
+pub fn parse_request(input: &[u8]) -> Result<Option<(Request, usize)>> {
     if input.is_empty() {
-        return Err(Error::msg("Empty input"));
+        return Ok(None);
     }
 
-    if !input.starts_with("*") {
+    if input[0] != b'*' {
         return Err(Error::msg("Invalid RESP array"));
     }
 
-    let lines = input.lines().collect::<Vec<&str>>();
-    if lines.is_empty() {
+    let Some((header, mut idx)) = read_line(input, 1)? else {
+        return Ok(None);
+    };
+    let param_count: usize = header
+        .parse()
+        .map_err(|_| Error::msg("Invalid command count"))?;
+    if param_count == 0 {
         return Err(Error::msg("Invalid input"));
     }
 
-    let line_count = lines.len();
+    let mut args: Vec<Vec<u8>> = Vec::with_capacity(param_count.min(MAX_PREALLOCATED_ITEMS));
+    for _ in 0..param_count {
+        if idx >= input.len() {
+            return Ok(None);
+        }
+        if input[idx] != b'$' {
+            return Err(Error::msg("Invalid bulk string"));
+        }
 
-    if line_count < 3 {
-        // We need a minimum of three lines
-        // First line for the array size
-        // second and third line for the command as bulk string
-        return Err(Error::msg("Invalid input"));
-    }
+        let Some((len, next)) = read_line(input, idx + 1)? else {
+            return Ok(None);
+        };
+        let len = bulk_len(len)?;
 
-    let param_count: usize = lines[0][1..]
-        .parse()
-        .map_err(|_| Error::msg("Invalid command count"))?;
-    let mut args: Vec<String> = Vec::with_capacity(param_count - 1); // -1 because the count also includes the command
+        // The content is followed by a trailing CRLF which is not part of the bulk string
+        let Some(end) = bulk_end(input, next, len)? else {
+            return Ok(None);
+        };
 
-    // First two lines are used to parse the command type
-    let _: usize = lines[1][1..]
-        .parse()
-        .map_err(|_| Error::msg("Invalid request format"))?; // We only parse for the protocol agreement
-    let command = lines[2].to_string();
-    let mut idx = 3; // ofsetting idx to start parsing args
+        args.push(input[next..next + len].to_vec());
+        idx = end;
+    }
 
-    // last line needs to be ignore
+    Ok(Some((Request::new(args), idx)))
+}
 
-    while idx < line_count - 1 {
-        let line = lines[idx];
-        if !line.starts_with("$") {
-            return Err(Error::msg("Invalid bulk string"));
+// parse_reply reads a single reply frame sent by another server, like those a sentinel gets
+// from the instances it monitors. Returns None while the input holds only part of it,
+// otherwise the frame and the number of bytes it took.
+pub fn parse_reply(input: &[u8]) -> Result<Option<(RespFrame, usize)>> {
+    parse_reply_at(input, 0)
+}
+
+fn parse_reply_at(input: &[u8], start: usize) -> Result<Option<(RespFrame, usize)>> {
+    if start >= input.len() {
+        return Ok(None);
+    }
+    let Some((line, next)) = read_line(input, start + 1)? else {
+        return Ok(None);
+    };
+    let frame = match input[start] {
+        b'+' => RespFrame::SimpleString(line.to_string()),
+        b'-' => RespFrame::Error(line.to_string()),
+        b':' => RespFrame::Integer(
+            line.parse()
+                .map_err(|_| Error::msg("Invalid integer reply"))?,
+        ),
+        b'$' if line == "-1" => RespFrame::NullBulkString,
+        b'$' => {
+            let len = bulk_len(line)?;
+            let Some(end) = bulk_end(input, next, len)? else {
+                return Ok(None);
+            };
+            let content = String::from_utf8_lossy(&input[next..next + len]).into_owned();
+            return Ok(Some((RespFrame::BulkString(content), end)));
         }
-        let _: usize = line[1..]
-            .parse()
-            .map_err(|_| Error::msg("Invalid bulk string length"))?; // We only parse to agree on the protocol and throw error. We actually don't need the size for our implementation
-        idx += 1;
-
-        if idx >= line_count {
-            // We have a malformatted request since we have size but no content in bulk string
-            return Err(Error::msg("Malformatted request"));
+        b'*' if line == "-1" => RespFrame::NullArray,
+        b'*' => {
+            let count: usize = line
+                .parse()
+                .map_err(|_| Error::msg("Invalid array length"))?;
+            let mut items = Vec::with_capacity(count.min(MAX_PREALLOCATED_ITEMS));
+            let mut idx = next;
+            for _ in 0..count {
+                let Some((item, end)) = parse_reply_at(input, idx)? else {
+                    return Ok(None);
+                };
+                items.push(item);
+                idx = end;
+            }
+            return Ok(Some((RespFrame::Array(items), idx)));
         }
+        _ => return Err(Error::msg("Invalid reply")),
+    };
+    Ok(Some((frame, next)))
+}
+
+// read_line returns the text between `start` and the next CRLF along with the index right after it.
+// A line still missing its CRLF past PROTO_INLINE_MAX_SIZE is an error rather than more to wait for.
+fn read_line(input: &[u8], start: usize) -> Result<Option<(&str, usize)>> {
+    let Some(end) = input[start..].windows(2).position(|w| w == b"\r\n") else {
+        if input.len() - start > PROTO_INLINE_MAX_SIZE {
+            return Err(Error::msg("Protocol error: too big header"));
+        }
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(&input[start..start + end])
+        .map_err(|_| Error::msg("Protocol error: invalid header"))?;
+    Ok(Some((line, start + end + 2)))
+}
 
-        let arg = lines[idx].to_string();
-        args.push(arg);
-        idx += 1;
+// bulk_len parses the declared length of a bulk string, refusing those above PROTO_MAX_BULK_LEN
+fn bulk_len(line: &str) -> Result<usize> {
+    let len: usize = line
+        .parse()
+        .map_err(|_| Error::msg("Invalid bulk string length"))?;
+    if len > PROTO_MAX_BULK_LEN {
+        return Err(Error::msg("Protocol error: invalid bulk length"));
     }
+    Ok(len)
+}
 
-    match command.to_lowercase().as_str() {
+// bulk_end returns the index right after a bulk string of `len` bytes starting at `start` and
+// its trailing CRLF, None while it hasn't fully arrived
+fn bulk_end(input: &[u8], start: usize, len: usize) -> Result<Option<usize>> {
+    let end = start
+        .checked_add(len)
+        .and_then(|end| end.checked_add(2))
+        .ok_or_else(|| Error::msg("Protocol error: invalid bulk length"))?;
+    if end > input.len() {
+        return Ok(None);
+    }
+    if &input[end - 2..end] != b"\r\n" {
+        return Err(Error::msg("Malformatted request"));
+    }
+    Ok(Some(end))
+}
+
+// Commands which modify the dataset, subcommands are listed as "command|subcommand"
+const WRITE_COMMANDS: &[&str] = &[
+    "set",
+    "del",
+    "rpush",
+    "lpush",
+    "lpop",
+    "pfadd",
+    "pfmerge",
+    "geoadd",
+    "geosearchstore",
+    "restore",
+    "restore-asking",
+    "flushdb",
+    "flushall",
+    "swapdb",
+    "move",
+    "function|load",
+    "function|delete",
+    "function|flush",
+    "function|restore",
+];
+
+pub fn is_write_command(request: &Request) -> bool {
+    WRITE_COMMANDS.contains(&request.name.as_str())
+        || request.args.first().is_some_and(|subcommand| {
+            let name = format!("{}|{}", request.name, subcommand.to_lowercase());
+            WRITE_COMMANDS.contains(&name.as_str())
+        })
+}
+
+// Commands whose first argument is their only key
+const SINGLE_KEY_COMMANDS: &[&str] = &[
+    "get",
+    "set",
+    "rpush",
+    "lpush",
+    "lrange",
+    "llen",
+    "lpop",
+    "pfadd",
+    "geoadd",
+    "geodist",
+    "geohash",
+    "geopos",
+    "geosearch",
+    "restore",
+    "restore-asking",
+    "move",
+];
+
+// Commands whose arguments are all keys, or sharded channels
+const ALL_KEYS_COMMANDS: &[&str] = &["del", "pfcount", "pfmerge", "ssubscribe", "sunsubscribe"];
+
+/// The keys a request touches, which a cluster node must serve. Sharded channels count as
+/// keys too, they live in the slot of their name.
+pub fn command_keys(request: &Request) -> Vec<&str> {
+    let args = &request.args;
+    let name = request.name.as_str();
+    if SINGLE_KEY_COMMANDS.contains(&name) {
+        return args.iter().take(1).map(String::as_str).collect();
+    }
+    if ALL_KEYS_COMMANDS.contains(&name) {
+        return args.iter().map(String::as_str).collect();
+    }
+    match name {
+        "geosearchstore" => args.iter().take(2).map(String::as_str).collect(),
+        "spublish" => args.iter().take(1).map(String::as_str).collect(),
+        // MIGRATE host port key|"" db timeout [...] [KEYS key...]
+        "migrate" => match args.get(2) {
+            Some(key) if !key.is_empty() => vec![key.as_str()],
+            _ => args
+                .iter()
+                .skip_while(|arg| !arg.eq_ignore_ascii_case("keys"))
+                .skip(1)
+                .map(String::as_str)
+                .collect(),
+        },
+        // Scripts declare their keys: script numkeys key... arg...
+        "eval" | "evalsha" | "fcall" | "fcall_ro" => {
+            let numkeys = args
+                .get(1)
+                .and_then(|numkeys| numkeys.parse::<usize>().ok())
+                .unwrap_or(0);
+            args.iter()
+                .skip(2)
+                .take(numkeys)
+                .map(String::as_str)
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+// parse_command returns the Command implementation for a parsed request
+
+pub fn parse_command(request: Request) -> Result<Box<dyn Command>> {
+    let Request { name, args, raw } = request;
+
+    match name.as_str() {
         "ping" => Ok(Box::new(Ping {})),
         "echo" => Ok(Box::new(Echo { args })),
         "get" => Ok(Box::new(GetCommand::new(args))),
-        "set" => Ok(Box::new(SetCommand::new(args))),
+        "set" => Ok(Box::new(SetCommand::new(args, raw))),
+        "del" => Ok(Box::new(DelCommand::new(args))),
         "rpush" => Ok(Box::new(crate::resp::commands::list::ListPushCommand::new(
             args, false,
         ))),
@@ -85,6 +322,114 @@ pub fn parse_resp(input: &str) -> Result<Box<dyn Command>> {
             args,
         ))),
         "info" => Ok(Box::new(crate::resp::commands::info::InfoCommand::new())),
+        "config" => Ok(Box::new(ConfigCommand::new(args))),
+        "pfadd" => Ok(Box::new(PfAddCommand::new(args, raw))),
+        "pfcount" => Ok(Box::new(PfCountCommand::new(args))),
+        "pfmerge" => Ok(Box::new(PfMergeCommand::new(args))),
+        "geoadd" => Ok(Box::new(GeoAddCommand::new(args))),
+        "geodist" => Ok(Box::new(GeoDistCommand::new(args))),
+        "geohash" => Ok(Box::new(GeoHashCommand::new(args))),
+        "geopos" => Ok(Box::new(GeoPosCommand::new(args))),
+        "geosearch" => Ok(Box::new(GeoSearchCommand::new(args))),
+        "geosearchstore" => Ok(Box::new(GeoSearchStoreCommand::new(args))),
+        "publish" => Ok(Box::new(PublishCommand::new(args, raw))),
+        "spublish" => Ok(Box::new(SPublishCommand::new(args, raw))),
+        "pubsub" => Ok(Box::new(PubSubCommand::new(args))),
+        "eval" => Ok(Box::new(EvalCommand::new(args, raw, false))),
+        "evalsha" => Ok(Box::new(EvalCommand::new(args, raw, true))),
+        "script" => Ok(Box::new(ScriptCommand::new(args))),
+        "save" => Ok(Box::new(SaveCommand::new(args))),
+        "bgsave" => Ok(Box::new(BgSaveCommand::new(args))),
+        "lastsave" => Ok(Box::new(LastSaveCommand::new(args))),
+        "bgrewriteaof" => Ok(Box::new(BgRewriteAofCommand::new(args))),
+        "function" => Ok(Box::new(FunctionCommand::new(args, raw))),
+        "fcall" => Ok(Box::new(FCallCommand::new(args, raw, false))),
+        "fcall_ro" => Ok(Box::new(FCallCommand::new(args, raw, true))),
+        "replicaof" | "slaveof" => Ok(Box::new(ReplicaOfCommand::new(name, args))),
+        "role" => Ok(Box::new(RoleCommand::new(args))),
+        "sentinel" => Ok(Box::new(SentinelCommand::new(args))),
+        "cluster" => Ok(Box::new(ClusterCommand::new(args))),
+        "restore" | "restore-asking" => Ok(Box::new(RestoreCommand::new(args, raw))),
+        "migrate" => Ok(Box::new(MigrateCommand::new(args))),
+        "dbsize" => Ok(Box::new(DbSizeCommand::new(args))),
+        "flushdb" => Ok(Box::new(FlushDbCommand::new(args))),
+        "flushall" => Ok(Box::new(FlushAllCommand::new(args))),
+        "swapdb" => Ok(Box::new(SwapDbCommand::new(args))),
+        "move" => Ok(Box::new(MoveCommand::new(args))),
+        "scan" => Ok(Box::new(ScanCommand::new(args))),
+        "keys" => Ok(Box::new(KeysCommand::new(args))),
+        "randomkey" => Ok(Box::new(RandomKeyCommand::new(args))),
         _ => Err(Error::msg("Unknown command")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_request_and_reports_its_length() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\nrest";
+        let (request, consumed) = parse_request(input).unwrap().unwrap();
+        assert_eq!(request.name, "get");
+        assert_eq!(request.args, vec!["key"]);
+        assert_eq!(consumed, input.len() - 4);
+    }
+
+    #[test]
+    fn keeps_binary_arguments_intact() {
+        let input = b"*2\r\n$4\r\necho\r\n$4\r\n\r\n\xff\x00\r\n";
+        let (request, _) = parse_request(input).unwrap().unwrap();
+        assert_eq!(request.raw, vec![b"\r\n\xff\x00".to_vec()]);
+    }
+
+    #[test]
+    fn waits_for_every_partial_frame() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+        for end in 0..input.len() {
+            assert!(
+                parse_request(&input[..end]).unwrap().is_none(),
+                "{} bytes",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn huge_array_count_does_not_preallocate() {
+        assert!(parse_request(b"*99999999999999\r\n").unwrap().is_none());
+        assert!(parse_reply(b"*99999999999999\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_oversized_bulk_lengths() {
+        assert!(parse_request(b"*1\r\n$536870913\r\n").is_err());
+        assert!(parse_request(b"*1\r\n$18446744073709551615\r\n").is_err());
+        assert!(parse_reply(b"$18446744073709551615\r\n").is_err());
+    }
+
+    #[test]
+    fn refuses_headers_without_end() {
+        let mut input = b"*1".to_vec();
+        input.extend(std::iter::repeat_n(b'1', PROTO_INLINE_MAX_SIZE + 1));
+        assert!(parse_request(&input).is_err());
+    }
+
+    #[test]
+    fn refuses_bulk_strings_without_trailing_crlf() {
+        assert!(parse_request(b"*1\r\n$3\r\nGETxx").is_err());
+    }
+
+    #[test]
+    fn parses_nested_replies() {
+        let input = b"*3\r\n:1\r\n$-1\r\n*1\r\n+OK\r\n";
+        let (reply, consumed) = parse_reply(input).unwrap().unwrap();
+        assert_eq!(consumed, input.len());
+        let RespFrame::Array(items) = reply else {
+            panic!("expected an array");
+        };
+        assert!(matches!(items[0], RespFrame::Integer(1)));
+        assert!(matches!(items[1], RespFrame::NullBulkString));
+        assert!(matches!(&items[2], RespFrame::Array(inner) if inner.len() == 1));
+    }
+}
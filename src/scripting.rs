@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, MultiValue, RegistryKey, StdLib, Table, Value,
+    Variadic,
+};
+
+use crate::{
+    config::Config,
+    functions, lualib,
+    mem::MemDB,
+    replication::READONLY_ERR,
+    resp::{
+        commands::structs::Data,
+        frame::RespFrame,
+        parser::{Request, is_write_command, parse_command},
+    },
+    transaction::{propagate_effects, with_exclusive_access},
+};
+
+pub const BUSY_ERR: &str =
+    "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+const KILLED_ERR: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+// How often, in Lua instructions, a running script checks whether it was killed
+const KILL_CHECK_INTERVAL: u32 = 100_000;
+
+// Commands which change the connection state or run scripts themselves can't be called from a script
+const NOSCRIPT_COMMANDS: &[&str] = &[
+    "eval",
+    "evalsha",
+    "script",
+    "fcall",
+    "fcall_ro",
+    "function",
+    "save",
+    "bgsave",
+    "bgrewriteaof",
+    "multi",
+    "exec",
+    "discard",
+    "watch",
+    "unwatch",
+    "subscribe",
+    "psubscribe",
+    "ssubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "sunsubscribe",
+    "hello",
+    "replconf",
+    "psync",
+    "replicaof",
+    "slaveof",
+    "role",
+    "sentinel",
+    "wait",
+    "waitaof",
+    "reset",
+    "quit",
+    "select",
+];
+
+// redis.call raises the error table redis.pcall returns
+const CALL_PRELUDE: &str = r#"
+redis.call = function(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+"#;
+
+// Globals are locked down so scripts can't leak state between runs or read undeclared
+// variables by mistake
+pub const PROTECT_GLOBALS: &str = r#"
+setmetatable(_G, {
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+    end,
+})
+"#;
+
+// Interpreter is a Lua VM kept across runs, along with the functions already compiled in it so
+// a cached script isn't parsed again
+struct Interpreter {
+    lua: Lua,
+    // EVAL scripts by SHA1, library functions by the SHA1 of the library code and their name
+    compiled: HashMap<String, RegistryKey>,
+}
+
+impl Interpreter {
+    fn new() -> Result<Self> {
+        let lua = new_vm().map_err(|err| anyhow!("ERR {}", err))?;
+        lua.load(CALL_PRELUDE)
+            .exec()
+            .and_then(|_| lua.load(PROTECT_GLOBALS).exec())
+            .map_err(|err| anyhow!("ERR {}", err))?;
+        Ok(Interpreter {
+            lua,
+            compiled: HashMap::new(),
+        })
+    }
+}
+
+// Returns the interpreter in the slot, creating it the first time
+fn interpreter(slot: &mut Option<Interpreter>) -> Result<&mut Interpreter> {
+    if slot.is_none() {
+        *slot = Some(Interpreter::new()?);
+    }
+    Ok(slot.as_mut().expect("interpreter was just created"))
+}
+
+// Running is the state of the script currently executing, shared with the clients
+// which ask whether the server is busy and with SCRIPT KILL
+struct Running {
+    started: Instant,
+    wrote: bool,
+    kill: bool,
+    // effects are the write commands the script executed, propagated once it is done
+    effects: Vec<Vec<Vec<u8>>>,
+}
+
+// Scripting holds the script cache and tracks the running script. Like Redis, EVAL scripts share
+// one Lua interpreter and functions another, which live until SCRIPT FLUSH or the libraries
+// change. A script holds the database lock for its whole duration.
+#[derive(Clone)]
+pub struct Scripting {
+    scripts: Arc<Mutex<HashMap<String, String>>>,
+    eval_vm: Arc<Mutex<Option<Interpreter>>>,
+    function_vm: Arc<Mutex<Option<Interpreter>>>,
+    running: Arc<Mutex<Option<Running>>>,
+    // busy_threshold is how long, in milliseconds, a script runs before other clients get BUSY
+    busy_threshold: Arc<AtomicU64>,
+}
+
+impl Scripting {
+    pub fn new() -> Self {
+        Scripting {
+            scripts: Arc::new(Mutex::new(HashMap::new())),
+            eval_vm: Arc::new(Mutex::new(None)),
+            function_vm: Arc::new(Mutex::new(None)),
+            running: Arc::new(Mutex::new(None)),
+            busy_threshold: Arc::new(AtomicU64::new(5000)),
+        }
+    }
+
+    pub fn busy_threshold(&self) -> u64 {
+        self.busy_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_busy_threshold(&self, millis: u64) {
+        self.busy_threshold.store(millis, Ordering::Relaxed);
+    }
+
+    /// Compiles the script and adds it to the cache, returning its SHA1
+    pub fn load(&self, body: &str) -> Result<String> {
+        let sha = sha1hex(body.as_bytes());
+        let mut vm = self.eval_vm.lock().unwrap();
+        let Interpreter { lua, compiled } = interpreter(&mut vm)?;
+        compiled_script(lua, compiled, &sha, body)?;
+
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(sha.clone(), body.to_string());
+        Ok(sha)
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(&sha.to_lowercase())
+            .cloned()
+    }
+
+    /// Empties the script cache, starting over with a new interpreter
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+        *self.eval_vm.lock().unwrap() = None;
+    }
+
+    /// Drops the functions interpreter after libraries were deleted or replaced, the ones left
+    /// are loaded again on their next call
+    pub fn reset_functions(&self) {
+        *self.function_vm.lock().unwrap() = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.lock().unwrap().is_some()
+    }
+
+    /// A script running for longer than the busy threshold blocks every other client
+    pub fn is_busy(&self) -> bool {
+        let threshold = Duration::from_millis(self.busy_threshold());
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|running| running.started.elapsed() >= threshold)
+    }
+
+    /// Asks the running script to stop, which is only possible as long as it hasn't written anything
+    pub fn kill(&self) -> Result<()> {
+        let mut running = self.running.lock().unwrap();
+        match running.as_mut() {
+            None => Err(anyhow!("NOTBUSY No scripts in execution right now.")),
+            Some(running) if running.wrote => Err(anyhow!(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command."
+            )),
+            Some(running) => {
+                running.kill = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the script atomically with the given KEYS and ARGV, caching it once it compiled
+    pub fn eval(
+        &self,
+        body: &str,
+        keys: &[Vec<u8>],
+        argv: &[Vec<u8>],
+        db: &RwLock<MemDB<Data>>,
+        config: &Config,
+    ) -> Result<RespFrame> {
+        let sha = sha1hex(body.as_bytes());
+        // A script can run for a long time, the worker thread is handed over so other
+        // connections, SCRIPT KILL among them, keep being served in the meantime
+        tokio::task::block_in_place(|| {
+            with_exclusive_access(db, |db| {
+                let mut vm = self.eval_vm.lock().unwrap();
+                let Interpreter { lua, compiled } = interpreter(&mut vm)?;
+                let main = compiled_script(lua, compiled, &sha, body)?;
+                self.scripts
+                    .lock()
+                    .unwrap()
+                    .entry(sha.clone())
+                    .or_insert_with(|| body.to_string());
+
+                let label = format!("f_{}", sha);
+                let run = Run {
+                    main,
+                    label: &label,
+                    eval: true,
+                    read_only: false,
+                };
+                self.run(lua, run, keys, argv, db, config)
+            })?
+        })
+    }
+
+    /// Runs a loaded function atomically. Functions get the keys and arguments as parameters,
+    /// the ones flagged no-writes can't write and are the only ones FCALL_RO runs.
+    pub fn fcall(
+        &self,
+        name: &str,
+        read_only: bool,
+        keys: &[Vec<u8>],
+        argv: &[Vec<u8>],
+        db: &RwLock<MemDB<Data>>,
+        config: &Config,
+    ) -> Result<RespFrame> {
+        let (library, function) = config
+            .functions
+            .find(name)
+            .ok_or(anyhow!("ERR Function not found"))?;
+        if read_only && !function.is_read_only() {
+            return Err(anyhow!(
+                "ERR Can not execute a script with write flag using *_ro command."
+            ));
+        }
+
+        tokio::task::block_in_place(|| {
+            with_exclusive_access(db, |db| {
+                let mut vm = self.function_vm.lock().unwrap();
+                let Interpreter { lua, compiled } = interpreter(&mut vm)?;
+                let run = Run {
+                    main: compiled_function(lua, compiled, &library, name)?,
+                    label: name,
+                    eval: false,
+                    read_only: function.is_read_only(),
+                };
+                self.run(lua, run, keys, argv, db, config)
+            })?
+        })
+    }
+
+    // Calls the compiled script or function, converting what it returns to a reply
+    fn run(
+        &self,
+        lua: &Lua,
+        run: Run,
+        keys: &[Vec<u8>],
+        argv: &[Vec<u8>],
+        db: &RwLock<MemDB<Data>>,
+        config: &Config,
+    ) -> Result<RespFrame> {
+        let Run {
+            main,
+            label,
+            eval,
+            read_only,
+        } = run;
+        let (keys, argv) = arguments(lua, keys, argv).map_err(|err| anyhow!("ERR {}", err))?;
+
+        *self.running.lock().unwrap() = Some(Running {
+            started: Instant::now(),
+            wrote: false,
+            kill: false,
+            effects: Vec::new(),
+        });
+
+        // The hook keeps failing once the script is killed, so a pcall in the script can't swallow it
+        let running = Arc::clone(&self.running);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+            move |_, _| {
+                if running.lock().unwrap().as_ref().is_some_and(|r| r.kill) {
+                    return Err(mlua::Error::RuntimeError(KILLED_ERR.to_string()));
+                }
+                Ok(())
+            },
+        );
+
+        let reply = lua.scope(|scope| {
+            let pcall = scope.create_function(|lua, args: MultiValue| {
+                let reply = self
+                    .call(args, db, config, read_only)
+                    .unwrap_or_else(|err| RespFrame::Error(err.to_string()));
+                frame_to_lua(lua, reply)
+            })?;
+            lua.globals()
+                .get::<_, Table>("redis")?
+                .set("pcall", pcall)?;
+
+            // EVAL scripts find their keys and arguments in KEYS and ARGV, functions get them as parameters
+            let protected: Function = lua.globals().get("pcall")?;
+            let results = if eval {
+                lua.globals().raw_set("KEYS", keys)?;
+                lua.globals().raw_set("ARGV", argv)?;
+                protected.call::<_, MultiValue>(main)?
+            } else {
+                protected.call::<_, MultiValue>((main, keys, argv))?
+            };
+            let mut results = results.into_iter();
+            let ok = matches!(results.next(), Some(Value::Boolean(true)));
+            let value = results.next().unwrap_or(Value::Nil);
+            if ok {
+                return Ok(lua_to_frame(value));
+            }
+            Ok(match value {
+                Value::Table(table) => match table.raw_get::<_, Option<String>>("err") {
+                    Ok(Some(err)) => RespFrame::Error(err),
+                    _ => RespFrame::Error(format!(
+                        "ERR Error running script (call to {}): unknown error",
+                        label
+                    )),
+                },
+                value => RespFrame::Error(format!(
+                    "ERR Error running script (call to {}): {}",
+                    label,
+                    error_message(value)
+                )),
+            })
+        });
+
+        lua.remove_hook();
+        let running = self.running.lock().unwrap().take();
+        let (killed, effects) = running.map_or((false, Vec::new()), |r| (r.kill, r.effects));
+        let index = db.read().map(|db| db.index()).unwrap_or_default();
+        propagate_effects(config, index, effects);
+        if killed {
+            return Err(anyhow!(KILLED_ERR));
+        }
+        reply.map_err(|err| anyhow!("ERR {}", err))
+    }
+
+    // Executes a command on behalf of redis.call / redis.pcall
+    fn call(
+        &self,
+        args: MultiValue,
+        db: &RwLock<MemDB<Data>>,
+        config: &Config,
+        read_only: bool,
+    ) -> Result<RespFrame> {
+        let mut raw = Vec::with_capacity(args.len());
+        for arg in args {
+            raw.push(match arg {
+                Value::String(s) => s.as_bytes().to_vec(),
+                Value::Integer(i) => i.to_string().into_bytes(),
+                Value::Number(n) => format_number(n).into_bytes(),
+                _ => {
+                    return Err(anyhow!(
+                        "ERR Lua redis lib command arguments must be strings or integers"
+                    ));
+                }
+            });
+        }
+        if raw.is_empty() {
+            return Err(anyhow!(
+                "ERR Please specify at least one argument for this redis lib call"
+            ));
+        }
+
+        let request = Request::new(raw);
+        if NOSCRIPT_COMMANDS.contains(&request.name.as_str()) {
+            return Err(anyhow!("ERR This Redis command is not allowed from script"));
+        }
+
+        let write = is_write_command(&request);
+        let argv = request.argv();
+        let command = parse_command(request)
+            .map_err(|_| anyhow!("ERR Unknown Redis command called from script"))?;
+        command.validate()?;
+
+        if write && read_only {
+            return Err(anyhow!(
+                "ERR Write commands are not allowed from read-only scripts."
+            ));
+        }
+        if write && config.replication.is_read_only() {
+            return Err(anyhow!(READONLY_ERR));
+        }
+        if write && let Some(running) = self.running.lock().unwrap().as_mut() {
+            running.wrote = true;
+        }
+        let reply = command.execute(db, config)?;
+        if write && let Some(running) = self.running.lock().unwrap().as_mut() {
+            running.effects.push(argv);
+        }
+        Ok(reply)
+    }
+}
+
+// Run is what a script or function call executes
+struct Run<'lua, 'a> {
+    main: Function<'lua>,
+    // label names the script or function in error messages
+    label: &'a str,
+    eval: bool,
+    read_only: bool,
+}
+
+// Returns the compiled EVAL script, compiling it the first time it runs
+fn compiled_script<'lua>(
+    lua: &'lua Lua,
+    compiled: &mut HashMap<String, RegistryKey>,
+    sha: &str,
+    body: &str,
+) -> Result<Function<'lua>> {
+    if let Some(key) = compiled.get(sha) {
+        return lua
+            .registry_value(key)
+            .map_err(|err| anyhow!("ERR {}", err));
+    }
+    let main = compile(lua, body)?;
+    let key = lua
+        .create_registry_value(main.clone())
+        .map_err(|err| anyhow!("ERR {}", err))?;
+    compiled.insert(sha.to_string(), key);
+    Ok(main)
+}
+
+// Returns a function of the library, loading the library the first time one of its functions
+// is called
+fn compiled_function<'lua>(
+    lua: &'lua Lua,
+    compiled: &mut HashMap<String, RegistryKey>,
+    library: &str,
+    name: &str,
+) -> Result<Function<'lua>> {
+    let code_sha = sha1hex(library.as_bytes());
+    let key = format!("{}:{}", code_sha, name);
+    if !compiled.contains_key(&key) {
+        for (info, callback) in functions::load_library(lua, library)? {
+            let callback = lua
+                .create_registry_value(callback)
+                .map_err(|err| anyhow!("ERR {}", err))?;
+            compiled.insert(format!("{}:{}", code_sha, info.name), callback);
+        }
+    }
+    let callback = compiled
+        .get(&key)
+        .ok_or(anyhow!("ERR Function not found"))?;
+    lua.registry_value(callback)
+        .map_err(|err| anyhow!("ERR {}", err))
+}
+
+/// Lowercase hex SHA1 digest, the name scripts are cached under
+pub fn sha1hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+pub fn compile<'lua>(lua: &'lua Lua, body: &str) -> Result<Function<'lua>> {
+    lua.load(body)
+        .set_name("@user_script")
+        .into_function()
+        .map_err(|err| anyhow!("ERR Error compiling script (new function): {}", err))
+}
+
+// Creates an interpreter with the libraries and the redis table scripts expect
+pub fn new_vm() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    globals.set("dofile", Value::Nil)?;
+    globals.set("loadfile", Value::Nil)?;
+
+    let redis = lua.create_table()?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, data: mlua::String| Ok(sha1hex(data.as_bytes())))?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, err: String| lua.create_table_from([("err", err)]))?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, status: String| lua.create_table_from([("ok", status)]))?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_, (_level, message): (i64, Variadic<String>)| {
+            println!("{}", message.join(" "));
+            Ok(())
+        })?,
+    )?;
+    // Scripts written for older Redis versions call this, commands are always replicated one by one
+    redis.set("replicate_commands", lua.create_function(|_, ()| Ok(true))?)?;
+    for (name, level) in [
+        ("LOG_DEBUG", 0),
+        ("LOG_VERBOSE", 1),
+        ("LOG_NOTICE", 2),
+        ("LOG_WARNING", 3),
+    ] {
+        redis.set(name, level)?;
+    }
+    globals.set("redis", redis)?;
+    lualib::register(&lua)?;
+    drop(globals);
+
+    Ok(lua)
+}
+
+fn arguments<'lua>(
+    lua: &'lua Lua,
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+) -> mlua::Result<(Table<'lua>, Table<'lua>)> {
+    let to_table = |values: &[Vec<u8>]| -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        for (i, value) in values.iter().enumerate() {
+            table.raw_set(i + 1, lua.create_string(value)?)?;
+        }
+        Ok(table)
+    };
+    Ok((to_table(keys)?, to_table(argv)?))
+}
+
+// Converts a command reply to a Lua value the way Redis does for RESP2 replies
+fn frame_to_lua(lua: &Lua, frame: RespFrame) -> mlua::Result<Value<'_>> {
+    Ok(match frame {
+        RespFrame::SimpleString(status) => Value::Table(lua.create_table_from([("ok", status)])?),
+        RespFrame::Error(err) => Value::Table(lua.create_table_from([("err", err)])?),
+        RespFrame::Integer(i) => Value::Integer(i),
+        RespFrame::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RespFrame::BulkBytes(b) => Value::String(lua.create_string(&b)?),
+        RespFrame::Array(items) | RespFrame::Push(items) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, frame_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RespFrame::Map(pairs) => {
+            let table = lua.create_table()?;
+            for (i, item) in pairs.into_iter().flat_map(|(k, v)| [k, v]).enumerate() {
+                table.raw_set(i + 1, frame_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RespFrame::EmptyArray => Value::Table(lua.create_table()?),
+        RespFrame::Null | RespFrame::NullBulkString | RespFrame::NullArray => Value::Boolean(false),
+    })
+}
+
+// Converts what a script returned to a reply, following the Redis conversion rules
+fn lua_to_frame(value: Value) -> RespFrame {
+    match value {
+        Value::Boolean(true) => RespFrame::Integer(1),
+        Value::Integer(i) => RespFrame::Integer(i),
+        // Like Redis, numbers lose their fraction
+        Value::Number(n) => RespFrame::Integer(n as i64),
+        Value::String(s) => RespFrame::BulkBytes(s.as_bytes().to_vec()),
+        Value::Table(table) => {
+            if let Ok(Some(err)) = table.raw_get::<_, Option<String>>("err") {
+                return RespFrame::Error(err);
+            }
+            if let Ok(Some(status)) = table.raw_get::<_, Option<String>>("ok") {
+                return RespFrame::SimpleString(status);
+            }
+            // Arrays end at the first nil
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(value) => items.push(lua_to_frame(value)),
+                }
+            }
+            RespFrame::Array(items)
+        }
+        Value::Error(err) => RespFrame::Error(err.to_string()),
+        _ => RespFrame::NullBulkString,
+    }
+}
+
+fn error_message(value: Value) -> String {
+    match value {
+        Value::String(s) => s.to_string_lossy().into_owned(),
+        Value::Error(err) => err.to_string(),
+        value => format!("{:?}", value),
+    }
+}
+
+// Numbers passed to redis.call are formatted like %.17g, integers without a fraction
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e17 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
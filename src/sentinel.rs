@@ -0,0 +1,1460 @@
+// Sentinel mode: the server holds no data, it watches masters along with their replicas and
+// promotes a replica once enough sentinels agree that its master is down.
+//
+// Every master, replica and other sentinel is an instance probed by a task of its own over a
+// command link: PING every second, INFO on masters and replicas to learn the replicas and
+// their state, and a hello message published on them so the sentinels monitoring the same
+// master find each other. A second link to each master and replica listens to those hellos.
+// The failure detection and the failover run in sentinel_cycle, ten times a second.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display},
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use rand::Rng;
+use tokio::{net::TcpStream, time::Instant};
+
+use crate::{
+    config::{Role, generate_random_alphanumeric},
+    connection::Connection,
+    pubsub::PubSub,
+    replication::MasterAddr,
+    resp::frame::RespFrame,
+};
+
+// The channel sentinels announce themselves and their view of the master on
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+const TIMER_PERIOD: Duration = Duration::from_millis(100);
+const PING_PERIOD: Duration = Duration::from_secs(1);
+const INFO_PERIOD: Duration = Duration::from_secs(10);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+const ASK_PERIOD: Duration = Duration::from_secs(1);
+
+// What another sentinel said about a master is forgotten once it is this old
+const ASK_VALIDITY: Duration = Duration::from_secs(5);
+
+// A sentinel which isn't elected within this time, or the failover timeout if shorter,
+// gives up the failover
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sentinels wait up to this long before starting a failover, so they don't all ask for
+// votes at once and split them
+const MAX_DESYNC: Duration = Duration::from_millis(1000);
+
+const DEFAULT_DOWN_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Source of the serial every instance gets, a link task runs as long as its serial is known
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Master,
+    Replica,
+    Sentinel,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Master => "master",
+            Kind::Replica => "slave",
+            Kind::Sentinel => "sentinel",
+        }
+    }
+}
+
+struct Instance {
+    serial: u64,
+    kind: Kind,
+    addr: MasterAddr,
+    // last_ok is when the instance last answered a PING, or was added. It is subjectively
+    // down (sdown) once that is longer ago than down-after-milliseconds.
+    last_ok: Instant,
+    sdown: bool,
+    // What the last INFO of a master or replica said, and when
+    info_at: Option<Instant>,
+    role: Option<Role>,
+    role_since: Instant,
+    master_addr: Option<MasterAddr>,
+    master_link_up: bool,
+    priority: u64,
+    offset: u64,
+    // reconf_at is when a replica was last told which master to follow
+    reconf_at: Option<Instant>,
+    // Another sentinel's ID and when it last said hello, then whether it considers the
+    // master down, since when, and who it voted for as the failover leader
+    runid: String,
+    last_hello: Option<Instant>,
+    master_down_at: Option<Instant>,
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+impl Instance {
+    fn new(kind: Kind, addr: MasterAddr) -> Self {
+        Instance {
+            serial: NEXT_SERIAL.fetch_add(1, Ordering::Relaxed),
+            kind,
+            addr,
+            last_ok: Instant::now(),
+            sdown: false,
+            info_at: None,
+            role: None,
+            role_since: Instant::now(),
+            master_addr: None,
+            master_link_up: false,
+            priority: 100,
+            offset: 0,
+            reconf_at: None,
+            runid: String::new(),
+            last_hello: None,
+            master_down_at: None,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = vec![self.kind.name()];
+        if self.sdown {
+            flags.push("s_down");
+        }
+        if self.master_down_at.is_some() {
+            flags.push("master_down");
+        }
+        flags.join(",")
+    }
+}
+
+// The steps of a failover, once this sentinel decided to try one
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    // Collecting the votes of the other sentinels
+    WaitStart,
+    SelectReplica,
+    // The chosen replica was sent REPLICAOF NO ONE, its INFO has to report it a master
+    WaitPromotion,
+    // The other replicas are being pointed at the promoted one
+    ReconfReplicas,
+}
+
+struct Failover {
+    epoch: u64,
+    stage: Stage,
+    since: Instant,
+    promoted: Option<String>,
+    // forced is set by SENTINEL FAILOVER, which needs no agreement
+    forced: bool,
+}
+
+// A master this sentinel monitors, with the replicas and sentinels found along with it
+struct Monitored {
+    name: String,
+    master: Instance,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    // config_epoch is the epoch of the failover which produced the current master
+    config_epoch: u64,
+    // replicas are keyed by "ip:port", sentinels by their ID
+    replicas: BTreeMap<String, Instance>,
+    sentinels: BTreeMap<String, Instance>,
+    // odown_since is set while the master is objectively down: enough sentinels, the
+    // quorum, consider it down. desync is how long to wait before trying a failover.
+    odown_since: Option<Instant>,
+    desync: Duration,
+    // The failover leader this sentinel voted for, and in which epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+    failover: Option<Failover>,
+    // failover_started is when the last failover was tried, or a vote was given for
+    // another sentinel's, pushed back by a random desync. The next try waits twice the
+    // failover timeout from then, so sentinels whose votes split don't retry in step.
+    failover_started: Option<Instant>,
+}
+
+impl Monitored {
+    fn instances_mut(&mut self) -> impl Iterator<Item = &mut Instance> {
+        std::iter::once(&mut self.master)
+            .chain(self.replicas.values_mut())
+            .chain(self.sentinels.values_mut())
+    }
+
+    fn find(&self, serial: u64) -> Option<&Instance> {
+        std::iter::once(&self.master)
+            .chain(self.replicas.values())
+            .chain(self.sentinels.values())
+            .find(|instance| instance.serial == serial)
+    }
+
+    fn find_mut(&mut self, serial: u64) -> Option<&mut Instance> {
+        self.instances_mut()
+            .find(|instance| instance.serial == serial)
+    }
+
+    // How events name an instance, like Redis: the type, name and address, then for
+    // replicas and sentinels the master they belong to
+    fn describe(&self, instance: &Instance) -> String {
+        let name = match instance.kind {
+            Kind::Master => return format!("master {} {}", self.name, addr_string(&instance.addr)),
+            Kind::Replica => addr_key(&instance.addr),
+            Kind::Sentinel => instance.runid.clone(),
+        };
+        format!(
+            "{} {} {} @ {} {}",
+            instance.kind.name(),
+            name,
+            addr_string(&instance.addr),
+            self.name,
+            addr_string(&self.master.addr)
+        )
+    }
+
+    // The replica to promote: reachable, recently heard of, allowed to be promoted, then
+    // the lowest priority and the most data
+    fn best_replica(&self) -> Option<String> {
+        let info_validity = if self.master.sdown {
+            PING_PERIOD * 5
+        } else {
+            INFO_PERIOD * 3
+        };
+        self.replicas
+            .iter()
+            .filter(|(_, replica)| {
+                !replica.sdown
+                    && replica.last_ok.elapsed() < PING_PERIOD * 5
+                    && replica
+                        .info_at
+                        .is_some_and(|at| at.elapsed() < info_validity)
+                    && replica.role == Some(Role::Replica)
+                    && replica.priority != 0
+            })
+            .min_by(|(a_key, a), (b_key, b)| {
+                a.priority
+                    .cmp(&b.priority)
+                    .then(b.offset.cmp(&a.offset))
+                    .then(a_key.cmp(b_key))
+            })
+            .map(|(key, _)| key.clone())
+    }
+
+    // Follows a new master, the old one is kept as one of its replicas
+    fn switch_to(&mut self, addr: MasterAddr) {
+        let old = std::mem::replace(&mut self.master, Instance::new(Kind::Master, addr.clone()));
+        let mut addrs: Vec<MasterAddr> = self
+            .replicas
+            .values()
+            .map(|replica| replica.addr.clone())
+            .filter(|replica| *replica != addr)
+            .collect();
+        if old.addr != addr {
+            addrs.push(old.addr);
+        }
+        self.replicas = addrs
+            .into_iter()
+            .map(|addr| (addr_key(&addr), Instance::new(Kind::Replica, addr)))
+            .collect();
+        for sentinel in self.sentinels.values_mut() {
+            sentinel.master_down_at = None;
+            sentinel.leader = None;
+        }
+        self.odown_since = None;
+        self.failover = None;
+    }
+}
+
+struct State {
+    // current_epoch is the highest epoch seen, failovers are voted on per epoch
+    current_epoch: u64,
+    masters: BTreeMap<String, Monitored>,
+}
+
+// An instance a link task works on
+struct Link {
+    kind: Kind,
+    addr: MasterAddr,
+    timeout: Duration,
+    ping_period: Duration,
+    info_period: Duration,
+    // ask is the SENTINEL is-master-down-by-addr a sentinel is sent while the master is down
+    ask: Option<Vec<String>>,
+}
+
+// A command sent to an instance outside of its link, like the REPLICAOF of a failover
+struct Order {
+    addr: MasterAddr,
+    argv: Vec<String>,
+}
+
+/// Sentinel is the state of a server started with --sentinel: the masters it monitors and
+/// what it learned about them
+#[derive(Clone)]
+pub struct Sentinel {
+    myid: String,
+    port: u32,
+    // Events like +sdown or +switch-master are published on a channel of their name
+    pubsub: PubSub,
+    state: Arc<Mutex<State>>,
+}
+
+impl Sentinel {
+    pub fn new(port: u32, pubsub: PubSub) -> Self {
+        Sentinel {
+            myid: generate_random_alphanumeric(40),
+            port,
+            pubsub,
+            state: Arc::new(Mutex::new(State {
+                current_epoch: 0,
+                masters: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn myid(&self) -> &str {
+        &self.myid
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.state.lock().unwrap().masters.keys().cloned().collect()
+    }
+
+    /// Starts monitoring a master, SENTINEL MONITOR name ip port quorum
+    pub fn monitor(&self, name: &str, addr: MasterAddr, quorum: usize) -> Result<()> {
+        if quorum == 0 {
+            return Err(anyhow!("ERR Quorum must be 1 or greater."));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.masters.contains_key(name) {
+            return Err(anyhow!("ERR Duplicated master name"));
+        }
+        let monitored = Monitored {
+            name: name.to_string(),
+            master: Instance::new(Kind::Master, addr),
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            config_epoch: 0,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            odown_since: None,
+            desync: Duration::ZERO,
+            leader: None,
+            leader_epoch: 0,
+            failover: None,
+            failover_started: None,
+        };
+        self.event(
+            "+monitor",
+            format!(
+                "{} quorum {}",
+                monitored.describe(&monitored.master),
+                quorum
+            ),
+        );
+        state.masters.insert(name.to_string(), monitored);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let monitored = self
+            .state
+            .lock()
+            .unwrap()
+            .masters
+            .remove(name)
+            .ok_or_else(no_such_master)?;
+        self.event("-monitor", monitored.describe(&monitored.master));
+        Ok(())
+    }
+
+    /// Changes a setting of a monitored master, SENTINEL SET name option value
+    pub fn set(&self, name: &str, option: &str, value: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let monitored = state.masters.get_mut(name).ok_or_else(no_such_master)?;
+        let invalid = || {
+            anyhow!(
+                "ERR Invalid argument '{}' for SENTINEL SET '{}'",
+                value,
+                option
+            )
+        };
+        match option.to_lowercase().as_str() {
+            "down-after-milliseconds" => {
+                let millis = value.parse::<u64>().ok().filter(|millis| *millis > 0);
+                monitored.down_after = Duration::from_millis(millis.ok_or_else(invalid)?);
+            }
+            "failover-timeout" => {
+                let millis = value.parse::<u64>().ok().filter(|millis| *millis > 0);
+                monitored.failover_timeout = Duration::from_millis(millis.ok_or_else(invalid)?);
+            }
+            "quorum" => {
+                let quorum = value.parse::<usize>().ok().filter(|quorum| *quorum > 0);
+                monitored.quorum = quorum.ok_or_else(invalid)?;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "ERR Invalid argument '{}' for SENTINEL SET",
+                    option
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The address of the current master of `name`, the one clients should connect to
+    pub fn master_addr(&self, name: &str) -> Option<MasterAddr> {
+        let state = self.state.lock().unwrap();
+        state
+            .masters
+            .get(name)
+            .map(|monitored| monitored.master.addr.clone())
+    }
+
+    /// The fields SENTINEL MASTERS and SENTINEL MASTER list for each master
+    pub fn masters(&self) -> Vec<Vec<(String, String)>> {
+        let state = self.state.lock().unwrap();
+        state.masters.values().map(master_fields).collect()
+    }
+
+    pub fn master(&self, name: &str) -> Result<Vec<(String, String)>> {
+        let state = self.state.lock().unwrap();
+        state
+            .masters
+            .get(name)
+            .map(master_fields)
+            .ok_or_else(no_such_master)
+    }
+
+    pub fn replicas(&self, name: &str) -> Result<Vec<Vec<(String, String)>>> {
+        let state = self.state.lock().unwrap();
+        let monitored = state.masters.get(name).ok_or_else(no_such_master)?;
+        Ok(monitored
+            .replicas
+            .iter()
+            .map(|(key, replica)| {
+                let mut fields = instance_fields(key, replica);
+                fields.extend([
+                    (
+                        "master-link-status",
+                        if replica.master_link_up { "ok" } else { "err" }.to_string(),
+                    ),
+                    (
+                        "master-host",
+                        replica
+                            .master_addr
+                            .as_ref()
+                            .map_or("?".to_string(), |addr| addr.host.clone()),
+                    ),
+                    (
+                        "master-port",
+                        replica
+                            .master_addr
+                            .as_ref()
+                            .map_or("0".to_string(), |addr| addr.port.to_string()),
+                    ),
+                    ("slave-priority", replica.priority.to_string()),
+                    ("slave-repl-offset", replica.offset.to_string()),
+                ]);
+                into_owned(fields)
+            })
+            .collect())
+    }
+
+    pub fn sentinels(&self, name: &str) -> Result<Vec<Vec<(String, String)>>> {
+        let state = self.state.lock().unwrap();
+        let monitored = state.masters.get(name).ok_or_else(no_such_master)?;
+        Ok(monitored
+            .sentinels
+            .values()
+            .map(|sentinel| {
+                let mut fields = instance_fields(&sentinel.runid, sentinel);
+                fields.extend([
+                    ("runid", sentinel.runid.clone()),
+                    (
+                        "last-hello-message",
+                        sentinel
+                            .last_hello
+                            .map_or(0, |at| at.elapsed().as_millis())
+                            .to_string(),
+                    ),
+                    (
+                        "voted-leader",
+                        sentinel.leader.clone().unwrap_or("?".to_string()),
+                    ),
+                    ("voted-leader-epoch", sentinel.leader_epoch.to_string()),
+                ]);
+                into_owned(fields)
+            })
+            .collect())
+    }
+
+    /// Answers another sentinel asking whether the master at `addr` is down. With a `runid`
+    /// other than "*" the sentinel also asks for our vote as the leader of the failover of
+    /// `epoch`. Returns the opinion along with the leader voted for and its epoch.
+    pub fn is_master_down_by_addr(
+        &self,
+        addr: &MasterAddr,
+        epoch: u64,
+        runid: &str,
+    ) -> (bool, Option<String>, u64) {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            current_epoch,
+            masters,
+        } = &mut *state;
+        let Some(monitored) = masters
+            .values_mut()
+            .find(|monitored| monitored.master.addr == *addr)
+        else {
+            return (false, None, 0);
+        };
+        let down = monitored.master.sdown;
+        if runid == "*" {
+            return (down, None, 0);
+        }
+        let (leader, leader_epoch) = self.vote(current_epoch, monitored, epoch, runid);
+        (down, leader, leader_epoch)
+    }
+
+    /// Fails the master over right away, without asking the other sentinels to agree
+    pub fn failover(&self, name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            current_epoch,
+            masters,
+        } = &mut *state;
+        let monitored = masters.get_mut(name).ok_or_else(no_such_master)?;
+        if monitored.failover.is_some() {
+            return Err(anyhow!("INPROG Failover already in progress"));
+        }
+        if monitored.best_replica().is_none() {
+            return Err(anyhow!("NOGOODSLAVE No suitable replica to promote"));
+        }
+        *current_epoch += 1;
+        self.event("+new-epoch", current_epoch.to_string());
+        self.start_failover(monitored, *current_epoch, true);
+        Ok(())
+    }
+
+    /// Checks the sentinels currently reachable could agree on a failover of `name`
+    pub fn ckquorum(&self, name: &str) -> Result<String> {
+        let state = self.state.lock().unwrap();
+        let monitored = state.masters.get(name).ok_or_else(no_such_master)?;
+        let voters = monitored.sentinels.len() + 1;
+        let usable = 1 + monitored
+            .sentinels
+            .values()
+            .filter(|sentinel| !sentinel.sdown)
+            .count();
+        if usable < monitored.quorum {
+            return Err(anyhow!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the specified quorum for this master",
+                usable
+            ));
+        }
+        if usable < voters / 2 + 1 {
+            return Err(anyhow!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the majority and authorize a failover",
+                usable
+            ));
+        }
+        Ok(format!(
+            "OK {} usable Sentinels. Quorum and failover authorization can be reached",
+            usable
+        ))
+    }
+
+    fn event(&self, kind: &str, detail: String) {
+        println!("{} {}", kind, detail);
+        self.pubsub.publish(kind, detail.as_bytes());
+    }
+
+    // Votes for `runid` as the leader of the failover of `epoch`, unless a vote was already
+    // given in that epoch. Returns the current vote.
+    fn vote(
+        &self,
+        current_epoch: &mut u64,
+        monitored: &mut Monitored,
+        epoch: u64,
+        runid: &str,
+    ) -> (Option<String>, u64) {
+        if epoch > *current_epoch {
+            *current_epoch = epoch;
+            self.event("+new-epoch", epoch.to_string());
+        }
+        if monitored.leader_epoch < epoch && *current_epoch <= epoch {
+            monitored.leader = Some(runid.to_string());
+            monitored.leader_epoch = *current_epoch;
+            self.event(
+                "+vote-for-leader",
+                format!("{} {}", runid, monitored.leader_epoch),
+            );
+            // Another sentinel is failing the master over, ours would get in the way
+            if runid != self.myid {
+                monitored.failover_started = Some(Instant::now() + random_desync());
+            }
+        }
+        (monitored.leader.clone(), monitored.leader_epoch)
+    }
+
+    // The leader of the failover of `epoch`, if a sentinel got the votes of the majority and
+    // at least the quorum. This sentinel votes for the one most voted for, or for itself.
+    fn leader(
+        &self,
+        current_epoch: &mut u64,
+        monitored: &mut Monitored,
+        epoch: u64,
+    ) -> Option<String> {
+        let mut votes: HashMap<String, usize> = HashMap::new();
+        for sentinel in monitored.sentinels.values() {
+            if let Some(leader) = &sentinel.leader
+                && sentinel.leader_epoch == epoch
+            {
+                *votes.entry(leader.clone()).or_default() += 1;
+            }
+        }
+        let candidate = most_voted(&votes).map_or(self.myid.clone(), |(winner, _)| winner);
+        if let (Some(vote), vote_epoch) = self.vote(current_epoch, monitored, epoch, &candidate)
+            && vote_epoch == epoch
+        {
+            *votes.entry(vote).or_default() += 1;
+        }
+
+        let (winner, count) = most_voted(&votes)?;
+        let voters = monitored.sentinels.len() + 1;
+        (count > voters / 2 && count >= monitored.quorum).then_some(winner)
+    }
+
+    fn start_failover(&self, monitored: &mut Monitored, epoch: u64, forced: bool) {
+        monitored.failover = Some(Failover {
+            epoch,
+            stage: Stage::WaitStart,
+            since: Instant::now(),
+            promoted: None,
+            forced,
+        });
+        monitored.failover_started = Some(Instant::now() + random_desync());
+        self.event("+try-failover", monitored.describe(&monitored.master));
+    }
+
+    // Every instance of every master, to start the link tasks of the new ones
+    fn serials(&self) -> Vec<(String, u64, Kind)> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .masters
+            .values_mut()
+            .flat_map(|monitored| {
+                let name = monitored.name.clone();
+                monitored
+                    .instances_mut()
+                    .map(|instance| (name.clone(), instance.serial, instance.kind))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    // What the link of an instance has to do, None once the instance is gone
+    fn link(&self, name: &str, serial: u64) -> Option<Link> {
+        let mut state = self.state.lock().unwrap();
+        let current_epoch = state.current_epoch;
+        let monitored = state.masters.get_mut(name)?;
+        let master_addr = monitored.master.addr.clone();
+        let master_down = monitored.master.sdown;
+        let info_period = if master_down || monitored.failover.is_some() {
+            PING_PERIOD
+        } else {
+            INFO_PERIOD
+        };
+        // While failing the master over, the other sentinels are asked for their vote too
+        let runid = match monitored.failover {
+            Some(_) => self.myid.clone(),
+            None => "*".to_string(),
+        };
+        let timeout = monitored.down_after;
+        let instance = monitored.find_mut(serial)?;
+        let ask = (instance.kind == Kind::Sentinel && master_down).then(|| {
+            vec![
+                "SENTINEL".to_string(),
+                "is-master-down-by-addr".to_string(),
+                master_addr.host,
+                master_addr.port.to_string(),
+                current_epoch.to_string(),
+                runid,
+            ]
+        });
+        Some(Link {
+            kind: instance.kind,
+            addr: instance.addr.clone(),
+            timeout,
+            // Pinged at least twice within down-after-milliseconds, so an instance answering
+            // every ping is never seen down between two of them
+            ping_period: PING_PERIOD.min(timeout / 2),
+            info_period,
+            ask,
+        })
+    }
+
+    fn with_instance(&self, name: &str, serial: u64, f: impl FnOnce(&mut Instance)) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(instance) = state
+            .masters
+            .get_mut(name)
+            .and_then(|monitored| monitored.find_mut(serial))
+        {
+            f(instance);
+        }
+    }
+
+    // The hello this sentinel publishes on the instances of a master: its address, ID and
+    // epoch, then the master as it sees it and the epoch of that configuration
+    fn hello(&self, name: &str, ip: IpAddr) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let monitored = state.masters.get(name)?;
+        Some(format!(
+            "{},{},{},{},{},{},{},{}",
+            ip,
+            self.port,
+            self.myid,
+            state.current_epoch,
+            name,
+            monitored.master.addr.host,
+            monitored.master.addr.port,
+            monitored.config_epoch
+        ))
+    }
+
+    // Records the hello of another sentinel: it is added to the sentinels of the master,
+    // and a newer configuration of the master, after a failover, is taken over
+    fn process_hello(&self, payload: &str) {
+        let fields: Vec<&str> = payload.split(',').collect();
+        let [
+            ip,
+            port,
+            runid,
+            epoch,
+            name,
+            master_ip,
+            master_port,
+            config_epoch,
+        ] = fields[..]
+        else {
+            return;
+        };
+        let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) = (
+            port.parse::<u16>(),
+            epoch.parse::<u64>(),
+            master_port.parse::<u16>(),
+            config_epoch.parse::<u64>(),
+        ) else {
+            return;
+        };
+        if runid == self.myid {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if epoch > state.current_epoch {
+            state.current_epoch = epoch;
+            self.event("+new-epoch", epoch.to_string());
+        }
+        let Some(monitored) = state.masters.get_mut(name) else {
+            return;
+        };
+
+        let addr = MasterAddr {
+            host: ip.to_string(),
+            port,
+        };
+        // A sentinel restarted at the same address comes with a new ID
+        let restarted: Vec<String> = monitored
+            .sentinels
+            .iter()
+            .filter(|(key, sentinel)| *key != runid && sentinel.addr == addr)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in restarted {
+            monitored.sentinels.remove(&key);
+        }
+        if !monitored.sentinels.contains_key(runid) {
+            let mut sentinel = Instance::new(Kind::Sentinel, addr.clone());
+            sentinel.runid = runid.to_string();
+            self.event("+sentinel", monitored.describe(&sentinel));
+            monitored.sentinels.insert(runid.to_string(), sentinel);
+        }
+        if let Some(sentinel) = monitored.sentinels.get_mut(runid) {
+            sentinel.addr = addr;
+            sentinel.last_hello = Some(Instant::now());
+        }
+
+        if config_epoch > monitored.config_epoch {
+            monitored.config_epoch = config_epoch;
+            let announced = MasterAddr {
+                host: master_ip.to_string(),
+                port: master_port,
+            };
+            if announced != monitored.master.addr {
+                let sentinel = &monitored.sentinels[runid];
+                self.event("+config-update-from", monitored.describe(sentinel));
+                self.switch_master(monitored, announced);
+            }
+        }
+    }
+
+    fn switch_master(&self, monitored: &mut Monitored, addr: MasterAddr) {
+        self.event(
+            "+switch-master",
+            format!(
+                "{} {} {}",
+                monitored.name,
+                addr_string(&monitored.master.addr),
+                addr_string(&addr)
+            ),
+        );
+        monitored.switch_to(addr);
+    }
+
+    // Takes in what INFO says about a master or replica. A master lists its replicas, which
+    // are monitored from then on.
+    fn process_info(&self, name: &str, serial: u64, info: &str) {
+        let fields: HashMap<&str, &str> = info
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .collect();
+        let role = match fields.get("role") {
+            Some(&"master") => Role::Master,
+            Some(&"slave") => Role::Replica,
+            _ => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let Some(monitored) = state.masters.get_mut(name) else {
+            return;
+        };
+        let Some(instance) = monitored.find_mut(serial) else {
+            return;
+        };
+        instance.info_at = Some(Instant::now());
+        if instance.role != Some(role.clone()) {
+            instance.role = Some(role.clone());
+            instance.role_since = Instant::now();
+        }
+        if role == Role::Replica {
+            instance.master_addr = match (fields.get("master_host"), fields.get("master_port")) {
+                (Some(host), Some(port)) => port.parse().ok().map(|port| MasterAddr {
+                    host: host.to_string(),
+                    port,
+                }),
+                _ => None,
+            };
+            instance.master_link_up = fields.get("master_link_status") == Some(&"up");
+            instance.priority = fields
+                .get("slave_priority")
+                .and_then(|priority| priority.parse().ok())
+                .unwrap_or(100);
+            instance.offset = fields
+                .get("slave_repl_offset")
+                .and_then(|offset| offset.parse().ok())
+                .unwrap_or(0);
+        }
+
+        if instance.kind != Kind::Master || role != Role::Master {
+            return;
+        }
+        for (key, value) in &fields {
+            if !key.starts_with("slave") || !key[5..].chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            let replica: HashMap<&str, &str> = value
+                .split(',')
+                .filter_map(|field| field.split_once('='))
+                .collect();
+            let (Some(ip), Some(Ok(port))) = (
+                replica.get("ip"),
+                replica.get("port").map(|port| port.parse::<u16>()),
+            ) else {
+                continue;
+            };
+            let addr = MasterAddr {
+                host: ip.to_string(),
+                port,
+            };
+            let key = addr_key(&addr);
+            if !monitored.replicas.contains_key(&key) {
+                let replica = Instance::new(Kind::Replica, addr);
+                self.event("+slave", monitored.describe(&replica));
+                monitored.replicas.insert(key, replica);
+            }
+        }
+    }
+
+    // Takes in the reply to SENTINEL is-master-down-by-addr: whether the other sentinel
+    // considers the master down, and who it voted for
+    fn process_down_reply(&self, name: &str, serial: u64, reply: RespFrame) {
+        let RespFrame::Array(items) = reply else {
+            return;
+        };
+        let [
+            RespFrame::Integer(down),
+            RespFrame::BulkString(leader),
+            RespFrame::Integer(leader_epoch),
+        ] = &items[..]
+        else {
+            return;
+        };
+        self.with_instance(name, serial, |sentinel| {
+            sentinel.master_down_at = (*down == 1).then(Instant::now);
+            if leader != "*" {
+                sentinel.leader = Some(leader.clone());
+                sentinel.leader_epoch = *leader_epoch as u64;
+            }
+        });
+    }
+
+    // Runs the failure detection and the failover of every master, returning the commands
+    // to send to the instances
+    fn tick(&self) -> Vec<Order> {
+        let mut orders = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        let State {
+            current_epoch,
+            masters,
+        } = &mut *state;
+        for monitored in masters.values_mut() {
+            self.detect(monitored);
+            if monitored
+                .odown_since
+                .is_some_and(|since| since.elapsed() >= monitored.desync)
+                && monitored.failover.is_none()
+                && monitored
+                    .failover_started
+                    .is_none_or(|started| started.elapsed() >= monitored.failover_timeout * 2)
+            {
+                *current_epoch += 1;
+                self.event("+new-epoch", current_epoch.to_string());
+                self.start_failover(monitored, *current_epoch, false);
+            }
+            self.advance_failover(current_epoch, monitored, &mut orders);
+            self.fix_replicas(monitored, &mut orders);
+        }
+        orders
+    }
+
+    // Marks the instances which stopped answering subjectively down, and the master
+    // objectively down once the quorum of sentinels agrees
+    fn detect(&self, monitored: &mut Monitored) {
+        let down_after = monitored.down_after;
+        let mut changes = Vec::new();
+        for instance in monitored.instances_mut() {
+            let sdown = instance.last_ok.elapsed() > down_after;
+            if sdown != instance.sdown {
+                instance.sdown = sdown;
+                changes.push((sdown, instance.serial));
+            }
+            if instance
+                .master_down_at
+                .is_some_and(|at| at.elapsed() > ASK_VALIDITY)
+            {
+                instance.master_down_at = None;
+                instance.leader = None;
+            }
+        }
+        for (sdown, serial) in changes {
+            if let Some(instance) = monitored.find(serial) {
+                let kind = if sdown { "+sdown" } else { "-sdown" };
+                self.event(kind, monitored.describe(instance));
+            }
+        }
+
+        let votes = monitored.master.sdown as usize
+            + monitored
+                .sentinels
+                .values()
+                .filter(|sentinel| sentinel.master_down_at.is_some())
+                .count();
+        let odown = monitored.master.sdown && votes >= monitored.quorum;
+        match (odown, monitored.odown_since) {
+            (true, None) => {
+                monitored.odown_since = Some(Instant::now());
+                monitored.desync = random_desync();
+                self.event(
+                    "+odown",
+                    format!(
+                        "{} #quorum {}/{}",
+                        monitored.describe(&monitored.master),
+                        votes,
+                        monitored.quorum
+                    ),
+                );
+            }
+            (false, Some(_)) => {
+                monitored.odown_since = None;
+                self.event("-odown", monitored.describe(&monitored.master));
+            }
+            _ => {}
+        }
+    }
+
+    fn advance_failover(
+        &self,
+        current_epoch: &mut u64,
+        monitored: &mut Monitored,
+        orders: &mut Vec<Order>,
+    ) {
+        let Some(failover) = monitored.failover.as_ref() else {
+            return;
+        };
+        let (epoch, stage, since, forced) = (
+            failover.epoch,
+            failover.stage,
+            failover.since,
+            failover.forced,
+        );
+        let promoted = failover.promoted.clone();
+        let master = monitored.describe(&monitored.master);
+
+        match stage {
+            Stage::WaitStart => {
+                let leader = if forced {
+                    Some(self.myid.clone())
+                } else {
+                    self.leader(current_epoch, monitored, epoch)
+                };
+                if leader.as_deref() == Some(self.myid.as_str()) {
+                    self.event("+elected-leader", master.clone());
+                    self.event("+failover-state-select-slave", master);
+                    self.set_stage(monitored, Stage::SelectReplica);
+                } else if since.elapsed() > ELECTION_TIMEOUT.min(monitored.failover_timeout) {
+                    self.event("-failover-abort-not-elected", master);
+                    monitored.failover = None;
+                }
+            }
+            Stage::SelectReplica => {
+                let Some(key) = monitored.best_replica() else {
+                    self.event("-failover-abort-no-good-slave", master);
+                    monitored.failover = None;
+                    return;
+                };
+                let replica = &monitored.replicas[&key];
+                self.event("+selected-slave", monitored.describe(replica));
+                orders.push(Order {
+                    addr: replica.addr.clone(),
+                    argv: vec!["REPLICAOF".to_string(), "NO".to_string(), "ONE".to_string()],
+                });
+                self.event(
+                    "+failover-state-wait-promotion",
+                    monitored.describe(replica),
+                );
+                if let Some(failover) = monitored.failover.as_mut() {
+                    failover.promoted = Some(key);
+                }
+                self.set_stage(monitored, Stage::WaitPromotion);
+            }
+            Stage::WaitPromotion => {
+                let Some(replica) = promoted.and_then(|key| monitored.replicas.get(&key)) else {
+                    self.event("-failover-abort-slave-timeout", master);
+                    monitored.failover = None;
+                    return;
+                };
+                let promoted = replica.role == Some(Role::Master)
+                    && replica.info_at.is_some_and(|at| at > since);
+                if promoted {
+                    let new_master = replica.addr.clone();
+                    self.event("+promoted-slave", monitored.describe(replica));
+                    self.event("+failover-state-reconf-slaves", master);
+                    // The new configuration wins over the old one in the hellos from now on
+                    monitored.config_epoch = epoch;
+                    let others = monitored
+                        .replicas
+                        .values()
+                        .map(|replica| replica.addr.clone())
+                        .chain(std::iter::once(monitored.master.addr.clone()))
+                        .filter(|addr| *addr != new_master);
+                    for addr in others {
+                        orders.push(Order {
+                            addr,
+                            argv: vec![
+                                "REPLICAOF".to_string(),
+                                new_master.host.clone(),
+                                new_master.port.to_string(),
+                            ],
+                        });
+                    }
+                    self.set_stage(monitored, Stage::ReconfReplicas);
+                } else if since.elapsed() > monitored.failover_timeout {
+                    self.event("-failover-abort-slave-timeout", master);
+                    monitored.failover = None;
+                }
+            }
+            Stage::ReconfReplicas => {
+                let Some(addr) = promoted
+                    .and_then(|key| monitored.replicas.get(&key))
+                    .map(|replica| replica.addr.clone())
+                else {
+                    monitored.failover = None;
+                    return;
+                };
+                self.event("+failover-end", master);
+                self.switch_master(monitored, addr);
+            }
+        }
+    }
+
+    fn set_stage(&self, monitored: &mut Monitored, stage: Stage) {
+        if let Some(failover) = monitored.failover.as_mut() {
+            failover.stage = stage;
+            failover.since = Instant::now();
+        }
+    }
+
+    // Points replicas following the wrong master, or none, at the master. Only done while
+    // the master looks fine, so it is never a replica which another sentinel just promoted.
+    fn fix_replicas(&self, monitored: &mut Monitored, orders: &mut Vec<Order>) {
+        let master = &monitored.master;
+        let sane = !master.sdown
+            && master.role == Some(Role::Master)
+            && master
+                .info_at
+                .is_some_and(|at| at.elapsed() < INFO_PERIOD * 3);
+        if !sane || monitored.failover.is_some() {
+            return;
+        }
+        let master_addr = master.addr.clone();
+        let mut fixed = Vec::new();
+        for replica in monitored.replicas.values_mut() {
+            if replica.sdown
+                || replica
+                    .reconf_at
+                    .is_some_and(|at| at.elapsed() < INFO_PERIOD)
+            {
+                continue;
+            }
+            let event = match &replica.role {
+                Some(Role::Master) if replica.role_since.elapsed() > HELLO_PERIOD * 4 => {
+                    "+convert-to-slave"
+                }
+                Some(Role::Replica) if replica.master_addr.as_ref() != Some(&master_addr) => {
+                    "+fix-slave-config"
+                }
+                _ => continue,
+            };
+            replica.reconf_at = Some(Instant::now());
+            orders.push(Order {
+                addr: replica.addr.clone(),
+                argv: vec![
+                    "REPLICAOF".to_string(),
+                    master_addr.host.clone(),
+                    master_addr.port.to_string(),
+                ],
+            });
+            fixed.push((event, replica.serial));
+        }
+        for (event, serial) in fixed {
+            if let Some(replica) = monitored.find(serial) {
+                self.event(event, monitored.describe(replica));
+            }
+        }
+    }
+}
+
+impl Display for Sentinel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        let mut builder = String::new();
+        builder.push_str("# Sentinel\r\n");
+        builder.push_str(&format!("sentinel_masters:{}\r\n", state.masters.len()));
+        for (index, monitored) in state.masters.values().enumerate() {
+            let status = if monitored.odown_since.is_some() {
+                "odown"
+            } else if monitored.master.sdown {
+                "sdown"
+            } else {
+                "ok"
+            };
+            builder.push_str(&format!(
+                "master{}:name={},status={},address={}:{},slaves={},sentinels={}\r\n",
+                index,
+                monitored.name,
+                status,
+                monitored.master.addr.host,
+                monitored.master.addr.port,
+                monitored.replicas.len(),
+                monitored.sentinels.len() + 1
+            ));
+        }
+        f.write_str(&builder)
+    }
+}
+
+fn no_such_master() -> anyhow::Error {
+    anyhow!("ERR No such master with that name")
+}
+
+// How replicas are keyed, "ip:port"
+fn addr_key(addr: &MasterAddr) -> String {
+    format!("{}:{}", addr.host, addr.port)
+}
+
+fn addr_string(addr: &MasterAddr) -> String {
+    format!("{} {}", addr.host, addr.port)
+}
+
+fn random_desync() -> Duration {
+    Duration::from_millis(rand::rng().random_range(0..MAX_DESYNC.as_millis() as u64))
+}
+
+fn most_voted(votes: &HashMap<String, usize>) -> Option<(String, usize)> {
+    votes
+        .iter()
+        .max_by(|(a_id, a), (b_id, b)| a.cmp(b).then(b_id.cmp(a_id)))
+        .map(|(id, count)| (id.clone(), *count))
+}
+
+fn instance_fields(name: &str, instance: &Instance) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("name", name.to_string()),
+        ("ip", instance.addr.host.clone()),
+        ("port", instance.addr.port.to_string()),
+        ("flags", instance.flags()),
+        (
+            "last-ok-ping-reply",
+            instance.last_ok.elapsed().as_millis().to_string(),
+        ),
+    ];
+    // Sentinels aren't sent INFO
+    if instance.kind == Kind::Sentinel {
+        return fields;
+    }
+    fields.extend([
+        (
+            "info-refresh",
+            instance
+                .info_at
+                .map_or(0, |at| at.elapsed().as_millis())
+                .to_string(),
+        ),
+        (
+            "role-reported",
+            match instance.role {
+                Some(Role::Replica) => "slave",
+                _ => "master",
+            }
+            .to_string(),
+        ),
+    ]);
+    fields
+}
+
+fn master_fields(monitored: &Monitored) -> Vec<(String, String)> {
+    let mut fields = instance_fields(&monitored.name, &monitored.master);
+    if monitored.odown_since.is_some() {
+        fields[3].1.push_str(",o_down");
+    }
+    if monitored.failover.is_some() {
+        fields[3].1.push_str(",failover_in_progress");
+    }
+    fields.extend([
+        ("num-slaves", monitored.replicas.len().to_string()),
+        ("num-other-sentinels", monitored.sentinels.len().to_string()),
+        ("quorum", monitored.quorum.to_string()),
+        (
+            "down-after-milliseconds",
+            monitored.down_after.as_millis().to_string(),
+        ),
+        (
+            "failover-timeout",
+            monitored.failover_timeout.as_millis().to_string(),
+        ),
+        ("config-epoch", monitored.config_epoch.to_string()),
+    ]);
+    into_owned(fields)
+}
+
+fn into_owned(fields: Vec<(&'static str, String)>) -> Vec<(String, String)> {
+    fields
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+/// Monitors the configured masters: starts a link for every instance found, and runs the
+/// failure detection and failovers ten times a second
+pub async fn sentinel_cycle(sentinel: Sentinel) {
+    let mut linked = HashSet::new();
+    let mut interval = tokio::time::interval(TIMER_PERIOD);
+    loop {
+        interval.tick().await;
+        let serials = sentinel.serials();
+        for (name, serial, kind) in &serials {
+            if linked.insert(*serial) {
+                tokio::spawn(command_link(sentinel.clone(), name.clone(), *serial));
+                if *kind != Kind::Sentinel {
+                    tokio::spawn(hello_link(sentinel.clone(), name.clone(), *serial));
+                }
+            }
+        }
+        linked.retain(|serial| serials.iter().any(|(_, known, _)| known == serial));
+
+        for order in sentinel.tick() {
+            tokio::spawn(async move {
+                let result = async {
+                    let (mut connection, _) = connect(&order.addr).await?;
+                    let argv: Vec<&str> = order.argv.iter().map(String::as_str).collect();
+                    call(&mut connection, &argv, PING_PERIOD * 5).await
+                }
+                .await;
+                if let Err(err) = result {
+                    eprintln!(
+                        "Unable to send {} to {}:{}: {}",
+                        order.argv.join(" "),
+                        order.addr.host,
+                        order.addr.port,
+                        err
+                    );
+                }
+            });
+        }
+    }
+}
+
+// Probes an instance until it is forgotten, connecting again whenever the link is lost
+async fn command_link(sentinel: Sentinel, name: String, serial: u64) {
+    while let Some(link) = sentinel.link(&name, serial) {
+        let _ = probe(&sentinel, &name, serial, &link.addr).await;
+        tokio::time::sleep(PING_PERIOD).await;
+    }
+}
+
+async fn probe(sentinel: &Sentinel, name: &str, serial: u64, addr: &MasterAddr) -> Result<()> {
+    let (mut connection, ip) = connect(addr).await?;
+    let (mut pinged, mut informed, mut greeted, mut asked) = (None, None, None, None);
+    let mut last_ask = None;
+    let due =
+        |last: Option<Instant>, period: Duration| last.is_none_or(|at| at.elapsed() >= period);
+    while let Some(link) = sentinel.link(name, serial) {
+        // The instance moved, after a sentinel restarted for example
+        if link.addr != *addr {
+            return Ok(());
+        }
+        if due(pinged, link.ping_period) {
+            pinged = Some(Instant::now());
+            let reply = call(&mut connection, &["PING"], link.timeout).await?;
+            let ok = match &reply {
+                RespFrame::SimpleString(pong) => pong == "PONG",
+                RespFrame::Error(err) => {
+                    err.starts_with("LOADING") || err.starts_with("MASTERDOWN")
+                }
+                _ => false,
+            };
+            if ok {
+                sentinel.with_instance(name, serial, |instance| instance.last_ok = Instant::now());
+            }
+        }
+        if link.kind != Kind::Sentinel && due(informed, link.info_period) {
+            informed = Some(Instant::now());
+            if let RespFrame::BulkString(info) =
+                call(&mut connection, &["INFO"], link.timeout).await?
+            {
+                sentinel.process_info(name, serial, &info);
+            }
+        }
+        if link.kind != Kind::Sentinel
+            && due(greeted, HELLO_PERIOD)
+            && let Some(hello) = sentinel.hello(name, ip)
+        {
+            greeted = Some(Instant::now());
+            call(
+                &mut connection,
+                &["PUBLISH", HELLO_CHANNEL, &hello],
+                link.timeout,
+            )
+            .await?;
+        }
+        // A new question, a vote needed for a failover which just started, goes out right away
+        if let Some(ask) = link.ask
+            && (due(asked, ASK_PERIOD) || last_ask.as_ref() != Some(&ask))
+        {
+            asked = Some(Instant::now());
+            let argv: Vec<&str> = ask.iter().map(String::as_str).collect();
+            let reply = call(&mut connection, &argv, link.timeout).await?;
+            sentinel.process_down_reply(name, serial, reply);
+            last_ask = Some(ask);
+        }
+        tokio::time::sleep(TIMER_PERIOD).await;
+    }
+    Ok(())
+}
+
+// Listens to the hellos published on a master or replica until it is forgotten
+async fn hello_link(sentinel: Sentinel, name: String, serial: u64) {
+    while let Some(link) = sentinel.link(&name, serial) {
+        let _ = listen(&sentinel, &name, serial, &link.addr).await;
+        tokio::time::sleep(PING_PERIOD).await;
+    }
+}
+
+async fn listen(sentinel: &Sentinel, name: &str, serial: u64, addr: &MasterAddr) -> Result<()> {
+    let (mut connection, _) = connect(addr).await?;
+    connection
+        .write(request(&["SUBSCRIBE", HELLO_CHANNEL]))
+        .await?;
+    loop {
+        let Ok(reply) = tokio::time::timeout(PING_PERIOD, connection.read_reply()).await else {
+            if sentinel
+                .link(name, serial)
+                .is_none_or(|link| link.addr != *addr)
+            {
+                return Ok(());
+            }
+            continue;
+        };
+        if let RespFrame::Array(items) = reply?
+            && let [
+                RespFrame::BulkString(kind),
+                _,
+                RespFrame::BulkString(payload),
+            ] = &items[..]
+            && kind == "message"
+        {
+            sentinel.process_hello(payload);
+        }
+    }
+}
+
+// Connects to an instance, returning the link along with the local address it goes out
+// from, which the hellos announce
+async fn connect(addr: &MasterAddr) -> Result<(Connection, IpAddr)> {
+    let stream = tokio::time::timeout(
+        PING_PERIOD,
+        TcpStream::connect((addr.host.as_str(), addr.port)),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out connecting to {}:{}", addr.host, addr.port))??;
+    let local = stream.local_addr()?.ip();
+    let peer = stream.peer_addr()?;
+    Ok((Connection::new(stream, peer), local))
+}
+
+async fn call(connection: &mut Connection, argv: &[&str], timeout: Duration) -> Result<RespFrame> {
+    connection.write(request(argv)).await?;
+    tokio::time::timeout(timeout, connection.read_reply())
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the reply to {}", argv[0]))?
+}
+
+fn request(argv: &[&str]) -> RespFrame {
+    RespFrame::Array(
+        argv.iter()
+            .map(|arg| RespFrame::BulkString(arg.to_string()))
+            .collect(),
+    )
+}
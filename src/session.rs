@@ -0,0 +1,614 @@
+use std::{net::SocketAddr, sync::RwLock, time::Duration};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    config::{Config, Role},
+    mem::MemDB,
+    pubsub::{Kind, Subscriber, push_frame},
+    replication::{self, Peer, READONLY_ERR, Resync, Wait},
+    resp::{
+        commands::structs::Data,
+        frame::RespFrame,
+        parser::{Request, command_keys, is_write_command, parse_command},
+    },
+    scripting::BUSY_ERR,
+    transaction::{Transaction, WatchedKeys, with_exclusive_access},
+};
+
+// Commands a RESP2 client may still send while it is subscribed to a channel or pattern
+const SUBSCRIBER_COMMANDS: &[&str] = &[
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "ssubscribe",
+    "sunsubscribe",
+    "ping",
+    "quit",
+    "reset",
+];
+
+// Commands a server started with --sentinel serves, it has no dataset for the others
+const SENTINEL_MODE_COMMANDS: &[&str] = &[
+    "sentinel",
+    "ping",
+    "info",
+    "role",
+    "hello",
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "publish",
+    "quit",
+    "reset",
+];
+
+// Commands handled by the session itself rather than through the Command trait
+const SESSION_COMMANDS: &[&str] = &[
+    "subscribe",
+    "psubscribe",
+    "ssubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "sunsubscribe",
+    "hello",
+    "replconf",
+    "psync",
+    "wait",
+    "waitaof",
+    "asking",
+];
+
+// Session holds the state a single client connection carries between requests.
+// Commands which change this state are handled here instead of going through the Command trait.
+pub struct Session {
+    id: u64,
+    addr: SocketAddr,
+    // master_link is set on the session applying the stream of this server's master, which
+    // writes even though clients of a replica can't
+    master_link: bool,
+    // resp3 is set once the client negotiated RESP3 through HELLO
+    resp3: bool,
+    subscriber: Subscriber,
+    // transaction is set between MULTI and EXEC/DISCARD, commands are queued instead of executed
+    transaction: Option<Transaction>,
+    watched: WatchedKeys,
+    // closing is set by QUIT, the connection is dropped once the reply is written
+    closing: bool,
+    // listening_port and capa_eof are sent by a replica through REPLCONF ahead of PSYNC.
+    // sync is set by PSYNC, the connection then turns into the stream feeding a replica.
+    listening_port: u16,
+    capa_eof: bool,
+    sync: Option<Resync>,
+    // write_offset is the replication offset right after the client's last write, which
+    // WAIT and WAITAOF wait on. wait is set by them, the reply comes once the wait is over.
+    write_offset: u64,
+    wait: Option<Wait>,
+    // asking is set by ASKING, the next command may touch a slot this node is importing
+    asking: bool,
+    // db is the index of the database SELECT picked
+    db: usize,
+}
+
+impl Session {
+    pub fn new(id: u64, addr: SocketAddr, config: &Config) -> Self {
+        Session {
+            id,
+            addr,
+            master_link: false,
+            resp3: false,
+            subscriber: Subscriber::new(id, config.pubsub.clone()),
+            transaction: None,
+            watched: WatchedKeys::new(id),
+            closing: false,
+            listening_port: 0,
+            capa_eof: false,
+            sync: None,
+            write_offset: 0,
+            wait: None,
+            asking: false,
+            db: 0,
+        }
+    }
+
+    /// A session applying the write stream of the master this server replicates, from
+    /// database `db` on
+    pub fn master_link(id: u64, addr: SocketAddr, db: usize, config: &Config) -> Self {
+        Session {
+            master_link: true,
+            db,
+            ..Session::new(id, addr, config)
+        }
+    }
+
+    pub fn selected_db(&self) -> usize {
+        self.db
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Takes the resync PSYNC prepared, once its reply was written
+    pub fn take_sync(&mut self) -> Option<Resync> {
+        self.sync.take()
+    }
+
+    /// Takes what WAIT or WAITAOF waits for, the client gets no reply until the wait is over
+    pub fn take_wait(&mut self) -> Option<Wait> {
+        self.wait.take()
+    }
+
+    /// Releases what the connection registered in the databases, called once it is gone
+    pub fn close(&mut self, dbs: &[&RwLock<MemDB<Data>>]) {
+        let _ = self.watched.clear(dbs);
+    }
+
+    /// Waits for the next pub/sub message if the client is subscribed to anything.
+    /// Never resolves otherwise, so it can be raced against reading the next request.
+    /// Resolves to None when the client fell too far behind and has to be disconnected.
+    pub async fn next_message(&mut self) -> Option<RespFrame> {
+        if self.subscriber.is_subscribed() {
+            return self
+                .subscriber
+                .recv()
+                .await
+                .map(|message| message.into_frame(self.resp3));
+        }
+        std::future::pending().await
+    }
+
+    /// Handles a request, returning the frames to write back to the client
+    pub fn handle(
+        &mut self,
+        request: Request,
+        dbs: &[&RwLock<MemDB<Data>>],
+        config: &Config,
+    ) -> Vec<RespFrame> {
+        let offset = config.replication.master.offset();
+        // ASKING only holds for the command right after it, or for a whole transaction
+        let asking = request.name == "asking";
+        let frames = self.dispatch(request, dbs, config);
+        if !asking && self.transaction.is_none() {
+            self.asking = false;
+        }
+        // Whatever the request propagated, the client waits for it with WAIT
+        let written = config.replication.master.offset();
+        if written != offset {
+            self.write_offset = written;
+        }
+        frames
+    }
+
+    fn dispatch(
+        &mut self,
+        request: Request,
+        dbs: &[&RwLock<MemDB<Data>>],
+        config: &Config,
+    ) -> Vec<RespFrame> {
+        let db = dbs[self.db];
+        // While a script or function runs past the busy threshold it can only be killed
+        if config.scripting.is_busy() && !is_kill(&request) {
+            return vec![RespFrame::Error(BUSY_ERR.to_string())];
+        }
+
+        if config.sentinel.is_some() && !SENTINEL_MODE_COMMANDS.contains(&request.name.as_str()) {
+            return vec![RespFrame::Error(format!(
+                "ERR unknown command '{}'",
+                request.name
+            ))];
+        }
+
+        if self.subscriber.is_subscribed()
+            && !self.resp3
+            && !SUBSCRIBER_COMMANDS.contains(&request.name.as_str())
+        {
+            return vec![RespFrame::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                request.name
+            ))];
+        }
+
+        // A cluster node only serves the keys of its own slots, clients are sent to the
+        // node serving the others
+        if !self.master_link
+            && let Some(cluster) = &config.cluster
+            && let Err(err) = cluster.route(
+                &command_keys(&request),
+                self.asking || request.name == "restore-asking",
+                db,
+            )
+        {
+            if let Some(transaction) = self.transaction.as_mut() {
+                transaction.abort();
+            }
+            return vec![RespFrame::Error(err.to_string())];
+        }
+
+        if !self.master_link && config.replication.is_read_only() && is_write_command(&request) {
+            if let Some(transaction) = self.transaction.as_mut() {
+                transaction.abort();
+            }
+            return vec![RespFrame::Error(READONLY_ERR.to_string())];
+        }
+
+        if self.transaction.is_some()
+            && !matches!(
+                request.name.as_str(),
+                "multi" | "exec" | "discard" | "watch" | "reset" | "quit" | "asking"
+            )
+        {
+            return vec![self.queue(request)];
+        }
+
+        let resp3 = self.resp3;
+        match request.name.as_str() {
+            "subscribe" | "psubscribe" | "ssubscribe" if request.args.is_empty() => {
+                vec![wrong_arity(&request.name)]
+            }
+            "subscribe" => self
+                .subscriber
+                .subscribe(Kind::Channel, &request.args, resp3),
+            "psubscribe" => self
+                .subscriber
+                .subscribe(Kind::Pattern, &request.args, resp3),
+            "ssubscribe" => self.subscriber.subscribe(Kind::Shard, &request.args, resp3),
+            "unsubscribe" => self
+                .subscriber
+                .unsubscribe(Kind::Channel, &request.args, resp3),
+            "punsubscribe" => self
+                .subscriber
+                .unsubscribe(Kind::Pattern, &request.args, resp3),
+            "sunsubscribe" => self
+                .subscriber
+                .unsubscribe(Kind::Shard, &request.args, resp3),
+            // In subscriber mode PING replies in the same shape as the messages
+            "ping" if self.subscriber.is_subscribed() && !resp3 => {
+                let payload = request.args.first().cloned().unwrap_or_default();
+                vec![push_frame(
+                    vec![
+                        RespFrame::BulkString("pong".to_string()),
+                        RespFrame::BulkString(payload),
+                    ],
+                    false,
+                )]
+            }
+            "hello" => vec![
+                self.hello(&request.args, config)
+                    .unwrap_or_else(error_frame),
+            ],
+            "multi" if self.transaction.is_some() => vec![RespFrame::Error(
+                "ERR MULTI calls can not be nested".to_string(),
+            )],
+            "multi" => {
+                self.transaction = Some(Transaction::new());
+                vec![RespFrame::SimpleString("OK".to_string())]
+            }
+            "exec" => match self.transaction.take() {
+                Some(transaction) => {
+                    let reply = transaction.exec(dbs, self.db, config, &self.watched);
+                    let _ = self.watched.clear(dbs);
+                    match reply {
+                        Ok((reply, selected)) => {
+                            self.db = selected;
+                            vec![reply]
+                        }
+                        Err(err) => vec![error_frame(err)],
+                    }
+                }
+                None => vec![RespFrame::Error("ERR EXEC without MULTI".to_string())],
+            },
+            "discard" => match self.transaction.take() {
+                Some(_) => {
+                    let _ = self.watched.clear(dbs);
+                    vec![RespFrame::SimpleString("OK".to_string())]
+                }
+                None => vec![RespFrame::Error("ERR DISCARD without MULTI".to_string())],
+            },
+            "watch" if self.transaction.is_some() => vec![RespFrame::Error(
+                "ERR WATCH inside MULTI is not allowed".to_string(),
+            )],
+            "watch" if request.args.is_empty() => vec![wrong_arity(&request.name)],
+            "watch" => match self.watched.watch(db, &request.args) {
+                Ok(()) => vec![RespFrame::SimpleString("OK".to_string())],
+                Err(err) => vec![error_frame(err)],
+            },
+            "unwatch" => match self.watched.clear(dbs) {
+                Ok(()) => vec![RespFrame::SimpleString("OK".to_string())],
+                Err(err) => vec![error_frame(err)],
+            },
+            "reset" => {
+                self.transaction = None;
+                let _ = self.watched.clear(dbs);
+                self.subscriber.clear();
+                self.resp3 = false;
+                self.db = 0;
+                vec![RespFrame::SimpleString("RESET".to_string())]
+            }
+            "quit" => {
+                self.closing = true;
+                vec![RespFrame::SimpleString("OK".to_string())]
+            }
+            "replconf" => vec![self.replconf(&request.args).unwrap_or_else(error_frame)],
+            "asking" if config.cluster.is_none() => vec![RespFrame::Error(
+                "ERR This instance has cluster support disabled".to_string(),
+            )],
+            "asking" => {
+                self.asking = true;
+                vec![RespFrame::SimpleString("OK".to_string())]
+            }
+            "select" if request.args.len() != 1 => vec![wrong_arity(&request.name)],
+            "select" => match select_db(&request.args[0], dbs.len(), config) {
+                Ok(index) => {
+                    self.db = index;
+                    vec![RespFrame::SimpleString("OK".to_string())]
+                }
+                Err(err) => vec![error_frame(err)],
+            },
+            "psync" if request.args.len() != 2 => vec![wrong_arity(&request.name)],
+            "psync" => {
+                let peer = Peer {
+                    ip: self.addr.ip().to_string(),
+                    port: self.listening_port,
+                    eof: self.capa_eof,
+                };
+                match replication::psync(
+                    self.id,
+                    peer,
+                    &request.args[0],
+                    &request.args[1],
+                    dbs,
+                    config,
+                ) {
+                    // The reply is written along with the sync, once it starts
+                    Ok(sync) => {
+                        self.sync = Some(sync);
+                        vec![]
+                    }
+                    Err(err) => vec![error_frame(err)],
+                }
+            }
+            "wait" | "waitaof" => match self.prepare_wait(&request, config) {
+                Ok(wait) => {
+                    self.wait = Some(wait);
+                    vec![]
+                }
+                Err(err) => vec![error_frame(err)],
+            },
+            _ => vec![execute(request, db, self.db, config).unwrap_or_else(error_frame)],
+        }
+    }
+
+    // Queues a command sent after MULTI. A command which can't be parsed or fails validation
+    // is reported right away and makes the EXEC fail.
+    fn queue(&mut self, request: Request) -> RespFrame {
+        let Some(transaction) = self.transaction.as_mut() else {
+            return RespFrame::Error("ERR EXEC without MULTI".to_string());
+        };
+
+        // Commands changing the connection state can't be deferred to EXEC
+        if SESSION_COMMANDS.contains(&request.name.as_str()) {
+            transaction.abort();
+            return RespFrame::Error("ERR Command not allowed inside a transaction".to_string());
+        }
+
+        // EXEC unwatches every key anyway, so there is nothing to run
+        if request.name == "unwatch" {
+            return RespFrame::SimpleString("QUEUED".to_string());
+        }
+
+        // SELECT switches the database of the commands queued after it, once EXEC runs them
+        if request.name == "select" {
+            if request.args.len() != 1 {
+                transaction.abort();
+                return wrong_arity(&request.name);
+            }
+            transaction.queue_select(request.args[0].clone());
+            return RespFrame::SimpleString("QUEUED".to_string());
+        }
+
+        let argv = is_write_command(&request).then(|| request.argv());
+        let command = match parse_command(request) {
+            Ok(command) => command,
+            Err(err) => {
+                transaction.abort();
+                return error_frame(err);
+            }
+        };
+        if let Err(err) = command.validate() {
+            transaction.abort();
+            return error_frame(err);
+        }
+
+        transaction.queue(command, argv);
+        RespFrame::SimpleString("QUEUED".to_string())
+    }
+
+    // REPLCONF option value [option value ...], sent by a replica ahead of PSYNC. The port it
+    // listens on and whether it takes an RDB file between EOF marks are kept, the other
+    // options are accepted without being used.
+    fn replconf(&mut self, args: &[String]) -> Result<RespFrame> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(anyhow!("ERR syntax error"));
+        }
+        for pair in args.chunks(2) {
+            match pair[0].to_lowercase().as_str() {
+                "listening-port" => {
+                    self.listening_port = pair[1]
+                        .parse()
+                        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+                }
+                "capa" if pair[1].eq_ignore_ascii_case("eof") => self.capa_eof = true,
+                "ip-address" | "capa" => {}
+                _ => {
+                    return Err(anyhow!("ERR Unrecognized REPLCONF option: {}", pair[0]));
+                }
+            }
+        }
+        Ok(RespFrame::SimpleString("OK".to_string()))
+    }
+
+    // WAIT numreplicas timeout
+    // WAITAOF numlocal numreplicas timeout
+    fn prepare_wait(&self, request: &Request, config: &Config) -> Result<Wait> {
+        let aof = request.name == "waitaof";
+        let args = &request.args;
+        if args.len() != if aof { 3 } else { 2 } {
+            return Err(anyhow!(
+                "ERR wrong number of arguments for '{}' command",
+                request.name
+            ));
+        }
+        if config.replication.role() == Role::Replica {
+            return Err(anyhow!(if aof {
+                "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+            } else {
+                "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated."
+            }));
+        }
+
+        let integer = |arg: &String| {
+            arg.parse::<u64>()
+                .map_err(|_| anyhow!("ERR value is not an integer or out of range"))
+        };
+        let local = aof && integer(&args[0])? > 0;
+        let replicas = integer(&args[args.len() - 2])? as usize;
+        let timeout = match args[args.len() - 1].parse::<i64>() {
+            Ok(timeout) if timeout < 0 => return Err(anyhow!("ERR timeout is negative")),
+            Ok(timeout) => timeout as u64,
+            Err(_) => return Err(anyhow!("ERR timeout is not an integer or out of range")),
+        };
+        if local && !config.aof.enabled() {
+            return Err(anyhow!(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+            ));
+        }
+
+        Ok(Wait {
+            offset: self.write_offset,
+            local,
+            replicas,
+            aof,
+            // A timeout of 0 blocks for as long as it takes
+            timeout: (timeout > 0).then(|| Duration::from_millis(timeout)),
+        })
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn hello(&mut self, args: &[String], config: &Config) -> Result<RespFrame> {
+        if let Some(version) = args.first() {
+            match version.parse::<i64>() {
+                Ok(2) => self.resp3 = false,
+                Ok(3) => self.resp3 = true,
+                Ok(_) => return Err(anyhow!("NOPROTO unsupported protocol version")),
+                Err(_) => {
+                    return Err(anyhow!(
+                        "ERR Protocol version is not an integer or out of range"
+                    ));
+                }
+            }
+        }
+
+        // AUTH and SETNAME are accepted for compatibility, there are no users or client names yet
+        let mut idx = 1;
+        while idx < args.len() {
+            match args[idx].to_lowercase().as_str() {
+                "auth" if idx + 2 < args.len() => idx += 3,
+                "setname" if idx + 1 < args.len() => idx += 2,
+                _ => return Err(anyhow!("ERR syntax error")),
+            }
+        }
+
+        let role = match config.replication.role() {
+            Role::Master => "master",
+            Role::Replica => "replica",
+        };
+        let fields = vec![
+            ("server", RespFrame::BulkString("redis".to_string())),
+            (
+                "version",
+                RespFrame::BulkString(config.server.redis_version.clone()),
+            ),
+            ("proto", RespFrame::Integer(if self.resp3 { 3 } else { 2 })),
+            ("id", RespFrame::Integer(self.id as i64)),
+            ("mode", RespFrame::BulkString("standalone".to_string())),
+            ("role", RespFrame::BulkString(role.to_string())),
+            ("modules", RespFrame::EmptyArray),
+        ];
+
+        if self.resp3 {
+            return Ok(RespFrame::Map(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (RespFrame::BulkString(key.to_string()), value))
+                    .collect(),
+            ));
+        }
+        Ok(RespFrame::Array(
+            fields
+                .into_iter()
+                .flat_map(|(key, value)| [RespFrame::BulkString(key.to_string()), value])
+                .collect(),
+        ))
+    }
+}
+
+// Writes run while holding the database, so they reach the AOF in the order they were applied
+fn execute(
+    request: Request,
+    db: &RwLock<MemDB<Data>>,
+    index: usize,
+    config: &Config,
+) -> Result<RespFrame> {
+    if !is_write_command(&request) {
+        return run(request, db, config);
+    }
+
+    let argv = request.argv();
+    with_exclusive_access(db, |db| {
+        let reply = run(request, db, config)?;
+        config.propagate(index, vec![argv]);
+        Ok(reply)
+    })?
+}
+
+fn run(request: Request, db: &RwLock<MemDB<Data>>, config: &Config) -> Result<RespFrame> {
+    let command = parse_command(request)?;
+    command.validate()?;
+    command.execute(db, config)
+}
+
+/// SELECT index, among the `count` databases. A cluster only has database 0.
+pub fn select_db(index: &str, count: usize, config: &Config) -> Result<usize> {
+    let index = index
+        .parse::<i64>()
+        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+    if config.cluster.is_some() && index != 0 {
+        return Err(anyhow!("ERR SELECT is not allowed in cluster mode"));
+    }
+    if index < 0 || index as usize >= count {
+        return Err(anyhow!("ERR DB index is out of range"));
+    }
+    Ok(index as usize)
+}
+
+fn is_kill(request: &Request) -> bool {
+    (request.name == "script" || request.name == "function")
+        && request
+            .args
+            .first()
+            .is_some_and(|sub| sub.eq_ignore_ascii_case("kill"))
+}
+
+fn error_frame(err: anyhow::Error) -> RespFrame {
+    RespFrame::Error(err.to_string())
+}
+
+fn wrong_arity(name: &str) -> RespFrame {
+    RespFrame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
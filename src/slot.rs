@@ -0,0 +1,31 @@
+// Key to hash slot mapping, the same one Redis Cluster uses.
+//
+// The slot is CRC16 (XMODEM) of the key modulo 16384. When the key contains a non empty
+// {hashtag} only the hashtag is hashed, which lets related keys share a slot.
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in buf {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    if let Some(start) = key.iter().position(|c| *c == b'{')
+        && let Some(len) = key[start + 1..].iter().position(|c| *c == b'}')
+        && len > 0
+    {
+        return crc16(&key[start + 1..start + 1 + len]) & (CLUSTER_SLOTS - 1);
+    }
+    crc16(key) & (CLUSTER_SLOTS - 1)
+}
@@ -0,0 +1,403 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Error, Result, anyhow};
+
+use crate::{
+    config::Config,
+    mem::{Databases, MemDB},
+    rdb::{self, Snapshot},
+    resp::commands::structs::Data,
+    transaction::exclusively,
+};
+
+// The save rules Redis starts with: after an hour with one change, after five minutes with
+// 100 changes and after a minute with 10000 changes
+const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+// How often the save rules are checked
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long to wait before retrying a background save which failed
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// SaveRule triggers a background save once `changes` writes happened within `seconds`
+#[derive(Clone, Copy)]
+struct SaveRule {
+    seconds: u64,
+    changes: u64,
+}
+
+struct State {
+    dir: String,
+    dbfilename: String,
+    rules: Vec<SaveRule>,
+    // last_save is when the last successful save finished, in unix seconds
+    last_save: u64,
+    last_attempt: SystemTime,
+    last_save_ok: bool,
+    // saved_changes is the change count of the database when the last save took its snapshot
+    saved_changes: u64,
+    bgsave_in_progress: bool,
+    // bgsave_scheduled is set by BGSAVE SCHEDULE while another save runs
+    bgsave_scheduled: bool,
+}
+
+// Persistence holds the RDB settings along with the state of the saves. BGSAVE clones the
+// keys while holding the read lock, then encodes and writes them on a separate thread so
+// clients only wait for the copy.
+#[derive(Clone)]
+pub struct Persistence {
+    state: Arc<Mutex<State>>,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+        Persistence {
+            state: Arc::new(Mutex::new(State {
+                dir: ".".to_string(),
+                dbfilename: "dump.rdb".to_string(),
+                rules: parse_rules(DEFAULT_SAVE_RULES).unwrap_or_default(),
+                last_save: unix_seconds(now),
+                last_attempt: now,
+                last_save_ok: true,
+                saved_changes: 0,
+                bgsave_in_progress: false,
+                bgsave_scheduled: false,
+            })),
+        }
+    }
+
+    pub fn dir(&self) -> String {
+        self.state.lock().unwrap().dir.clone()
+    }
+
+    pub fn set_dir(&self, dir: &str) {
+        self.state.lock().unwrap().dir = dir.to_string();
+    }
+
+    pub fn dbfilename(&self) -> String {
+        self.state.lock().unwrap().dbfilename.clone()
+    }
+
+    pub fn set_dbfilename(&self, name: &str) -> Result<()> {
+        if name.contains('/') || name.contains('\\') {
+            return Err(anyhow!("dbfilename can't be a path, just a filename"));
+        }
+        self.state.lock().unwrap().dbfilename = name.to_string();
+        Ok(())
+    }
+
+    /// Formats the save rules the way CONFIG GET save shows them
+    pub fn save_rules(&self) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .rules
+            .iter()
+            .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Replaces the save rules, an empty value disables automatic saves
+    pub fn set_save_rules(&self, rules: &str) -> Result<()> {
+        self.state.lock().unwrap().rules = parse_rules(rules)?;
+        Ok(())
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.state.lock().unwrap().last_save
+    }
+
+    pub fn path(&self) -> PathBuf {
+        let state = self.state.lock().unwrap();
+        PathBuf::from(&state.dir).join(&state.dbfilename)
+    }
+
+    /// Loads the RDB file into the empty databases, a missing file leaves them empty
+    pub fn load(&self, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<()> {
+        let path = self.path();
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context(format!("reading {}", path.display())),
+        };
+        let snapshot = rdb::decode(&data).with_context(|| format!("loading {}", path.display()))?;
+
+        let keys = restore(snapshot, dbs, config)?;
+        self.mark_saved(dbs)?;
+        println!("DB loaded from disk: {} keys", keys);
+        Ok(())
+    }
+
+    /// Records the databases as matching the RDB file, so the save rules start counting from here
+    pub fn mark_saved(&self, dbs: &[&RwLock<MemDB<Data>>]) -> Result<()> {
+        let mut changes = 0;
+        for db in dbs {
+            changes += db
+                .read()
+                .map_err(|_| Error::msg("Unable to acquire lock"))?
+                .changes();
+        }
+        self.state.lock().unwrap().saved_changes = changes;
+        Ok(())
+    }
+
+    /// Writes the RDB file while the caller waits
+    pub fn save(&self, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<()> {
+        if self.state.lock().unwrap().bgsave_in_progress {
+            return Err(anyhow!("ERR Background save already in progress"));
+        }
+
+        let (snapshot, changes) = exclusively(|| take_snapshot(dbs, config))?;
+        let result = self.write(&snapshot, &config.server.redis_version, "temp");
+        self.finish(result.is_ok(), changes);
+        result.map_err(|err| anyhow!("ERR {}", err))
+    }
+
+    /// Starts writing the RDB file in the background. With `schedule` a save already
+    /// running isn't an error, another one starts once it is done.
+    pub fn bgsave(
+        &self,
+        dbs: &[&RwLock<MemDB<Data>>],
+        config: &Config,
+        schedule: bool,
+    ) -> Result<BgSaveStatus> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.bgsave_in_progress {
+                if !schedule {
+                    return Err(anyhow!("ERR Background save already in progress"));
+                }
+                state.bgsave_scheduled = true;
+                return Ok(BgSaveStatus::Scheduled);
+            }
+            state.bgsave_in_progress = true;
+            state.bgsave_scheduled = false;
+        }
+
+        // The copy is taken while no command runs, so it is a single point in time
+        let (snapshot, changes) = match exclusively(|| take_snapshot(dbs, config)) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                self.finish(false, 0);
+                return Err(err);
+            }
+        };
+        let persistence = self.clone();
+        let redis_version = config.server.redis_version.clone();
+        std::thread::spawn(move || {
+            let result = persistence.write(&snapshot, &redis_version, "temp-bgsave");
+            if let Err(err) = &result {
+                eprintln!("Background saving error: {}", err);
+            }
+            persistence.finish(result.is_ok(), changes);
+        });
+        Ok(BgSaveStatus::Started)
+    }
+
+    // Encodes the snapshot into a temporary file renamed over the RDB file once complete, so
+    // a crash halfway never leaves a truncated file behind. SAVE and BGSAVE use different
+    // temporary files as a background save may start while SAVE writes.
+    fn write(&self, snapshot: &Snapshot, redis_version: &str, temp_prefix: &str) -> Result<()> {
+        self.write_file(&rdb::encode(snapshot, redis_version), temp_prefix)
+    }
+
+    /// Replaces the RDB file with one a master sent, which the replica then loads from disk
+    pub fn store(&self, rdb: &[u8]) -> Result<PathBuf> {
+        self.write_file(rdb, "temp-sync")?;
+        Ok(self.path())
+    }
+
+    // The data reaches the disk before the rename, and the rename itself is synced through the
+    // directory, so after a crash the RDB file is either the old or the new one, complete
+    fn write_file(&self, data: &[u8], temp_prefix: &str) -> Result<()> {
+        let path = self.path();
+        let temp = path.with_file_name(format!("{}-{}.rdb", temp_prefix, std::process::id()));
+        File::create(&temp)
+            .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .and_then(|_| sync_dir(&path))
+            .map_err(|err| {
+                let _ = std::fs::remove_file(&temp);
+                anyhow!("Failed saving the DB: {}", err)
+            })
+    }
+
+    fn finish(&self, ok: bool, changes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        state.bgsave_in_progress = false;
+        state.last_attempt = now;
+        state.last_save_ok = ok;
+        if ok {
+            state.last_save = unix_seconds(now);
+            state.saved_changes = changes;
+        }
+    }
+
+    // A background save is due when one was scheduled, or when a save rule matches. After
+    // a failure rules are only retried after a delay.
+    fn save_due(&self, changes: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.bgsave_in_progress {
+            return false;
+        }
+        if state.bgsave_scheduled {
+            return true;
+        }
+
+        let since_save = unix_seconds(SystemTime::now()).saturating_sub(state.last_save);
+        let retry_ok = state.last_save_ok
+            || state.last_attempt.elapsed().unwrap_or_default() >= BGSAVE_RETRY_DELAY;
+        let dirty = changes.saturating_sub(state.saved_changes);
+        retry_ok
+            && state
+                .rules
+                .iter()
+                .any(|rule| dirty >= rule.changes && since_save >= rule.seconds)
+    }
+}
+
+// What BGSAVE did
+pub enum BgSaveStatus {
+    Started,
+    Scheduled,
+}
+
+// The persistence section of INFO
+impl Display for Persistence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        let mut builder = String::new();
+        builder.push_str("# Persistence\r\n");
+        builder.push_str(&format!(
+            "rdb_bgsave_in_progress:{}\r\n",
+            state.bgsave_in_progress as u8
+        ));
+        builder.push_str(&format!("rdb_last_save_time:{}\r\n", state.last_save));
+        builder.push_str(&format!(
+            "rdb_last_bgsave_status:{}\r\n",
+            if state.last_save_ok { "ok" } else { "err" }
+        ));
+        f.write_str(&builder)
+    }
+}
+
+/// Starts background saves when the save rules call for one, runs for as long as the server does
+pub async fn save_cycle(dbs: Arc<Databases<Data>>, config: Config) {
+    let mut interval = tokio::time::interval(SAVE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // The check is skipped while a transaction or a script holds a database
+        let Ok(changes) = dbs
+            .all()
+            .iter()
+            .map(|db| db.try_read().map(|db| db.changes()))
+            .sum::<Result<u64, _>>()
+        else {
+            continue;
+        };
+        if config.persistence.save_due(changes)
+            && let Err(err) = config.persistence.bgsave(&dbs.all(), &config, false)
+        {
+            eprintln!("Background saving error: {}", err);
+        }
+    }
+}
+
+/// Adds the libraries and the keys of a snapshot to the databases, returning how many keys
+/// were loaded
+pub fn restore(snapshot: Snapshot, dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<usize> {
+    for code in &snapshot.libraries {
+        config.functions.load(code, true)?;
+    }
+    let mut guards = dbs
+        .iter()
+        .map(|db| db.write())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::msg("Unable to acquire lock"))?;
+    let mut keys = 0;
+    for (index, key, data) in snapshot.entries {
+        let Some(db) = guards.get_mut(index) else {
+            eprintln!(
+                "Skipping key '{}' of database {}, there are only {} databases",
+                key,
+                index,
+                dbs.len()
+            );
+            continue;
+        };
+        db.set(key, data);
+        keys += 1;
+    }
+    Ok(keys)
+}
+
+// Syncs the directory holding the file, which makes a rename into it durable
+pub fn sync_dir(file: &Path) -> std::io::Result<()> {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Copies the live keys of every database and the function libraries, along with the change
+// count they match. The databases are copied one after the other, callers run it within
+// `exclusively` so the copy is a single point in time.
+pub fn take_snapshot(dbs: &[&RwLock<MemDB<Data>>], config: &Config) -> Result<(Snapshot, u64)> {
+    let mut entries = Vec::new();
+    let mut changes = 0;
+    for (index, db) in dbs.iter().enumerate() {
+        let db = db
+            .read()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+        entries.extend(
+            db.iter()
+                .filter(|(_, data)| !data.expired())
+                .map(|(key, data)| (index, key.clone(), data.clone())),
+        );
+        changes += db.changes();
+    }
+    let libraries = config
+        .functions
+        .list(None)
+        .into_iter()
+        .map(|library| library.code)
+        .collect();
+    Ok((Snapshot { entries, libraries }, changes))
+}
+
+// Parses "<seconds> <changes> [<seconds> <changes> ...]"
+fn parse_rules(rules: &str) -> Result<Vec<SaveRule>> {
+    let parts: Vec<&str> = rules.split_whitespace().collect();
+    if !parts.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid save parameters"));
+    }
+    parts
+        .chunks(2)
+        .map(|pair| {
+            let seconds = pair[0].parse();
+            let changes = pair[1].parse();
+            match (seconds, changes) {
+                (Ok(seconds), Ok(changes)) => Ok(SaveRule { seconds, changes }),
+                _ => Err(anyhow!("Invalid save parameters")),
+            }
+        })
+        .collect()
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
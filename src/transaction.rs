@@ -0,0 +1,500 @@
+use std::{
+    cell::{Cell, RefCell},
+    sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockWriteGuard, TryLockError},
+};
+
+use anyhow::{Error, Result, anyhow};
+
+use crate::{
+    config::Config,
+    mem::MemDB,
+    resp::{
+        commands::{Command, structs::Data},
+        frame::RespFrame,
+    },
+    session::select_db,
+};
+
+// Only one exclusive section runs at a time, whichever databases it touches. Writes to the
+// databases all happen in one, so while it runs nothing else changes the dataset.
+static EXCLUSIVE_ACCESS: Mutex<()> = Mutex::new(());
+
+// A write command along with the index of the database it ran against
+type Effect = (usize, Vec<Vec<u8>>);
+
+thread_local! {
+    // Set while the thread runs an exclusive section, the ones it starts from within run
+    // right away
+    static IN_EXCLUSIVE_SECTION: Cell<bool> = const { Cell::new(false) };
+
+    // Set while the thread runs EXEC, the writes a queued command propagates on its own, like
+    // those of a script, wait here to go out in order along with the rest of the transaction
+    static DEFERRED_EFFECTS: RefCell<Option<Vec<Effect>>> = const { RefCell::new(None) };
+}
+
+/// Propagates the write commands a command executed against database `db`, unless it runs
+/// within EXEC in which case they are handed back to the transaction to propagate them in order
+/// along with its own writes.
+pub fn propagate_effects(config: &Config, db: usize, effects: Vec<Vec<Vec<u8>>>) {
+    let effects = DEFERRED_EFFECTS.with_borrow_mut(|deferred| match deferred {
+        Some(deferred) => {
+            deferred.extend(effects.into_iter().map(|argv| (db, argv)));
+            None
+        }
+        None => Some(effects),
+    });
+    if let Some(effects) = effects {
+        config.propagate(db, effects);
+    }
+}
+
+// A queued command, along with its arguments when it writes so EXEC can propagate it, or a
+// SELECT which makes the commands after it run against another database
+enum Queued {
+    Command(Box<dyn Command>, Option<Vec<Vec<u8>>>),
+    Select(String),
+}
+
+// Transaction is the state a connection carries between MULTI and EXEC
+#[derive(Default)]
+pub struct Transaction {
+    commands: Vec<Queued>,
+    // aborted is set when a command couldn't be queued, EXEC then discards the whole transaction
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn queue(&mut self, command: Box<dyn Command>, argv: Option<Vec<Vec<u8>>>) {
+        self.commands.push(Queued::Command(command, argv));
+    }
+
+    pub fn queue_select(&mut self, index: String) {
+        self.commands.push(Queued::Select(index));
+    }
+
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Runs every queued command without letting any other client in between them,
+    /// returning their replies in order along with the database selected in the end.
+    /// A failing command doesn't stop the ones after it. Nothing runs and a null array is
+    /// returned when one of the watched keys changed.
+    pub fn exec(
+        self,
+        dbs: &[&RwLock<MemDB<Data>>],
+        selected: usize,
+        config: &Config,
+        watched: &WatchedKeys,
+    ) -> Result<(RespFrame, usize)> {
+        if self.aborted {
+            return Err(anyhow!(
+                "EXECABORT Transaction discarded because of previous errors."
+            ));
+        }
+
+        exclusively(|| {
+            if watched.changed(dbs) {
+                return Ok((RespFrame::NullArray, selected));
+            }
+
+            let mut index = selected;
+            let mut replies = Vec::new();
+            // The writes go out as one transaction, switching database where the client did
+            let mut written = Vec::new();
+            let (mut first_written, mut written_db) = (None, None);
+            let mut commands = self.commands.into_iter().peekable();
+            loop {
+                // The commands up to the next SELECT hold their database the whole time they run
+                let mut batch = Vec::new();
+                while let Some(Queued::Command(..)) = commands.peek() {
+                    if let Some(Queued::Command(command, argv)) = commands.next() {
+                        batch.push((command, argv));
+                    }
+                }
+                with_exclusive_access(dbs[index], |db| {
+                    for (command, argv) in batch {
+                        DEFERRED_EFFECTS.set(Some(Vec::new()));
+                        let result = command.execute(db, config);
+                        let deferred = DEFERRED_EFFECTS.take().unwrap_or_default();
+                        match result {
+                            Ok(reply) => {
+                                let effects = argv.map(|argv| (index, argv)).into_iter();
+                                for (db, argv) in effects.chain(deferred) {
+                                    if written_db.is_some_and(|written| written != db) {
+                                        written.push(vec![
+                                            b"SELECT".to_vec(),
+                                            db.to_string().into_bytes(),
+                                        ]);
+                                    }
+                                    first_written.get_or_insert(db);
+                                    written_db = Some(db);
+                                    written.push(argv);
+                                }
+                                replies.push(reply);
+                            }
+                            Err(err) => replies.push(RespFrame::Error(err.to_string())),
+                        }
+                    }
+                })?;
+
+                match commands.next() {
+                    Some(Queued::Select(arg)) => match select_db(&arg, dbs.len(), config) {
+                        Ok(selected) => {
+                            index = selected;
+                            replies.push(RespFrame::SimpleString("OK".to_string()));
+                        }
+                        Err(err) => replies.push(RespFrame::Error(err.to_string())),
+                    },
+                    _ => break,
+                }
+            }
+            if let Some(db) = first_written {
+                config.propagate(db, written);
+            }
+            Ok((RespFrame::Array(replies), index))
+        })
+    }
+}
+
+// WatchedKeys are the keys a connection called WATCH on with the index of their database,
+// along with whether each one held a value at that time so a key expiring before EXEC is
+// noticed as well
+pub struct WatchedKeys {
+    client: u64,
+    keys: Vec<(usize, String, bool)>,
+}
+
+impl WatchedKeys {
+    pub fn new(client: u64) -> Self {
+        WatchedKeys {
+            client,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn watch(&mut self, db: &RwLock<MemDB<Data>>, keys: &[String]) -> Result<()> {
+        let mut db = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let index = db.index();
+        for key in keys {
+            if self
+                .keys
+                .iter()
+                .any(|(watched_index, watched, _)| *watched_index == index && watched == key)
+            {
+                continue;
+            }
+            db.watch(key, self.client);
+            self.keys.push((index, key.clone(), db.exists(key)));
+        }
+        Ok(())
+    }
+
+    /// Forgets every watched key, done on EXEC, DISCARD, UNWATCH and when the connection closes
+    pub fn clear(&mut self, dbs: &[&RwLock<MemDB<Data>>]) -> Result<()> {
+        let keys = std::mem::take(&mut self.keys);
+        for (index, db) in dbs.iter().enumerate() {
+            let keys: Vec<String> = keys
+                .iter()
+                .filter(|(key_index, _, _)| *key_index == index)
+                .map(|(_, key, _)| key.clone())
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            db.write()
+                .map_err(|_| Error::msg("Unable to acquire lock"))?
+                .unwatch(&keys, self.client);
+        }
+        Ok(())
+    }
+
+    fn changed(&self, dbs: &[&RwLock<MemDB<Data>>]) -> bool {
+        dbs.iter().enumerate().any(|(index, db)| {
+            let mut keys = self
+                .keys
+                .iter()
+                .filter(|(key_index, _, _)| *key_index == index)
+                .peekable();
+            if keys.peek().is_none() {
+                return false;
+            }
+            db.read().is_ok_and(|db| {
+                db.is_dirty(self.client)
+                    || keys.any(|(_, key, existed)| *existed && !db.exists(key))
+            })
+        })
+    }
+}
+
+/// Runs `f` while holding the write lock on the database once for its whole duration.
+/// The store is moved into a lock private to `f`, so the commands it runs keep taking
+/// their own locks as usual while every other client waits on the real one. The other
+/// databases are only read or written by `f` through their own locks.
+pub fn with_exclusive_access<R>(
+    db: &RwLock<MemDB<Data>>,
+    f: impl FnOnce(&RwLock<MemDB<Data>>) -> R,
+) -> Result<R> {
+    exclusively(|| {
+        let mut guard = db
+            .write()
+            .map_err(|_| Error::msg("Unable to acquire lock"))?;
+
+        let private = RwLock::new(std::mem::replace(&mut *guard, MemDB::new()));
+        let restore = Restore {
+            db,
+            guard: Some(guard),
+            private,
+        };
+        Ok(f(&restore.private))
+    })
+}
+
+// Restore moves the store back from the private lock into the real one once `f` is done,
+// even when it panicked, so the dataset outlives a failing command
+struct Restore<'a> {
+    db: &'a RwLock<MemDB<Data>>,
+    guard: Option<RwLockWriteGuard<'a, MemDB<Data>>>,
+    private: RwLock<MemDB<Data>>,
+}
+
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        let store = self
+            .private
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(mut guard) = self.guard.take() {
+            *guard = std::mem::replace(store, MemDB::new());
+        }
+        // Releasing the guard while unwinding poisons the lock, which would fail every later
+        // command on the database
+        self.db.clear_poison();
+    }
+}
+
+/// Runs `f` while no other exclusive section runs, so no database changes in the meantime
+pub fn exclusively<R>(f: impl FnOnce() -> R) -> R {
+    if IN_EXCLUSIVE_SECTION.get() {
+        return f();
+    }
+    let guard = EXCLUSIVE_ACCESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    run_exclusive(guard, f)
+}
+
+/// Like exclusively, but returns None right away when another exclusive section runs
+pub fn try_exclusively<R>(f: impl FnOnce() -> R) -> Option<R> {
+    if IN_EXCLUSIVE_SECTION.get() {
+        return Some(f());
+    }
+    let guard = match EXCLUSIVE_ACCESS.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return None,
+    };
+    Some(run_exclusive(guard, f))
+}
+
+fn run_exclusive<R>(_guard: MutexGuard<()>, f: impl FnOnce() -> R) -> R {
+    IN_EXCLUSIVE_SECTION.set(true);
+    // Cleared even if `f` panics, the thread goes on serving other clients
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            IN_EXCLUSIVE_SECTION.set(false);
+        }
+    }
+    let _reset = Reset;
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{AssertUnwindSafe, catch_unwind},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        aof,
+        resp::{
+            commands::structs::Value,
+            parser::{Request, parse_command},
+        },
+    };
+
+    fn string(value: &str) -> Data {
+        Data {
+            value: Value::String(value.as_bytes().to_vec()),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn store_survives_a_panicking_section() {
+        let db = RwLock::new(MemDB::with_index(3));
+        db.write().unwrap().set("kept".to_string(), string("1"));
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            with_exclusive_access(&db, |private| {
+                private
+                    .write()
+                    .unwrap()
+                    .set("written".to_string(), string("2"));
+                panic!("command failed");
+            })
+        }));
+        assert!(result.is_err());
+
+        let db = db.read().expect("lock is usable after the panic");
+        assert_eq!(db.index(), 3);
+        assert!(db.exists("kept"));
+        assert!(db.exists("written"));
+        assert!(!IN_EXCLUSIVE_SECTION.get());
+    }
+
+    #[test]
+    fn scripts_propagate_in_order_within_exec() {
+        let dir = std::env::temp_dir().join(format!("exec-propagation-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::new(6379, None);
+        config.persistence.set_dir(dir.to_str().unwrap());
+        config.aof.set_use_rdb_preamble(false);
+        config.aof.set_enabled(true);
+        config.aof.start(&config.databases.all(), &config).unwrap();
+        while config.aof.to_string().contains("aof_rewrite_in_progress:1") {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let argv = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        let command = |args: &[&str]| parse_command(Request::new(argv(args))).unwrap();
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["SET", "k", "1"]), Some(argv(&["SET", "k", "1"])));
+        let script = "return redis.call('set', KEYS[1], ARGV[1])";
+        transaction.queue(command(&["EVAL", script, "1", "k", "2"]), None);
+        transaction.queue_select("1".to_string());
+        transaction.queue(command(&["EVAL", script, "1", "j", "3"]), None);
+        let dbs = config.databases.all();
+        let (reply, selected) = transaction
+            .exec(&dbs, 0, &config, &WatchedKeys::new(1))
+            .unwrap();
+        assert_eq!(selected, 1);
+        assert_eq!(
+            reply.encode(),
+            b"*4\r\n+OK\r\n+OK\r\n+OK\r\n+OK\r\n".to_vec()
+        );
+
+        // The script writes follow the ones queued before them, in a single MULTI/EXEC
+        let incr = dir.join("appendonlydir/appendonly.aof.1.incr.aof");
+        let expected = aof::encode_commands(&[
+            argv(&["SELECT", "0"]),
+            argv(&["MULTI"]),
+            argv(&["SET", "k", "1"]),
+            argv(&["set", "k", "2"]),
+            argv(&["SELECT", "1"]),
+            argv(&["set", "j", "3"]),
+            argv(&["EXEC"]),
+        ]);
+        assert_eq!(std::fs::read(&incr).unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exec_runs_queued_selects_in_order() {
+        let config = Config::new(6379, None);
+        let argv = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        let command = |args: &[&str]| parse_command(Request::new(argv(args))).unwrap();
+        let mut transaction = Transaction::new();
+        transaction.queue(command(&["SET", "a", "1"]), Some(argv(&["SET", "a", "1"])));
+        transaction.queue_select("2".to_string());
+        transaction.queue(command(&["SET", "a", "2"]), Some(argv(&["SET", "a", "2"])));
+        transaction.queue(command(&["GET", "a"]), None);
+        // A SELECT which fails leaves the database as it was, like outside a transaction
+        transaction.queue_select("99".to_string());
+        transaction.queue(command(&["GET", "a"]), None);
+
+        let dbs = config.databases.all();
+        let (reply, selected) = transaction
+            .exec(&dbs, 0, &config, &WatchedKeys::new(1))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(reply.encode()).unwrap(),
+            "*6\r\n+OK\r\n+OK\r\n+OK\r\n$1\r\n2\r\n-ERR DB index is out of range\r\n$1\r\n2\r\n"
+        );
+        // The client stays on the database selected last
+        assert_eq!(selected, 2);
+        let value = |db: usize| match &dbs[db].read().unwrap().get("a").unwrap().unwrap().value {
+            Value::String(value) => value.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(value(0), b"1");
+        assert_eq!(value(2), b"2");
+    }
+
+    #[test]
+    fn exec_aborts_once_a_watched_key_changed() {
+        let config = Config::new(6379, None);
+        let dbs = config.databases.all();
+        let argv = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        let run = |db: usize, args: &[&str]| {
+            let command = parse_command(Request::new(argv(args))).unwrap();
+            command.execute(dbs[db], &config).unwrap();
+        };
+        // A transaction setting "done", run after watching `keys` and calling `between`
+        let exec = |db: usize, keys: &[&str], between: &dyn Fn()| {
+            let mut watched = WatchedKeys::new(1);
+            let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+            watched.watch(dbs[db], &keys).unwrap();
+            between();
+            let mut transaction = Transaction::new();
+            let set = argv(&["SET", "done", "1"]);
+            transaction.queue(parse_command(Request::new(set.clone())).unwrap(), Some(set));
+            let (reply, _) = transaction.exec(&dbs, db, &config, &watched).unwrap();
+            watched.clear(&dbs).unwrap();
+            let done = dbs[db].read().unwrap().exists("done");
+            run(db, &["DEL", "done"]);
+            (reply.encode(), done)
+        };
+        let aborted = (b"*-1\r\n".to_vec(), false);
+        let ran = (b"*1\r\n+OK\r\n".to_vec(), true);
+
+        assert_eq!(exec(0, &["k"], &|| {}), ran);
+        assert_eq!(exec(0, &["k"], &|| run(0, &["SET", "k", "v"])), aborted);
+        assert_eq!(exec(0, &["k"], &|| run(0, &["DEL", "k"])), aborted);
+        // Only a write to the watched key of that database counts,
+        assert_eq!(exec(0, &["k"], &|| run(0, &["SET", "other", "v"])), ran);
+        assert_eq!(exec(1, &["k"], &|| run(0, &["SET", "k", "v"])), ran);
+        // while FLUSHALL writes every key of every database
+        assert_eq!(exec(1, &["k"], &|| run(2, &["FLUSHALL"])), aborted);
+        // A watched key expiring before EXEC counts as well
+        run(0, &["SET", "k", "v", "PX", "20"]);
+        assert_eq!(
+            exec(0, &["k"], &|| std::thread::sleep(Duration::from_millis(50))),
+            aborted
+        );
+
+        // A command which couldn't be queued discards the transaction
+        let mut transaction = Transaction::new();
+        transaction.abort();
+        let result = transaction.exec(&dbs, 0, &config, &WatchedKeys::new(1));
+        assert!(result.is_err_and(|err| err.to_string().starts_with("EXECABORT")));
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+// Score wraps a f64 so it can be totally ordered inside the BTreeSet
+#[derive(Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// SortedSet keeps members ordered by (score, member) like the Redis zset type.
+// The HashMap gives O(1) score lookups while the BTreeSet serves range queries.
+#[derive(Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Inserts or updates the member, returning the previous score if it existed
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(old) = previous {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous
+    }
+
+    /// Returns every member in score order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Returns members with min <= score < max, in score order
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&str, f64)> {
+        self.ordered
+            .range((Score(min), String::new())..)
+            .take_while(move |(score, _)| score.0 < max)
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+}
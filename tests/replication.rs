@@ -0,0 +1,277 @@
+// Runs the server binary as a master, replicas and sentinels on local ports and talks to them
+// over RESP, the way clients and the other instances see it.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn text(&self) -> String {
+        match self {
+            Reply::Status(text) | Reply::Error(text) | Reply::Bulk(Some(text)) => text.clone(),
+            Reply::Integer(n) => n.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn items(&self) -> &[Reply] {
+        match self {
+            Reply::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(port: u16) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("server is listening");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        Client {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    fn cmd(&mut self, args: &[&str]) -> Reply {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.reader.get_mut().write_all(request.as_bytes()).unwrap();
+        self.read()
+    }
+
+    fn read(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Status(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "_" => Reply::Bulk(None),
+            "$" => {
+                let len: i64 = rest.parse().unwrap();
+                if len < 0 {
+                    return Reply::Bulk(None);
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut data).unwrap();
+                data.truncate(len as usize);
+                Reply::Bulk(Some(String::from_utf8(data).unwrap()))
+            }
+            "*" => {
+                let len: i64 = rest.parse().unwrap();
+                Reply::Array((0..len.max(0)).map(|_| self.read()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+// A server process, killed along with its directory once dropped
+struct Server {
+    port: u16,
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(args: &[&str]) -> Server {
+        let port = free_port();
+        let dir = std::env::temp_dir().join(format!("redis-test-{}-{}", std::process::id(), port));
+        std::fs::create_dir_all(&dir).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+            .args(["--port", &port.to_string(), "--dir", dir.to_str().unwrap()])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server starts");
+        let server = Server { port, child, dir };
+        wait_for("the server to listen", || {
+            TcpStream::connect(("127.0.0.1", port)).is_ok()
+        });
+        server
+    }
+
+    fn client(&self) -> Client {
+        Client::connect(self.port)
+    }
+
+    fn replica_of(&self) -> String {
+        format!("127.0.0.1 {}", self.port)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// The value of a field of INFO
+fn info_field(client: &mut Client, section: &str, field: &str) -> Option<String> {
+    let info = client.cmd(&["INFO", section]).text();
+    info.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+        .map(String::from)
+}
+
+#[test]
+fn replicas_follow_the_master() {
+    let master = Server::start(&[]);
+    let mut client = master.client();
+    client.cmd(&["SET", "before", "1"]);
+
+    let replicas = [
+        Server::start(&["--replicaof", &master.replica_of()]),
+        Server::start(&[
+            "--replicaof",
+            &master.replica_of(),
+            "--repl-diskless-sync",
+            "yes",
+            "--repl-diskless-sync-delay",
+            "0",
+        ]),
+    ];
+    wait_for("both replicas to connect", || {
+        info_field(&mut client, "replication", "connected_slaves").as_deref() == Some("2")
+    });
+
+    client.cmd(&["SET", "after", "2"]);
+    client.cmd(&["RPUSH", "list", "a", "b"]);
+    client.cmd(&["SELECT", "1"]);
+    client.cmd(&["SET", "other", "3"]);
+    assert_eq!(client.cmd(&["WAIT", "2", "5000"]), Reply::Integer(2));
+
+    for replica in &replicas {
+        let mut client = replica.client();
+        assert_eq!(client.cmd(&["GET", "before"]).text(), "1");
+        assert_eq!(client.cmd(&["GET", "after"]).text(), "2");
+        assert_eq!(client.cmd(&["LRANGE", "list", "0", "-1"]).items().len(), 2);
+        client.cmd(&["SELECT", "1"]);
+        assert_eq!(client.cmd(&["GET", "other"]).text(), "3");
+        assert!(matches!(
+            client.cmd(&["SET", "x", "1"]),
+            Reply::Error(err) if err.starts_with("READONLY")
+        ));
+    }
+}
+
+#[test]
+fn sentinels_fail_over_a_dead_master() {
+    let master = Server::start(&[]);
+    let replicas = [
+        Server::start(&["--replicaof", &master.replica_of()]),
+        Server::start(&["--replicaof", &master.replica_of()]),
+    ];
+    let mut client = master.client();
+    wait_for("both replicas to connect", || {
+        info_field(&mut client, "replication", "connected_slaves").as_deref() == Some("2")
+    });
+    client.cmd(&["SET", "key", "value"]);
+    assert_eq!(client.cmd(&["WAIT", "2", "5000"]), Reply::Integer(2));
+
+    let monitor = format!("mymaster 127.0.0.1 {} 2", master.port);
+    let sentinels: Vec<Server> = (0..3)
+        .map(|_| {
+            Server::start(&[
+                "--sentinel",
+                "--sentinel-monitor",
+                &monitor,
+                "--sentinel-set",
+                "mymaster down-after-milliseconds 1000",
+                "--sentinel-set",
+                "mymaster failover-timeout 5000",
+            ])
+        })
+        .collect();
+    for sentinel in &sentinels {
+        let mut client = sentinel.client();
+        wait_for("the sentinels to find the replicas and each other", || {
+            client
+                .cmd(&["SENTINEL", "REPLICAS", "mymaster"])
+                .items()
+                .len()
+                == 2
+                && client
+                    .cmd(&["SENTINEL", "SENTINELS", "mymaster"])
+                    .items()
+                    .len()
+                    == 2
+        });
+    }
+
+    let master_port = master.port;
+    drop(master);
+
+    // Every sentinel ends up pointing at the same promoted replica
+    let mut promoted = None;
+    for sentinel in &sentinels {
+        let mut client = sentinel.client();
+        wait_for("the failover", || {
+            let addr = client.cmd(&["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"]);
+            let port: u16 = addr.items()[1].text().parse().unwrap();
+            if port == master_port {
+                return false;
+            }
+            assert!(promoted.is_none_or(|promoted| promoted == port));
+            promoted = Some(port);
+            true
+        });
+    }
+    let promoted = promoted.unwrap();
+    let (new_master, other) = if replicas[0].port == promoted {
+        (&replicas[0], &replicas[1])
+    } else {
+        (&replicas[1], &replicas[0])
+    };
+
+    let mut client = new_master.client();
+    assert_eq!(
+        info_field(&mut client, "replication", "role").as_deref(),
+        Some("master")
+    );
+    assert_eq!(client.cmd(&["GET", "key"]).text(), "value");
+    // The other replica is pointed at the new master and gets its writes
+    wait_for("the other replica to follow the new master", || {
+        client.cmd(&["SET", "promoted", "yes"]);
+        other.client().cmd(&["GET", "promoted"]).text() == "yes"
+    });
+}